@@ -0,0 +1,41 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use proto_hackers::fuzzing;
+
+#[derive(Arbitrary, Debug)]
+enum CipherOp {
+    Reversebits,
+    Xor(u8),
+    Xorpos,
+    Add(u8),
+    Addpos,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    ops: Vec<CipherOp>,
+    raw_spec: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+fn to_spec(ops: &[CipherOp]) -> Vec<u8> {
+    let mut spec = Vec::new();
+    for op in ops {
+        match op {
+            CipherOp::Reversebits => spec.push(0x01),
+            CipherOp::Xor(n) => spec.extend([0x02, *n]),
+            CipherOp::Xorpos => spec.push(0x03),
+            CipherOp::Add(n) => spec.extend([0x04, *n]),
+            CipherOp::Addpos => spec.push(0x05),
+        }
+    }
+    spec.push(0x00);
+    spec
+}
+
+fuzz_target!(|input: Input| {
+    fuzzing::cipher(&input.raw_spec, &input.payload);
+    fuzzing::cipher(&to_spec(&input.ops), &input.payload);
+});
@@ -0,0 +1,40 @@
+#![no_main]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libfuzzer_sys::fuzz_target;
+use proto_hackers::fuzzing::{self, PESTCONTROL_MAX_MESSAGE_LEN};
+
+// Decoded messages hold a few times more bytes than their wire format
+const MAX_ALLOCATION: usize = 8 * PESTCONTROL_MAX_MESSAGE_LEN;
+
+struct TrackingAllocator;
+
+static LARGEST_ALLOCATION: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LARGEST_ALLOCATION.fetch_max(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        LARGEST_ALLOCATION.fetch_max(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+fuzz_target!(|data: &[u8]| {
+    LARGEST_ALLOCATION.store(0, Ordering::Relaxed);
+    fuzzing::pestcontrol(data);
+    let largest = LARGEST_ALLOCATION.load(Ordering::Relaxed);
+    assert!(largest <= MAX_ALLOCATION, "allocated {largest} bytes");
+});
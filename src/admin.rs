@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use serde_json::{Map, Value, json};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::utils::FrameReader;
+use crate::{Config, ProtoError, RunningServer, Server, ServerType};
+
+// Commands are a few words, anything longer is not worth buffering
+const MAX_COMMAND_LEN: usize = 1024;
+
+// What the admin socket acts on. When it owns the running challenge, it can
+// also stop it and start another one on the same address
+pub struct Admin {
+    current: Mutex<Current>,
+    config: Option<Config>,
+}
+
+struct Current {
+    server: ServerType,
+    running: Option<RunningServer>,
+}
+
+impl Admin {
+    pub fn new(server: ServerType) -> Self {
+        Self {
+            current: Mutex::new(Current {
+                server,
+                running: None,
+            }),
+            config: None,
+        }
+    }
+
+    // Challenges switched to are built from `config`
+    pub fn switchable(running: RunningServer, config: Config) -> Self {
+        Self {
+            current: Mutex::new(Current {
+                server: running.server.clone(),
+                running: Some(running),
+            }),
+            config: Some(config),
+        }
+    }
+
+    // Connections accepted by the previous challenge are served until they
+    // close, so that long-lived upstream connections are kept
+    async fn switch(&self, part: u8) -> Result<Value, ProtoError> {
+        let Some(config) = &self.config else {
+            return Err("challenge switching not available".into());
+        };
+        let server = Server::with_config(part, config)?;
+        let mut current = self.current.lock().await;
+        let Some(running) = current.running.take() else {
+            return Err("no challenge running".into());
+        };
+        let addr = running.bind_addr.clone();
+        running.shutdown().await;
+        let running = match server.start(&addr).await {
+            Ok(running) => running,
+            Err(err) => {
+                tracing::error!("could not start challenge {part} on {addr}: {err}");
+                return Err("could not start the challenge, none is running".into());
+            }
+        };
+        tracing::info!("switched to challenge {part} on {addr}");
+        current.server = running.server.clone();
+        current.running = Some(running);
+        Ok(json!({"challenge": part, "addr": addr}))
+    }
+}
+
+// Line-based debug socket, each command is answered with one JSON line:
+// - `state` dumps the sizes of the server's internal collections
+// - `site <id>` dumps a pestcontrol site's targets, policies and connection
+// - `switch <challenge>` replaces the running challenge by another one
+async fn answer(admin: &Admin, line: &str) -> Value {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+    let server = admin.current.lock().await.server.clone();
+    match (command, args.as_slice()) {
+        ("state", _) => {
+            let sizes: Map<String, Value> = server
+                .state_sizes()
+                .await
+                .into_iter()
+                .map(|(name, size)| (name.to_owned(), json!(size)))
+                .collect();
+            Value::Object(sizes)
+        }
+        ("switch", [part]) => {
+            let Ok(part) = part.parse() else {
+                return json!({"error": "invalid challenge number"});
+            };
+            admin
+                .switch(part)
+                .await
+                .unwrap_or_else(|err| json!({"error": err.to_string()}))
+        }
+        _ => match server.admin_command(command, &args).await {
+            Ok(response) => response,
+            Err(err) => json!({"error": err.to_string()}),
+        },
+    }
+}
+
+async fn handle_connection(admin: Arc<Admin>, stream: TcpStream) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = FrameReader::new(reader).with_max_len(MAX_COMMAND_LEN);
+    while let Ok(Some(command)) = reader.read_line().await {
+        let response = answer(&admin, &String::from_utf8_lossy(&command)).await;
+        if writer
+            .write_all(format!("{response}\n").as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+pub async fn serve(listener: TcpListener, admin: Arc<Admin>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(handle_connection(Arc::clone(&admin), stream));
+    }
+}
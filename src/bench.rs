@@ -0,0 +1,290 @@
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+use crate::clients::{Camera, ChatClient, JobClient, MeansClient, PrimeClient};
+use crate::config::DEFAULT_PORT;
+use crate::get_ip;
+
+const CHAT_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct BenchConfig {
+    pub challenge: u8,
+    pub addr: String,
+    pub clients: usize,
+    pub duration: Duration,
+}
+
+impl BenchConfig {
+    // Parse `bench <challenge> [--addr ADDR] [--clients N] [--duration SECS]`
+    pub fn from_args() -> Result<Self, &'static str> {
+        let mut challenge = None;
+        let mut addr = None;
+        let mut clients = 10;
+        let mut duration = Duration::from_secs(10);
+
+        let mut args = env::args().skip(2);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--addr" => addr = Some(args.next().ok_or("missing option value")?),
+                "--clients" => {
+                    let value = args.next().ok_or("missing option value")?;
+                    clients = value.parse().or(Err("invalid number of clients"))?;
+                }
+                "--duration" => {
+                    let value = args.next().ok_or("missing option value")?;
+                    duration = Duration::from_secs(value.parse().or(Err("invalid duration"))?);
+                }
+                _ => challenge = Some(arg.parse().or(Err("parsing error"))?),
+            }
+        }
+
+        let addr = match addr {
+            Some(addr) => addr,
+            None => {
+                let ip = get_ip().or(Err("could not get ip address"))?;
+                format!("{ip}:{DEFAULT_PORT}")
+            }
+        };
+        Ok(Self {
+            challenge: challenge.ok_or("missing challenge number")?,
+            addr,
+            clients,
+            duration,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    operations: u64,
+    errors: u64,
+    latencies: Vec<Duration>,
+}
+
+impl Stats {
+    fn record(&mut self, latency: Duration) {
+        self.operations += 1;
+        self.latencies.push(latency);
+    }
+
+    async fn timed<T>(&mut self, operation: impl Future<Output = Option<T>>) -> Option<T> {
+        let start = Instant::now();
+        let result = operation.await;
+        match result {
+            Some(_) => self.record(start.elapsed()),
+            None => self.errors += 1,
+        }
+        result
+    }
+
+    fn merge(&mut self, other: Stats) {
+        self.operations += other.operations;
+        self.errors += other.errors;
+        self.latencies.extend(other.latencies);
+    }
+}
+
+pub struct Report {
+    clients: usize,
+    elapsed: Duration,
+    operations: u64,
+    errors: u64,
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    fn percentile(&self, percent: usize) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = (self.latencies.len() * percent / 100).min(self.latencies.len() - 1);
+        self.latencies[index]
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let throughput = self.operations as f64 / self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "{} clients, {} operations in {:.2?} ({throughput:.0} ops/s), {} errors",
+            self.clients, self.operations, self.elapsed, self.errors
+        )?;
+        write!(
+            f,
+            "latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.percentile(50),
+            self.percentile(90),
+            self.percentile(99),
+            self.latencies.last().copied().unwrap_or_default()
+        )
+    }
+}
+
+async fn bench_prime(addr: String, id: usize, deadline: Instant) -> Stats {
+    let mut stats = Stats::default();
+    let Ok(mut client) = PrimeClient::connect(addr).await else {
+        stats.errors += 1;
+        return stats;
+    };
+    let mut number = id as u64 * 1_000_003;
+    while Instant::now() < deadline {
+        if stats.timed(client.is_prime(number)).await.is_none() {
+            break;
+        }
+        number += 1;
+    }
+    stats
+}
+
+async fn bench_means(addr: String, id: usize, deadline: Instant) -> Stats {
+    let mut stats = Stats::default();
+    let Ok(mut client) = MeansClient::connect(addr).await else {
+        stats.errors += 1;
+        return stats;
+    };
+    let mut timestamp = 0;
+    while Instant::now() < deadline {
+        let insert = async { client.insert(timestamp, id as i32).await.ok() };
+        if stats.timed(insert).await.is_none() {
+            break;
+        }
+        if timestamp % 100 == 99 && stats.timed(client.query(0, timestamp)).await.is_none() {
+            break;
+        }
+        timestamp += 1;
+    }
+    stats
+}
+
+// Every client chats at a fixed pace, the latency is the time taken for a
+// message to be relayed to another participant. Messages carry the time left
+// before the shared deadline as a common clock between clients
+async fn bench_chat(addr: String, id: usize, deadline: Instant) -> Stats {
+    let mut stats = Stats::default();
+    let Ok(mut client) = ChatClient::join(addr, &format!("bench{id}")).await else {
+        stats.errors += 1;
+        return stats;
+    };
+    let mut interval = time::interval(CHAT_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = time::sleep_until(deadline) => break,
+            _ = interval.tick() => {
+                let remaining = deadline.saturating_duration_since(Instant::now()).as_micros();
+                if client.send(&format!("{id} {remaining}")).await.is_err() {
+                    stats.errors += 1;
+                    break;
+                }
+            }
+            msg = client.recv() => {
+                let Some(msg) = msg else {
+                    stats.errors += 1;
+                    break;
+                };
+                let Some((_, content)) = msg.split_once("] ") else {
+                    continue;
+                };
+                let sent_remaining = content
+                    .split_once(' ')
+                    .and_then(|(_, micros)| micros.parse().ok())
+                    .map(Duration::from_micros);
+                if let Some(sent_remaining) = sent_remaining {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    stats.record(sent_remaining.saturating_sub(remaining));
+                }
+            }
+        }
+    }
+    stats
+}
+
+// Pairs of cameras on the same road flood the server with plates
+async fn bench_speed(addr: String, id: usize, deadline: Instant) -> Stats {
+    let mut stats = Stats::default();
+    let road = (id / 2) as u16;
+    let mile = (id % 2) as u16;
+    let Ok(mut camera) = Camera::connect(addr, road, mile, 60).await else {
+        stats.errors += 1;
+        return stats;
+    };
+    let mut timestamp = mile as u32 * 30;
+    while Instant::now() < deadline {
+        let plate = format!("B{}", timestamp % 1000);
+        let send = async { camera.plate(&plate, timestamp).await.ok() };
+        if stats.timed(send).await.is_none() {
+            break;
+        }
+        timestamp += 86400;
+    }
+    stats
+}
+
+async fn bench_jobs(addr: String, id: usize, deadline: Instant) -> Stats {
+    let mut stats = Stats::default();
+    let Ok(mut client) = JobClient::connect(addr).await else {
+        stats.errors += 1;
+        return stats;
+    };
+    let queue = format!("bench{}", id % 4);
+    let mut priority = 0;
+    while Instant::now() < deadline {
+        let put = client.put(&queue, json!({"client": id}), priority);
+        if stats.timed(put).await.is_none() {
+            break;
+        }
+        let Some(job) = stats.timed(client.get(&[&queue], false)).await else {
+            break;
+        };
+        if let Some(job) = job {
+            if stats.timed(client.delete(job.id)).await.is_none() {
+                break;
+            }
+        }
+        priority += 1;
+    }
+    stats
+}
+
+fn spawn_client(challenge: u8, addr: String, id: usize, deadline: Instant) -> JoinHandle<Stats> {
+    match challenge {
+        1 => tokio::spawn(bench_prime(addr, id, deadline)),
+        2 => tokio::spawn(bench_means(addr, id, deadline)),
+        3 => tokio::spawn(bench_chat(addr, id, deadline)),
+        6 => tokio::spawn(bench_speed(addr, id, deadline)),
+        9 => tokio::spawn(bench_jobs(addr, id, deadline)),
+        _ => unreachable!(),
+    }
+}
+
+pub async fn run(config: &BenchConfig) -> Result<Report, &'static str> {
+    if ![1, 2, 3, 6, 9].contains(&config.challenge) {
+        return Err("no bench scenario for this challenge");
+    }
+
+    let start = Instant::now();
+    let deadline = start + config.duration;
+    let handles: Vec<_> = (0..config.clients)
+        .map(|id| spawn_client(config.challenge, config.addr.clone(), id, deadline))
+        .collect();
+
+    let mut stats = Stats::default();
+    for handle in handles {
+        stats.merge(handle.await.or(Err("bench client panicked"))?);
+    }
+    stats.latencies.sort_unstable();
+
+    Ok(Report {
+        clients: config.clients,
+        elapsed: start.elapsed(),
+        operations: stats.operations,
+        errors: stats.errors,
+        latencies: stats.latencies,
+    })
+}
@@ -0,0 +1,88 @@
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, process};
+
+use proto_hackers::MockAuthority;
+use tokio::net::TcpListener;
+
+const DEFAULT_ADDR: &str = "0.0.0.0:12234";
+
+type Targets = Vec<(String, u32, u32)>;
+
+// Parse `species:min:max,species:min:max`
+fn parse_targets(value: &str) -> Result<Targets, &'static str> {
+    value
+        .split(',')
+        .filter(|target| !target.is_empty())
+        .map(|target| {
+            let mut fields = target.split(':');
+            let (Some(species), Some(min), Some(max), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err("invalid target, expected species:min:max");
+            };
+            let min = min.parse().or(Err("invalid target minimum"))?;
+            let max = max.parse().or(Err("invalid target maximum"))?;
+            Ok((species.to_owned(), min, max))
+        })
+        .collect()
+}
+
+fn as_refs(targets: &Targets) -> Vec<(&str, u32, u32)> {
+    targets
+        .iter()
+        .map(|(species, min, max)| (species.as_str(), *min, *max))
+        .collect()
+}
+
+// Parse `authority-sim [--listen ADDR] [--site SITE=TARGETS]... [--default
+// TARGETS] [--latency MS] [--error-rate P] [--seed N] [--first-policy-id N]`
+fn from_args() -> Result<(String, MockAuthority), &'static str> {
+    let mut addr = DEFAULT_ADDR.to_owned();
+    let mut authority = MockAuthority::new();
+    let mut error_rate = 0.0;
+    let mut seed = 0;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or("missing option value")?;
+        match arg.as_str() {
+            "--listen" => addr = value,
+            "--site" => {
+                let (site, targets) = value.split_once('=').ok_or("invalid site")?;
+                let site = site.parse().or(Err("invalid site number"))?;
+                authority = authority.with_site(site, &as_refs(&parse_targets(targets)?));
+            }
+            "--default" => {
+                authority = authority.with_default_targets(&as_refs(&parse_targets(&value)?));
+            }
+            "--latency" => {
+                let latency = value.parse().or(Err("invalid latency"))?;
+                authority = authority.with_latency(Duration::from_millis(latency));
+            }
+            "--error-rate" => error_rate = value.parse().or(Err("invalid error rate"))?,
+            "--seed" => seed = value.parse().or(Err("invalid seed"))?,
+            "--first-policy-id" => {
+                let id = value.parse().or(Err("invalid policy id"))?;
+                authority = authority.with_first_policy_id(id);
+            }
+            _ => return Err("unknown option"),
+        }
+    }
+    Ok((addr, authority.with_errors(error_rate, seed)))
+}
+
+#[tokio::main]
+async fn main() {
+    let (addr, authority) = from_args().unwrap_or_else(|err_msg| {
+        println!("Error in argument: {err_msg}");
+        process::exit(1);
+    });
+
+    let listener = TcpListener::bind(&addr).await.unwrap_or_else(|err| {
+        println!("Could not listen on {addr}: {err}");
+        process::exit(1);
+    });
+    println!("Authority simulator listening on {addr}");
+    Arc::new(authority).run(listener).await;
+}
@@ -0,0 +1,186 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Raw IP packets, the version is read from the first nibble
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const MAX_SEGMENT_SIZE: usize = 1460;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+// Writes the traffic seen by the framework to a pcap file. Only payloads are
+// known, so IP, TCP and UDP headers are synthesized around them: each TCP
+// connection gets a handshake, sequence numbers following the data and a FIN
+// from both sides when it ends
+pub struct Capture {
+    file: Mutex<File>,
+}
+
+impl Capture {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::with_capacity(24);
+        header.extend(0xa1b2c3d4u32.to_le_bytes());
+        header.extend(2u16.to_le_bytes());
+        header.extend(4u16.to_le_bytes());
+        header.extend(0i32.to_le_bytes());
+        header.extend(0u32.to_le_bytes());
+        header.extend(SNAPLEN.to_le_bytes());
+        header.extend(LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn write_packet(&self, packet: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend((timestamp.as_secs() as u32).to_le_bytes());
+        record.extend(timestamp.subsec_micros().to_le_bytes());
+        record.extend((packet.len() as u32).to_le_bytes());
+        record.extend((packet.len() as u32).to_le_bytes());
+        record.extend(packet);
+
+        // Write whole records at once so that concurrent connections don't
+        // interleave, and the file stays readable if the server crashes
+        if let Err(err) = self.file.lock().unwrap().write_all(&record) {
+            tracing::warn!("could not write capture: {err}");
+        }
+    }
+
+    pub fn udp_datagram(&self, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+        let mut segment = Vec::with_capacity(8 + data.len());
+        segment.extend(src.port().to_be_bytes());
+        segment.extend(dst.port().to_be_bytes());
+        segment.extend(((8 + data.len()) as u16).to_be_bytes());
+        segment.extend(0u16.to_be_bytes());
+        segment.extend(data);
+        self.write_packet(&ip_packet(src.ip(), dst.ip(), PROTO_UDP, &segment));
+    }
+
+    pub fn tcp_flow(self: &Arc<Self>, client: SocketAddr, server: SocketAddr) -> TcpFlow {
+        let mut flow = TcpFlow {
+            capture: Arc::clone(self),
+            client,
+            server,
+            client_seq: 0,
+            server_seq: 0,
+        };
+        flow.segment(true, TCP_SYN, &[]);
+        flow.client_seq += 1;
+        flow.segment(false, TCP_SYN | TCP_ACK, &[]);
+        flow.server_seq += 1;
+        flow.segment(true, TCP_ACK, &[]);
+        flow
+    }
+}
+
+// A single TCP connection in the capture, closed when dropped
+pub struct TcpFlow {
+    capture: Arc<Capture>,
+    client: SocketAddr,
+    server: SocketAddr,
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl TcpFlow {
+    pub fn data(&mut self, from_client: bool, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT_SIZE) {
+            self.segment(from_client, TCP_PSH | TCP_ACK, chunk);
+            let seq = match from_client {
+                true => &mut self.client_seq,
+                false => &mut self.server_seq,
+            };
+            *seq = seq.wrapping_add(chunk.len() as u32);
+        }
+    }
+
+    fn segment(&self, from_client: bool, flags: u8, data: &[u8]) {
+        let (src, dst, seq, ack) = match from_client {
+            true => (self.client, self.server, self.client_seq, self.server_seq),
+            false => (self.server, self.client, self.server_seq, self.client_seq),
+        };
+        let ack = if flags & TCP_ACK != 0 { ack } else { 0 };
+
+        let mut segment = Vec::with_capacity(20 + data.len());
+        segment.extend(src.port().to_be_bytes());
+        segment.extend(dst.port().to_be_bytes());
+        segment.extend(seq.to_be_bytes());
+        segment.extend(ack.to_be_bytes());
+        segment.push(5 << 4);
+        segment.push(flags);
+        segment.extend(u16::MAX.to_be_bytes());
+        segment.extend(0u32.to_be_bytes());
+        segment.extend(data);
+        self.capture
+            .write_packet(&ip_packet(src.ip(), dst.ip(), PROTO_TCP, &segment));
+    }
+}
+
+impl Drop for TcpFlow {
+    fn drop(&mut self) {
+        self.segment(true, TCP_FIN | TCP_ACK, &[]);
+        self.client_seq = self.client_seq.wrapping_add(1);
+        self.segment(false, TCP_FIN | TCP_ACK, &[]);
+        self.server_seq = self.server_seq.wrapping_add(1);
+        self.segment(true, TCP_ACK, &[]);
+    }
+}
+
+fn ip_packet(src: IpAddr, dst: IpAddr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut packet = Vec::with_capacity(20 + payload.len());
+            packet.extend([0x45, 0]);
+            packet.extend(((20 + payload.len()) as u16).to_be_bytes());
+            packet.extend([0, 0, 0x40, 0, 64, protocol, 0, 0]);
+            packet.extend(src.octets());
+            packet.extend(dst.octets());
+            let checksum = ipv4_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend(payload);
+            packet
+        }
+        // Mixed families only happen on dual-stack sockets, fall back to
+        // IPv4-mapped addresses
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            let mut packet = Vec::with_capacity(40 + payload.len());
+            packet.extend([0x60, 0, 0, 0]);
+            packet.extend((payload.len() as u16).to_be_bytes());
+            packet.extend([protocol, 64]);
+            packet.extend(to_v6(src).octets());
+            packet.extend(to_v6(dst).octets());
+            packet.extend(payload);
+            packet
+        }
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
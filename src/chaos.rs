@@ -0,0 +1,137 @@
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+
+use crate::config::ChaosConfig;
+use crate::utils::{AsyncStream, DatagramSocket, Rng};
+
+// Time for a lost TCP segment to be retransmitted, about the minimum RTO
+const RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
+// Chunks in flight in each direction of a connection before reads stall
+const QUEUE_SIZE: usize = 1024;
+
+// Draws the fate of the data going through, from a single generator so that a
+// run can be replayed from its seed, as long as the traffic is the same
+pub struct Chaos {
+    config: ChaosConfig,
+    rng: Mutex<Rng>,
+}
+
+impl Chaos {
+    pub fn new(config: &ChaosConfig) -> Option<Self> {
+        let active = !config.latency.is_zero()
+            || !config.jitter.is_zero()
+            || config.loss > 0.0
+            || config.reorder > 0.0;
+        if !active {
+            return None;
+        }
+        let seed = config.seed.unwrap_or_else(|| Rng::from_time().next_u64());
+        tracing::info!("simulating network faults with seed {seed}");
+        Some(Self {
+            config: *config,
+            rng: Mutex::new(Rng::new(seed)),
+        })
+    }
+
+    // How long a datagram takes to arrive, if it ever does
+    pub fn datagram_delay(&self) -> Option<Duration> {
+        let mut rng = self.rng.lock().unwrap();
+        if rng.chance(self.config.loss) {
+            return None;
+        }
+        let mut delay = self.config.latency + self.config.jitter.mul_f64(rng.next_f64());
+        if rng.chance(self.config.reorder) {
+            delay += self.config.jitter + Duration::from_millis(10);
+        }
+        Some(delay)
+    }
+
+    fn segment_delay(&self) -> Duration {
+        let mut rng = self.rng.lock().unwrap();
+        let mut delay = self.config.latency + self.config.jitter.mul_f64(rng.next_f64());
+        if rng.chance(self.config.loss) {
+            delay += RETRANSMIT_DELAY;
+        }
+        delay
+    }
+
+    // Shuttle bytes between the client and the pipe to the server, delayed in
+    // both directions. Nothing is lost or reordered, as over TCP
+    pub async fn relay(self: Arc<Self>, client: impl AsyncStream + 'static, server: DuplexStream) {
+        let (client_reader, client_writer) = io::split(client);
+        let (server_reader, server_writer) = io::split(server);
+        tokio::join!(
+            delay_line(&self, client_reader, server_writer),
+            delay_line(&self, server_reader, client_writer),
+        );
+    }
+
+    pub fn wrap_socket(self: Arc<Self>, socket: Arc<dyn DatagramSocket>) -> ChaoticSocket {
+        ChaoticSocket {
+            socket,
+            chaos: self,
+        }
+    }
+}
+
+// Chunks are delivered in order, each one once its own delay has passed
+async fn delay_line(
+    chaos: &Chaos,
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+) {
+    let (sender, mut receiver) = mpsc::channel(QUEUE_SIZE);
+    let forward = async move {
+        let mut buffer = [0; 1024];
+        let mut deliver_at = Instant::now();
+        while let Ok(n @ 1..) = reader.read(&mut buffer).await {
+            deliver_at = deliver_at.max(Instant::now() + chaos.segment_delay());
+            let chunk = buffer[..n].to_vec();
+            if sender.send((deliver_at, chunk)).await.is_err() {
+                break;
+            }
+        }
+    };
+    let mut deliver = pin!(async move {
+        while let Some((deliver_at, data)) = receiver.recv().await {
+            time::sleep_until(deliver_at).await;
+            if writer.write_all(&data).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+    // Once the other side is gone, there is no point in reading any more
+    tokio::select! {
+        () = forward => deliver.await,
+        () = &mut deliver => {}
+    }
+}
+
+// Datagrams sent are lost or delivered later, in the background
+pub struct ChaoticSocket {
+    socket: Arc<dyn DatagramSocket>,
+    chaos: Arc<Chaos>,
+}
+
+#[async_trait]
+impl DatagramSocket for ChaoticSocket {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let Some(delay) = self.chaos.datagram_delay() else {
+            return Ok(buf.len());
+        };
+        let socket = Arc::clone(&self.socket);
+        let data = buf.to_vec();
+        tokio::spawn(async move {
+            time::sleep(delay).await;
+            let _ = socket.send_to(&data, addr).await;
+        });
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time;
+
+mod chat;
+mod jobs;
+mod kv;
+mod means;
+mod pestcontrol;
+mod prime;
+mod speed;
+mod vcs;
+
+pub use chat::ChatClient;
+pub use jobs::{Job, JobClient};
+pub use kv::KvClient;
+pub use means::MeansClient;
+pub use pestcontrol::SiteVisitor;
+pub use prime::PrimeClient;
+pub use speed::{Camera, Dispatcher, SpeedMessage, Ticket};
+pub use vcs::VcsClient;
+
+// Buffered TCP connection shared by all the protocol clients, receiving
+// methods return None on EOF, IO error or timeout
+pub struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    timeout: Option<Duration>,
+}
+
+impl Connection {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Self::from_stream(stream))
+    }
+
+    pub fn from_stream(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data).await
+    }
+
+    pub async fn send_line(&mut self, line: &str) -> io::Result<()> {
+        self.send(format!("{line}\n").as_bytes()).await
+    }
+
+    // Read more data into the buffer, returns the number of bytes read (0 on
+    // EOF or error) or None on timeout
+    async fn read_more(&mut self) -> Option<usize> {
+        let mut buf = [0; 1024];
+        let read = self.stream.read(&mut buf);
+        let result = match self.timeout {
+            Some(timeout) => time::timeout(timeout, read).await.ok()?,
+            None => read.await,
+        };
+        let n = result.unwrap_or(0);
+        self.buffer.extend_from_slice(&buf[..n]);
+        Some(n)
+    }
+
+    async fn fill_buffer(&mut self) -> bool {
+        self.read_more().await.is_some_and(|n| n > 0)
+    }
+
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.stream.shutdown().await
+    }
+
+    pub async fn recv_exact(&mut self, nb_bytes: usize) -> Option<Vec<u8>> {
+        while self.buffer.len() < nb_bytes {
+            if !self.fill_buffer().await {
+                return None;
+            }
+        }
+        Some(self.buffer.drain(..nb_bytes).collect())
+    }
+
+    pub async fn recv_line(&mut self) -> Option<String> {
+        loop {
+            if let Some(index) = self.buffer.iter().position(|&c| c == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=index).collect();
+                return Some(String::from_utf8_lossy(&line[..index]).into_owned());
+            }
+            if !self.fill_buffer().await {
+                return None;
+            }
+        }
+    }
+
+    pub async fn recv_u8(&mut self) -> Option<u8> {
+        Some(self.recv_exact(1).await?[0])
+    }
+
+    pub async fn recv_u16(&mut self) -> Option<u16> {
+        let bytes = self.recv_exact(2).await?;
+        Some(u16::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub async fn recv_u32(&mut self) -> Option<u32> {
+        let bytes = self.recv_exact(4).await?;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    // True if the peer closed the connection without sending more data
+    pub async fn is_closed(&mut self) -> bool {
+        self.buffer.is_empty() && self.read_more().await == Some(0)
+    }
+}
@@ -0,0 +1,56 @@
+use tokio::io;
+use tokio::net::ToSocketAddrs;
+
+use super::Connection;
+
+pub struct ChatClient {
+    connection: Connection,
+    members: Vec<String>,
+}
+
+impl ChatClient {
+    // Connect and join the room, the members already present are then
+    // available through `members`
+    pub async fn join(addr: impl ToSocketAddrs, name: &str) -> io::Result<Self> {
+        let mut connection = Connection::connect(addr).await?;
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        connection
+            .recv_line()
+            .await
+            .ok_or_else(|| invalid("no welcome message"))?;
+        connection.send_line(name).await?;
+        let room = connection
+            .recv_line()
+            .await
+            .ok_or_else(|| invalid("name rejected"))?;
+        let members = room
+            .strip_prefix("* The room contains ")
+            .ok_or_else(|| invalid("invalid room message"))?
+            .split(", ")
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect();
+
+        Ok(Self {
+            connection,
+            members,
+        })
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    pub async fn send(&mut self, msg: &str) -> io::Result<()> {
+        self.connection.send_line(msg).await
+    }
+
+    pub async fn recv(&mut self) -> Option<String> {
+        self.connection.recv_line().await
+    }
+}
@@ -0,0 +1,74 @@
+use serde_json::{Value, json};
+use tokio::io;
+use tokio::net::ToSocketAddrs;
+
+use super::Connection;
+
+#[derive(Debug, PartialEq)]
+pub struct Job {
+    pub id: u64,
+    pub queue: String,
+    pub priority: u64,
+    pub task: Value,
+}
+
+pub struct JobClient {
+    connection: Connection,
+}
+
+impl JobClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let connection = Connection::connect(addr).await?;
+        Ok(Self { connection })
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    pub async fn request(&mut self, request: &Value) -> Option<Value> {
+        self.connection.send_line(&request.to_string()).await.ok()?;
+        let response = self.connection.recv_line().await?;
+        serde_json::from_str(&response).ok()
+    }
+
+    async fn request_status(&mut self, request: &Value) -> Option<bool> {
+        let response = self.request(request).await?;
+        match response.get("status")?.as_str()? {
+            "ok" => Some(true),
+            "no-job" => Some(false),
+            _ => None,
+        }
+    }
+
+    pub async fn put(&mut self, queue: &str, task: Value, priority: u64) -> Option<u64> {
+        let request = json!({"request": "put", "queue": queue, "job": task, "pri": priority});
+        let response = self.request(&request).await?;
+        response.get("id")?.as_u64()
+    }
+
+    // Returns Some(None) when no job is available and `wait` is false
+    pub async fn get(&mut self, queues: &[&str], wait: bool) -> Option<Option<Job>> {
+        let request = json!({"request": "get", "queues": queues, "wait": wait});
+        let mut response = self.request(&request).await?;
+        if response.get("status")? == "no-job" {
+            return Some(None);
+        }
+        Some(Some(Job {
+            id: response.get("id")?.as_u64()?,
+            queue: response.get("queue")?.as_str()?.to_owned(),
+            priority: response.get("pri")?.as_u64()?,
+            task: response.get_mut("job")?.take(),
+        }))
+    }
+
+    pub async fn delete(&mut self, id: u64) -> Option<bool> {
+        self.request_status(&json!({"request": "delete", "id": id}))
+            .await
+    }
+
+    pub async fn abort(&mut self, id: u64) -> Option<bool> {
+        self.request_status(&json!({"request": "abort", "id": id}))
+            .await
+    }
+}
@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use tokio::io;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::time;
+
+pub struct KvClient {
+    socket: UdpSocket,
+    timeout: Duration,
+}
+
+impl KvClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+        Ok(Self {
+            socket,
+            timeout: Duration::from_secs(1),
+        })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub async fn insert(&self, key: &str, value: &str) -> io::Result<()> {
+        self.socket
+            .send(format!("{key}={value}").as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    // Datagrams may be lost, so None is returned if no answer came in time
+    pub async fn retrieve(&self, key: &str) -> Option<String> {
+        self.socket.send(key.as_bytes()).await.ok()?;
+        let mut buffer = [0; 1024];
+        let n = time::timeout(self.timeout, self.socket.recv(&mut buffer))
+            .await
+            .ok()?
+            .ok()?;
+        let response = String::from_utf8_lossy(&buffer[..n]);
+        let (_, value) = response.split_once('=')?;
+        Some(value.to_owned())
+    }
+}
@@ -0,0 +1,36 @@
+use tokio::io;
+use tokio::net::ToSocketAddrs;
+
+use super::Connection;
+
+pub struct MeansClient {
+    connection: Connection,
+}
+
+impl MeansClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let connection = Connection::connect(addr).await?;
+        Ok(Self { connection })
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    async fn send(&mut self, kind: u8, first: i32, second: i32) -> io::Result<()> {
+        let mut msg = vec![kind];
+        msg.extend(first.to_be_bytes());
+        msg.extend(second.to_be_bytes());
+        self.connection.send(&msg).await
+    }
+
+    pub async fn insert(&mut self, timestamp: i32, price: i32) -> io::Result<()> {
+        self.send(b'I', timestamp, price).await
+    }
+
+    pub async fn query(&mut self, min_time: i32, max_time: i32) -> Option<i32> {
+        self.send(b'Q', min_time, max_time).await.ok()?;
+        let mean = self.connection.recv_exact(4).await?;
+        Some(i32::from_be_bytes(mean.try_into().unwrap()))
+    }
+}
@@ -0,0 +1,69 @@
+use tokio::io;
+use tokio::net::ToSocketAddrs;
+
+use super::Connection;
+
+fn encode_str(data: &str) -> Vec<u8> {
+    let mut bytes = (data.len() as u32).to_be_bytes().to_vec();
+    bytes.extend(data.as_bytes());
+    bytes
+}
+
+fn encode_msg(msg_type: u8, data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![msg_type];
+    bytes.extend((data.len() as u32 + 6).to_be_bytes());
+    bytes.extend(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |acc, &v| acc.wrapping_add(v))
+        .wrapping_neg();
+    bytes.push(checksum);
+    bytes
+}
+
+pub struct SiteVisitor {
+    connection: Connection,
+}
+
+impl SiteVisitor {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let mut connection = Connection::connect(addr).await?;
+        let mut hello = encode_str("pestcontrol");
+        hello.extend(1u32.to_be_bytes());
+        let hello = encode_msg(0x50, &hello);
+        connection.send(&hello).await?;
+
+        match connection.recv_exact(hello.len()).await {
+            Some(response) if response == hello => Ok(Self { connection }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid Hello message",
+            )),
+        }
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    pub async fn visit(&mut self, site: u32, populations: &[(&str, u32)]) -> io::Result<()> {
+        let mut data = site.to_be_bytes().to_vec();
+        data.extend((populations.len() as u32).to_be_bytes());
+        for (species, count) in populations {
+            data.extend(encode_str(species));
+            data.extend(count.to_be_bytes());
+        }
+        self.connection.send(&encode_msg(0x58, &data)).await
+    }
+
+    // Visits are never acknowledged, the server only answers with errors
+    pub async fn recv_error(&mut self) -> Option<String> {
+        let msg_type = self.connection.recv_u8().await?;
+        let length = self.connection.recv_u32().await? as usize;
+        let data = self.connection.recv_exact(length.checked_sub(5)?).await?;
+        if msg_type != 0x51 || data.len() < 5 {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&data[4..data.len() - 1]).into_owned())
+    }
+}
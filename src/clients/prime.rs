@@ -0,0 +1,35 @@
+use serde_json::{Value, json};
+use tokio::io;
+use tokio::net::ToSocketAddrs;
+
+use super::Connection;
+
+pub struct PrimeClient {
+    connection: Connection,
+}
+
+impl PrimeClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let connection = Connection::connect(addr).await?;
+        Ok(Self { connection })
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    pub async fn request(&mut self, request: &Value) -> Option<Value> {
+        self.connection.send_line(&request.to_string()).await.ok()?;
+        let response = self.connection.recv_line().await?;
+        serde_json::from_str(&response).ok()
+    }
+
+    pub async fn is_prime(&mut self, number: impl Into<Value>) -> Option<bool> {
+        let request = json!({"method": "isPrime", "number": number.into()});
+        let response = self.request(&request).await?;
+        if response.get("method")? != "isPrime" {
+            return None;
+        }
+        response.get("prime")?.as_bool()
+    }
+}
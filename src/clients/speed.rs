@@ -0,0 +1,128 @@
+use tokio::io;
+use tokio::net::ToSocketAddrs;
+
+use super::Connection;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ticket {
+    pub plate: String,
+    pub road: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    pub timestamp2: u32,
+    pub speed: u16,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SpeedMessage {
+    Error(String),
+    Ticket(Ticket),
+    Heartbeat,
+}
+
+async fn recv_str(connection: &mut Connection) -> Option<String> {
+    let len = connection.recv_u8().await?;
+    let data = connection.recv_exact(len as usize).await?;
+    Some(String::from_utf8_lossy(&data).into_owned())
+}
+
+async fn recv_message(connection: &mut Connection) -> Option<SpeedMessage> {
+    match connection.recv_u8().await? {
+        0x10 => Some(SpeedMessage::Error(recv_str(connection).await?)),
+        0x21 => Some(SpeedMessage::Ticket(Ticket {
+            plate: recv_str(connection).await?,
+            road: connection.recv_u16().await?,
+            mile1: connection.recv_u16().await?,
+            timestamp1: connection.recv_u32().await?,
+            mile2: connection.recv_u16().await?,
+            timestamp2: connection.recv_u32().await?,
+            speed: connection.recv_u16().await?,
+        })),
+        0x41 => Some(SpeedMessage::Heartbeat),
+        _ => None,
+    }
+}
+
+async fn want_heartbeat(connection: &mut Connection, interval: u32) -> io::Result<()> {
+    let mut msg = vec![0x40];
+    msg.extend(interval.to_be_bytes());
+    connection.send(&msg).await
+}
+
+pub struct Camera {
+    connection: Connection,
+}
+
+impl Camera {
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        road: u16,
+        mile: u16,
+        limit: u16,
+    ) -> io::Result<Self> {
+        let mut connection = Connection::connect(addr).await?;
+        let mut msg = vec![0x80];
+        msg.extend(road.to_be_bytes());
+        msg.extend(mile.to_be_bytes());
+        msg.extend(limit.to_be_bytes());
+        connection.send(&msg).await?;
+        Ok(Self { connection })
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    pub async fn plate(&mut self, plate: &str, timestamp: u32) -> io::Result<()> {
+        let mut msg = vec![0x20, plate.len() as u8];
+        msg.extend(plate.as_bytes());
+        msg.extend(timestamp.to_be_bytes());
+        self.connection.send(&msg).await
+    }
+
+    pub async fn want_heartbeat(&mut self, interval: u32) -> io::Result<()> {
+        want_heartbeat(&mut self.connection, interval).await
+    }
+
+    pub async fn recv(&mut self) -> Option<SpeedMessage> {
+        recv_message(&mut self.connection).await
+    }
+}
+
+pub struct Dispatcher {
+    connection: Connection,
+}
+
+impl Dispatcher {
+    pub async fn connect(addr: impl ToSocketAddrs, roads: &[u16]) -> io::Result<Self> {
+        let mut connection = Connection::connect(addr).await?;
+        let mut msg = vec![0x81, roads.len() as u8];
+        msg.extend(roads.iter().flat_map(|road| road.to_be_bytes()));
+        connection.send(&msg).await?;
+        Ok(Self { connection })
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    pub async fn want_heartbeat(&mut self, interval: u32) -> io::Result<()> {
+        want_heartbeat(&mut self.connection, interval).await
+    }
+
+    pub async fn recv(&mut self) -> Option<SpeedMessage> {
+        recv_message(&mut self.connection).await
+    }
+
+    // Skip heartbeats until a ticket arrives
+    pub async fn recv_ticket(&mut self) -> Option<Ticket> {
+        loop {
+            match self.recv().await? {
+                SpeedMessage::Ticket(ticket) => return Some(ticket),
+                SpeedMessage::Heartbeat => continue,
+                SpeedMessage::Error(_) => return None,
+            }
+        }
+    }
+}
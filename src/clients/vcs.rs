@@ -0,0 +1,91 @@
+use tokio::io;
+use tokio::net::ToSocketAddrs;
+
+use super::Connection;
+
+pub struct VcsClient {
+    connection: Connection,
+}
+
+impl VcsClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let mut connection = Connection::connect(addr).await?;
+        match connection.recv_line().await.as_deref() {
+            Some("READY") => Ok(Self { connection }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no READY greeting",
+            )),
+        }
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    // Errors are returned as the server's "ERR ..." line. Not every error is
+    // followed by READY, so leftover ones are skipped here instead
+    async fn recv_status(&mut self) -> Option<Result<String, String>> {
+        let mut line = self.connection.recv_line().await?;
+        while line == "READY" {
+            line = self.connection.recv_line().await?;
+        }
+        match line.strip_prefix("OK ") {
+            Some(status) => Some(Ok(status.to_owned())),
+            None => Some(Err(line)),
+        }
+    }
+
+    async fn recv_ready(&mut self) -> Option<()> {
+        (self.connection.recv_line().await? == "READY").then_some(())
+    }
+
+    pub async fn put(&mut self, path: &str, data: &[u8]) -> Option<Result<usize, String>> {
+        let mut request = format!("PUT {path} {}\n", data.len()).into_bytes();
+        request.extend_from_slice(data);
+        self.connection.send(&request).await.ok()?;
+        let status = match self.recv_status().await? {
+            Ok(status) => status,
+            Err(err) => return Some(Err(err)),
+        };
+        let revision = status.strip_prefix('r')?.parse().ok()?;
+        self.recv_ready().await?;
+        Some(Ok(revision))
+    }
+
+    pub async fn get(
+        &mut self,
+        path: &str,
+        revision: Option<usize>,
+    ) -> Option<Result<Vec<u8>, String>> {
+        let request = match revision {
+            Some(revision) => format!("GET {path} r{revision}"),
+            None => format!("GET {path}"),
+        };
+        self.connection.send_line(&request).await.ok()?;
+        let length = match self.recv_status().await? {
+            Ok(status) => status.parse().ok()?,
+            Err(err) => return Some(Err(err)),
+        };
+        let data = self.connection.recv_exact(length).await?;
+        self.recv_ready().await?;
+        Some(Ok(data))
+    }
+
+    pub async fn list(&mut self, dir: &str) -> Option<Result<Vec<String>, String>> {
+        self.connection
+            .send_line(&format!("LIST {dir}"))
+            .await
+            .ok()?;
+        let nb_entries = match self.recv_status().await? {
+            Ok(status) => status.parse().ok()?,
+            Err(err) => return Some(Err(err)),
+        };
+        let mut entries = Vec::with_capacity(nb_entries);
+        for _ in 0..nb_entries {
+            entries.push(self.connection.recv_line().await?);
+        }
+        self.recv_ready().await?;
+        Some(Ok(entries))
+    }
+}
@@ -0,0 +1,675 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs};
+
+use toml::{Table, Value};
+
+use crate::ProtoError;
+use crate::utils::Connector;
+
+const AUTHORITY_ADDR: &str = "pestcontrol.protohackers.com:20547";
+const CHAT_ADDR: &str = "chat.protohackers.com:16963";
+pub const DEFAULT_PORT: u16 = 12233;
+const CONFIG_FILE: &str = "protohackers.toml";
+
+// Options taking a value on the command line
+const OPTIONS: &[&str] = &[
+    "--authority",
+    "--policy-cache",
+    "--record",
+    "--pcap",
+    "--upstream",
+    "--proxy-record",
+    "--metrics",
+    "--ticket-audit",
+    "--conn-events",
+    "--idle-timeout",
+    "--admin",
+    "--outbound-addr",
+    "--bind",
+    "--port",
+    "--challenge",
+    "--interface",
+    "--max-connections",
+    "--overflow",
+    "--tls-cert",
+    "--tls-key",
+    "--keepalive",
+    "--config",
+    "--log-level",
+    "--rate-limit",
+    "--rate-burst",
+    "--pidfile",
+    "--log-file",
+    "--chaos-latency",
+    "--chaos-jitter",
+    "--chaos-loss",
+    "--chaos-reorder",
+    "--chaos-seed",
+    "--stats-interval",
+    "--echo-mode",
+];
+
+// Options taking no value
+const FLAGS: &[&str] = &[
+    "--dashboard",
+    "--public-ip",
+    "--dual-stack",
+    "--reuse-addr",
+    "--nodelay",
+    "--daemon",
+    "--list",
+];
+
+#[derive(Clone)]
+pub struct PestControlConfig {
+    pub authority_addr: String,
+    pub authority_timeout: Duration,
+    pub targets_ttl: Option<Duration>,
+    pub audit_interval: Option<Duration>,
+    pub metrics_interval: Option<Duration>,
+    pub policy_cache: Option<PathBuf>,
+    pub connector: Connector,
+}
+
+impl Default for PestControlConfig {
+    fn default() -> Self {
+        Self {
+            authority_addr: AUTHORITY_ADDR.to_owned(),
+            authority_timeout: Duration::from_secs(10),
+            targets_ttl: None,
+            audit_interval: None,
+            metrics_interval: None,
+            policy_cache: None,
+            connector: Connector::default(),
+        }
+    }
+}
+
+impl PestControlConfig {
+    fn apply_toml(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            match key.as_str() {
+                "authority" => self.authority_addr = toml_str(key, value)?,
+                "authority_timeout" => self.authority_timeout = toml_secs(key, value)?,
+                "targets_ttl" => self.targets_ttl = Some(toml_secs(key, value)?),
+                "policy_cache" => self.policy_cache = Some(toml_str(key, value)?.into()),
+                _ => return Err(format!("unknown setting pestcontrol.{key}")),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct ProxyConfig {
+    pub upstream_addr: String,
+    pub record_path: Option<PathBuf>,
+    pub connector: Connector,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            upstream_addr: CHAT_ADDR.to_owned(),
+            record_path: None,
+            connector: Connector::default(),
+        }
+    }
+}
+
+impl ProxyConfig {
+    fn apply_toml(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            match key.as_str() {
+                "upstream" => self.upstream_addr = toml_str(key, value)?,
+                "record" => self.record_path = Some(toml_str(key, value)?.into()),
+                _ => return Err(format!("unknown setting proxy.{key}")),
+            }
+        }
+        Ok(())
+    }
+}
+
+// How the echo server sends back what it reads, written on the command line as
+// `plain`, `lines`, `delay:<millis>` or `limit:<bytes>`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EchoMode {
+    // Each chunk as soon as it is read
+    #[default]
+    Plain,
+    // Whole lines only, once their newline is read
+    Lines,
+    // Each chunk after a delay
+    Delayed(Duration),
+    // Up to that many bytes, after which the connection is closed
+    Limited(usize),
+}
+
+impl EchoMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.split_once(':') {
+            None if value == "plain" => Some(Self::Plain),
+            None if value == "lines" => Some(Self::Lines),
+            Some(("delay", millis)) => {
+                Some(Self::Delayed(Duration::from_millis(millis.parse().ok()?)))
+            }
+            Some(("limit", bytes)) => Some(Self::Limited(bytes.parse().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct EchoConfig {
+    pub mode: EchoMode,
+}
+
+impl EchoConfig {
+    fn apply_toml(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            match key.as_str() {
+                "mode" => {
+                    let mode = toml_str(key, value)?;
+                    self.mode = EchoMode::parse(&mode).ok_or("invalid echo mode")?;
+                }
+                _ => return Err(format!("unknown setting echo.{key}")),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SpeedConfig {
+    // JSON lines file every issued ticket is appended to
+    pub audit_path: Option<PathBuf>,
+}
+
+// What happens to the connections accepted beyond the limit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    // Left in the listen backlog until a connection closes
+    #[default]
+    Queue,
+    Reset,
+}
+
+impl Overflow {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "queue" => Some(Self::Queue),
+            "reset" => Some(Self::Reset),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionLimit {
+    // Connections served at once, unbounded when unset
+    pub max: Option<usize>,
+    pub overflow: Overflow,
+}
+
+// Network faults simulated between the clients and the server, in both
+// directions. Nothing is simulated while all of them are zero
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChaosConfig {
+    pub latency: Duration,
+    // Random delay on top of the latency, up to this much
+    pub jitter: Duration,
+    // Probability for a datagram to be lost, or for a TCP segment to wait for
+    // its retransmission
+    pub loss: f64,
+    // Probability for a datagram to be held back behind the following ones
+    pub reorder: f64,
+    // Random when unset, the one used is logged to replay a run
+    pub seed: Option<u64>,
+}
+
+// Connections and datagrams accepted from each peer address
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimit {
+    // A second, unlimited when unset
+    pub per_second: Option<f64>,
+    // Accepted at once after a quiet period, the rate per second by default
+    pub burst: Option<u32>,
+}
+
+// How to pick the address to listen on when none is given
+#[derive(Clone, Debug, Default)]
+pub struct IpPreference {
+    pub interface: Option<String>,
+    pub public: bool,
+}
+
+// Tuning of the listening sockets and of the connections they accept
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SocketOptions {
+    // Rebind right away after a restart, even with connections of the
+    // previous run in TIME_WAIT, instead of relying on the platform default
+    pub reuse_addr: bool,
+    pub nodelay: bool,
+    // Idle time before probing the peer, keepalive being off when unset
+    pub keepalive: Option<Duration>,
+}
+
+// TLS is terminated in front of the TCP servers when both PEM files are given
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+}
+
+// Running in the background, see `daemon::daemonize`. The log file is also
+// written to in the foreground
+#[derive(Clone, Default)]
+pub struct DaemonConfig {
+    pub enabled: bool,
+    pub pid_file: Option<PathBuf>,
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Default)]
+pub struct Config {
+    pub echo: EchoConfig,
+    pub pestcontrol: PestControlConfig,
+    pub proxy: ProxyConfig,
+    pub speed: SpeedConfig,
+    pub tls: TlsConfig,
+    pub daemon: DaemonConfig,
+    pub record_dir: Option<PathBuf>,
+    pub pcap_path: Option<PathBuf>,
+    pub metrics_addr: Option<String>,
+    pub admin_addr: Option<String>,
+    pub dashboard: bool,
+    pub events_path: Option<PathBuf>,
+    pub idle_timeout: Option<Duration>,
+    // Period of the log line with the sizes of the server's internal state
+    pub stats_interval: Option<Duration>,
+    pub connection_limit: ConnectionLimit,
+    pub rate_limit: RateLimit,
+    pub chaos: ChaosConfig,
+    pub socket_options: SocketOptions,
+    // Interface address to listen on, guessed from the machine when unset.
+    // `unix:<path>` listens on a UNIX socket instead, ignoring the port
+    pub bind_ip: Option<String>,
+    // Also listen on IPv6 when bound to an IPv4 address
+    pub dual_stack: bool,
+    pub ip_preference: IpPreference,
+    pub port: Option<u16>,
+    // Overrides the positional challenge argument
+    pub challenge: Option<u8>,
+    // Filter of the logged events, as in RUST_LOG
+    pub log_level: Option<String>,
+}
+
+fn get_duration(var: &str) -> Option<Duration> {
+    env::var(var)
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+}
+
+fn get_path(var: &str) -> Option<PathBuf> {
+    env::var(var).ok().map(PathBuf::from)
+}
+
+// Set variables override the config file, unset ones leave it be
+fn override_with<T>(value: &mut Option<T>, from_env: Option<T>) {
+    if from_env.is_some() {
+        *value = from_env;
+    }
+}
+
+// Given with --config or PROTOHACKERS_CONFIG, or found in the working directory
+fn config_file() -> Option<PathBuf> {
+    let path = env::args().skip_while(|arg| arg != "--config").nth(1);
+    if let Some(path) = path.or_else(|| env::var("PROTOHACKERS_CONFIG").ok()) {
+        return Some(path.into());
+    }
+    let path = PathBuf::from(CONFIG_FILE);
+    path.exists().then_some(path)
+}
+
+fn toml_str(key: &str, value: &Value) -> Result<String, String> {
+    let value = value
+        .as_str()
+        .ok_or_else(|| format!("{key} should be a string"))?;
+    Ok(value.to_owned())
+}
+
+fn toml_int<T: TryFrom<i64>>(key: &str, value: &Value) -> Result<T, String> {
+    value
+        .as_integer()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| format!("{key} should be a non-negative integer in range"))
+}
+
+fn toml_secs(key: &str, value: &Value) -> Result<Duration, String> {
+    toml_int(key, value).map(Duration::from_secs)
+}
+
+fn toml_table<'a>(key: &str, value: &'a Value) -> Result<&'a Table, String> {
+    value
+        .as_table()
+        .ok_or_else(|| format!("{key} should be a table"))
+}
+
+impl Config {
+    // Local address of the connections to the upstream chat and the authority
+    pub fn set_outbound_addr(&mut self, addr: IpAddr) {
+        self.proxy.connector.local_addr = Some(addr);
+        self.pestcontrol.connector.local_addr = Some(addr);
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ProtoError> {
+        fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| Self::from_toml(&text))
+            .map_err(|msg| ProtoError::Config(format!("{}: {msg}", path.display())))
+    }
+
+    fn from_toml(text: &str) -> Result<Self, String> {
+        let table: Table = text
+            .parse()
+            .map_err(|err: toml::de::Error| err.to_string())?;
+        let mut config = Self::default();
+        for (key, value) in &table {
+            match key.as_str() {
+                "bind" => config.bind_ip = Some(toml_str(key, value)?),
+                "port" => config.port = Some(toml_int(key, value)?),
+                "log_level" => config.log_level = Some(toml_str(key, value)?),
+                "idle_timeout" => config.idle_timeout = Some(toml_secs(key, value)?),
+                "stats_interval" => config.stats_interval = Some(toml_secs(key, value)?),
+                "echo" => config.echo.apply_toml(toml_table(key, value)?)?,
+                "proxy" => config.proxy.apply_toml(toml_table(key, value)?)?,
+                "pestcontrol" => config.pestcontrol.apply_toml(toml_table(key, value)?)?,
+                _ => return Err(format!("unknown setting {key}")),
+            }
+        }
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) {
+        let pestcontrol = &mut self.pestcontrol;
+        if let Ok(addr) = env::var("PESTCONTROL_AUTHORITY_ADDR") {
+            pestcontrol.authority_addr = addr;
+        }
+        if let Some(timeout) = get_duration("PESTCONTROL_AUTHORITY_TIMEOUT") {
+            pestcontrol.authority_timeout = timeout;
+        }
+        override_with(
+            &mut pestcontrol.targets_ttl,
+            get_duration("PESTCONTROL_TARGETS_TTL"),
+        );
+        let audit_interval = get_duration("PESTCONTROL_AUDIT_INTERVAL");
+        override_with(&mut pestcontrol.audit_interval, audit_interval);
+        let metrics_interval = get_duration("PESTCONTROL_METRICS_INTERVAL");
+        override_with(&mut pestcontrol.metrics_interval, metrics_interval);
+        override_with(
+            &mut pestcontrol.policy_cache,
+            get_path("PESTCONTROL_POLICY_CACHE"),
+        );
+        if let Some(mode) = env::var("ECHO_MODE").ok().and_then(|m| EchoMode::parse(&m)) {
+            self.echo.mode = mode;
+        }
+        if let Ok(addr) = env::var("PROXY_UPSTREAM_ADDR") {
+            self.proxy.upstream_addr = addr;
+        }
+        override_with(&mut self.proxy.record_path, get_path("PROXY_RECORD"));
+        override_with(&mut self.speed.audit_path, get_path("SPEED_TICKET_AUDIT"));
+        override_with(&mut self.tls.cert_path, get_path("TLS_CERT"));
+        override_with(&mut self.tls.key_path, get_path("TLS_KEY"));
+        override_with(&mut self.daemon.pid_file, get_path("PID_FILE"));
+        override_with(&mut self.daemon.log_file, get_path("LOG_FILE"));
+        override_with(&mut self.metrics_addr, env::var("METRICS_ADDR").ok());
+        override_with(&mut self.admin_addr, env::var("ADMIN_ADDR").ok());
+        override_with(&mut self.events_path, get_path("CONNECTION_EVENTS"));
+        override_with(&mut self.idle_timeout, get_duration("IDLE_TIMEOUT"));
+        override_with(&mut self.stats_interval, get_duration("STATS_INTERVAL"));
+        let max_connections = env::var("MAX_CONNECTIONS")
+            .ok()
+            .and_then(|m| m.parse().ok());
+        override_with(&mut self.connection_limit.max, max_connections);
+        let overflow = env::var("CONNECTION_OVERFLOW")
+            .ok()
+            .and_then(|o| Overflow::parse(&o));
+        if let Some(overflow) = overflow {
+            self.connection_limit.overflow = overflow;
+        }
+        let rate = env::var("RATE_LIMIT").ok().and_then(|r| r.parse().ok());
+        override_with(&mut self.rate_limit.per_second, rate);
+        let burst = env::var("RATE_BURST").ok().and_then(|b| b.parse().ok());
+        override_with(&mut self.rate_limit.burst, burst);
+        self.apply_chaos_env();
+        if let Some(addr) = env::var("OUTBOUND_ADDR").ok().and_then(|a| a.parse().ok()) {
+            self.set_outbound_addr(addr);
+        }
+        override_with(&mut self.bind_ip, env::var("BIND_ADDR").ok());
+        override_with(
+            &mut self.ip_preference.interface,
+            env::var("BIND_INTERFACE").ok(),
+        );
+        self.dual_stack |= env::var("DUAL_STACK").is_ok();
+        self.socket_options.reuse_addr |= env::var("REUSE_ADDR").is_ok();
+        self.socket_options.nodelay |= env::var("TCP_NODELAY").is_ok();
+        override_with(
+            &mut self.socket_options.keepalive,
+            get_duration("TCP_KEEPALIVE"),
+        );
+        override_with(
+            &mut self.port,
+            env::var("PORT").ok().and_then(|p| p.parse().ok()),
+        );
+        override_with(&mut self.log_level, env::var("RUST_LOG").ok());
+    }
+
+    fn apply_chaos_env(&mut self) {
+        let chaos = &mut self.chaos;
+        let millis = |var| env::var(var).ok()?.parse().ok().map(Duration::from_millis);
+        let probability = |var| env::var(var).ok()?.parse().ok();
+        if let Some(latency) = millis("CHAOS_LATENCY") {
+            chaos.latency = latency;
+        }
+        if let Some(jitter) = millis("CHAOS_JITTER") {
+            chaos.jitter = jitter;
+        }
+        if let Some(loss) = probability("CHAOS_LOSS") {
+            chaos.loss = loss;
+        }
+        if let Some(reorder) = probability("CHAOS_REORDER") {
+            chaos.reorder = reorder;
+        }
+        let seed = env::var("CHAOS_SEED").ok().and_then(|s| s.parse().ok());
+        override_with(&mut chaos.seed, seed);
+    }
+
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        config.apply_env();
+        config
+    }
+
+    // Settings of the config file, then of the environment, then of the
+    // command line, each overriding the previous ones
+    pub fn from_args() -> Result<Self, ProtoError> {
+        let mut config = match config_file() {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env();
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--dashboard" {
+                config.dashboard = true;
+            }
+            if arg == "--public-ip" {
+                config.ip_preference.public = true;
+            }
+            if arg == "--dual-stack" {
+                config.dual_stack = true;
+            }
+            if arg == "--reuse-addr" {
+                config.socket_options.reuse_addr = true;
+            }
+            if arg == "--nodelay" {
+                config.socket_options.nodelay = true;
+            }
+            if arg == "--daemon" {
+                config.daemon.enabled = true;
+            }
+            if !OPTIONS.contains(&arg.as_str()) {
+                continue;
+            }
+            let value = args.next().ok_or("missing option value")?;
+            match arg.as_str() {
+                "--authority" => config.pestcontrol.authority_addr = value,
+                "--policy-cache" => config.pestcontrol.policy_cache = Some(value.into()),
+                "--record" => config.record_dir = Some(value.into()),
+                "--pcap" => config.pcap_path = Some(value.into()),
+                "--upstream" => config.proxy.upstream_addr = value,
+                "--proxy-record" => config.proxy.record_path = Some(value.into()),
+                "--metrics" => config.metrics_addr = Some(value),
+                "--admin" => config.admin_addr = Some(value),
+                "--ticket-audit" => config.speed.audit_path = Some(value.into()),
+                "--tls-cert" => config.tls.cert_path = Some(value.into()),
+                "--tls-key" => config.tls.key_path = Some(value.into()),
+                "--conn-events" => config.events_path = Some(value.into()),
+                "--pidfile" => config.daemon.pid_file = Some(value.into()),
+                "--log-file" => config.daemon.log_file = Some(value.into()),
+                "--outbound-addr" => {
+                    let addr = value.parse().or(Err("invalid outbound address"))?;
+                    config.set_outbound_addr(addr);
+                }
+                "--config" => {}
+                "--log-level" => config.log_level = Some(value),
+                "--bind" => config.bind_ip = Some(value),
+                "--interface" => config.ip_preference.interface = Some(value),
+                "--port" => config.port = Some(value.parse().or(Err("invalid port"))?),
+                "--challenge" => {
+                    let part = crate::parse_challenge(&value).or(Err("invalid challenge"))?;
+                    config.challenge = Some(part);
+                }
+                "--max-connections" => {
+                    let max = value.parse().or(Err("invalid maximum connection count"))?;
+                    config.connection_limit.max = Some(max);
+                }
+                "--overflow" => {
+                    let overflow = Overflow::parse(&value).ok_or("invalid overflow policy")?;
+                    config.connection_limit.overflow = overflow;
+                }
+                "--keepalive" => {
+                    let secs = value.parse().or(Err("invalid keepalive time"))?;
+                    config.socket_options.keepalive = Some(Duration::from_secs(secs));
+                }
+                "--rate-limit" => {
+                    let rate = value.parse().or(Err("invalid rate limit"))?;
+                    config.rate_limit.per_second = Some(rate);
+                }
+                "--rate-burst" => {
+                    let burst = value.parse().or(Err("invalid rate burst"))?;
+                    config.rate_limit.burst = Some(burst);
+                }
+                "--chaos-latency" => {
+                    let millis = value.parse().or(Err("invalid chaos latency"))?;
+                    config.chaos.latency = Duration::from_millis(millis);
+                }
+                "--chaos-jitter" => {
+                    let millis = value.parse().or(Err("invalid chaos jitter"))?;
+                    config.chaos.jitter = Duration::from_millis(millis);
+                }
+                "--chaos-loss" => {
+                    config.chaos.loss = value.parse().or(Err("invalid chaos loss rate"))?;
+                }
+                "--chaos-reorder" => {
+                    config.chaos.reorder = value.parse().or(Err("invalid chaos reorder rate"))?;
+                }
+                "--chaos-seed" => {
+                    config.chaos.seed = Some(value.parse().or(Err("invalid chaos seed"))?);
+                }
+                "--idle-timeout" => {
+                    let secs = value.parse().or(Err("invalid idle timeout"))?;
+                    config.idle_timeout = Some(Duration::from_secs(secs));
+                }
+                "--stats-interval" => {
+                    let secs = value.parse().or(Err("invalid stats interval"))?;
+                    config.stats_interval = Some(Duration::from_secs(secs));
+                }
+                "--echo-mode" => {
+                    config.echo.mode = EchoMode::parse(&value).ok_or("invalid echo mode")?;
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(config)
+    }
+}
+
+pub fn positional_args() -> Vec<String> {
+    let mut positional_args = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        } else if !FLAGS.contains(&arg.as_str()) {
+            positional_args.push(arg);
+        }
+    }
+    positional_args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_points_servers_at_local_mocks() {
+        let config = Config::from_toml(
+            r#"
+            bind = "127.0.0.1"
+            port = 4000
+            log_level = "debug"
+            idle_timeout = 30
+            stats_interval = 60
+
+            [echo]
+            mode = "delay:250"
+
+            [proxy]
+            upstream = "127.0.0.1:16963"
+
+            [pestcontrol]
+            authority = "127.0.0.1:20547"
+            authority_timeout = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind_ip.as_deref(), Some("127.0.0.1"));
+        assert_eq!(config.port, Some(4000));
+        assert_eq!(config.log_level.as_deref(), Some("debug"));
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.stats_interval, Some(Duration::from_secs(60)));
+        assert_eq!(
+            config.echo.mode,
+            EchoMode::Delayed(Duration::from_millis(250))
+        );
+        assert_eq!(config.proxy.upstream_addr, "127.0.0.1:16963");
+        assert_eq!(config.pestcontrol.authority_addr, "127.0.0.1:20547");
+        assert_eq!(config.pestcontrol.authority_timeout, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn config_file_errors_name_the_setting() {
+        let err = |text| Config::from_toml(text).err().unwrap();
+        assert_eq!(err("prot = 4000"), "unknown setting prot");
+        assert_eq!(
+            err("port = 70000"),
+            "port should be a non-negative integer in range"
+        );
+        assert_eq!(err("[proxy]\nupstream = 1"), "upstream should be a string");
+        assert_eq!(err("[echo]\nmode = \"limit:-1\""), "invalid echo mode");
+    }
+}
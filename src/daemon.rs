@@ -0,0 +1,65 @@
+use std::path::Path;
+
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+
+use crate::ProtoError;
+use crate::config::DaemonConfig;
+
+// Forks to the background, the parent exiting right away. The runtime cannot
+// survive a fork, so this must be called before it is built. The working
+// directory is kept, for relative paths of the config to still make sense
+#[cfg(unix)]
+pub fn daemonize(config: &DaemonConfig) -> Result<(), ProtoError> {
+    let daemon_error = |err: &dyn std::fmt::Display| ProtoError::Daemon(err.to_string());
+    let working_dir = std::env::current_dir().map_err(|err| daemon_error(&err))?;
+    let mut daemon = daemonize::Daemonize::new().working_directory(working_dir);
+    if let Some(path) = &config.pid_file {
+        daemon = daemon.pid_file(path);
+    }
+    daemon.start().map_err(|err| daemon_error(&err))
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_config: &DaemonConfig) -> Result<(), ProtoError> {
+    Err(ProtoError::NotCompiled("daemon"))
+}
+
+// A new file is started every day, suffixed with its date
+pub fn log_file(path: &Path) -> Result<RollingFileAppender, ProtoError> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let Some(prefix) = path.file_name().and_then(|name| name.to_str()) else {
+        return Err(ProtoError::LogFile(path.display().to_string()));
+    };
+    RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(prefix)
+        .build(dir)
+        .map_err(|err| ProtoError::LogFile(format!("{}: {err}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn log_file_is_suffixed_with_date() {
+        let dir = env::temp_dir().join(format!("protohackers-logs-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut file = log_file(&dir.join("server.log")).unwrap();
+        file.write_all(b"started\n").unwrap();
+
+        let names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with("server.log."));
+    }
+}
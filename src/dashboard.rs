@@ -0,0 +1,170 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::crossterm::event::{self, Event, KeyCode};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::Constraint;
+use ratatui::style::{Style, Stylize};
+use ratatui::widgets::{Block, Row, Table};
+use ratatui::{Frame, Terminal};
+
+use crate::MAX_CHALLENGES;
+use crate::metrics::{self, Counter};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Counters of every challenge at some point in time
+struct Snapshot {
+    taken_at: Instant,
+    counters: Vec<[u64; 4]>,
+}
+
+impl Snapshot {
+    fn take() -> Self {
+        let counters = (0..MAX_CHALLENGES)
+            .map(|challenge| {
+                [
+                    Counter::Connections,
+                    Counter::Requests,
+                    Counter::ProtocolErrors,
+                    Counter::Disconnects,
+                ]
+                .map(|counter| metrics::value(challenge, counter))
+            })
+            .collect();
+        Self {
+            taken_at: Instant::now(),
+            counters,
+        }
+    }
+}
+
+// One row per challenge that saw any traffic, the running one always shown
+fn rows(part: u8, previous: &Snapshot, current: &Snapshot) -> Vec<Row<'static>> {
+    let elapsed = current
+        .taken_at
+        .duration_since(previous.taken_at)
+        .as_secs_f64()
+        .max(f64::EPSILON);
+    let rate = |before: u64, after: u64| format!("{:.1}", (after - before) as f64 / elapsed);
+
+    (0..MAX_CHALLENGES)
+        .zip(previous.counters.iter().zip(&current.counters))
+        .filter(|(challenge, (_, after))| *challenge == part || after.iter().any(|&v| v > 0))
+        .map(|(challenge, (before, after))| {
+            let [connections, requests, errors, disconnects] = *after;
+            Row::new([
+                format!("{challenge:02}"),
+                connections.saturating_sub(disconnects).to_string(),
+                rate(before[1], requests),
+                rate(before[2], errors),
+                requests.to_string(),
+                errors.to_string(),
+            ])
+        })
+        .collect()
+}
+
+fn render(frame: &mut Frame, part: u8, previous: &Snapshot, current: &Snapshot) {
+    let header = Row::new([
+        "Challenge",
+        "Connections",
+        "Requests/s",
+        "Errors/s",
+        "Requests",
+        "Errors",
+    ])
+    .style(Style::new().bold());
+    let widths = [Constraint::Length(12); 6];
+    let title = format!(" proto_hackers - server {part:02} (q to quit) ");
+    let table = Table::new(rows(part, previous, current), widths)
+        .header(header)
+        .block(Block::bordered().title(title));
+    frame.render_widget(table, frame.area());
+}
+
+fn draw_loop(terminal: &mut Terminal<impl Backend>, part: u8) -> io::Result<()> {
+    let mut previous = Snapshot::take();
+    let mut current = Snapshot::take();
+    loop {
+        terminal.draw(|frame| render(frame, part, &previous, &current))?;
+        if event::poll(REFRESH_INTERVAL)? {
+            if let Event::Key(key) = event::read()? {
+                if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    return Ok(());
+                }
+            }
+        }
+        if current.taken_at.elapsed() >= REFRESH_INTERVAL {
+            previous = current;
+            current = Snapshot::take();
+        }
+    }
+}
+
+// Drawn on stderr, so redirecting stdout keeps the server logs out of the way.
+// Blocks until the user quits
+pub fn run(part: u8) -> io::Result<()> {
+    terminal::enable_raw_mode()?;
+    execute!(io::stderr(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
+    let result = draw_loop(&mut terminal, part);
+    terminal::disable_raw_mode()?;
+    execute!(io::stderr(), LeaveAlternateScreen)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::backend::TestBackend;
+
+    use super::*;
+
+    fn snapshot(taken_at: Instant) -> Snapshot {
+        Snapshot {
+            taken_at,
+            counters: vec![[0; 4]; MAX_CHALLENGES as usize],
+        }
+    }
+
+    // Words of each screen line, borders left out
+    fn screen(part: u8, previous: &Snapshot, current: &Snapshot) -> Vec<Vec<String>> {
+        let mut terminal = Terminal::new(TestBackend::new(80, 6)).unwrap();
+        terminal
+            .draw(|frame| render(frame, part, previous, current))
+            .unwrap();
+        let buffer = terminal.backend().buffer();
+        let symbols: Vec<&str> = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        symbols
+            .chunks(buffer.area.width as usize)
+            .map(|line| {
+                line.concat()
+                    .replace('│', " ")
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn running_challenge_is_always_shown() {
+        let snapshot = snapshot(Instant::now());
+        let lines = screen(6, &snapshot, &snapshot);
+        assert!(lines[0].join(" ").contains("server 06"));
+        assert_eq!(lines[2], ["06", "0", "0.0", "0.0", "0", "0"]);
+        assert!(lines[3].is_empty());
+    }
+
+    #[test]
+    fn rates_are_computed_between_snapshots() {
+        let before = snapshot(Instant::now());
+        let mut after = snapshot(before.taken_at + Duration::from_secs(2));
+        after.counters[3] = [5, 10, 4, 2];
+        let lines = screen(6, &before, &after);
+        assert_eq!(lines[2], ["03", "3", "5.0", "2.0", "10", "4"]);
+        assert_eq!(lines[3], ["06", "0", "0.0", "0.0", "0", "0"]);
+    }
+}
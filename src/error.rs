@@ -0,0 +1,72 @@
+use thiserror::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum ProtoError {
+    #[error("invalid challenge number {0}")]
+    InvalidChallenge(u8),
+    #[error("could not parse challenge number {0:?}")]
+    ChallengeArg(String),
+    #[error("could not get ip address: {0}")]
+    Ip(String),
+    #[error("{0} support not compiled in")]
+    NotCompiled(&'static str),
+    #[error("challenge number {0} is taken by a built-in server")]
+    BuiltinChallenge(u8),
+    #[error("challenge number {0} is out of range")]
+    ChallengeOutOfRange(u8),
+    #[error("challenge number {0} is already registered")]
+    AlreadyRegistered(u8),
+    #[error("no challenge compiled in")]
+    NoChallengeCompiled,
+    #[error("could not set up TLS: {0}")]
+    Tls(String),
+    #[error("invalid config file {0}")]
+    Config(String),
+    #[error("could not run in the background: {0}")]
+    Daemon(String),
+    #[error("could not open log file {0}")]
+    LogFile(String),
+    #[error("unknown command")]
+    UnknownCommand,
+    // Invalid arguments of an admin command
+    #[error("{0}")]
+    Admin(&'static str),
+
+    // The peer closed the connection between two messages
+    #[error("connection closed")]
+    Closed,
+    #[error("not enough bytes to read {what} at offset {offset}")]
+    Truncated { what: &'static str, offset: usize },
+    #[error("unknown message type 0x{0:02x}")]
+    UnknownMessageType(u8),
+    #[error("additional data after {0} message")]
+    TrailingData(&'static str),
+    #[error("invalid message length {0}")]
+    InvalidLength(usize),
+    #[error("frame longer than {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("invalid UTF-8")]
+    InvalidUtf8,
+    #[error("invalid JSON: {0}")]
+    InvalidJson(String),
+    #[error("invalid checksum, frame sums to 0x{0:02x}")]
+    InvalidChecksum(u8),
+    #[error("invalid Hello message (protocol: {protocol}, version {version})")]
+    InvalidHello { protocol: String, version: u32 },
+    #[error("conflicting counts for species {0:?}")]
+    ConflictingCounts(String),
+    #[error("authority server unavailable for site {0}")]
+    AuthorityUnavailable(u32),
+    #[error("authority server timed out")]
+    AuthorityTimeout,
+
+    // Anything not worth a variant of its own
+    #[error("{0}")]
+    Message(&'static str),
+}
+
+impl From<&'static str> for ProtoError {
+    fn from(msg: &'static str) -> Self {
+        ProtoError::Message(msg)
+    }
+}
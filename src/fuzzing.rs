@@ -0,0 +1,25 @@
+use std::future::Future;
+
+use tokio::runtime::{Builder, Runtime};
+
+thread_local! {
+    static RUNTIME: Runtime = Builder::new_current_thread().enable_all().build().unwrap();
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    RUNTIME.with(|runtime| runtime.block_on(future))
+}
+
+pub fn speed_daemon(data: &[u8]) {
+    block_on(crate::server_06::parse_stream(data))
+}
+
+pub const PESTCONTROL_MAX_MESSAGE_LEN: usize = crate::server_11::MAX_MESSAGE_LEN;
+
+pub fn pestcontrol(data: &[u8]) {
+    crate::server_11::parse_frames(data)
+}
+
+pub fn cipher(spec: &[u8], payload: &[u8]) {
+    crate::server_08::check_cipher(spec, payload)
+}
@@ -1,134 +1,953 @@
-use std::net::SocketAddr;
-use std::process::Command;
+use std::any::Any;
+use std::fmt;
+use std::future::{self, Future};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::pin;
 use std::sync::Arc;
-use std::{env, fs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::Poll;
+use std::time::Duration;
 
 use async_trait::async_trait;
+use serde_json::Value;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{Semaphore, oneshot};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tokio::time;
+use tracing::{Instrument, Span};
 
-mod server_00;
-mod server_01;
-mod server_02;
-mod server_03;
-mod server_04;
-mod server_05;
-mod server_06;
-mod server_07;
-mod server_08;
-mod server_09;
-mod server_10;
-mod server_11;
+pub mod admin;
+pub mod bench;
+pub mod capture;
+pub mod chaos;
+pub mod clients;
+pub mod config;
+pub mod daemon;
+#[cfg(feature = "dashboard")]
+mod dashboard;
+mod error;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+pub mod lifecycle;
+pub mod metrics;
+mod plugins;
+pub mod ratelimit;
+pub mod selftest;
+#[cfg(feature = "tower")]
+pub mod service;
+pub mod soak;
+pub mod tap;
+pub mod testing;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod transcript;
+// Helpers shared by the challenges, some of which may be left out
+#[cfg_attr(not(feature = "all"), allow(dead_code))]
 mod utils;
 
-pub fn get_challenge() -> Result<u8, &'static str> {
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() >= 2 {
-        return args[1].parse().or(Err("parsing error"));
-    }
-
-    fs::read_dir("./src/")
-        .unwrap()
-        .filter_map(|entry| {
-            entry.ok().and_then(|e| {
-                e.file_type()
-                    .is_ok_and(|e| e.is_file())
-                    .then(|| {
-                        let file_name = e.file_name();
-                        let file_name = file_name.to_str()?;
-                        file_name
-                            .strip_prefix("server_")?
-                            .strip_suffix(".rs")?
-                            .parse::<u8>()
-                            .ok()
-                    })
-                    .flatten()
-            })
-        })
+use admin::Admin;
+pub use config::{
+    ChaosConfig, Config, ConnectionLimit, EchoConfig, EchoMode, IpPreference, Overflow, RateLimit,
+    SocketOptions,
+};
+pub use error::ProtoError;
+use metrics::Counter;
+#[cfg(feature = "server_11")]
+pub use server_11::mock::MockAuthority;
+use tap::Taps;
+pub use utils::{
+    AsyncStream, ClientReader, ClientStream, ClientWriter, Connector, DatagramSocket, Faults,
+    FlakySocket, UdpSender,
+};
+
+// Declares the module of each built-in challenge along with its number, names
+// and transport, the optional field being the part of the configuration given
+// to its server. Challenges are numbered from 0 without gaps, the ones left out
+// of the build keeping their number
+macro_rules! challenges {
+    ($(
+        #[cfg($enabled:meta)]
+        $part:literal => $module:ident as $name:literal $(| $alias:literal)*:
+            $transport:ident $(($field:ident))?
+    ),* $(,)?) => {
+        $(
+            #[cfg($enabled)]
+            mod $module;
+        )*
+
+        pub const NB_CHALLENGES: u8 = [$($part),*].len() as u8;
+
+        pub const CHALLENGES: &[ChallengeInfo] = &[$(ChallengeInfo {
+            part: $part,
+            name: $name,
+            aliases: &[$($alias),*],
+            transport: Transport::$transport,
+            compiled: cfg!($enabled),
+        }),*];
+
+        // Only some challenges are given part of the configuration
+        #[allow(unused_variables)]
+        fn builtin_server(part: u8, config: &Config) -> Result<Option<ServerType>, ProtoError> {
+            match part {
+                $(
+                    #[cfg($enabled)]
+                    $part => Ok(Some(ServerType::$transport(Arc::new(
+                        $module::Server::new($(config.$field.clone())?),
+                    )))),
+                    #[cfg(not($enabled))]
+                    $part => Err(ProtoError::NotCompiled(concat!("challenge ", $part))),
+                )*
+                _ => Ok(None),
+            }
+        }
+    };
+}
+
+// Each challenge has a cargo feature of the same name, all of them being on by
+// default
+challenges! {
+    #[cfg(feature = "server_00")]
+    0 => server_00 as "echo" | "smoke": Tcp(echo),
+    #[cfg(feature = "server_01")]
+    1 => server_01 as "prime": Tcp,
+    #[cfg(feature = "server_02")]
+    2 => server_02 as "means": Tcp,
+    #[cfg(feature = "server_03")]
+    3 => server_03 as "budgetchat" | "chat": Tcp,
+    #[cfg(feature = "server_04")]
+    4 => server_04 as "database" | "kv": Udp,
+    #[cfg(feature = "server_05")]
+    5 => server_05 as "mob" | "proxy": Tcp(proxy),
+    #[cfg(feature = "server_06")]
+    6 => server_06 as "speed": Tcp(speed),
+    #[cfg(feature = "server_07")]
+    7 => server_07 as "lrcp": Udp,
+    #[cfg(feature = "server_08")]
+    8 => server_08 as "isl" | "cipher": Tcp,
+    #[cfg(feature = "server_09")]
+    9 => server_09 as "jobcentre" | "jobs": Tcp,
+    #[cfg(feature = "server_10")]
+    10 => server_10 as "vcs": Tcp,
+    #[cfg(feature = "server_11")]
+    11 => server_11 as "pestcontrol": Tcp(pestcontrol),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+// What the CLI knows of a challenge, to select it by name and list it
+#[derive(Clone, Debug)]
+pub struct ChallengeInfo {
+    pub part: u8,
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub transport: Transport,
+    // Left out of the build by its cargo feature
+    pub compiled: bool,
+}
+
+impl ChallengeInfo {
+    fn is_called(&self, name: &str) -> bool {
+        let mut names = std::iter::once(&self.name).chain(self.aliases);
+        names.any(|known| known.eq_ignore_ascii_case(name))
+    }
+}
+
+// Built-in challenges then registered ones, by number
+pub fn list_challenges() -> Vec<ChallengeInfo> {
+    let mut challenges = CHALLENGES.to_vec();
+    challenges.extend(plugins::list());
+    challenges
+}
+
+// A challenge number, or the name or alias of a challenge
+pub fn parse_challenge(arg: &str) -> Result<u8, ProtoError> {
+    if let Ok(part) = arg.parse() {
+        return Ok(part);
+    }
+    list_challenges()
+        .iter()
+        .find(|challenge| challenge.is_called(arg))
+        .map(|challenge| challenge.part)
+        .ok_or_else(|| ProtoError::ChallengeArg(arg.to_owned()))
+}
+
+// Challenges registered by other crates are numbered below this
+pub const MAX_CHALLENGES: u8 = 64;
+
+// Longest datagram a UDP server accepts unless it says otherwise
+pub const MAX_DATAGRAM_SIZE: usize = 1024;
+// Largest payload of a UDP datagram over IPv4
+pub const MAX_UDP_PAYLOAD: usize = 65507;
+// Datagrams read per wakeup of the UDP loop, before letting other tasks run
+const DATAGRAM_BATCH: usize = 64;
+// Pause after a failed accept, doubled while they keep failing: running out of
+// file descriptors only resolves once some connections close
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+// Bind addresses starting with it are paths of UNIX sockets, served to any TCP
+// challenge. Their clients have no address, so they are given this one
+const UNIX_PREFIX: &str = "unix:";
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+// Shared by TCP connections and UDP datagrams of every server
+static CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+// Span every log line of a connection is emitted in, to tell apart the output
+// of concurrent clients
+fn connection_span(kind: &'static str, part: u8, peer: &dyn fmt::Display) -> Span {
+    let id = CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    tracing::info_span!("conn", kind, challenge = part, %peer, id)
+}
+
+pub fn get_challenge() -> Result<u8, ProtoError> {
+    if let Some(arg) = config::positional_args().first() {
+        return parse_challenge(arg);
+    }
+
+    // The latest challenge by default
+    latest_compiled(&list_challenges())
+}
+
+// Challenges left out of the build cannot be run, so they are not a default
+fn latest_compiled(challenges: &[ChallengeInfo]) -> Result<u8, ProtoError> {
+    challenges
+        .iter()
+        .filter(|challenge| challenge.compiled)
+        .map(|challenge| challenge.part)
         .max()
-        .ok_or("no source file found")
+        .ok_or(ProtoError::NoChallengeCompiled)
+}
+
+pub fn get_ip() -> Result<String, ProtoError> {
+    find_ip(&IpPreference::default())
 }
 
-pub fn get_ip() -> Result<String, &'static str> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(r"ip -f inet addr show wlo1 | sed -En -e 's/.*inet ([0-9.]+).*/\1/p'")
-        .output()
-        .map_err(|_| "could not read ip")?;
-    let ip = String::from_utf8(output.stdout).map_err(|_| "could not parse ip")?;
-    Ok(ip.trim().to_string())
+// First IPv4 address of the machine, from the preferred interface and public
+// if asked, or 0.0.0.0 when there is none besides loopback
+pub fn find_ip(preference: &IpPreference) -> Result<String, ProtoError> {
+    let interfaces = if_addrs::get_if_addrs().map_err(|err| ProtoError::Ip(err.to_string()))?;
+    let candidates = interfaces.iter().filter_map(|iface| match iface.ip() {
+        IpAddr::V4(ip) => Some((iface.name.as_str(), ip)),
+        IpAddr::V6(_) => None,
+    });
+    let ip = pick_ip(candidates, preference).unwrap_or(Ipv4Addr::UNSPECIFIED);
+    Ok(ip.to_string())
 }
 
+fn pick_ip<'a>(
+    candidates: impl Iterator<Item = (&'a str, Ipv4Addr)>,
+    preference: &IpPreference,
+) -> Option<Ipv4Addr> {
+    let is_public = |ip: &Ipv4Addr| !(ip.is_private() || ip.is_link_local());
+    candidates
+        .filter(|(_, ip)| !ip.is_loopback())
+        .min_by_key(|(name, ip)| {
+            let other_interface = preference.interface.as_ref().is_some_and(|i| i != name);
+            (other_interface, preference.public && !is_public(ip))
+        })
+        .map(|(_, ip)| ip)
+}
+
+// Sizes of the collections a server keeps across connections, to spot leaks
+pub type StateSizes = Vec<(&'static str, usize)>;
+
 #[async_trait]
 pub trait TcpServer: Send + Sync {
-    async fn handle_connection(&self, mut stream: TcpStream);
+    async fn handle_connection(&self, mut stream: ClientStream);
+
+    async fn state_sizes(&self) -> StateSizes {
+        Vec::new()
+    }
+
+    // Server-specific commands of the admin socket
+    async fn admin_command(&self, _command: &str, _args: &[&str]) -> Result<Value, ProtoError> {
+        Err(ProtoError::UnknownCommand)
+    }
+
+    // Servers whose clients wait on each reply, which Nagle's algorithm would
+    // delay, have TCP_NODELAY set whatever the configuration
+    fn wants_nodelay(&self) -> bool {
+        false
+    }
 }
 
 #[async_trait]
 pub trait UdpServer: Send + Sync {
-    async fn handle_connection(&self, socket: Arc<UdpSocket>, data: &[u8], addr: &SocketAddr);
+    async fn handle_connection(&self, sender: UdpSender, data: &[u8], addr: &SocketAddr);
+
+    async fn state_sizes(&self) -> StateSizes {
+        Vec::new()
+    }
+
+    async fn admin_command(&self, _command: &str, _args: &[&str]) -> Result<Value, ProtoError> {
+        Err(ProtoError::UnknownCommand)
+    }
+
+    // Handlers that never wait on anything but their reply are run by the
+    // receive loop itself rather than in a task of their own
+    fn handles_inline(&self) -> bool {
+        false
+    }
+
+    // Longer datagrams are dropped rather than handled cut short
+    fn max_datagram_size(&self) -> usize {
+        MAX_DATAGRAM_SIZE
+    }
 }
 
+#[derive(Clone)]
 pub enum ServerType {
     Tcp(Arc<dyn TcpServer>),
     Udp(Arc<dyn UdpServer>),
 }
 
+impl ServerType {
+    pub async fn state_sizes(&self) -> StateSizes {
+        match self {
+            Self::Tcp(server) => server.state_sizes().await,
+            Self::Udp(server) => server.state_sizes().await,
+        }
+    }
+
+    pub async fn admin_command(&self, command: &str, args: &[&str]) -> Result<Value, ProtoError> {
+        match self {
+            Self::Tcp(server) => server.admin_command(command, args).await,
+            Self::Udp(server) => server.admin_command(command, args).await,
+        }
+    }
+}
+
+// How a challenge server embedded in another application is run
+#[derive(Clone)]
+pub struct RunOptions {
+    // Port 0 picks an ephemeral one, see `RunningServer::addr`
+    pub addr: String,
+    pub config: Config,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:0".to_string(),
+            config: Config::default(),
+        }
+    }
+}
+
+// Challenge server running in the background of the caller's runtime. Dropping
+// it leaves the server running, like dropping its join handle would
+pub struct RunningServer {
+    // Unspecified when listening on a UNIX socket
+    pub addr: SocketAddr,
+    // IPv6 socket served along the IPv4 one, see `Config::dual_stack`
+    pub addr_v6: Option<SocketAddr>,
+    pub handle: JoinHandle<()>,
+    shutdown: oneshot::Sender<()>,
+    server: ServerType,
+    // What the server was started on, to start another one in its place
+    bind_addr: String,
+}
+
+impl RunningServer {
+    // Stops accepting connections or receiving datagrams, the connections
+    // already accepted are served until they close
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(());
+        let _ = self.handle.await;
+    }
+}
+
+// Connection tasks of an accept loop, left running when the loop is stopped as
+// `RunningServer::shutdown` promises
+#[derive(Default)]
+struct Connections(JoinSet<()>);
+
+impl Drop for Connections {
+    fn drop(&mut self) {
+        self.0.detach_all();
+    }
+}
+
+// Bind and run a challenge server, without the metrics endpoint, admin socket
+// or dashboard the binary may add around it
+pub async fn run_challenge(part: u8, options: RunOptions) -> io::Result<RunningServer> {
+    Server::with_config(part, &options.config)
+        .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?
+        .start(&options.addr)
+        .await
+}
+
 pub struct Server {
     part: u8,
+    // Only set for challenges registered by other crates
+    name: Option<&'static str>,
     server: ServerType,
+    taps: Taps,
+    connection_limit: ConnectionLimit,
+    socket_options: SocketOptions,
+    dual_stack: bool,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<String>,
+    admin_addr: Option<String>,
+    #[cfg(feature = "dashboard")]
+    dashboard: bool,
+    // Other challenges are built from it when switched to from the admin socket
+    config: Config,
 }
 
 impl Server {
-    pub fn new(part: u8) -> Result<Self, &'static str> {
-        let server = match part {
-            0 => ServerType::Tcp(Arc::new(server_00::Server::new())),
-            1 => ServerType::Tcp(Arc::new(server_01::Server::new())),
-            2 => ServerType::Tcp(Arc::new(server_02::Server::new())),
-            3 => ServerType::Tcp(Arc::new(server_03::Server::new())),
-            4 => ServerType::Udp(Arc::new(server_04::Server::new())),
-            5 => ServerType::Tcp(Arc::new(server_05::Server::new())),
-            6 => ServerType::Tcp(Arc::new(server_06::Server::new())),
-            7 => ServerType::Udp(Arc::new(server_07::Server::new())),
-            8 => ServerType::Tcp(Arc::new(server_08::Server::new())),
-            9 => ServerType::Tcp(Arc::new(server_09::Server::new())),
-            10 => ServerType::Tcp(Arc::new(server_10::Server::new())),
-            11 => ServerType::Tcp(Arc::new(server_11::Server::new())),
-            _ => return Err("invalid challenge number"),
+    pub fn new(part: u8) -> Result<Self, ProtoError> {
+        Self::with_config(part, &Config::from_env())
+    }
+
+    // Make a challenge implemented by another crate available to the runner,
+    // the CLI and the test harness under the given number
+    pub fn register(part: u8, name: &'static str, server: ServerType) -> Result<(), ProtoError> {
+        plugins::register(part, name, server)
+    }
+
+    pub fn with_config(part: u8, config: &Config) -> Result<Self, ProtoError> {
+        let mut plugin_name = None;
+        let server = match builtin_server(part, config)? {
+            Some(server) => server,
+            None => match plugins::lookup(part) {
+                Some((name, server)) => {
+                    plugin_name = Some(name);
+                    server
+                }
+                None => return Err(ProtoError::InvalidChallenge(part)),
+            },
         };
-        Ok(Self { part, server })
+        let taps = Taps::from_config(config, part)?;
+        if cfg!(not(feature = "metrics")) && config.metrics_addr.is_some() {
+            return Err(ProtoError::NotCompiled("metrics"));
+        }
+        if cfg!(not(feature = "dashboard")) && config.dashboard {
+            return Err(ProtoError::NotCompiled("dashboard"));
+        }
+        if cfg!(not(feature = "tls")) && config.tls.cert_path.is_some() {
+            return Err(ProtoError::NotCompiled("tls"));
+        }
+        let mut socket_options = config.socket_options;
+        if let ServerType::Tcp(server) = &server {
+            socket_options.nodelay |= server.wants_nodelay();
+        }
+        Ok(Self {
+            part,
+            name: plugin_name,
+            server,
+            taps,
+            connection_limit: config.connection_limit,
+            socket_options,
+            dual_stack: config.dual_stack,
+            #[cfg(feature = "metrics")]
+            metrics_addr: config.metrics_addr.clone(),
+            admin_addr: config.admin_addr.clone(),
+            #[cfg(feature = "dashboard")]
+            dashboard: config.dashboard,
+            config: config.clone(),
+        })
     }
 
-    pub async fn run(self, ip: &str, port: u32) {
-        println!("Running server {}", self.part);
-        match self.server {
-            ServerType::Tcp(server) => Self::run_tcp(server, ip, port).await,
-            ServerType::Udp(server) => Self::run_udp(server, ip, port).await,
+    pub async fn run(self, ip: &str, port: u16) {
+        match self.name {
+            Some(name) => println!("Running server {} ({name})", self.part),
+            None => println!("Running server {}", self.part),
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = &self.metrics_addr {
+            let listener = TcpListener::bind(addr).await.unwrap();
+            println!("Serving metrics on {addr}");
+            utils::spawn_named("metrics endpoint", metrics::serve(listener));
+        }
+        let admin_listener = match &self.admin_addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await.unwrap();
+                println!("Serving admin socket on {addr}");
+                Some(listener)
+            }
+            None => None,
+        };
+        // Quitting the dashboard stops the server
+        #[cfg(feature = "dashboard")]
+        if self.dashboard {
+            let part = self.part;
+            tokio::task::spawn_blocking(move || {
+                if let Err(err) = dashboard::run(part) {
+                    println!("Dashboard error: {err}");
+                }
+                std::process::exit(0);
+            });
+        }
+
+        let config = self.config.clone();
+        let addr = match ip.starts_with(UNIX_PREFIX) {
+            true => ip.to_owned(),
+            false => format!("{ip}:{port}"),
+        };
+        let running = self.start(&addr).await.unwrap();
+        // The admin socket owns the running challenge, to be able to switch it
+        match admin_listener {
+            Some(listener) => {
+                let admin = Arc::new(Admin::switchable(running, config));
+                admin::serve(listener, admin).await;
+            }
+            None => running.handle.await.unwrap(),
         }
     }
 
-    async fn run_tcp(server: Arc<dyn TcpServer>, ip: &str, port: u32) {
-        let listener = TcpListener::bind(format!("{ip}:{port}")).await.unwrap();
+    async fn start(self, bind_addr: &str) -> io::Result<RunningServer> {
+        let (shutdown, stopped) = oneshot::channel();
+        let period = self.config.stats_interval;
+        let stats = Self::report_stats(self.part, self.server.clone(), period);
+        let addr = bind_addr;
+        let unix_path = addr.strip_prefix(UNIX_PREFIX);
+        let (addr, addr_v6, handle) = match (self.server.clone(), unix_path) {
+            #[cfg(unix)]
+            (ServerType::Tcp(server), Some(path)) => {
+                let listener = utils::listen_unix(path)?;
+                let run = Self::run_unix(self.part, server, listener, self.taps, path.to_owned());
+                let run = Self::until_stopped(run, stats, stopped);
+                (UNIX_PEER, None, utils::spawn_named("unix accept loop", run))
+            }
+            (ServerType::Udp(_), Some(_)) => {
+                let msg = "datagram challenges cannot listen on a UNIX socket";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+            (ServerType::Tcp(server), _) => {
+                let options = self.socket_options;
+                let listener = utils::listen(addr, options.reuse_addr).await?;
+                let addr = listener.local_addr()?;
+                let mut listeners = vec![listener];
+                if self.dual_stack && addr.is_ipv4() {
+                    listeners.push(utils::listen_v6(addr, options.reuse_addr)?);
+                }
+                let addr_v6 = listeners.get(1).map(TcpListener::local_addr).transpose()?;
+                let limit = self.connection_limit;
+                let run = Self::run_tcp(self.part, server, listeners, self.taps, limit, options);
+                let run = Self::until_stopped(run, stats, stopped);
+                (addr, addr_v6, utils::spawn_named("tcp accept loop", run))
+            }
+            (ServerType::Udp(server), _) => {
+                let socket = UdpSocket::bind(addr).await?;
+                let addr = socket.local_addr()?;
+                let mut sockets = vec![socket];
+                if self.dual_stack && addr.is_ipv4() {
+                    sockets.push(utils::bind_udp_v6(addr)?);
+                }
+                let addr_v6 = sockets.get(1).map(UdpSocket::local_addr).transpose()?;
+                let run = Self::run_udp(self.part, server, sockets, self.taps);
+                let run = Self::until_stopped(run, stats, stopped);
+                (addr, addr_v6, utils::spawn_named("udp receive loop", run))
+            }
+        };
+        Ok(RunningServer {
+            addr,
+            addr_v6,
+            handle,
+            shutdown,
+            server: self.server,
+            bind_addr: match unix_path {
+                Some(_) => bind_addr.to_owned(),
+                None => addr.to_string(),
+            },
+        })
+    }
+
+    // Stopping drops the listener or socket, a dropped sender never stops.
+    // The stats reporter never returns, it is stopped along with the server
+    async fn until_stopped(
+        run: impl Future<Output = ()>,
+        stats: impl Future<Output = ()>,
+        stopped: oneshot::Receiver<()>,
+    ) {
+        tokio::select! {
+            _ = run => {}
+            _ = stats => {}
+            Ok(()) = stopped => {}
+        }
+    }
 
+    // Log the sizes of the server's internal state, to see them grow or not
+    // during long runs. Servers with nothing to report are left alone
+    async fn report_stats(part: u8, server: ServerType, period: Option<Duration>) {
+        let Some(period) = period else {
+            return future::pending().await;
+        };
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        interval.tick().await;
         loop {
-            let (stream, _) = listener.accept().await.unwrap();
-            println!("Connection established!");
+            interval.tick().await;
+            let sizes = server.state_sizes().await;
+            if sizes.is_empty() {
+                return future::pending().await;
+            }
+            let sizes: Vec<String> = sizes
+                .iter()
+                .map(|(name, size)| format!("{name}={size}"))
+                .collect();
+            tracing::info!(challenge = part, "state sizes: {}", sizes.join(" "));
+        }
+    }
 
-            let server = Arc::clone(&server);
-            tokio::spawn(async move { server.handle_connection(stream).await });
+    // A panicking handler is a bug on our side, the other connections go on
+    async fn count_panics(part: u8, handler: impl Future<Output = ()> + Send + 'static) {
+        let Err(err) = tokio::spawn(handler).await else {
+            return;
+        };
+        metrics::increment(part, Counter::InternalErrors);
+        if let Ok(payload) = err.try_into_panic() {
+            Self::report_panic(&*payload);
         }
     }
 
-    async fn run_udp(server: Arc<dyn UdpServer>, ip: &str, port: u32) {
-        let socket = Arc::new(UdpSocket::bind(format!("{ip}:{port}")).await.unwrap());
+    // Logged in the span of the connection, which tells the peer and challenge
+    fn report_panic(payload: &(dyn Any + Send)) {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic payload");
+        tracing::error!("handler panicked: {msg}");
+    }
+
+    // Connections accepted on any of the listeners, the first one going first
+    async fn accept(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+        future::poll_fn(|cx| {
+            listeners
+                .iter()
+                .find_map(|listener| match listener.poll_accept(cx) {
+                    Poll::Ready(accepted) => Some(accepted),
+                    Poll::Pending => None,
+                })
+                .map_or(Poll::Pending, Poll::Ready)
+        })
+        .await
+    }
+
+    async fn run_tcp(
+        part: u8,
+        server: Arc<dyn TcpServer>,
+        listeners: Vec<TcpListener>,
+        taps: Taps,
+        limit: ConnectionLimit,
+        options: SocketOptions,
+    ) {
+        let slots = Arc::new(Semaphore::new(limit.max.unwrap_or(Semaphore::MAX_PERMITS)));
+        let mut connections = Connections::default();
+        let mut backoff = ACCEPT_BACKOFF_MIN;
         loop {
-            let mut buffer = [0; 1024];
-            let (n, addr) = socket.recv_from(&mut buffer).await.unwrap();
+            let next = async {
+                // Waiting for a slot before accepting leaves the clients beyond
+                // the limit in the listen backlog
+                let queued = match limit.overflow {
+                    Overflow::Queue => Some(Arc::clone(&slots).acquire_owned().await.unwrap()),
+                    Overflow::Reset => None,
+                };
+                (queued, Self::accept(&listeners).await)
+            };
+            let (queued, accepted) = tokio::select! {
+                next = next => next,
+                Some(result) = connections.0.join_next() => {
+                    Self::log_failure(result);
+                    continue;
+                }
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    accepted
+                }
+                Err(err) => {
+                    Self::accept_failed(part, err, &mut backoff).await;
+                    continue;
+                }
+            };
+            let Ok(local_addr) = stream.local_addr() else {
+                continue;
+            };
+            let span = connection_span("tcp", part, &peer);
+            if !taps.admits(&peer) {
+                tracing::debug!(parent: &span, "over the rate limit, resetting");
+                let _ = stream.set_linger(Some(Duration::ZERO));
+                continue;
+            }
+            let Some(slot) = queued.or_else(|| Arc::clone(&slots).try_acquire_owned().ok()) else {
+                tracing::warn!(parent: &span, "over the connection limit, resetting");
+                metrics::increment(part, Counter::RejectedConnections);
+                let _ = stream.set_linger(Some(Duration::ZERO));
+                continue;
+            };
+            if let Err(err) = utils::tune_stream(&stream, &options) {
+                tracing::warn!(parent: &span, "could not set socket options: {err}");
+            }
+            tracing::info!(parent: &span, "connection established");
+            metrics::increment(part, Counter::Connections);
+
+            let addrs = (peer, local_addr);
+            let stream = ClientStream::from(stream);
+            let serve = Self::serve(part, Arc::clone(&server), taps.clone(), stream, addrs);
+            let connection = async move {
+                serve.await;
+                drop(slot);
+            };
+            connections.0.spawn(connection.instrument(span));
+        }
+    }
+
+    // Rate and connection limits are about remote peers, and socket options
+    // about TCP, so none of them apply here
+    #[cfg(unix)]
+    async fn run_unix(
+        part: u8,
+        server: Arc<dyn TcpServer>,
+        listener: UnixListener,
+        taps: Taps,
+        path: String,
+    ) {
+        let mut connections = Connections::default();
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(result) = connections.0.join_next() => {
+                    Self::log_failure(result);
+                    continue;
+                }
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    stream
+                }
+                Err(err) => {
+                    Self::accept_failed(part, err, &mut backoff).await;
+                    continue;
+                }
+            };
+            let span = connection_span("unix", part, &path);
+            tracing::info!(parent: &span, "connection established");
+            metrics::increment(part, Counter::Connections);
+
+            let addrs = (UNIX_PEER, UNIX_PEER);
+            let stream = ClientStream::new(stream);
+            let serve = Self::serve(part, Arc::clone(&server), taps.clone(), stream, addrs);
+            connections.0.spawn(serve.instrument(span));
+        }
+    }
+
+    async fn serve(
+        part: u8,
+        server: Arc<dyn TcpServer>,
+        taps: Taps,
+        stream: ClientStream,
+        addrs: (SocketAddr, SocketAddr),
+    ) {
+        Self::count_panics(part, async move {
+            if taps.is_empty() {
+                server.handle_connection(stream).await;
+            } else {
+                taps.handle_connection(server, stream, addrs).await;
+            }
+        })
+        .await;
+        metrics::increment(part, Counter::Disconnects);
+        tracing::info!("connection closed");
+    }
+
+    async fn accept_failed(part: u8, err: io::Error, backoff: &mut Duration) {
+        // The client left before being accepted
+        if Self::is_connection_error(&err) {
+            return;
+        }
+        tracing::error!("could not accept connection, retrying in {backoff:?}: {err}");
+        metrics::increment(part, Counter::InternalErrors);
+        time::sleep(*backoff).await;
+        *backoff = (*backoff * 2).min(ACCEPT_BACKOFF_MAX);
+    }
+
+    fn is_connection_error(err: &io::Error) -> bool {
+        matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::Interrupted
+        )
+    }
+
+    // Panics are already caught within the task, so this is only a safety net
+    fn log_failure(result: Result<(), JoinError>) {
+        match result {
+            Ok(()) => {}
+            Err(err) if err.is_cancelled() => tracing::debug!("connection task cancelled"),
+            Err(err) => tracing::error!("connection task failed: {err}"),
+        }
+    }
+
+    // Same as `count_panics`, for handlers run without a task of their own
+    async fn count_inline_panics(part: u8, handler: impl Future<Output = ()>) {
+        let mut handler = pin!(handler);
+        let result = future::poll_fn(|cx| {
+            match panic::catch_unwind(AssertUnwindSafe(|| handler.as_mut().poll(cx))) {
+                Ok(poll) => poll.map(Ok),
+                Err(payload) => Poll::Ready(Err(payload)),
+            }
+        })
+        .await;
+        if let Err(payload) = result {
+            metrics::increment(part, Counter::InternalErrors);
+            Self::report_panic(&*payload);
+        }
+    }
+
+    async fn run_udp(part: u8, server: Arc<dyn UdpServer>, sockets: Vec<UdpSocket>, taps: Taps) {
+        // Dropped when the server stops, which aborts the receive loops
+        let mut receivers = JoinSet::new();
+        for socket in sockets {
             let server = Arc::clone(&server);
-            let socket = Arc::clone(&socket);
-            tokio::spawn(
-                async move { server.handle_connection(socket, &buffer[..n], &addr).await },
-            );
+            receivers.spawn(Self::receive_datagrams(part, server, socket, taps.clone()));
+        }
+        receivers.join_all().await;
+    }
+
+    // Every datagram already queued is read on each wakeup, up to a batch
+    async fn receive_datagrams(
+        part: u8,
+        server: Arc<dyn UdpServer>,
+        socket: UdpSocket,
+        taps: Taps,
+    ) {
+        let socket = Arc::new(socket);
+        let sender = UdpSender::new(taps.wrap_socket(Arc::clone(&socket)));
+        let inline = server.handles_inline();
+        let max_size = server.max_datagram_size();
+        // One more byte than accepted, to tell longer datagrams apart
+        let mut buffer = vec![0; max_size + 1];
+        loop {
+            if let Err(err) = socket.readable().await {
+                tracing::error!("udp socket failed: {err}");
+                return;
+            }
+            for _ in 0..DATAGRAM_BATCH {
+                let (n, addr) = match socket.try_recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    // Such as an ICMP error about an earlier reply, reported
+                    // on the next receive on some platforms
+                    Err(err) => {
+                        tracing::debug!("could not receive datagram: {err}");
+                        continue;
+                    }
+                };
+                let span = connection_span("udp", part, &addr);
+                metrics::increment(part, Counter::Datagrams);
+                // Floods are dropped before anything, replies included, is done
+                if !taps.admits(&addr) {
+                    tracing::debug!(parent: &span, "over the rate limit, dropping datagram");
+                    continue;
+                }
+                let Some(delay) = taps.datagram_delay() else {
+                    tracing::debug!(parent: &span, "simulating the loss of the datagram");
+                    continue;
+                };
+                taps.received_datagram(&socket, addr, &buffer[..n]);
+                if n > max_size {
+                    tracing::warn!(parent: &span, "datagram over {max_size} bytes, dropping it");
+                    metrics::increment(part, Counter::TruncatedDatagrams);
+                    continue;
+                }
+                let sender = sender.clone();
+                if inline && delay.is_zero() {
+                    let handler = server.handle_connection(sender, &buffer[..n], &addr);
+                    let handler = Self::count_inline_panics(part, handler);
+                    handler.instrument(span).await;
+                } else {
+                    let server = Arc::clone(&server);
+                    let data = buffer[..n].to_vec();
+                    let handler = Self::count_panics(part, async move {
+                        time::sleep(delay).await;
+                        server.handle_connection(sender, &data, &addr).await
+                    });
+                    tokio::spawn(handler.instrument(span));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_follows_preference() {
+        let candidates = [
+            ("lo", Ipv4Addr::new(127, 0, 0, 1)),
+            ("eth0", Ipv4Addr::new(192, 168, 1, 2)),
+            ("wlan0", Ipv4Addr::new(10, 0, 0, 3)),
+            ("wan0", Ipv4Addr::new(203, 0, 113, 4)),
+        ];
+        let pick = |interface: Option<&str>, public| {
+            let preference = IpPreference {
+                interface: interface.map(String::from),
+                public,
+            };
+            pick_ip(candidates.into_iter(), &preference)
+        };
+        assert_eq!(pick(None, false), Some(candidates[1].1));
+        assert_eq!(pick(Some("wlan0"), false), Some(candidates[2].1));
+        assert_eq!(pick(None, true), Some(candidates[3].1));
+        assert_eq!(pick(Some("wlan0"), true), Some(candidates[2].1));
+        // Unknown interfaces are only a preference
+        assert_eq!(pick(Some("wlo1"), false), Some(candidates[1].1));
+        let loopback_only = candidates[..1].iter().copied();
+        assert_eq!(pick_ip(loopback_only, &IpPreference::default()), None);
+    }
+
+    #[test]
+    fn default_challenge_is_compiled() {
+        let mut challenges = CHALLENGES.to_vec();
+        for challenge in &mut challenges {
+            challenge.compiled = challenge.part < 8;
         }
+        assert_eq!(latest_compiled(&challenges), Ok(7));
+        challenges
+            .iter_mut()
+            .for_each(|challenge| challenge.compiled = false);
+        assert_eq!(
+            latest_compiled(&challenges),
+            Err(ProtoError::NoChallengeCompiled)
+        );
+    }
+
+    #[cfg(all(feature = "server_00", feature = "server_06", feature = "server_09"))]
+    #[tokio::test]
+    async fn servers_waited_on_get_nodelay() {
+        let config = Config::default();
+        let nodelay = |part| {
+            let server = Server::with_config(part, &config).unwrap();
+            server.socket_options.nodelay
+        };
+        assert!(!nodelay(0));
+        assert!(nodelay(6));
+        assert!(nodelay(9));
     }
 }
@@ -0,0 +1,82 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json::json;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    // The client closed its side first
+    Eof,
+    // The server hung up on the client, which it only does on invalid data
+    ProtocolError,
+    // The client sent nothing for the configured idle timeout
+    Timeout,
+    // Data from the server could not be written to the client
+    WriteFailure,
+}
+
+impl CloseReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::Eof => "eof",
+            CloseReason::ProtocolError => "protocol_error",
+            CloseReason::Timeout => "timeout",
+            CloseReason::WriteFailure => "write_failure",
+        }
+    }
+}
+
+pub struct ConnectionEvent {
+    pub challenge: u8,
+    pub peer: SocketAddr,
+    pub duration: Duration,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub reason: CloseReason,
+}
+
+impl ConnectionEvent {
+    pub fn to_json(&self) -> String {
+        json!({
+            "challenge": self.challenge,
+            "peer": self.peer.to_string(),
+            "duration_ms": self.duration.as_millis() as u64,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "reason": self.reason.as_str(),
+        })
+        .to_string()
+    }
+}
+
+// JSON lines file getting one event per closed connection
+pub struct EventLog {
+    path: Mutex<PathBuf>,
+}
+
+impl EventLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path: Mutex::new(path),
+        }
+    }
+
+    pub fn push(&self, event: &ConnectionEvent) {
+        let path = self.path.lock().unwrap();
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&*path)
+            .and_then(|mut file| writeln!(file, "{}", event.to_json()));
+        if let Err(err) = result {
+            println!(
+                "Could not log connection event to {}: {err}",
+                path.display()
+            );
+        }
+    }
+}
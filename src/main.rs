@@ -1,17 +1,144 @@
-use std::process;
+use std::{env, process};
 
-use proto_hackers::{Server, get_challenge, get_ip};
+use proto_hackers::bench::{self, BenchConfig};
+use proto_hackers::config::DEFAULT_PORT;
+use proto_hackers::daemon;
+use proto_hackers::soak::{self, SoakConfig};
+use proto_hackers::transcript::{self, ReplayConfig};
+use proto_hackers::{Config, Server, find_ip, get_challenge, list_challenges, selftest};
+use tracing_appender::rolling::RollingFileAppender;
 
-#[tokio::main]
-async fn main() {
-    let server = get_challenge()
-        .and_then(Server::new)
-        .unwrap_or_else(|err_msg| {
+async fn run_bench() {
+    let report = match BenchConfig::from_args() {
+        Ok(config) => bench::run(&config).await,
+        Err(err_msg) => {
             println!("Error in argument: {err_msg}");
             process::exit(1);
+        }
+    };
+    match report {
+        Ok(report) => println!("{report}"),
+        Err(err_msg) => {
+            println!("Error in bench: {err_msg}");
+            process::exit(1);
+        }
+    }
+}
+
+async fn run_soak() {
+    let config = SoakConfig::from_args().unwrap_or_else(|err_msg| {
+        println!("Error in argument: {err_msg}");
+        process::exit(1);
+    });
+    if let Err(err_msg) = soak::run(&config).await {
+        println!("Error in soak: {err_msg}");
+        process::exit(1);
+    }
+}
+
+async fn run_selftest() {
+    let challenges = selftest::challenges_from_args().unwrap_or_else(|err_msg| {
+        println!("Error in argument: {err_msg}");
+        process::exit(1);
+    });
+    if !selftest::run(&challenges).await {
+        process::exit(1);
+    }
+}
+
+async fn run_replay() {
+    let config = ReplayConfig::from_args().unwrap_or_else(|err_msg| {
+        println!("Error in argument: {err_msg}");
+        process::exit(1);
+    });
+    if !transcript::run(&config).await {
+        process::exit(1);
+    }
+}
+
+fn print_challenges() {
+    for challenge in list_challenges() {
+        let names = [&[challenge.name], challenge.aliases].concat().join(", ");
+        let missing = match challenge.compiled {
+            true => "",
+            false => " (not compiled)",
+        };
+        let (part, transport) = (challenge.part, challenge.transport);
+        println!("{part:>2} {transport} {names}{missing}");
+    }
+}
+
+// RUST_LOG=debug also shows the messages exchanged with each client
+#[cfg_attr(feature = "console", allow(unused_variables))]
+fn init_tracing(log_level: &str, log_file: Option<RollingFileAppender>) {
+    #[cfg(feature = "console")]
+    console_subscriber::init();
+    #[cfg(not(feature = "console"))]
+    {
+        let builder = tracing_subscriber::fmt().with_env_filter(log_level);
+        match log_file {
+            Some(file) => builder.with_ansi(false).with_writer(file).init(),
+            None => builder.init(),
+        }
+    }
+}
+
+// The runtime is only built once in the background, see `daemon::daemonize`
+fn main() {
+    let command = env::args().nth(1);
+    // Subcommands parse options of their own
+    let config = match command.as_deref() {
+        Some("bench" | "soak" | "selftest" | "replay") => Config::from_env(),
+        _ => Config::from_args().unwrap_or_else(|err_msg| {
+            println!("Error in argument: {err_msg}");
+            process::exit(1);
+        }),
+    };
+    if env::args().any(|arg| arg == "--list") {
+        return print_challenges();
+    }
+    let log_file = config.daemon.log_file.as_deref().map(|path| {
+        daemon::log_file(path).unwrap_or_else(|err| {
+            println!("{err}");
+            process::exit(1);
+        })
+    });
+    if config.daemon.enabled {
+        if let Err(err) = daemon::daemonize(&config.daemon) {
+            println!("{err}");
+            process::exit(1);
+        }
+    }
+    init_tracing(config.log_level.as_deref().unwrap_or("info"), log_file);
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(run(command, config));
+}
+
+async fn run(command: Option<String>, config: Config) {
+    match command.as_deref() {
+        Some("bench") => return run_bench().await,
+        Some("soak") => return run_soak().await,
+        Some("selftest") => return run_selftest().await,
+        Some("replay") => return run_replay().await,
+        _ => {}
+    }
+
+    let server = config
+        .challenge
+        .map_or_else(get_challenge, Ok)
+        .and_then(|part| Server::with_config(part, &config))
+        .unwrap_or_else(|err| {
+            println!("Error in argument: {err}");
+            process::exit(1);
         });
 
-    let ip = get_ip().expect("Could not get IP address");
-    let port = 12233;
-    server.run(&ip, port).await;
+    let ip = match config.bind_ip {
+        Some(ip) => ip,
+        None => find_ip(&config.ip_preference).unwrap_or_else(|err| {
+            println!("{err}, listening on all interfaces");
+            "0.0.0.0".to_string()
+        }),
+    };
+    server.run(&ip, config.port.unwrap_or(DEFAULT_PORT)).await;
 }
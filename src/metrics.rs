@@ -0,0 +1,250 @@
+use std::time::Duration;
+
+// Counters kept per challenge, latency histograms kept per challenge and
+// message type, and gauges set by the servers themselves when the `metrics`
+// feature is enabled, recording is free otherwise
+#[derive(Clone, Copy)]
+pub enum Counter {
+    Connections,
+    Requests,
+    // Invalid data sent by a client
+    ProtocolErrors,
+    Disconnects,
+    // Failures of a server this one depends on (proxied chat, authority)
+    UpstreamErrors,
+    // Failures on our side: panicking handlers, unwritable logs and files
+    InternalErrors,
+    // Received by the UDP run loop, and those dropped for being too long
+    Datagrams,
+    TruncatedDatagrams,
+    // Reset right away for being over the connection limit
+    RejectedConnections,
+    // Connections reset and datagrams dropped for being over the rate limit
+    RateLimited,
+    // Payload bytes from and to the clients, TCP ones only counted when the
+    // metrics endpoint is served as they need the connection to be relayed
+    BytesIn,
+    BytesOut,
+    // Written back by the echo server, whether the connection is relayed or not
+    EchoedBytes,
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn increment(_challenge: u8, _counter: Counter) {}
+
+#[cfg(not(feature = "metrics"))]
+pub fn add(_challenge: u8, _counter: Counter, _amount: u64) {}
+
+#[cfg(not(feature = "metrics"))]
+pub fn observe(_challenge: u8, _msg_type: &'static str, _latency: Duration) {}
+
+#[cfg(not(feature = "metrics"))]
+pub fn set_gauge(_challenge: u8, _name: &'static str, _label: Option<(&str, &str)>, _value: usize) {
+}
+
+#[cfg(feature = "metrics")]
+pub use registry::{add, increment, observe, render, serve, set_gauge, value};
+
+#[cfg(feature = "metrics")]
+mod registry {
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{Counter, Duration};
+    use crate::MAX_CHALLENGES;
+
+    const COUNTERS: [Counter; 13] = [
+        Counter::Connections,
+        Counter::Requests,
+        Counter::ProtocolErrors,
+        Counter::Disconnects,
+        Counter::UpstreamErrors,
+        Counter::InternalErrors,
+        Counter::Datagrams,
+        Counter::TruncatedDatagrams,
+        Counter::RejectedConnections,
+        Counter::RateLimited,
+        Counter::BytesIn,
+        Counter::BytesOut,
+        Counter::EchoedBytes,
+    ];
+
+    static VALUES: [[AtomicU64; COUNTERS.len()]; MAX_CHALLENGES as usize] =
+        [const { [const { AtomicU64::new(0) }; COUNTERS.len()] }; MAX_CHALLENGES as usize];
+
+    // Upper bounds of the latency buckets, in seconds
+    const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+    #[derive(Default)]
+    struct Histogram {
+        // Observations falling in each bucket, the last one being unbounded
+        counts: [u64; BUCKETS.len() + 1],
+        sum: f64,
+    }
+
+    static HISTOGRAMS: Mutex<BTreeMap<(u8, &str), Histogram>> = Mutex::new(BTreeMap::new());
+
+    type GaugeKey = (&'static str, u8, Option<(String, String)>);
+
+    static GAUGES: Mutex<BTreeMap<GaugeKey, usize>> = Mutex::new(BTreeMap::new());
+
+    impl Counter {
+        fn name(self) -> &'static str {
+            match self {
+                Counter::Connections => "proto_hackers_connections_total",
+                Counter::Requests => "proto_hackers_requests_total",
+                Counter::ProtocolErrors => "proto_hackers_protocol_errors_total",
+                Counter::Disconnects => "proto_hackers_disconnects_total",
+                Counter::UpstreamErrors => "proto_hackers_upstream_errors_total",
+                Counter::InternalErrors => "proto_hackers_internal_errors_total",
+                Counter::Datagrams => "proto_hackers_datagrams_total",
+                Counter::TruncatedDatagrams => "proto_hackers_truncated_datagrams_total",
+                Counter::RejectedConnections => "proto_hackers_rejected_connections_total",
+                Counter::RateLimited => "proto_hackers_rate_limited_total",
+                Counter::BytesIn => "proto_hackers_received_bytes_total",
+                Counter::BytesOut => "proto_hackers_sent_bytes_total",
+                Counter::EchoedBytes => "proto_hackers_echoed_bytes_total",
+            }
+        }
+    }
+
+    pub fn increment(challenge: u8, counter: Counter) {
+        add(challenge, counter, 1);
+    }
+
+    pub fn add(challenge: u8, counter: Counter, amount: u64) {
+        VALUES[challenge as usize][counter as usize].fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn value(challenge: u8, counter: Counter) -> u64 {
+        VALUES[challenge as usize][counter as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn observe(challenge: u8, msg_type: &'static str, latency: Duration) {
+        let latency = latency.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| latency <= bound)
+            .unwrap_or(BUCKETS.len());
+        let mut histograms = HISTOGRAMS.lock().unwrap();
+        let histogram = histograms.entry((challenge, msg_type)).or_default();
+        histogram.counts[bucket] += 1;
+        histogram.sum += latency;
+    }
+
+    // Labeled gauges going back to zero are dropped, so that short-lived label
+    // values (e.g. job queues) do not pile up
+    pub fn set_gauge(challenge: u8, name: &'static str, label: Option<(&str, &str)>, value: usize) {
+        let label = label.map(|(key, val)| (key.to_owned(), val.to_owned()));
+        let mut gauges = GAUGES.lock().unwrap();
+        if value == 0 && label.is_some() {
+            gauges.remove(&(name, challenge, label));
+        } else {
+            gauges.insert((name, challenge, label), value);
+        }
+    }
+
+    fn escape_label(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+
+    fn render_gauges(output: &mut String) {
+        let mut previous_name = None;
+        for ((name, challenge, label), value) in GAUGES.lock().unwrap().iter() {
+            let name = format!("proto_hackers_{name}");
+            if previous_name.as_ref() != Some(&name) {
+                output.push_str(&format!("# TYPE {name} gauge\n"));
+            }
+            let mut labels = format!("challenge=\"{challenge}\"");
+            if let Some((key, val)) = label {
+                labels.push_str(&format!(",{key}=\"{}\"", escape_label(val)));
+            }
+            output.push_str(&format!("{name}{{{labels}}} {value}\n"));
+            previous_name = Some(name);
+        }
+    }
+
+    fn render_histograms(output: &mut String) {
+        let name = "proto_hackers_handling_seconds";
+        output.push_str(&format!("# TYPE {name} histogram\n"));
+        for ((challenge, msg_type), histogram) in HISTOGRAMS.lock().unwrap().iter() {
+            let labels = format!("challenge=\"{challenge}\",type=\"{msg_type}\"");
+            let mut count = 0;
+            for (bound, bucket_count) in BUCKETS.iter().zip(histogram.counts) {
+                count += bucket_count;
+                output.push_str(&format!(
+                    "{name}_bucket{{{labels},le=\"{bound}\"}} {count}\n"
+                ));
+            }
+            count += histogram.counts[BUCKETS.len()];
+            output.push_str(&format!("{name}_bucket{{{labels},le=\"+Inf\"}} {count}\n"));
+            output.push_str(&format!("{name}_sum{{{labels}}} {}\n", histogram.sum));
+            output.push_str(&format!("{name}_count{{{labels}}} {count}\n"));
+        }
+    }
+
+    // Counters, histograms and gauges in the Prometheus text format, leaving
+    // out the challenges and message types that never touched them
+    pub fn render() -> String {
+        let mut output = String::new();
+        for counter in COUNTERS {
+            output.push_str(&format!("# TYPE {} counter\n", counter.name()));
+            for (challenge, values) in VALUES.iter().enumerate() {
+                let value = values[counter as usize].load(Ordering::Relaxed);
+                if value > 0 {
+                    output.push_str(&format!(
+                        "{}{{challenge=\"{challenge}\"}} {value}\n",
+                        counter.name()
+                    ));
+                }
+            }
+        }
+        render_active_connections(&mut output);
+        render_histograms(&mut output);
+        render_gauges(&mut output);
+        output
+    }
+
+    // Connections accepted and not yet closed
+    fn render_active_connections(output: &mut String) {
+        let name = "proto_hackers_active_connections";
+        output.push_str(&format!("# TYPE {name} gauge\n"));
+        for (challenge, values) in VALUES.iter().enumerate() {
+            let accepted = values[Counter::Connections as usize].load(Ordering::Relaxed);
+            let closed = values[Counter::Disconnects as usize].load(Ordering::Relaxed);
+            if accepted > 0 {
+                let active = accepted.saturating_sub(closed);
+                output.push_str(&format!("{name}{{challenge=\"{challenge}\"}} {active}\n"));
+            }
+        }
+    }
+
+    // Minimal HTTP endpoint answering any request with the counters
+    pub async fn serve(listener: TcpListener) {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(respond(stream));
+        }
+    }
+
+    async fn respond(mut stream: TcpStream) {
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request).await;
+        let body = render();
+        let header = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            body.len()
+        );
+        let _ = stream.write_all((header + &body).as_bytes()).await;
+        let _ = stream.shutdown().await;
+    }
+}
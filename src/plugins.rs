@@ -0,0 +1,42 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use crate::{ChallengeInfo, MAX_CHALLENGES, NB_CHALLENGES, ProtoError, ServerType, Transport};
+
+// Challenges implemented outside of this crate, looked up after the built-in
+// ones. The same server instance is shared by every run of its challenge
+static PLUGINS: RwLock<BTreeMap<u8, (&'static str, ServerType)>> = RwLock::new(BTreeMap::new());
+
+pub fn register(part: u8, name: &'static str, server: ServerType) -> Result<(), ProtoError> {
+    if part < NB_CHALLENGES {
+        return Err(ProtoError::BuiltinChallenge(part));
+    }
+    if part >= MAX_CHALLENGES {
+        return Err(ProtoError::ChallengeOutOfRange(part));
+    }
+    let mut plugins = PLUGINS.write().unwrap();
+    if plugins.contains_key(&part) {
+        return Err(ProtoError::AlreadyRegistered(part));
+    }
+    plugins.insert(part, (name, server));
+    Ok(())
+}
+
+pub fn lookup(part: u8) -> Option<(&'static str, ServerType)> {
+    PLUGINS.read().unwrap().get(&part).cloned()
+}
+
+pub fn list() -> Vec<ChallengeInfo> {
+    let plugins = PLUGINS.read().unwrap();
+    let challenges = plugins.iter().map(|(&part, (name, server))| ChallengeInfo {
+        part,
+        name,
+        aliases: &[],
+        transport: match server {
+            ServerType::Tcp(_) => Transport::Tcp,
+            ServerType::Udp(_) => Transport::Udp,
+        },
+        compiled: true,
+    });
+    challenges.collect()
+}
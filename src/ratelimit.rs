@@ -0,0 +1,121 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use dashmap::DashMap;
+use tokio::time::{Duration, Instant};
+
+use crate::config::RateLimit;
+
+// Buckets are only swept once there are this many, to keep the common case of
+// a few peers free of any bookkeeping
+const SWEEP_THRESHOLD: usize = 1024;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token bucket per peer IP address, each connection or datagram taking one
+// token. A full bucket is the same as no bucket, so those are swept, otherwise
+// a flood of spoofed source addresses would grow the map without bounds
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: DashMap<IpAddr, Bucket>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> Option<Self> {
+        let per_second = limit.per_second.filter(|&rate| rate > 0.0)?;
+        let burst = limit.burst.map_or(per_second.ceil(), f64::from);
+        Some(Self {
+            per_second,
+            burst: burst.max(1.0),
+            buckets: DashMap::new(),
+            last_sweep: Mutex::new(Instant::now()),
+        })
+    }
+
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        if self.buckets.len() >= SWEEP_THRESHOLD {
+            self.sweep(now);
+        }
+        let mut bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refill = (now - bucket.updated).as_secs_f64() * self.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn sweep(&self, now: Instant) {
+        let mut last_sweep = self.last_sweep.lock().unwrap();
+        if now - *last_sweep < SWEEP_INTERVAL {
+            return;
+        }
+        *last_sweep = now;
+        let refill_time = Duration::from_secs_f64(self.burst / self.per_second);
+        self.buckets
+            .retain(|_, bucket| now - bucket.updated < refill_time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+
+    fn limiter(per_second: f64, burst: u32) -> RateLimiter {
+        let limit = RateLimit {
+            per_second: Some(per_second),
+            burst: Some(burst),
+        };
+        RateLimiter::new(&limit).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bursts_then_refills_at_rate() {
+        let limiter = limiter(2.0, 3);
+        let ip = "10.0.0.1".parse().unwrap();
+        assert!((0..3).all(|_| limiter.admits(ip)));
+        assert!(!limiter.admits(ip));
+
+        time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.admits(ip));
+        assert!(!limiter.admits(ip));
+
+        // Other peers have buckets of their own
+        assert!(limiter.admits("10.0.0.2".parse().unwrap()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refilled_buckets_are_swept() {
+        let limiter = limiter(10.0, 1);
+        for i in 0..SWEEP_THRESHOLD as u32 {
+            assert!(limiter.admits(IpAddr::from(i.to_be_bytes())));
+        }
+        time::advance(Duration::from_secs(2)).await;
+        assert!(limiter.admits("10.0.0.1".parse().unwrap()));
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+
+    #[test]
+    fn unset_or_zero_rate_is_unlimited() {
+        assert!(RateLimiter::new(&RateLimit::default()).is_none());
+        let limit = RateLimit {
+            per_second: Some(0.0),
+            burst: None,
+        };
+        assert!(RateLimiter::new(&limit).is_none());
+    }
+}
@@ -0,0 +1,295 @@
+use std::env;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::time;
+
+use crate::clients::{
+    Camera, ChatClient, Dispatcher, JobClient, KvClient, MeansClient, PrimeClient, Ticket,
+    VcsClient,
+};
+use crate::testing::{self, TestServer};
+use crate::{Config, NB_CHALLENGES};
+#[cfg(feature = "server_11")]
+use crate::{MockAuthority, clients::SiteVisitor};
+
+const SMOKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type SmokeResult = Result<(), &'static str>;
+
+// Parse `selftest [challenge]`, every challenge being tested when none is given
+pub fn challenges_from_args() -> Result<Vec<u8>, &'static str> {
+    match env::args().nth(2) {
+        None => Ok((0..NB_CHALLENGES).collect()),
+        Some(arg) => match crate::parse_challenge(&arg) {
+            Ok(challenge) if challenge < NB_CHALLENGES => Ok(vec![challenge]),
+            _ => Err("invalid challenge"),
+        },
+    }
+}
+
+fn check(condition: bool, err_msg: &'static str) -> SmokeResult {
+    if condition { Ok(()) } else { Err(err_msg) }
+}
+
+async fn echo(server: &TestServer) -> SmokeResult {
+    let mut client = server.connect().await.or(Err("could not connect"))?;
+    client.send(b"smoke test\n").await.or(Err("send failed"))?;
+    let response = client.recv_exact(11).await.ok_or("no echo")?;
+    check(response == b"smoke test\n", "wrong echo")
+}
+
+async fn prime(server: &TestServer) -> SmokeResult {
+    let mut client = PrimeClient::connect(server.addr)
+        .await
+        .or(Err("could not connect"))?;
+    check(client.is_prime(7919).await == Some(true), "7919 is prime")?;
+    check(
+        client.is_prime(7917).await == Some(false),
+        "7917 is not prime",
+    )
+}
+
+async fn means(server: &TestServer) -> SmokeResult {
+    let mut client = MeansClient::connect(server.addr)
+        .await
+        .or(Err("could not connect"))?;
+    for (timestamp, price) in [(12345, 101), (12346, 102), (12347, 100), (40960, 5)] {
+        client
+            .insert(timestamp, price)
+            .await
+            .or(Err("insert failed"))?;
+    }
+    check(client.query(12288, 16384).await == Some(101), "wrong mean")
+}
+
+async fn chat(server: &TestServer) -> SmokeResult {
+    let mut alice = ChatClient::join(server.addr, "alice")
+        .await
+        .or(Err("alice could not join"))?;
+    let mut bob = ChatClient::join(server.addr, "bob")
+        .await
+        .or(Err("bob could not join"))?;
+    check(bob.members() == ["alice"], "wrong room members")?;
+    let joined = alice.recv().await.ok_or("no join notification")?;
+    check(
+        joined == "* bob has entered the room",
+        "wrong join notification",
+    )?;
+    bob.send("hello").await.or(Err("send failed"))?;
+    let msg = alice.recv().await.ok_or("no message")?;
+    check(msg == "[bob] hello", "wrong message")
+}
+
+async fn kv(server: &TestServer) -> SmokeResult {
+    let client = KvClient::connect(server.addr)
+        .await
+        .or(Err("could not connect"))?;
+    client.insert("foo", "bar").await.or(Err("insert failed"))?;
+    let value = client.retrieve("foo").await.ok_or("no value")?;
+    check(value == "bar", "wrong value")?;
+    let version = client.retrieve("version").await.ok_or("no version")?;
+    check(!version.is_empty(), "empty version")
+}
+
+// The proxy is pointed at a budget chat server spawned alongside it
+async fn proxy() -> SmokeResult {
+    let upstream = testing::spawn(3)
+        .await
+        .or(Err("could not start upstream"))?;
+    let mut config = Config::default();
+    config.proxy.upstream_addr = upstream.addr.to_string();
+    let server = testing::spawn_with_config(5, &config)
+        .await
+        .or(Err("could not start server"))?;
+
+    let mut alice = ChatClient::join(upstream.addr, "alice")
+        .await
+        .or(Err("alice could not join"))?;
+    let mut bob = ChatClient::join(server.addr, "bob")
+        .await
+        .or(Err("bob could not join through the proxy"))?;
+    alice.recv().await.ok_or("no join notification")?;
+    bob.send("send to 7F1u3wSD5RbOHQmupo9nx4TnhQ please")
+        .await
+        .or(Err("send failed"))?;
+    let msg = alice.recv().await.ok_or("no message")?;
+    check(
+        msg == "[bob] send to 7YWHMfk9JZe0LM0g1ZauHuiSxhI please",
+        "address not rewritten",
+    )
+}
+
+async fn speed(server: &TestServer) -> SmokeResult {
+    let mut first = Camera::connect(server.addr, 123, 8, 60)
+        .await
+        .or(Err("could not connect camera"))?;
+    let mut second = Camera::connect(server.addr, 123, 9, 60)
+        .await
+        .or(Err("could not connect camera"))?;
+    first.plate("UN1X", 0).await.or(Err("send failed"))?;
+    second.plate("UN1X", 45).await.or(Err("send failed"))?;
+
+    let mut dispatcher = Dispatcher::connect(server.addr, &[123])
+        .await
+        .or(Err("could not connect dispatcher"))?;
+    let ticket = dispatcher.recv_ticket().await.ok_or("no ticket")?;
+    let expected = Ticket {
+        plate: String::from("UN1X"),
+        road: 123,
+        mile1: 8,
+        timestamp1: 0,
+        mile2: 9,
+        timestamp2: 45,
+        speed: 8000,
+    };
+    check(ticket == expected, "wrong ticket")
+}
+
+async fn lrcp(server: &TestServer) -> SmokeResult {
+    let client = server.udp_client().await.or(Err("could not bind"))?;
+    client
+        .send(b"/connect/1234/")
+        .await
+        .or(Err("send failed"))?;
+    let ack = client.recv().await.ok_or("no connect ack")?;
+    check(ack == b"/ack/1234/0/", "wrong connect ack")?;
+    client
+        .send(b"/data/1234/0/hello\n/")
+        .await
+        .or(Err("send failed"))?;
+    let ack = client.recv().await.ok_or("no data ack")?;
+    check(ack == b"/ack/1234/6/", "wrong data ack")?;
+    let data = client.recv().await.ok_or("no reversed line")?;
+    check(data == b"/data/1234/0/olleh\n/", "wrong reversed line")?;
+    client.send(b"/close/1234/").await.or(Err("send failed"))
+}
+
+// Cipher spec xor(1), so both requests and responses are xored with 1
+async fn cipher(server: &TestServer) -> SmokeResult {
+    let mut client = server.connect().await.or(Err("could not connect"))?;
+    let request: Vec<u8> = b"4x dog,5x car\n".iter().map(|c| c ^ 1).collect();
+    client
+        .send(&[0x02, 0x01, 0x00])
+        .await
+        .or(Err("send failed"))?;
+    client.send(&request).await.or(Err("send failed"))?;
+    let response = client.recv_exact(7).await.ok_or("no response")?;
+    let response: Vec<u8> = response.iter().map(|c| c ^ 1).collect();
+    check(response == b"5x car\n", "wrong response")
+}
+
+async fn jobs(server: &TestServer) -> SmokeResult {
+    let mut client = JobClient::connect(server.addr)
+        .await
+        .or(Err("could not connect"))?;
+    let id = client
+        .put("queue", json!({"title": "smoke"}), 10)
+        .await
+        .ok_or("put failed")?;
+    let job = client
+        .get(&["queue"], false)
+        .await
+        .ok_or("get failed")?
+        .ok_or("no job")?;
+    check(job.id == id, "wrong job")?;
+    check(client.delete(id).await == Some(true), "delete failed")?;
+    let job = client.get(&["queue"], false).await.ok_or("get failed")?;
+    check(job.is_none(), "job not deleted")
+}
+
+async fn vcs(server: &TestServer) -> SmokeResult {
+    let mut client = VcsClient::connect(server.addr)
+        .await
+        .or(Err("could not connect"))?;
+    check(
+        client.put("/smoke/file", b"one\n").await == Some(Ok(1)),
+        "put failed",
+    )?;
+    check(
+        client.put("/smoke/file", b"two\n").await == Some(Ok(2)),
+        "put failed",
+    )?;
+    check(
+        client.get("/smoke/file", Some(1)).await == Some(Ok(b"one\n".to_vec())),
+        "wrong revision",
+    )?;
+    check(
+        client.list("/").await == Some(Ok(vec![String::from("smoke/ DIR")])),
+        "wrong listing",
+    )
+}
+
+// The authority server is a mock started alongside the server
+#[cfg(feature = "server_11")]
+async fn pestcontrol() -> SmokeResult {
+    let (authority_addr, authority) = MockAuthority::new()
+        .with_site(7, &[("fox", 0, 1)])
+        .spawn("127.0.0.1:0")
+        .await
+        .or(Err("could not start authority"))?;
+    let mut config = Config::default();
+    config.pestcontrol.authority_addr = authority_addr.to_string();
+    let server = testing::spawn_with_config(11, &config)
+        .await
+        .or(Err("could not start server"))?;
+
+    let mut visitor = SiteVisitor::connect(server.addr)
+        .await
+        .or(Err("could not connect"))?;
+    visitor
+        .visit(7, &[("fox", 3)])
+        .await
+        .or(Err("visit failed"))?;
+    loop {
+        let policies = authority.policies(7).await;
+        if !policies.is_empty() {
+            return check(policies == [(1, String::from("fox"), 0x90)], "wrong policy");
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn smoke(challenge: u8) -> SmokeResult {
+    match challenge {
+        5 => return proxy().await,
+        #[cfg(feature = "server_11")]
+        11 => return pestcontrol().await,
+        _ => {}
+    }
+
+    let server = testing::spawn(challenge)
+        .await
+        .or(Err("could not start server"))?;
+    match challenge {
+        0 => echo(&server).await,
+        1 => prime(&server).await,
+        2 => means(&server).await,
+        3 => chat(&server).await,
+        4 => kv(&server).await,
+        6 => speed(&server).await,
+        7 => lrcp(&server).await,
+        8 => cipher(&server).await,
+        9 => jobs(&server).await,
+        10 => vcs(&server).await,
+        _ => Err("unknown challenge"),
+    }
+}
+
+// Returns whether every challenge passed its smoke test
+pub async fn run(challenges: &[u8]) -> bool {
+    let mut success = true;
+    for &challenge in challenges {
+        let result = time::timeout(SMOKE_TIMEOUT, smoke(challenge))
+            .await
+            .unwrap_or(Err("timed out"));
+        match result {
+            Ok(()) => println!("server {challenge:02}: ok"),
+            Err(err_msg) => {
+                println!("server {challenge:02}: FAILED ({err_msg})");
+                success = false;
+            }
+        }
+    }
+    success
+}
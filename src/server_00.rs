@@ -1,26 +1,67 @@
 use async_trait::async_trait;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::time::{self, Duration};
 
-use crate::TcpServer;
+use crate::config::{EchoConfig as Config, EchoMode};
+use crate::metrics::{self, Counter};
+use crate::utils::{BUFFER_POOL, FrameReader};
+use crate::{ClientStream, TcpServer};
 
-pub struct Server {}
+const CHALLENGE: u8 = 0;
+
+pub struct Server {
+    mode: EchoMode,
+}
 impl Server {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(config: Config) -> Self {
+        Self { mode: config.mode }
+    }
+
+    // Data after the last newline is never echoed
+    async fn echo_lines(stream: ClientStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = FrameReader::new(reader);
+        while let Ok(Some(mut line)) = reader.read_line().await {
+            metrics::increment(CHALLENGE, Counter::Requests);
+            line.extend_from_slice(b"\n");
+            if let Err(err) = writer.write_all(&line).await {
+                return tracing::debug!("could not echo to client: {err}");
+            }
+            metrics::add(CHALLENGE, Counter::EchoedBytes, line.len() as u64);
+        }
+        let _ = writer.shutdown().await;
     }
 }
 
 #[async_trait]
 impl TcpServer for Server {
-    async fn handle_connection(&self, mut stream: TcpStream) {
-        loop {
-            let mut buffer = [0; 1024];
-            match stream.read(&mut buffer).await {
+    async fn handle_connection(&self, mut stream: ClientStream) {
+        let (delay, mut remaining) = match self.mode {
+            EchoMode::Plain => (Duration::ZERO, usize::MAX),
+            EchoMode::Lines => return Self::echo_lines(stream).await,
+            EchoMode::Delayed(delay) => (delay, usize::MAX),
+            EchoMode::Limited(limit) => (Duration::ZERO, limit),
+        };
+        let mut buffer = BUFFER_POOL.lease();
+        while remaining > 0 {
+            buffer.clear();
+            let n = match stream.read_buf(&mut *buffer).await {
                 Ok(0) => break,
-                Ok(n) => stream.write(&buffer[0..n]).await.unwrap(),
-                Err(err) => panic!("{}", err),
+                Ok(n) => n,
+                Err(err) => return tracing::debug!("could not read from client: {err}"),
             };
+            metrics::increment(CHALLENGE, Counter::Requests);
+            if !delay.is_zero() {
+                time::sleep(delay).await;
+            }
+            let n = n.min(remaining);
+            remaining -= n;
+            if let Err(err) = stream.write_all(&buffer[..n]).await {
+                return tracing::debug!("could not echo to client: {err}");
+            }
+            metrics::add(CHALLENGE, Counter::EchoedBytes, n as u64);
         }
+        // Only once everything was echoed, the peer being gone otherwise
+        let _ = stream.shutdown().await;
     }
 }
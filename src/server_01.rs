@@ -1,71 +1,302 @@
 use async_trait::async_trait;
-use serde_json::json;
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use serde_json::Number;
+use thiserror::Error;
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
 
-use crate::{TcpServer, utils};
+use crate::metrics::{self, Counter};
+use crate::utils::FrameReader;
+use crate::utils::json_lines::JsonLines;
+use crate::{ClientStream, ProtoError, StateSizes, TcpServer};
 
-fn is_prime(n: f64) -> bool {
-    if n.fract() != 0.0 || n < 2.0 {
-        return false;
-    }
+mod cache;
 
-    let n = n as u64;
-    if n == 2 || n == 3 || n == 5 {
-        return true;
-    }
+use cache::PrimeCache;
+
+const CHALLENGE: u8 = 1;
+// Sent back to malformed requests, any response that is not a valid one would do
+const MALFORMED_RESPONSE: &[u8] = b"{}\n";
 
-    if n % 2 == 0 || n % 3 == 0 || n % 5 == 0 {
+// Longest integer tested for primality, a prime of this size taking a
+// fraction of a second. Longer ones make the request malformed
+const MAX_DIGITS: usize = 1000;
+// Bounds the work a single request asks for, a line holding a few dozen
+// numbers of `MAX_DIGITS` digits at most
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+// Bases making Miller-Rabin exact below 3.3e24, also used for trial division
+const BASES: [u32; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+// Digits of the number if it is a non-negative integer. Per the spec only
+// integers may be prime, so numbers written with a fraction or an exponent
+// are not, even `7.0`
+fn integer_digits(number: &str) -> Option<&str> {
+    let is_integer = number.bytes().all(|c| c.is_ascii_digit());
+    is_integer.then_some(number)
+}
+
+fn test_digits(digits: &str) -> bool {
+    let n = BigUint::parse_bytes(digits.as_bytes(), 10).unwrap();
+    if n < BigUint::from(2u32) {
         return false;
     }
-
-    let limit = (n as f64).sqrt().abs() as u64 + 1;
-    let mut k = 1;
-    while 6 * k < limit {
-        if n % (6 * k + 1) == 0 || n % (6 * k + 5) == 0 {
+    for base in BASES {
+        if n == BigUint::from(base) {
+            return true;
+        }
+        let remainder = digits
+            .bytes()
+            .fold(0, |rem, digit| (rem * 10 + u32::from(digit - b'0')) % base);
+        if remainder == 0 {
             return false;
         }
-        k += 1;
     }
-    true
+    passes_miller_rabin(&n, &BASES)
 }
 
-pub struct Server {}
-impl Server {
-    pub fn new() -> Self {
-        Self {}
+// Miller-Rabin test with the given bases, all smaller than `n`, which must be
+// odd and greater than 2
+fn passes_miller_rabin(n: &BigUint, bases: &[u32]) -> bool {
+    let one = BigUint::from(1u32);
+    let n_minus_one = n - 1u32;
+    let nb_twos = n_minus_one.trailing_zeros().unwrap();
+    let odd_part = &n_minus_one >> nb_twos;
+    'bases: for &base in bases {
+        let mut x = BigUint::from(base).modpow(&odd_part, n);
+        if x == one || x == n_minus_one {
+            continue;
+        }
+        for _ in 1..nb_twos {
+            x = &x * &x % n;
+            if x == n_minus_one {
+                continue 'bases;
+            }
+        }
+        return false;
     }
+    true
+}
+
+// Fields other than `method` and `number` are ignored. Integers longer than
+// `MAX_DIGITS` digits make the request malformed
+#[derive(Deserialize)]
+struct Request {
+    method: String,
+    number: Numbers,
+}
+
+// `number` may also be an array of numbers, `prime` then being an array of
+// the answers
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Numbers {
+    One(Number),
+    Many(Vec<Number>),
+}
 
-    fn get_response(buf: &str) -> Option<String> {
-        let object: serde_json::Value = serde_json::from_str(buf).ok()?;
-        let object = object.as_object()?;
+#[derive(Serialize)]
+struct Response {
+    method: &'static str,
+    prime: Answers,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Answers {
+    One(bool),
+    Many(Vec<bool>),
+}
 
-        let method = object.get("method")?.as_str()?;
-        let number = object.get("number")?.as_f64()?;
+// Why a request is malformed, rules the request types cannot express
+// having a variant of their own
+#[derive(Debug, Error)]
+enum Malformed {
+    // Not a JSON line of the shape of a request
+    #[error(transparent)]
+    Decoding(#[from] ProtoError),
+    #[error("unknown method {0:?}")]
+    UnknownMethod(String),
+    #[error("integer of {0} digits is too long to be tested")]
+    TooLong(usize),
+}
 
-        if method != "isPrime" {
-            return None;
+pub struct Server {
+    cache: PrimeCache,
+}
+impl Server {
+    pub fn new() -> Self {
+        Self {
+            cache: PrimeCache::new(),
         }
+    }
 
-        let response = json!({
-            "method": "isPrime",
-            "prime": is_prime(number)
-        });
+    // Numbers are tested as written, big integers not fitting any primitive.
+    // Answers are exact below 3.3e24, and only probable primes above it
+    fn is_prime(&self, number: &Number) -> Result<bool, Malformed> {
+        let number = number.to_string();
+        let Some(digits) = integer_digits(&number) else {
+            return Ok(false);
+        };
+        if digits.len() > MAX_DIGITS {
+            return Err(Malformed::TooLong(digits.len()));
+        }
+        Ok(self.cache.get_or_test(digits, || test_digits(digits)))
+    }
 
-        Some(response.to_string() + "\n")
+    fn get_response(&self, request: Request) -> Result<Response, Malformed> {
+        if request.method != "isPrime" {
+            return Err(Malformed::UnknownMethod(request.method));
+        }
+        let prime = match request.number {
+            Numbers::One(number) => Answers::One(self.is_prime(&number)?),
+            Numbers::Many(numbers) => {
+                let answers = numbers.iter().map(|n| self.is_prime(n));
+                Answers::Many(answers.collect::<Result<_, _>>()?)
+            }
+        };
+        Ok(Response {
+            method: "isPrime",
+            prime,
+        })
     }
 }
 
 #[async_trait]
 impl TcpServer for Server {
-    async fn handle_connection(&self, mut stream: TcpStream) {
-        let mut buffer = [0; 1024];
-        while let Some(request) = utils::read_until(&mut stream, &mut buffer, '\n').await {
-            let response = Self::get_response(&request).unwrap_or(String::from("{}\n"));
-            println!("Request {} -> response {}", request.trim(), response.trim());
-            if stream.write_all(response.as_bytes()).await.is_err() {
+    // A malformed request gets a malformed response, after which the
+    // connection is closed
+    async fn handle_connection(&self, stream: ClientStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut requests = JsonLines::new(FrameReader::new(reader).with_max_len(MAX_REQUEST_LEN));
+        while let Some(request) = requests.next::<Request>().await {
+            metrics::increment(CHALLENGE, Counter::Requests);
+            let response = request
+                .map_err(Malformed::from)
+                .and_then(|request| self.get_response(request));
+            let response = match response {
+                Ok(response) => serde_json::to_string(&response).unwrap() + "\n",
+                Err(err) => {
+                    tracing::debug!("malformed request: {err}");
+                    metrics::increment(CHALLENGE, Counter::ProtocolErrors);
+                    let _ = writer.write_all(MALFORMED_RESPONSE).await;
+                    break;
+                }
+            };
+            tracing::debug!("--> {}", response.trim());
+            if writer.write_all(response.as_bytes()).await.is_err() {
                 break;
             }
         }
     }
+
+    async fn state_sizes(&self) -> StateSizes {
+        vec![("cached_results", self.cache.len())]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn is_prime(number: &str) -> bool {
+        let number = serde_json::from_str(number).unwrap();
+        Server::new().is_prime(&number).unwrap()
+    }
+
+    fn trial_division(n: u32) -> bool {
+        n >= 2
+            && (2..)
+                .take_while(|d| d * d <= n)
+                .all(|d| !n.is_multiple_of(d))
+    }
+
+    #[test]
+    fn big_integers_are_tested_exactly() {
+        // 2^53 + 5, which rounds to an even number as a f64
+        assert!(is_prime("9007199254740997"));
+        assert!(is_prime(&(2u128.pow(89) - 1).to_string()));
+        assert!(is_prime(&(2u128.pow(127) - 1).to_string()));
+        // Strong pseudoprime to bases 2, 3, 5 and 7
+        assert!(!is_prime("3215031751"));
+        let product = u128::from(2u64.pow(61) - 1) * u128::from(2u64.pow(31) - 1);
+        assert!(!is_prime(&product.to_string()));
+        // 2^521 - 1, a Mersenne prime with 157 digits
+        let mersenne = "6864797660130609714981900799081393217269435300143305409394463459185543183397656052122559640661454554977296311391480858037121987999716643812574028291115057151";
+        assert!(is_prime(mersenne));
+        assert!(!is_prime(&format!("{mersenne}1")));
+    }
+
+    #[test]
+    fn longest_integers_are_tested_in_time() {
+        // 10^999 + 7, a prime with `MAX_DIGITS` digits, the slowest to answer
+        let prime = format!("1{}7", "0".repeat(MAX_DIGITS - 2));
+        let start = Instant::now();
+        assert!(is_prime(&prime));
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+
+        let number = Number::from_string_unchecked(format!("{prime}1"));
+        let too_long = Server::new().is_prime(&number);
+        assert!(matches!(too_long, Err(Malformed::TooLong(1001))));
+    }
+
+    #[test]
+    fn only_integers_may_be_prime() {
+        for (number, prime) in [
+            ("13", true),
+            ("1.3e1", false),
+            ("7.0", false),
+            ("130E-1", false),
+            ("7.5", false),
+            ("1e400", false),
+            ("-7", false),
+            ("0", false),
+            ("0.0", false),
+            ("1", false),
+        ] {
+            assert_eq!(is_prime(number), prime, "{number}");
+        }
+    }
+
+    #[test]
+    fn requests_keep_numbers_as_written() {
+        let server = Server::new();
+        let answer = |line| {
+            let request = serde_json::from_str(line).map_err(|_| "invalid request")?;
+            let response = server.get_response(request).map_err(|_| "malformed")?;
+            Ok::<_, &str>(serde_json::to_string(&response).unwrap())
+        };
+        assert_eq!(
+            answer(r#"{"method":"isPrime","number":9007199254740997}"#),
+            Ok(r#"{"method":"isPrime","prime":true}"#.to_owned())
+        );
+        assert_eq!(
+            answer(r#"{"number":[9007199254740997,1.3e1,4],"method":"isPrime"}"#),
+            Ok(r#"{"method":"isPrime","prime":[true,false,false]}"#.to_owned())
+        );
+        assert_eq!(
+            answer(r#"{"method":"isPrime","number":[3,"5"]}"#),
+            Err("invalid request")
+        );
+        assert_eq!(
+            answer(r#"{"method":"isprime","number":3}"#),
+            Err("malformed")
+        );
+        let too_long = format!(
+            r#"{{"method":"isPrime","number":[3,{}]}}"#,
+            "7".repeat(MAX_DIGITS + 1)
+        );
+        assert_eq!(answer(&too_long), Err("malformed"));
+    }
+
+    proptest! {
+        #[test]
+        fn small_numbers_match_trial_division(n in 0..1_000_000u32) {
+            prop_assert_eq!(is_prime(&n.to_string()), trial_division(n));
+        }
+    }
 }
@@ -0,0 +1,84 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+// Results of the numbers tested lately, shared by all the connections as the
+// same numbers tend to be asked again. Once full, the least recently used
+// result is dropped
+pub struct PrimeCache {
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    // Result and last use of each number
+    results: HashMap<String, (bool, u64)>,
+    by_last_use: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, digits: &str) -> Option<bool> {
+        let (prime, last_use) = self.results.get_mut(digits)?;
+        let digits = self.by_last_use.remove(last_use).unwrap();
+        self.clock += 1;
+        *last_use = self.clock;
+        self.by_last_use.insert(self.clock, digits);
+        Some(*prime)
+    }
+}
+
+impl PrimeCache {
+    const CAPACITY: usize = 4096;
+
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    // Tests `digits` with `test` unless its result is known. The lock is not
+    // held meanwhile, so the same number may be tested twice at once
+    pub fn get_or_test(&self, digits: &str, test: impl FnOnce() -> bool) -> bool {
+        if let Some(prime) = self.entries.lock().unwrap().touch(digits) {
+            return prime;
+        }
+        let prime = test();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.touch(digits).is_none() {
+            if entries.results.len() >= Self::CAPACITY {
+                let (_, oldest) = entries.by_last_use.pop_first().unwrap();
+                entries.results.remove(&oldest);
+            }
+            entries.clock += 1;
+            let clock = entries.clock;
+            entries.results.insert(digits.to_owned(), (prime, clock));
+            entries.by_last_use.insert(clock, digits.to_owned());
+        }
+        prime
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().results.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_result_is_evicted() {
+        let cache = PrimeCache::new();
+        for n in 0..PrimeCache::CAPACITY {
+            cache.get_or_test(&n.to_string(), || false);
+        }
+        // Used again, so no longer the oldest
+        assert!(!cache.get_or_test("0", || unreachable!()));
+        cache.get_or_test("new", || true);
+
+        assert_eq!(cache.len(), PrimeCache::CAPACITY);
+        assert!(!cache.get_or_test("0", || unreachable!()));
+        assert!(cache.get_or_test("1", || true));
+        assert!(cache.get_or_test("new", || unreachable!()));
+    }
+}
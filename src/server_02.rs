@@ -1,8 +1,18 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+
 use async_trait::async_trait;
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
 
-use crate::{TcpServer, utils};
+use crate::metrics::{self, Counter};
+use crate::utils::FrameReader;
+use crate::{ClientStream, TcpServer};
+
+const CHALLENGE: u8 = 2;
+
+// Prices of a session by timestamp, so that queries only go through the
+// prices in their range
+type Prices = BTreeMap<i32, i64>;
 
 pub struct Server {}
 impl Server {
@@ -10,19 +20,19 @@ impl Server {
         Self {}
     }
 
-    fn get_response(data: &mut Vec<(i32, i32)>, buf: &[u8]) -> Option<i32> {
+    fn get_response(prices: &mut Prices, buf: &[u8]) -> Option<i32> {
         let query_type: char = char::from(buf[0]);
         let first = i32::from_be_bytes(buf[1..5].try_into().unwrap());
         let second = i32::from_be_bytes(buf[5..].try_into().unwrap());
 
         match query_type {
             'Q' => {
-                let (sum, count) = data
-                    .iter()
-                    .filter(|(timestamp, _)| (first..=second).contains(timestamp))
-                    .fold((0, 0), |(sum, count), &(_, price)| {
-                        (sum + (price as i64), count + 1)
-                    });
+                if first > second {
+                    return Some(0);
+                }
+                let (sum, count) = prices
+                    .range(first..=second)
+                    .fold((0, 0), |(sum, count), (_, price)| (sum + price, count + 1));
                 if count > 0 {
                     Some((sum / count) as i32)
                 } else {
@@ -30,24 +40,35 @@ impl Server {
                 }
             }
             'I' => {
-                data.push((first, second));
+                // Undefined by the spec, the first price is kept
+                match prices.entry(first) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(second.into());
+                    }
+                    Entry::Occupied(_) => tracing::debug!("price already inserted at {first}"),
+                }
+                None
+            }
+            _ => {
+                metrics::increment(CHALLENGE, Counter::ProtocolErrors);
                 None
             }
-            _ => None,
         }
     }
 }
 
 #[async_trait]
 impl TcpServer for Server {
-    async fn handle_connection(&self, mut stream: TcpStream) {
-        let mut data = Vec::new();
-        let mut buffer: Vec<u8> = Vec::new();
-        while let Some(request) = utils::read_for(&mut stream, &mut buffer, 9).await {
-            println!("Request: {:?}", request);
-            let response = Self::get_response(&mut data, &request);
+    async fn handle_connection(&self, stream: ClientStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = FrameReader::new(reader);
+        let mut prices = Prices::new();
+        while let Ok(Some(request)) = reader.read_exact(9).await {
+            tracing::debug!("request {request:?}");
+            metrics::increment(CHALLENGE, Counter::Requests);
+            let response = Self::get_response(&mut prices, &request);
             if response.is_some()
-                && stream
+                && writer
                     .write_all(&response.unwrap().to_be_bytes())
                     .await
                     .is_err()
@@ -57,3 +78,104 @@ impl TcpServer for Server {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Insert(i32, i32),
+        Query(i32, i32),
+    }
+
+    fn request(kind: u8, first: i32, second: i32) -> Vec<u8> {
+        let mut buf = vec![kind];
+        buf.extend(first.to_be_bytes());
+        buf.extend(second.to_be_bytes());
+        buf
+    }
+
+    // Timestamps mostly close to each other so that queries hit several prices
+    fn timestamp() -> impl Strategy<Value = i32> {
+        prop_oneof![4 => -100..100, 1 => any::<i32>()]
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (timestamp(), any::<i32>()).prop_map(|(t, price)| Op::Insert(t, price)),
+            (timestamp(), timestamp()).prop_map(|(min, max)| Op::Query(min, max)),
+        ]
+    }
+
+    // Naive reference going through every price, the mean being rounded
+    // towards zero. An inverted range selects nothing
+    fn reference_mean(prices: &[(i32, i32)], min: i32, max: i32) -> i32 {
+        let selected: Vec<i128> = prices
+            .iter()
+            .filter(|&&(timestamp, _)| min <= timestamp && timestamp <= max)
+            .map(|&(_, price)| price.into())
+            .collect();
+        if selected.is_empty() {
+            return 0;
+        }
+        (selected.iter().sum::<i128>() / selected.len() as i128) as i32
+    }
+
+    proptest! {
+        #[test]
+        fn queries_match_reference(ops in prop::collection::vec(op(), 0..200)) {
+            let mut data = Prices::new();
+            let mut reference = Vec::new();
+            for op in ops {
+                match op {
+                    // Several prices with the same timestamp are undefined behaviour
+                    Op::Insert(timestamp, _) if reference.iter().any(|&(t, _)| t == timestamp) => {}
+                    Op::Insert(timestamp, price) => {
+                        reference.push((timestamp, price));
+                        let insert = request(b'I', timestamp, price);
+                        prop_assert_eq!(Server::get_response(&mut data, &insert), None);
+                    }
+                    Op::Query(min, max) => {
+                        let response = Server::get_response(&mut data, &request(b'Q', min, max));
+                        let expected = reference_mean(&reference, min, max);
+                        prop_assert_eq!(response, Some(expected), "Q {} {}", min, max);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn duplicate_timestamps_keep_the_first_price(
+            timestamp in timestamp(),
+            first in any::<i32>(),
+            second in any::<i32>(),
+        ) {
+            let mut data = Prices::new();
+            Server::get_response(&mut data, &request(b'I', timestamp, first));
+            Server::get_response(&mut data, &request(b'I', timestamp, second));
+            let query = request(b'Q', timestamp, timestamp);
+            prop_assert_eq!(Server::get_response(&mut data, &query), Some(first));
+        }
+
+        #[test]
+        fn inverted_or_empty_ranges_give_zero(
+            prices in prop::collection::btree_map(timestamp(), any::<i32>(), 0..50),
+            min in timestamp(),
+            max in timestamp(),
+        ) {
+            let mut data = Prices::new();
+            for (&timestamp, &price) in &prices {
+                Server::get_response(&mut data, &request(b'I', timestamp, price));
+            }
+            let (min, max) = (min.max(max), min.min(max));
+            if min != max {
+                prop_assert_eq!(Server::get_response(&mut data, &request(b'Q', min, max)), Some(0));
+            }
+            let empty = (i32::MIN..i32::MAX).find(|t| !prices.contains_key(t)).unwrap();
+            prop_assert_eq!(Server::get_response(&mut data, &request(b'Q', empty, empty)), Some(0));
+        }
+    }
+}
@@ -1,94 +1,175 @@
 use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpStream, tcp::OwnedWriteHalf};
-use tokio::sync::Mutex;
+use tokio::io::AsyncRead;
+use tokio::sync::RwLock;
 
-use crate::{TcpServer, utils};
+use crate::metrics::{self, Counter};
+use crate::utils::{self, FrameReader, QueuedWriter};
+use crate::{ClientStream, StateSizes, TcpServer};
 
+const CHALLENGE: u8 = 3;
+
+// Chat messages only need read access to the room, so they are broadcast
+// concurrently, each member's writer keeping their lines whole
 pub struct Server {
-    connections: Arc<Mutex<HashMap<String, OwnedWriteHalf>>>,
+    connections: Arc<RwLock<HashMap<String, QueuedWriter>>>,
 }
 
 impl Server {
     pub fn new() -> Self {
         Self {
-            connections: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
     fn is_valid(name: &str) -> bool {
-        name.chars().all(|c| c.is_alphanumeric())
+        !name.is_empty() && name.chars().all(|c| c.is_alphanumeric())
     }
 
-    async fn add_user(&self, username: &str, writer: OwnedWriteHalf) {
-        self.connections
-            .lock()
-            .await
-            .insert(username.to_string(), writer);
-    }
+    // Listing the room, announcing the newcomer and adding it happen under the
+    // same lock, otherwise a concurrent join could be missed or seen twice
+    async fn join(&self, username: &str, writer: QueuedWriter) {
+        let mut connections = self.connections.write().await;
+        let names: Vec<_> = connections.keys().cloned().collect();
+        writer.send(format!("* The room contains {}\n", names.join(", ")));
 
-    async fn remove_user(&self, username: &str) {
-        self.connections.lock().await.remove(username);
+        let join_msg = Bytes::from(format!("* {username} has entered the room\n"));
+        for writer in connections.values() {
+            writer.send(join_msg.clone());
+        }
+        connections.insert(username.to_string(), writer);
     }
 
-    async fn send_to(&self, username: &str, msg: &str) {
-        let mut connections = self.connections.lock().await;
-        let writer = connections.get_mut(username).unwrap();
-        writer.write_all(msg.as_bytes()).await.unwrap();
+    async fn leave(&self, username: &str) {
+        let mut connections = self.connections.write().await;
+        connections.remove(username);
+        let exit_msg = Bytes::from(format!("* {username} has left the room\n"));
+        for writer in connections.values() {
+            writer.send(exit_msg.clone());
+        }
     }
 
-    async fn broadcast_from(&self, username: &str, msg: &str) {
-        for (name, writer) in self.connections.lock().await.iter_mut() {
+    async fn broadcast_from(&self, username: &str, msg: Bytes) {
+        for (name, writer) in self.connections.read().await.iter() {
+            // A member leaving concurrently must not take the sender down
             if name != username {
-                writer.write_all(msg.as_bytes()).await.unwrap();
+                writer.send(msg.clone());
             }
         }
     }
 
     async fn broadcast_chat(&self, from: &str, msg: &str) {
-        self.broadcast_from(from, &format!("[{from}] {msg}\n"))
-            .await;
+        let msg = format!("[{from}] {msg}\n");
+        self.broadcast_from(from, msg.into()).await;
+    }
+}
+
+// Next line, split off the pooled buffer of the reader, none once the stream
+// is closed. Lines too long to be buffered end the connection as well
+async fn next_line(reader: &mut FrameReader<impl AsyncRead + Unpin>) -> Option<BytesMut> {
+    match reader.read_line().await {
+        Ok(line) => line,
+        Err(err) => {
+            if utils::protocol_error(&err).is_some() {
+                metrics::increment(CHALLENGE, Counter::ProtocolErrors);
+            }
+            tracing::debug!("connection lost: {err}");
+            None
+        }
     }
 }
 
 #[async_trait]
 impl TcpServer for Server {
-    async fn handle_connection(&self, stream: TcpStream) {
-        let mut buffer = [0; 1024];
+    async fn handle_connection(&self, stream: ClientStream) {
+        let (reader, writer) = stream.into_split();
+        let mut reader = FrameReader::new(reader);
+        let writer = QueuedWriter::new(writer);
+        writer.send(&b"Welcome to budgetchat! What shall I call you?\n"[..]);
 
-        let (mut reader, mut writer) = stream.into_split();
-        writer
-            .write_all("Welcome to budgetchat! What shall I call you?\n".as_bytes())
-            .await
-            .unwrap();
-
-        let username = match utils::read_until(&mut reader, &mut buffer, '\n').await {
+        let username = match next_line(&mut reader).await {
             None => return,
-            Some(name) if !Self::is_valid(&name) => return,
-            Some(name) => name,
+            Some(name) => String::from_utf8_lossy(&name).into_owned(),
         };
+        if !Self::is_valid(&username) {
+            return metrics::increment(CHALLENGE, Counter::ProtocolErrors);
+        }
 
-        let connections = self.connections.lock().await;
-        let welcome_msg = format!(
-            "* The room contains {}\n",
-            connections.keys().cloned().collect::<Vec<_>>().join(", ")
-        );
-        drop(connections);
-
-        self.add_user(&username, writer).await;
-        self.send_to(&username, &welcome_msg).await;
-
-        let join_msg = format!("* {username} has entered the room\n");
-        self.broadcast_from(&username, &join_msg).await;
+        // Members that cannot be written to are let go without waiting for
+        // them to send anything
+        let closed = writer.closed().clone();
+        self.join(&username, writer).await;
 
-        while let Some(msg) = utils::read_until(&mut reader, &mut buffer, '\n').await {
+        while let Some(Some(msg)) = closed.run_until_cancelled(next_line(&mut reader)).await {
+            metrics::increment(CHALLENGE, Counter::Requests);
+            let msg = String::from_utf8_lossy(&msg);
             self.broadcast_chat(&username, &msg).await;
         }
 
-        self.remove_user(&username).await;
-        let exit_msg = format!("* {username} has left the room\n");
-        self.broadcast_from(&username, &exit_msg).await;
+        self.leave(&username).await;
+    }
+
+    async fn state_sizes(&self) -> StateSizes {
+        vec![("members", self.connections.read().await.len())]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+    use super::*;
+    use crate::testing;
+    use crate::utils::MAX_FRAME_LEN;
+
+    async fn join(server: &Arc<Server>, name: &str) -> BufReader<DuplexStream> {
+        let mut client = BufReader::new(testing::duplex(Arc::clone(server) as _));
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        let name = format!("{name}\n");
+        client.write_all(name.as_bytes()).await.unwrap();
+        line.clear();
+        client.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("* The room contains"));
+        client
+    }
+
+    #[tokio::test]
+    async fn chat_reaches_other_members() {
+        let server = Arc::new(Server::new());
+        let mut alice = join(&server, "alice").await;
+        let mut bob = join(&server, "bob").await;
+
+        let mut line = String::new();
+        alice.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "* bob has entered the room\n");
+
+        bob.write_all(b"hi\n").await.unwrap();
+        line.clear();
+        alice.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "[bob] hi\n");
+        assert_eq!(server.state_sizes().await, vec![("members", 2)]);
+    }
+
+    #[tokio::test]
+    async fn oversized_line_disconnects_the_member() {
+        let server = Arc::new(Server::new());
+        let mut alice = join(&server, "alice").await;
+        let mut bob = join(&server, "bob").await;
+
+        let mut line = String::new();
+        alice.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "* bob has entered the room\n");
+
+        // The server stops reading once the line is known to be too long
+        let _ = bob.write_all(&vec![b'x'; MAX_FRAME_LEN + 1]).await;
+        line.clear();
+        alice.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "* bob has left the room\n");
+        line.clear();
+        assert_eq!(bob.read_line(&mut line).await.unwrap(), 0);
+        assert_eq!(server.state_sizes().await, vec![("members", 1)]);
     }
 }
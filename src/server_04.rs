@@ -3,9 +3,11 @@ use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 
 use async_trait::async_trait;
-use tokio::net::UdpSocket;
 
-use crate::UdpServer;
+use crate::metrics::{self, Counter};
+use crate::{MAX_UDP_PAYLOAD, StateSizes, UdpSender, UdpServer};
+
+const CHALLENGE: u8 = 4;
 
 pub struct Server {
     database: Arc<RwLock<HashMap<String, String>>>,
@@ -20,7 +22,7 @@ impl Server {
     }
 
     fn process_request(&self, request: &str) -> Option<String> {
-        println!("Processing {request}");
+        tracing::debug!("processing {request:?}");
         match request {
             req if req.starts_with("version=") => None,
             req if req.contains("=") => {
@@ -43,11 +45,25 @@ impl Server {
 
 #[async_trait]
 impl UdpServer for Server {
-    async fn handle_connection(&self, socket: Arc<UdpSocket>, data: &[u8], addr: &SocketAddr) {
+    async fn handle_connection(&self, sender: UdpSender, data: &[u8], addr: &SocketAddr) {
+        metrics::increment(CHALLENGE, Counter::Requests);
         let request = String::from_utf8_lossy(data);
 
         if let Some(response) = self.process_request(&request) {
-            socket.send_to(response.as_bytes(), addr).await.unwrap();
+            sender.send(*addr, response);
         }
     }
+
+    async fn state_sizes(&self) -> StateSizes {
+        vec![("keys", self.database.read().unwrap().len())]
+    }
+
+    fn handles_inline(&self) -> bool {
+        true
+    }
+
+    // Values are not bounded by the store, only by what fits in a datagram
+    fn max_datagram_size(&self) -> usize {
+        MAX_UDP_PAYLOAD
+    }
 }
@@ -1,55 +1,178 @@
-use std::sync::LazyLock;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use async_trait::async_trait;
-use fancy_regex::Regex;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use crate::{TcpServer, utils};
+use crate::config::ProxyConfig;
+use crate::metrics::{self, Counter};
+use crate::transcript::{ProxyEvent, ProxySession};
+use crate::utils::{Connector, FrameReader};
+use crate::{ClientStream, TcpServer};
 
-static BOGUSCOIN_RE: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"(?<=^| )7[[:alnum:]]{25,34}(?=$| )").unwrap());
+const CHALLENGE: u8 = 5;
+
+const TONY_ADDRESS: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
+
+// Lines relayed so far in a session being recorded
+struct SessionLog {
+    start: Instant,
+    session: Mutex<ProxySession>,
+}
+
+impl SessionLog {
+    fn push(&self, from_client: bool, original: &str, rewritten: &str) {
+        self.session.lock().unwrap().events.push(ProxyEvent {
+            elapsed: self.start.elapsed(),
+            from_client,
+            original: original.as_bytes().to_vec(),
+            rewritten: rewritten.as_bytes().to_vec(),
+        });
+    }
+}
+
+pub struct Server {
+    upstream_addr: String,
+    connector: Connector,
+    // Serializes appends to the fixture file
+    record_path: Option<Mutex<PathBuf>>,
+}
 
-pub struct Server {}
 impl Server {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(config: ProxyConfig) -> Self {
+        Self {
+            upstream_addr: config.upstream_addr,
+            connector: config.connector,
+            record_path: config.record_path.map(Mutex::new),
+        }
     }
 
+    // Starts with a 7, is 26 to 35 alphanumeric characters long, and stands
+    // alone between spaces or line ends
+    fn is_boguscoin(word: &str) -> bool {
+        word.starts_with('7')
+            && (26..=35).contains(&word.len())
+            && word.bytes().all(|c| c.is_ascii_alphanumeric())
+    }
+
+    // Words are split on single spaces so that joining them back keeps the
+    // line as it was, linear in its length
     fn poison_msg(msg: String) -> String {
-        BOGUSCOIN_RE
-            .replace_all(&msg, "7YWHMfk9JZe0LM0g1ZauHuiSxhI")
-            .into()
+        msg.split(' ')
+            .map(|word| {
+                if Self::is_boguscoin(word) {
+                    TONY_ADDRESS
+                } else {
+                    word
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
     }
 
-    async fn connect_streams(reader: &mut OwnedReadHalf, writer: &mut OwnedWriteHalf) {
-        let mut buffer = [0; 1024];
-        while let Some(msg) = utils::read_until(reader, &mut buffer, '\n').await {
-            let poisoned_msg = Self::poison_msg(msg) + "\n";
-            let _ = writer.write_all(poisoned_msg.as_bytes()).await;
+    async fn connect_streams(
+        reader: impl AsyncRead + Unpin,
+        writer: &mut (impl AsyncWrite + Unpin),
+        log: Option<(Arc<SessionLog>, bool)>,
+    ) {
+        let mut reader = FrameReader::new(reader);
+        while let Ok(Some(line)) = reader.read_line().await {
+            let msg = String::from_utf8_lossy(&line).into_owned();
+            metrics::increment(CHALLENGE, Counter::Requests);
+            let poisoned_msg = Self::poison_msg(msg.clone());
+            if let Some((log, from_client)) = &log {
+                log.push(*from_client, &msg, &poisoned_msg);
+            }
+            let _ = writer.write_all((poisoned_msg + "\n").as_bytes()).await;
         }
     }
 }
 
 #[async_trait]
 impl TcpServer for Server {
-    async fn handle_connection(&self, stream: TcpStream) {
-        let server_stream = TcpStream::connect("chat.protohackers.com:16963")
-            .await
-            .unwrap();
-        let (mut client_reader, mut client_writer) = stream.into_split();
-        let (mut server_reader, mut server_writer) = server_stream.into_split();
+    async fn handle_connection(&self, stream: ClientStream) {
+        let server_stream = match self.connector.connect(&self.upstream_addr).await {
+            Ok(server_stream) => server_stream,
+            Err(err) => {
+                metrics::increment(CHALLENGE, Counter::UpstreamErrors);
+                return tracing::warn!("could not connect to {}: {err}", self.upstream_addr);
+            }
+        };
+        let (client_reader, mut client_writer) = stream.into_split();
+        let (server_reader, mut server_writer) = server_stream.into_split();
+
+        let log = self.record_path.as_ref().map(|_| {
+            Arc::new(SessionLog {
+                start: Instant::now(),
+                session: Mutex::new(ProxySession::default()),
+            })
+        });
+        let client_log = log.clone().map(|log| (log, true));
+        let server_log = log.clone().map(|log| (log, false));
 
         let thread_1 = tokio::spawn(async move {
-            Self::connect_streams(&mut client_reader, &mut server_writer).await;
+            Self::connect_streams(client_reader, &mut server_writer, client_log).await;
         });
 
         let thread_2 = tokio::spawn(async move {
-            Self::connect_streams(&mut server_reader, &mut client_writer).await;
+            Self::connect_streams(server_reader, &mut client_writer, server_log).await;
         });
 
         let _ = thread_1.await.unwrap();
         let _ = thread_2.await.unwrap();
+
+        if let (Some(log), Some(path)) = (log, &self.record_path) {
+            let path = path.lock().unwrap();
+            let session = log.session.lock().unwrap();
+            if let Err(err) = session.append_to(&path) {
+                metrics::increment(CHALLENGE, Counter::InternalErrors);
+                tracing::error!("could not record session to {}: {err}", path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poison(msg: &str) -> String {
+        Server::poison_msg(msg.to_owned())
+    }
+
+    #[test]
+    fn rewrites_standalone_addresses() {
+        let address = "7F1u3wSD5RbOHQmupo9nx4TnhQ";
+        assert_eq!(poison(address), TONY_ADDRESS);
+        assert_eq!(
+            poison(&format!("Send to {address} please")),
+            format!("Send to {TONY_ADDRESS} please")
+        );
+        assert_eq!(
+            poison(&format!("{address} {address}")),
+            format!("{TONY_ADDRESS} {TONY_ADDRESS}")
+        );
+        assert_eq!(
+            poison(&format!("  {address}  ")),
+            format!("  {TONY_ADDRESS}  ")
+        );
+    }
+
+    #[test]
+    fn leaves_other_words_alone() {
+        let too_short = format!("7{}", "a".repeat(24));
+        let too_long = format!("7{}", "a".repeat(35));
+        let longest = format!("7{}", "a".repeat(34));
+        for msg in [
+            too_short.as_str(),
+            too_long.as_str(),
+            "8F1u3wSD5RbOHQmupo9nx4TnhQ",
+            "7F1u3wSD5RbOHQmupo9nx4TnhQ-1234",
+            "This is a product ID, not a Boguscoin: 7F1u3wSD5RbOHQmupo9nx4TnhQ-",
+        ] {
+            assert_eq!(poison(msg), msg);
+        }
+        assert_eq!(poison(&longest), TONY_ADDRESS);
     }
 }
@@ -1,15 +1,33 @@
-use std::{collections::HashMap, sync::Arc};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 use async_trait::async_trait;
-use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use bytes::{Bytes, BytesMut};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde_json::json;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncRead, AsyncWriteExt};
 use tokio::sync::Mutex;
 use tokio::time;
+use tokio_util::sync::CancellationToken;
 
-use crate::{utils, TcpServer};
+use crate::config::SpeedConfig as Config;
+use crate::metrics::{self, Counter};
+use crate::utils::wire::{LengthPrefix, WireReader, WireWriter};
+use crate::utils::{self, FrameReader, QueuedWriter};
+use crate::{ClientStream, ProtoError, StateSizes, TcpServer};
 
-type ServerResult = Result<Vec<ServerMessage>, &'static str>;
+const CHALLENGE: u8 = 6;
+
+type ServerResult = Result<Vec<ServerMessage>, ProtoError>;
+
+// Type of a client message, when it was fully received and the messages to
+// send in response
+type Handled = (&'static str, Instant, Vec<ServerMessage>);
 
 type Id = u16;
 
@@ -68,7 +86,6 @@ struct ServerState {
     observations: Vec<Observation>,
     have_heartbeats: Vec<Id>,
     ticket_queue: Vec<ServerMessage>,
-    ticket_sent: HashMap<String, Vec<u32>>,
 }
 
 impl ServerState {
@@ -79,7 +96,6 @@ impl ServerState {
             observations: Vec::new(),
             have_heartbeats: Vec::new(),
             ticket_queue: Vec::new(),
-            ticket_sent: HashMap::new(),
         }
     }
 
@@ -105,59 +121,49 @@ impl ServerState {
             .next()
     }
 
-    fn generate_tickets(
-        &mut self,
-        obs1: &Observation,
-        obs2: &Observation,
-        speed: u16,
-    ) -> ServerResult {
-        let start_ts = obs1.timestamp.min(obs2.timestamp);
-        let end_ts = obs1.timestamp.max(obs2.timestamp);
-        let start_day = start_ts / 86400;
-        let end_day = end_ts / 86400;
-
-        if self
-            .ticket_sent
-            .get(&obs1.plate)
-            .is_some_and(|days| days.iter().any(|day| (start_day..=end_day).contains(day)))
-        {
-            return Ok(Vec::new());
-        }
-        self.ticket_sent
-            .entry(obs1.plate.clone())
-            .and_modify(|days| days.extend(start_day..=end_day))
-            .or_insert((start_day..=end_day).collect());
-
-        let dispatcher = self.get_dispatcher(obs1.road);
-
+    // Ticket with no recipient yet, whether one was already issued for these
+    // days is checked by the caller
+    fn generate_ticket(obs1: &Observation, obs2: &Observation, speed: u16) -> ServerMessage {
         let (mile1, mile2) = if obs1.timestamp < obs2.timestamp {
             (obs1.mile, obs2.mile)
         } else {
             (obs2.mile, obs1.mile)
         };
-        let ticket = ServerMessage::Ticket {
-            recipient: dispatcher,
+        ServerMessage::Ticket {
+            recipient: None,
             plate: obs1.plate.clone(),
             road: obs1.road,
             mile1,
-            timestamp1: start_ts,
+            timestamp1: obs1.timestamp.min(obs2.timestamp),
             mile2,
-            timestamp2: end_ts,
+            timestamp2: obs1.timestamp.max(obs2.timestamp),
             speed: 100 * speed,
-        };
+        }
+    }
 
-        if dispatcher.is_some() {
-            Ok(vec![ticket])
-        } else {
-            self.ticket_queue.push(ticket);
-            Ok(Vec::new())
+    // Tickets for roads without a dispatcher are queued until one connects
+    fn route_tickets(&mut self, tickets: Vec<ServerMessage>) -> Vec<ServerMessage> {
+        let mut routed = Vec::new();
+        for mut ticket in tickets {
+            if let ServerMessage::Ticket {
+                recipient, road, ..
+            } = &mut ticket
+            {
+                *recipient = self.get_dispatcher(*road);
+                if recipient.is_none() {
+                    self.ticket_queue.push(ticket);
+                    continue;
+                }
+            }
+            routed.push(ticket);
         }
+        routed
     }
 
     fn read_plate(&mut self, plate: Plate) -> ServerResult {
         let camera = match self.cameras.iter().find(|&camera| camera.id == plate.id) {
             Some(camera) => camera,
-            None => return Err("non-camera can't read plate"),
+            None => return Err("non-camera can't read plate".into()),
         };
 
         let observation = Observation {
@@ -180,7 +186,7 @@ impl ServerState {
         for obs in relevant_obs {
             let avg_speed = Self::compute_speed(&observation, &obs);
             if avg_speed > speed_limit {
-                tickets.extend_from_slice(&self.generate_tickets(&observation, &obs, avg_speed)?);
+                tickets.push(Self::generate_ticket(&observation, &obs, avg_speed));
             }
         }
         Ok(tickets)
@@ -188,7 +194,7 @@ impl ServerState {
 
     fn mark_heartbeat(&mut self, heartbeat: Heartbeat) -> ServerResult {
         if self.have_heartbeats.contains(&heartbeat.id) {
-            Err("client already asked for heartbeat")
+            Err("client already asked for heartbeat".into())
         } else {
             self.have_heartbeats.push(heartbeat.id);
             Ok(vec![ServerMessage::WantHeartbeat {
@@ -199,7 +205,7 @@ impl ServerState {
 
     fn add_camera(&mut self, camera: Camera) -> ServerResult {
         if self.has_client(camera.id) {
-            Err("client was already a camera or a dispatcher")
+            Err("client was already a camera or a dispatcher".into())
         } else {
             self.cameras.push(camera);
             Ok(Vec::new())
@@ -208,7 +214,7 @@ impl ServerState {
 
     fn add_dispatcher(&mut self, dispatcher: Dispatcher) -> ServerResult {
         if self.has_client(dispatcher.id) {
-            return Err("client was already a camera or a dispatcher");
+            return Err("client was already a camera or a dispatcher".into());
         }
         self.dispatchers.push(dispatcher.clone());
 
@@ -264,30 +270,80 @@ impl ServerState {
     }
 }
 
+async fn append_line(path: &Path, line: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(format!("{line}\n").as_bytes()).await
+}
+
+// A failed read is no different from the client hanging up
+fn read_error(err: io::Error) -> ProtoError {
+    utils::protocol_error(&err)
+        .cloned()
+        .unwrap_or(ProtoError::Closed)
+}
+
+// The next `nb_bytes` bytes, or what is left of them when the client hangs up
+// mid-message, so that parsing them tells which field is missing
+async fn read_bytes(
+    reader: &mut FrameReader<impl AsyncRead + Unpin>,
+    nb_bytes: usize,
+) -> Result<BytesMut, ProtoError> {
+    match reader.read_exact(nb_bytes).await {
+        Ok(Some(frame)) => Ok(frame),
+        Ok(None) => Ok(BytesMut::from(reader.buffered())),
+        Err(err) => Err(read_error(err)),
+    }
+}
+
+// Count in front of a variable-length message, left to be read with the rest
+async fn peek_u8(reader: &mut FrameReader<impl AsyncRead + Unpin>) -> Result<u8, ProtoError> {
+    match reader.peek(1).await {
+        Ok(Some(bytes)) => Ok(bytes[0]),
+        Ok(None) => Err(ProtoError::Truncated {
+            what: "u8",
+            offset: 0,
+        }),
+        Err(err) => Err(read_error(err)),
+    }
+}
+
 pub struct Server {
-    writers: Arc<Mutex<HashMap<Id, Arc<Mutex<OwnedWriteHalf>>>>>,
+    // Sending to a client never waits on the state lock nor on other clients
+    writers: DashMap<Id, QueuedWriter>,
+    next_client_id: AtomicU16,
     state: Arc<Mutex<ServerState>>,
+    // Days each plate was ticketed on, checked without the state lock so that
+    // tickets from different plates do not wait on each other
+    ticketed_days: DashMap<String, Vec<u32>>,
+    // Serializes appends to the ticket audit file
+    audit_path: Option<Mutex<PathBuf>>,
 }
 
 impl Server {
-    pub fn new() -> Self {
+    pub fn new(config: Config) -> Self {
         Self {
-            writers: Arc::new(Mutex::new(HashMap::new())),
+            writers: DashMap::new(),
+            next_client_id: AtomicU16::new(0),
             state: Arc::new(Mutex::new(ServerState::new())),
+            ticketed_days: DashMap::new(),
+            audit_path: config.audit_path.map(Mutex::new),
         }
     }
 
     async fn parse_plate(
-        stream: &mut OwnedReadHalf,
+        stream: &mut FrameReader<impl AsyncRead + Unpin>,
         id: Id,
-        buffer: &mut Vec<u8>,
-    ) -> Option<Plate> {
-        let plate_len = utils::read_for(stream, buffer, 1).await?[0] as usize;
-        let plate = utils::read_for(stream, buffer, plate_len).await?;
-        let plate = String::from_utf8_lossy(&plate).into_owned();
-        let timestamp = utils::read_for(stream, buffer, 4).await?;
-        let timestamp = u32::from_be_bytes(timestamp.try_into().unwrap());
-        Some(Plate {
+    ) -> Result<Plate, ProtoError> {
+        let plate_len = peek_u8(stream).await? as usize;
+        let frame = read_bytes(stream, 1 + plate_len + 4).await?;
+        let mut reader = WireReader::new(&frame);
+        let plate = reader.read_lp_string(LengthPrefix::U8)?;
+        let timestamp = reader.read_u32_be()?;
+        Ok(Plate {
             id,
             plate,
             timestamp,
@@ -295,27 +351,24 @@ impl Server {
     }
 
     async fn parse_heartbeat(
-        stream: &mut OwnedReadHalf,
+        stream: &mut FrameReader<impl AsyncRead + Unpin>,
         id: Id,
-        buffer: &mut Vec<u8>,
-    ) -> Option<Heartbeat> {
-        let interval = utils::read_for(stream, buffer, 4).await?;
-        let interval = u32::from_be_bytes(interval.try_into().unwrap());
-        Some(Heartbeat { id, interval })
+    ) -> Result<Heartbeat, ProtoError> {
+        let frame = read_bytes(stream, 4).await?;
+        let interval = WireReader::new(&frame).read_u32_be()?;
+        Ok(Heartbeat { id, interval })
     }
 
     async fn parse_camera(
-        stream: &mut OwnedReadHalf,
+        stream: &mut FrameReader<impl AsyncRead + Unpin>,
         id: Id,
-        buffer: &mut Vec<u8>,
-    ) -> Option<Camera> {
-        let road = utils::read_for(stream, buffer, 2).await?;
-        let road = u16::from_be_bytes(road.try_into().unwrap());
-        let mile = utils::read_for(stream, buffer, 2).await?;
-        let mile = u16::from_be_bytes(mile.try_into().unwrap());
-        let limit = utils::read_for(stream, buffer, 2).await?;
-        let limit = u16::from_be_bytes(limit.try_into().unwrap());
-        Some(Camera {
+    ) -> Result<Camera, ProtoError> {
+        let frame = read_bytes(stream, 6).await?;
+        let mut reader = WireReader::new(&frame);
+        let road = reader.read_u16_be()?;
+        let mile = reader.read_u16_be()?;
+        let limit = reader.read_u16_be()?;
+        Ok(Camera {
             id,
             road,
             mile,
@@ -324,83 +377,117 @@ impl Server {
     }
 
     async fn parse_dispatcher(
-        stream: &mut OwnedReadHalf,
+        stream: &mut FrameReader<impl AsyncRead + Unpin>,
         id: Id,
-        buffer: &mut Vec<u8>,
-    ) -> Option<Dispatcher> {
-        let numroads = utils::read_for(stream, buffer, 1).await?[0];
+    ) -> Result<Dispatcher, ProtoError> {
+        let numroads = peek_u8(stream).await? as usize;
+        let frame = read_bytes(stream, 1 + 2 * numroads).await?;
+        let mut reader = WireReader::new(&frame);
+        reader.read_u8()?;
         let mut roads = Vec::new();
         for _ in 0..numroads {
-            let road = utils::read_for(stream, buffer, 2).await?;
-            let road = u16::from_be_bytes(road.try_into().unwrap());
-            roads.push(road);
+            roads.push(reader.read_u16_be()?);
         }
-        Some(Dispatcher { id, roads })
+        Ok(Dispatcher { id, roads })
     }
 
-    async fn get_client_id(&self) -> Id {
-        let clients = self.writers.lock().await;
-        let clients_id = clients.keys().cloned().collect::<Vec<_>>();
-        drop(clients);
-        for i in 0.. {
-            if !clients_id.contains(&i) {
-                return i;
-            }
+    // At most one ticket per plate and per day: the days spanned by a ticket
+    // are taken only if none of them already was
+    fn claim_days(&self, ticket: &ServerMessage) -> bool {
+        let ServerMessage::Ticket {
+            plate,
+            timestamp1,
+            timestamp2,
+            ..
+        } = ticket
+        else {
+            return true;
+        };
+        let days = timestamp1 / 86400..=timestamp2 / 86400;
+        let mut ticketed = self.ticketed_days.entry(plate.clone()).or_default();
+        if ticketed.iter().any(|day| days.contains(day)) {
+            return false;
         }
-        unreachable!()
+        ticketed.extend(days);
+        true
     }
 
-    async fn add_client(&self, client_id: Id, writer: Arc<Mutex<OwnedWriteHalf>>) {
-        self.writers.lock().await.insert(client_id, writer);
+    // Ids of disconnected clients are reused once the counter wraps around
+    fn add_client(&self, writer: QueuedWriter) -> Id {
+        loop {
+            let client_id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+            if let Entry::Vacant(entry) = self.writers.entry(client_id) {
+                entry.insert(writer);
+                return client_id;
+            }
+        }
     }
 
     async fn process_request(
         &self,
         client_id: Id,
-        reader: &mut OwnedReadHalf,
-        buffer: &mut Vec<u8>,
-    ) -> ServerResult {
-        let Some(msg_type) = utils::read_for(reader, buffer, 1).await else {
-            return Err("could not receive message type");
+        reader: &mut FrameReader<impl AsyncRead + Unpin>,
+    ) -> Result<Handled, ProtoError> {
+        // Hanging up between two messages is not an error of the client
+        let msg_type = match reader.read_exact(1).await {
+            Ok(Some(msg_type)) => msg_type[0],
+            Ok(None) => return Err(ProtoError::Closed),
+            Err(err) => return Err(read_error(err)),
         };
-        let msg_type = msg_type[0];
 
         match msg_type {
             0x20 => {
-                let plate = Self::parse_plate(reader, client_id, buffer)
-                    .await
-                    .ok_or("error when parsing plate")?;
-                self.state.lock().await.read_plate(plate)
+                let plate = Self::parse_plate(reader, client_id).await?;
+                let received = Instant::now();
+                let tickets = self.state.lock().await.read_plate(plate)?;
+                let tickets: Vec<_> = tickets
+                    .into_iter()
+                    .filter(|ticket| self.claim_days(ticket))
+                    .collect();
+                let messages = if tickets.is_empty() {
+                    Vec::new()
+                } else {
+                    self.state.lock().await.route_tickets(tickets)
+                };
+                Ok(("plate", received, messages))
             }
             0x40 => {
-                let heartbeat = Self::parse_heartbeat(reader, client_id, buffer)
-                    .await
-                    .ok_or("error when parsing heartbeat")?;
-                self.state.lock().await.mark_heartbeat(heartbeat)
+                let heartbeat = Self::parse_heartbeat(reader, client_id).await?;
+                let received = Instant::now();
+                let messages = self.state.lock().await.mark_heartbeat(heartbeat)?;
+                Ok(("heartbeat", received, messages))
             }
             0x80 => {
-                let camera = Self::parse_camera(reader, client_id, buffer)
-                    .await
-                    .ok_or("error when parsing camera")?;
-                self.state.lock().await.add_camera(camera)
+                let camera = Self::parse_camera(reader, client_id).await?;
+                let received = Instant::now();
+                let messages = self.state.lock().await.add_camera(camera)?;
+                Ok(("camera", received, messages))
             }
             0x81 => {
-                let dispatcher = Self::parse_dispatcher(reader, client_id, buffer)
-                    .await
-                    .ok_or("error when parsing dispatcher")?;
-                self.state.lock().await.add_dispatcher(dispatcher)
+                let dispatcher = Self::parse_dispatcher(reader, client_id).await?;
+                let received = Instant::now();
+                let messages = self.state.lock().await.add_dispatcher(dispatcher)?;
+                Ok(("dispatcher", received, messages))
             }
-            _ => Err("invalid message type"),
+            _ => Err(ProtoError::UnknownMessageType(msg_type)),
         }
     }
 
-    async fn process_msg(&self, msg: ServerMessage, writer: &Arc<Mutex<OwnedWriteHalf>>) {
+    // Returns the client a ticket was sent to
+    async fn process_msg(
+        &self,
+        msg: ServerMessage,
+        writer: &QueuedWriter,
+        disconnect: &CancellationToken,
+    ) -> Option<Id> {
         match msg {
             ServerMessage::WantHeartbeat { interval } => {
                 if interval > 0 {
-                    let writer = Arc::clone(writer);
-                    tokio::spawn(async move { Self::send_heartbeat(writer, interval).await });
+                    let writer = writer.clone();
+                    let task = Self::send_heartbeat(writer, interval, disconnect.clone());
+                    utils::spawn_named("speed heartbeat sender", task);
                 }
+                None
             }
 
             ServerMessage::Ticket {
@@ -413,66 +500,255 @@ impl Server {
                 timestamp2,
                 speed,
             } => {
-                let mut ticket_data = vec![0x21];
-                ticket_data.push(plate.len() as u8);
-                ticket_data.extend_from_slice(plate.as_bytes());
-                ticket_data.extend(road.to_be_bytes());
-                ticket_data.extend(mile1.to_be_bytes());
-                ticket_data.extend(timestamp1.to_be_bytes());
-                ticket_data.extend(mile2.to_be_bytes());
-                ticket_data.extend(timestamp2.to_be_bytes());
-                ticket_data.extend(speed.to_be_bytes());
-                self.send_to(recipient.unwrap(), ticket_data).await;
+                let mut ticket = WireWriter::with_capacity(2 + plate.len() + 16);
+                ticket.write_u8(0x21);
+                ticket.write_lp_string(LengthPrefix::U8, &plate);
+                ticket.write_u16_be(road);
+                ticket.write_u16_be(mile1);
+                ticket.write_u32_be(timestamp1);
+                ticket.write_u16_be(mile2);
+                ticket.write_u32_be(timestamp2);
+                ticket.write_u16_be(speed);
+                let dispatcher = recipient.unwrap();
+                self.send_to(dispatcher, ticket.into_bytes().freeze());
+
+                if let Some(path) = &self.audit_path {
+                    let entry = json!({
+                        "plate": plate,
+                        "road": road,
+                        "mile1": mile1,
+                        "timestamp1": timestamp1,
+                        "mile2": mile2,
+                        "timestamp2": timestamp2,
+                        "speed": speed,
+                        "dispatcher": dispatcher,
+                    });
+                    let path = path.lock().await;
+                    if let Err(err) = append_line(&path, &entry.to_string()).await {
+                        metrics::increment(CHALLENGE, Counter::InternalErrors);
+                        tracing::error!("could not audit ticket to {}: {err}", path.display());
+                    }
+                }
+                Some(dispatcher)
             }
         }
     }
 
-    async fn send_to(&self, client_id: Id, data: Vec<u8>) {
-        let mut client_map = self.writers.lock().await;
-        let writer = client_map.get_mut(&client_id).unwrap();
-        let _ = writer.lock().await.write_all(&data).await;
+    // Tickets sent in a burst are written together by the writer task
+    fn send_to(&self, client_id: Id, data: Bytes) {
+        if let Some(writer) = self.writers.get(&client_id) {
+            writer.send(data);
+        }
     }
 
-    async fn send_heartbeat(writer: Arc<Mutex<OwnedWriteHalf>>, interval: u32) {
-        let mut interval = time::interval(time::Duration::from_millis((100 * interval).into()));
-        let heartbeat = Vec::from([0x41]);
-        loop {
-            interval.tick().await;
-            let mut writer = writer.lock().await;
-            if writer.write_all(&heartbeat).await.is_err() {
-                break;
-            }
+    // Runs until the client is disconnected
+    async fn send_heartbeat(writer: QueuedWriter, interval: u32, disconnect: CancellationToken) {
+        let mut interval = time::interval(time::Duration::from_millis(100 * u64::from(interval)));
+        let heartbeat = Bytes::from_static(&[0x41]);
+        while disconnect
+            .run_until_cancelled(interval.tick())
+            .await
+            .is_some()
+        {
+            writer.send(heartbeat.clone());
         }
     }
 }
 
+// Feed a raw client byte stream through the message parser and server state
+#[cfg(feature = "fuzzing")]
+pub async fn parse_stream(data: &[u8]) {
+    let server = Server::new(Config::default());
+    let mut reader = FrameReader::new(data);
+    while server.process_request(0, &mut reader).await.is_ok() {}
+}
+
 #[async_trait]
 impl TcpServer for Server {
-    async fn handle_connection(&self, stream: TcpStream) {
-        let (mut reader, writer) = stream.into_split();
-        let writer = Arc::new(Mutex::new(writer));
-        let client_id = self.get_client_id().await;
-        self.add_client(client_id, Arc::clone(&writer)).await;
-        let mut buffer = Vec::new();
+    async fn handle_connection(&self, stream: ClientStream) {
+        let (reader, writer) = stream.into_split();
+        let writer = QueuedWriter::new(writer);
+        // A client that cannot be written to is disconnected without waiting
+        // for its next message
+        let disconnect = writer.closed().child_token();
+        let mut reader = FrameReader::new(reader).with_cancel(disconnect.clone());
+        let client_id = self.add_client(writer.clone());
         loop {
-            match self
-                .process_request(client_id, &mut reader, &mut buffer)
-                .await
-            {
-                Ok(message_list) => {
+            match self.process_request(client_id, &mut reader).await {
+                Ok((msg_type, received, message_list)) => {
+                    metrics::increment(CHALLENGE, Counter::Requests);
+                    let mut recipients = Vec::new();
                     for msg in message_list {
-                        self.process_msg(msg, &writer).await;
+                        recipients.extend(self.process_msg(msg, &writer, &disconnect).await);
                     }
+                    for _ in recipients {
+                        metrics::observe(CHALLENGE, "ticket", received.elapsed());
+                    }
+                    metrics::observe(CHALLENGE, msg_type, received.elapsed());
                 }
-                Err(err_msg) => {
-                    let err_data =
-                        [vec![0x10, err_msg.len() as u8], err_msg.as_bytes().to_vec()].concat();
-                    self.send_to(client_id, err_data).await;
+                Err(err) => {
+                    if err != ProtoError::Closed {
+                        metrics::increment(CHALLENGE, Counter::ProtocolErrors);
+                    }
+                    let err_msg = err.to_string();
+                    let mut error = WireWriter::with_capacity(2 + err_msg.len());
+                    error.write_u8(0x10);
+                    error.write_lp_string(LengthPrefix::U8, &err_msg);
+                    writer.send(error.into_bytes());
                     break;
                 }
             };
         }
 
         self.state.lock().await.remove_client(client_id);
+        self.writers.remove(&client_id);
+        disconnect.cancel();
+    }
+
+    fn wants_nodelay(&self) -> bool {
+        true
+    }
+
+    async fn state_sizes(&self) -> StateSizes {
+        let writers = self.writers.len();
+        let state = self.state.lock().await;
+        vec![
+            ("writers", writers),
+            ("cameras", state.cameras.len()),
+            ("dispatchers", state.dispatchers.len()),
+            ("observations", state.observations.len()),
+            ("heartbeats", state.have_heartbeats.len()),
+            ("queued_tickets", state.ticket_queue.len()),
+            ("ticketed_plates", self.ticketed_days.len()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{self, AsyncReadExt};
+    use tokio::time::{Duration, Instant};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_follow_interval() {
+        let (writer, mut reader) = io::duplex(64);
+        let writer = QueuedWriter::new(writer);
+        let start = Instant::now();
+        tokio::spawn(Server::send_heartbeat(writer, 25, CancellationToken::new()));
+
+        let mut heartbeats = Vec::new();
+        for _ in 0..5 {
+            assert_eq!(reader.read_u8().await.unwrap(), 0x41);
+            heartbeats.push(start.elapsed());
+        }
+        let expected: Vec<_> = (0..5).map(|i| Duration::from_millis(2500 * i)).collect();
+        assert_eq!(heartbeats, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_stop_when_client_leaves() {
+        let (writer, reader) = io::duplex(64);
+        let writer = QueuedWriter::new(writer);
+        let disconnect = writer.closed().child_token();
+        let heartbeat = tokio::spawn(Server::send_heartbeat(writer, 10, disconnect.clone()));
+
+        drop(reader);
+        time::timeout(Duration::from_secs(5), heartbeat)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(disconnect.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_stop_on_disconnect() {
+        let (writer, mut reader) = io::duplex(64);
+        let writer = QueuedWriter::new(writer);
+        let disconnect = writer.closed().child_token();
+        let heartbeat = tokio::spawn(Server::send_heartbeat(writer, 10, disconnect.clone()));
+        assert_eq!(reader.read_u8().await.unwrap(), 0x41);
+
+        disconnect.cancel();
+        heartbeat.await.unwrap();
+        assert_eq!(
+            reader.read_u8().await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn large_heartbeat_interval_does_not_overflow() {
+        let (writer, mut reader) = io::duplex(64);
+        let writer = QueuedWriter::new(writer);
+        tokio::spawn(Server::send_heartbeat(
+            writer,
+            u32::MAX,
+            CancellationToken::new(),
+        ));
+        assert_eq!(reader.read_u8().await.unwrap(), 0x41);
+    }
+
+    fn ticket(plate: &str, day1: u32, day2: u32) -> ServerMessage {
+        ServerMessage::Ticket {
+            recipient: None,
+            plate: plate.to_owned(),
+            road: 1,
+            mile1: 0,
+            timestamp1: day1 * 86400,
+            mile2: 10,
+            timestamp2: day2 * 86400 + 100,
+            speed: 10000,
+        }
+    }
+
+    #[test]
+    fn one_ticket_per_plate_and_day() {
+        let server = Server::new(Config::default());
+        assert!(server.claim_days(&ticket("UN1X", 1, 2)));
+        assert!(!server.claim_days(&ticket("UN1X", 2, 2)));
+        assert!(!server.claim_days(&ticket("UN1X", 0, 1)));
+        assert!(server.claim_days(&ticket("UN1X", 3, 3)));
+        assert!(server.claim_days(&ticket("RE05BKG", 1, 1)));
+        assert_eq!(server.ticketed_days.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn parse_errors_tell_what_failed() {
+        let server = Server::new(Config::default());
+        let error = |data: &'static [u8]| {
+            let server = &server;
+            async move {
+                let mut reader = FrameReader::new(data);
+                server.process_request(0, &mut reader).await.err()
+            }
+        };
+        assert_eq!(error(b"").await, Some(ProtoError::Closed));
+        assert_eq!(
+            error(b"\x99").await,
+            Some(ProtoError::UnknownMessageType(0x99))
+        );
+        assert_eq!(
+            error(b"\x20\x03ab").await,
+            Some(ProtoError::Truncated {
+                what: "str",
+                offset: 1
+            })
+        );
+        assert_eq!(
+            error(b"\x80\x00\x01\x00").await,
+            Some(ProtoError::Truncated {
+                what: "u16",
+                offset: 2
+            })
+        );
+        assert_eq!(
+            error(b"\x81").await,
+            Some(ProtoError::Truncated {
+                what: "u8",
+                offset: 0
+            })
+        );
     }
 }
@@ -2,10 +2,19 @@ use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use anyhow::Result;
 use async_trait::async_trait;
-use fancy_regex::Regex;
-use tokio::{net::UdpSocket, sync::Mutex, task::JoinHandle, time};
+use regex::Regex;
+use tokio::{sync::Mutex, task::JoinHandle, time};
+use tracing::Instrument;
 
-use crate::UdpServer;
+use crate::metrics::{self, Counter};
+use crate::utils;
+use crate::{StateSizes, UdpSender, UdpServer};
+
+const CHALLENGE: u8 = 7;
+const RETRANSMIT_INTERVAL: time::Duration = time::Duration::from_millis(500);
+const MAX_RETRANSMITS: usize = 20;
+// LRCP messages are smaller than 1000 bytes
+const MAX_MESSAGE_SIZE: usize = 999;
 
 #[derive(Clone)]
 enum ServerMessage {
@@ -209,22 +218,23 @@ impl ServerState {
     }
 
     fn process_request(&mut self, request: &str) -> Result<Vec<ServerMessage>> {
-        if let Some(caps) = self.regexes["connect"].captures(request).unwrap() {
+        if let Some(caps) = self.regexes["connect"].captures(request) {
             let session_id = caps["session_id"].parse()?;
             Ok(self.connect_session(session_id)?)
-        } else if let Some(caps) = self.regexes["data"].captures(request)? {
+        } else if let Some(caps) = self.regexes["data"].captures(request) {
             let session_id = caps["session_id"].parse()?;
             let pos = caps["pos"].parse()?;
             let data = &caps["data"];
             Ok(self.receive_data(session_id, pos, data)?)
-        } else if let Some(caps) = self.regexes["ack"].captures(request)? {
+        } else if let Some(caps) = self.regexes["ack"].captures(request) {
             let session_id = caps["session_id"].parse()?;
             let pos = caps["pos"].parse()?;
             Ok(self.receive_ack(session_id, pos)?)
-        } else if let Some(caps) = self.regexes["close"].captures(request)? {
+        } else if let Some(caps) = self.regexes["close"].captures(request) {
             let session_id = caps["session_id"].parse()?;
             Ok(self.close_session(session_id)?)
         } else {
+            metrics::increment(CHALLENGE, Counter::ProtocolErrors);
             Ok(Vec::new())
         }
     }
@@ -250,33 +260,24 @@ impl Server {
         }
     }
 
-    async fn send_data(
-        &self,
-        socket: &Arc<UdpSocket>,
-        addr: SocketAddr,
-        session_id: u32,
-        data: String,
-    ) {
-        let socket = socket.clone();
+    async fn send_data(&self, sender: UdpSender, addr: SocketAddr, session_id: u32, data: String) {
         let ack_tasks_copy = Arc::clone(&self.ack_tasks);
         let state = Arc::clone(&self.state);
-        let thread = tokio::spawn(async move {
-            Self::send_message_loop(socket, addr, data.as_bytes().to_vec()).await;
+        let retransmitter = async move {
+            Self::send_message_loop(sender, addr, data).await;
             Self::close_session(session_id, ack_tasks_copy, state).await;
-        });
+        };
+        // Retransmissions are logged with the datagram that sent the data
+        let thread = utils::spawn_named("lrcp retransmitter", retransmitter.in_current_span());
         let mut ack_tasks = self.ack_tasks.lock().await;
         ack_tasks.insert(session_id, thread);
     }
 
-    async fn send_message_loop(socket: Arc<UdpSocket>, addr: SocketAddr, data: Vec<u8>) {
-        let mut interval = time::interval(time::Duration::from_millis(500));
-        for _ in 0..=20 {
-            interval.tick().await;
-            println!(
-                "{addr:?} --> {}",
-                String::from_utf8_lossy(&data).replace("\n", r"\n")
-            );
-            let _ = socket.send_to(data.as_slice(), addr).await;
+    async fn send_message_loop(sender: UdpSender, addr: SocketAddr, data: String) {
+        tracing::debug!(%addr, "--> {data:?}");
+        let sent = sender.send_expect_ack(addr, data.into(), RETRANSMIT_INTERVAL, MAX_RETRANSMITS);
+        if let Err(err) = sent.await {
+            tracing::warn!(%addr, "{err}");
         }
     }
 
@@ -292,19 +293,21 @@ impl Server {
         }
 
         let _ = state.lock().await.close_session(session_id);
+        metrics::increment(CHALLENGE, Counter::Disconnects);
     }
 }
 
 #[async_trait]
 impl UdpServer for Server {
-    async fn handle_connection(&self, socket: Arc<UdpSocket>, data: &[u8], addr: &SocketAddr) {
+    async fn handle_connection(&self, sender: UdpSender, data: &[u8], addr: &SocketAddr) {
         let request = String::from_utf8_lossy(data);
         let request = request.trim();
-        println!("{addr:?} <-- {}", request.replace("\n", r"\n"));
+        tracing::debug!("<-- {request:?}");
 
+        metrics::increment(CHALLENGE, Counter::Requests);
         let mut state = self.state.lock().await;
         let Ok(responses) = state.process_request(request) else {
-            return;
+            return metrics::increment(CHALLENGE, Counter::ProtocolErrors);
         };
         for response in responses {
             match response {
@@ -312,18 +315,258 @@ impl UdpServer for Server {
 
                 ServerMessage::Data { session_id, data } => {
                     if data.starts_with("/ack/") {
-                        println!("{addr:?} --> {}", data.replace("\n", r"\n"));
-                        let _ = socket.send_to(data.as_bytes(), addr).await;
+                        tracing::debug!("--> {data:?}");
+                        sender.send(*addr, data);
                     } else {
-                        self.send_data(&socket, *addr, session_id, data).await
+                        self.send_data(sender.clone(), *addr, session_id, data)
+                            .await
                     }
                 }
 
                 ServerMessage::Close { session_id } => {
+                    metrics::increment(CHALLENGE, Counter::Disconnects);
                     let msg = format!("/close/{session_id}/");
-                    let _ = socket.send_to(msg.as_bytes(), addr).await;
+                    sender.send(*addr, msg);
                 }
             }
         }
     }
+
+    fn max_datagram_size(&self) -> usize {
+        MAX_MESSAGE_SIZE
+    }
+
+    async fn state_sizes(&self) -> StateSizes {
+        let sessions = self.state.lock().await.sessions.len();
+        let ack_tasks = self.ack_tasks.lock().await;
+        let running = ack_tasks
+            .values()
+            .filter(|task| !task.is_finished())
+            .count();
+        vec![
+            ("sessions", sessions),
+            ("ack_tasks", ack_tasks.len()),
+            ("running_ack_tasks", running),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use proptest::prelude::*;
+    use tokio::time::{Duration, Instant};
+
+    use super::*;
+    use crate::DatagramSocket;
+
+    // Records the time of every datagram sent, relative to its creation
+    struct RecordingSocket {
+        start: Instant,
+        sent: StdMutex<Vec<(Duration, Vec<u8>)>>,
+    }
+
+    impl RecordingSocket {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                start: Instant::now(),
+                sent: StdMutex::new(Vec::new()),
+            })
+        }
+
+        fn sender(self: &Arc<Self>) -> UdpSender {
+            UdpSender::new(self.clone())
+        }
+
+        fn send_times(&self) -> Vec<Duration> {
+            self.sent.lock().unwrap().iter().map(|(t, _)| *t).collect()
+        }
+    }
+
+    #[async_trait]
+    impl DatagramSocket for RecordingSocket {
+        async fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> std::io::Result<usize> {
+            let elapsed = self.start.elapsed();
+            self.sent.lock().unwrap().push((elapsed, buf.to_vec()));
+            Ok(buf.len())
+        }
+    }
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:9".parse().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unacked_data_is_retransmitted_then_dropped() {
+        let socket = RecordingSocket::new();
+        let data = String::from("/data/1/0/olleh\n/");
+        Server::send_message_loop(socket.sender(), peer(), data.clone()).await;
+        tokio::task::yield_now().await;
+
+        let expected: Vec<_> = (0..=MAX_RETRANSMITS as u32)
+            .map(|i| RETRANSMIT_INTERVAL * i)
+            .collect();
+        assert_eq!(socket.send_times(), expected);
+        assert!(socket
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(_, d)| *d == data.as_bytes()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ack_stops_retransmissions() {
+        let server = Server::new();
+        server
+            .state
+            .lock()
+            .await
+            .process_request("/connect/1/")
+            .unwrap();
+        let socket = RecordingSocket::new();
+        server
+            .send_data(socket.sender(), peer(), 1, String::from("/data/1/0/a/"))
+            .await;
+
+        time::sleep(Duration::from_millis(1200)).await;
+        server.acknowledge(1).await;
+        time::sleep(Duration::from_secs(30)).await;
+
+        let expected: Vec<_> = (0..3).map(|i| RETRANSMIT_INTERVAL * i).collect();
+        assert_eq!(socket.send_times(), expected);
+        assert!(server.state.lock().await.sessions.contains_key(&1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_closes_after_last_retransmission() {
+        let server = Server::new();
+        server
+            .state
+            .lock()
+            .await
+            .process_request("/connect/1/")
+            .unwrap();
+        let socket = RecordingSocket::new();
+        server
+            .send_data(socket.sender(), peer(), 1, String::from("/data/1/0/a/"))
+            .await;
+
+        time::sleep(RETRANSMIT_INTERVAL * MAX_RETRANSMITS as u32 + Duration::from_millis(1)).await;
+        assert_eq!(socket.send_times().len(), MAX_RETRANSMITS + 1);
+        assert!(!server.state.lock().await.sessions.contains_key(&1));
+        assert!(server.ack_tasks.lock().await.is_empty());
+    }
+
+    fn data_msg(session_id: u32, pos: usize, data: &str) -> String {
+        format!("/data/{session_id}/{pos}/{}/", ServerState::escape(data))
+    }
+
+    // Returns the position and unescaped payload of a data message
+    fn parse_data(msg: &str) -> Option<(usize, String)> {
+        let (pos, data) = msg.strip_prefix("/data/1/")?.split_once('/')?;
+        let data = data.strip_suffix('/')?;
+        Some((pos.parse().unwrap(), ServerState::unescape(data)))
+    }
+
+    // Split the responses into the acks sent back and the data to transmit
+    fn split_responses(responses: Vec<ServerMessage>) -> (Vec<String>, Option<(usize, String)>) {
+        let mut acks = Vec::new();
+        let mut sent = None;
+        for response in responses {
+            if let ServerMessage::Data { data, .. } = response {
+                if data.starts_with("/ack/") {
+                    acks.push(data);
+                } else {
+                    assert!(sent.is_none(), "more than one data message in flight");
+                    sent = parse_data(&data);
+                }
+            }
+        }
+        (acks, sent)
+    }
+
+    fn chunks(data: &str, cuts: &[usize]) -> Vec<String> {
+        let mut cuts: Vec<_> = cuts.iter().map(|cut| cut % (data.len() + 1)).collect();
+        cuts.extend([0, data.len()]);
+        cuts.sort_unstable();
+        cuts.dedup();
+        cuts.windows(2)
+            .map(|window| data[window[0]..window[1]].to_owned())
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn unescape_inverts_escape(data in any::<String>()) {
+            prop_assert_eq!(ServerState::unescape(&ServerState::escape(&data)), data);
+        }
+
+        #[test]
+        fn escaped_data_matches_data_message(data in "[ -~\n]{1,100}") {
+            let msg = data_msg(1, 0, &data);
+            let regex = &ServerState::new().regexes["data"];
+            let caps = regex.captures(&msg).unwrap();
+            prop_assert_eq!(ServerState::unescape(&caps["data"]), data);
+        }
+
+        #[test]
+        fn chunked_lines_are_reversed(
+            lines in prop::collection::vec("[ -~]{0,40}", 0..20),
+            cuts in prop::collection::vec(any::<usize>(), 0..20),
+            duplicates in prop::collection::vec(any::<bool>(), 20),
+        ) {
+            let input: String = lines.iter().map(|line| format!("{line}\n")).collect();
+            let expected: String = lines
+                .iter()
+                .map(|line| format!("{}\n", line.chars().rev().collect::<String>()))
+                .collect();
+
+            let mut state = ServerState::new();
+            state.process_request("/connect/1/").unwrap();
+            let mut received = 0;
+            let mut output = String::new();
+            for (chunk, duplicate) in chunks(&input, &cuts).iter().zip(duplicates.iter().cycle()) {
+                let responses = state.process_request(&data_msg(1, received, chunk)).unwrap();
+                received += chunk.len();
+                let (acks, mut sent) = split_responses(responses);
+                prop_assert_eq!(acks, vec![format!("/ack/1/{received}/")]);
+
+                // A retransmitted chunk is acked at the current position and ignored
+                if *duplicate {
+                    let pos = received - chunk.len();
+                    let responses = state.process_request(&data_msg(1, pos, chunk)).unwrap();
+                    let (acks, _) = split_responses(responses);
+                    prop_assert_eq!(acks, vec![format!("/ack/1/{received}/")]);
+                }
+
+                while let Some((pos, payload)) = sent.take() {
+                    prop_assert_eq!(pos, output.len());
+                    output.push_str(&payload);
+                    let ack = format!("/ack/1/{}/", output.len());
+                    (_, sent) = split_responses(state.process_request(&ack).unwrap());
+                }
+            }
+            prop_assert_eq!(output, expected);
+        }
+
+        #[test]
+        fn partial_ack_retransmits_unacked_data(line in "[ -~]{1,2000}", acked in any::<usize>()) {
+            let mut state = ServerState::new();
+            state.process_request("/connect/1/").unwrap();
+            let input = format!("{line}\n");
+            let (_, sent) = split_responses(state.process_request(&data_msg(1, 0, &input)).unwrap());
+            let (pos, payload) = sent.unwrap();
+            prop_assert_eq!(pos, 0);
+
+            let acked = acked % (payload.len() + 1);
+            let (_, resent) = split_responses(state.process_request(&format!("/ack/1/{acked}/")).unwrap());
+            if acked == payload.len() {
+                prop_assert_eq!(resent.is_some(), input.len() > 950);
+            } else {
+                prop_assert_eq!(resent, Some((0, payload)));
+            }
+        }
+    }
 }
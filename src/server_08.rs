@@ -2,10 +2,13 @@ use std::ops::BitXor;
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use tokio::net::TcpStream;
+use bytes::{Bytes, BytesMut};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::TcpServer;
+use crate::metrics::{self, Counter};
+use crate::{ClientStream, TcpServer};
+
+const CHALLENGE: u8 = 8;
 
 fn get_most_freq_toy(request: &str) -> &str {
     request
@@ -71,28 +74,28 @@ impl CipherOp {
         }
     }
 
-    fn parse_spec(spec: &[u8]) -> Vec<Self> {
+    // Positions only matter modulo 256, so every byte at every position is checked
+    fn is_noop(cipher_ops: &[Self]) -> bool {
+        (0..256).all(|pos| {
+            (0..=255).all(|byte| cipher_ops.iter().fold(byte, |b, op| op.encode(b, pos)) == byte)
+        })
+    }
+
+    fn parse_spec(spec: &[u8]) -> Result<Vec<Self>> {
         let mut cipher_ops = Vec::new();
-        let mut index = 0usize;
-        while index < spec.len() {
-            cipher_ops.push(match spec[index] {
+        let mut bytes = spec.iter();
+        while let Some(&op) = bytes.next() {
+            cipher_ops.push(match op {
                 0x00 => break,
                 0x01 => CipherOp::Reversebits,
-                0x02 => {
-                    index += 1;
-                    CipherOp::Xor(spec[index])
-                }
+                0x02 => CipherOp::Xor(*bytes.next().ok_or(anyhow!("ERROR: missing xor operand"))?),
                 0x03 => CipherOp::Xorpos,
-                0x04 => {
-                    index += 1;
-                    CipherOp::Add(spec[index])
-                }
+                0x04 => CipherOp::Add(*bytes.next().ok_or(anyhow!("ERROR: missing add operand"))?),
                 0x05 => CipherOp::Addpos,
-                _ => unreachable!(),
+                _ => return Err(anyhow!("ERROR: invalid cipher operation {op}")),
             });
-            index += 1;
         }
-        cipher_ops
+        Ok(cipher_ops)
     }
 }
 
@@ -103,27 +106,27 @@ struct ObfuscationLayer {
 }
 
 impl ObfuscationLayer {
-    fn new(spec: Vec<u8>) -> Result<Self> {
-        let cipher_ops = CipherOp::parse_spec(&spec);
+    fn new(spec: &[u8]) -> Result<Self> {
+        let cipher_ops = CipherOp::parse_spec(spec)?;
 
-        let mut layer = Self {
+        if CipherOp::is_noop(&cipher_ops) {
+            return Err(anyhow!("ERROR: spec is a no-op cipher"));
+        }
+
+        Ok(Self {
             cipher_ops,
             client_pos: 0,
             server_pos: 0,
-        };
+        })
+    }
 
-        let equal_test = "123abcdefg789";
-        if layer.encode(equal_test) != equal_test.as_bytes() {
-            layer.server_pos = 0; // reset pos
-            Ok(layer)
-        } else {
-            Err(anyhow!("ERROR: spec is a no-op cipher"))
-        }
+    fn encode(&mut self, msg: &str) -> Bytes {
+        self.encode_bytes(msg.as_bytes())
     }
 
-    fn encode(&mut self, msg: &str) -> Vec<u8> {
-        msg.bytes()
-            .map(|c| {
+    fn encode_bytes(&mut self, msg: &[u8]) -> Bytes {
+        msg.iter()
+            .map(|&c| {
                 self.server_pos += 1;
                 self.cipher_ops
                     .iter()
@@ -133,21 +136,50 @@ impl ObfuscationLayer {
     }
 
     fn decode(&mut self, msg: &[u8]) -> String {
-        String::from_utf8_lossy(
-            &msg.iter()
-                .map(|&b| {
-                    self.client_pos += 1;
-                    self.cipher_ops
-                        .iter()
-                        .rev()
-                        .fold(b, |byte, op| op.decode(byte, self.client_pos - 1))
-                })
-                .collect::<Vec<_>>(),
-        )
-        .to_string()
+        String::from_utf8_lossy(&self.decode_bytes(msg)).to_string()
+    }
+
+    fn decode_bytes(&mut self, msg: &[u8]) -> Vec<u8> {
+        msg.iter()
+            .map(|&b| {
+                self.client_pos += 1;
+                self.cipher_ops
+                    .iter()
+                    .rev()
+                    .fold(b, |byte, op| op.decode(byte, self.client_pos - 1))
+            })
+            .collect()
     }
 }
 
+// Check a cipher spec against a payload: parsing must not panic, decoding must
+// invert encoding and the no-op detection must agree with an exhaustive check
+#[cfg(feature = "fuzzing")]
+pub fn check_cipher(spec: &[u8], payload: &[u8]) {
+    let Ok(cipher_ops) = CipherOp::parse_spec(spec) else {
+        return;
+    };
+
+    let is_identity = (0..=255).all(|byte| {
+        let mut layer = ObfuscationLayer {
+            cipher_ops: CipherOp::parse_spec(spec).unwrap(),
+            client_pos: 0,
+            server_pos: 0,
+        };
+        let stream = [byte; 256];
+        layer.encode_bytes(&stream) == stream[..]
+    });
+    assert_eq!(ObfuscationLayer::new(spec).is_err(), is_identity);
+
+    let mut layer = ObfuscationLayer {
+        cipher_ops,
+        client_pos: 0,
+        server_pos: 0,
+    };
+    let encoded = layer.encode_bytes(payload);
+    assert_eq!(layer.decode_bytes(&encoded), payload);
+}
+
 pub struct Server {}
 
 impl Server {
@@ -158,41 +190,41 @@ impl Server {
 
 #[async_trait]
 impl TcpServer for Server {
-    async fn handle_connection(&self, mut stream: TcpStream) {
-        let mut buffer = Vec::new();
+    async fn handle_connection(&self, mut stream: ClientStream) {
+        // Data is read straight into the buffer, which is then split in place
+        let mut buffer = BytesMut::with_capacity(1024);
 
         while !buffer.contains(&0) {
-            let mut buf = [0; 1024];
-            match stream.read(&mut buf).await {
-                Ok(0) | Err(_) => return,
-                Ok(n) => buffer.extend_from_slice(&buf[..n]),
-            };
+            if let Ok(0) | Err(_) = stream.read_buf(&mut buffer).await {
+                return;
+            }
         }
         let index = buffer.iter().position(|&b| b == 0).unwrap();
-        let cipher_spec = buffer.drain(..=index).collect();
-        println!("Cipher spec: {:?}", cipher_spec);
+        let cipher_spec = buffer.split_to(index + 1);
+        tracing::debug!("cipher spec {:?}", &cipher_spec[..]);
 
-        let Ok(mut obfuscation_layer) = ObfuscationLayer::new(cipher_spec) else {
-            return;
+        let Ok(mut obfuscation_layer) = ObfuscationLayer::new(&cipher_spec) else {
+            return metrics::increment(CHALLENGE, Counter::ProtocolErrors);
         };
 
         let mut workshop = Workshop::new();
         loop {
             if buffer.is_empty() {
-                let mut buf = [0; 1024];
-                match stream.read(&mut buf).await {
-                    Ok(0) | Err(_) => return,
-                    Ok(n) => buffer.extend_from_slice(&buf[..n]),
-                };
+                if let Ok(0) | Err(_) = stream.read_buf(&mut buffer).await {
+                    return;
+                }
             }
 
             let msg = obfuscation_layer.decode(&buffer);
             buffer.clear();
-            println!("Decoded: {msg}");
+            tracing::debug!("<-- {msg}");
             for resp_msg in workshop.add_data(msg) {
-                println!("Response: {resp_msg}");
+                metrics::increment(CHALLENGE, Counter::Requests);
+                tracing::debug!("--> {resp_msg}");
                 let response = obfuscation_layer.encode(&resp_msg);
-                stream.write_all(&response).await.unwrap();
+                if stream.write_all(&response).await.is_err() {
+                    return;
+                }
             }
         }
     }
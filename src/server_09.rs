@@ -1,13 +1,20 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 use async_trait::async_trait;
+use dashmap::DashMap;
 use serde_json::{json, Value};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
 use tokio::sync::{Mutex, Notify};
 
-use crate::{utils, TcpServer};
+use crate::metrics::{self, Counter};
+use crate::utils::json_lines::JsonLines;
+use crate::utils::{FrameReader, QueuedWriter};
+use crate::ProtoError;
+use crate::{ClientStream, StateSizes, TcpServer};
+
+const CHALLENGE: u8 = 9;
 
 type ClientId = u64;
 type JobId = u64;
@@ -42,7 +49,21 @@ impl ServerState {
         }
     }
 
+    // Saturation gauges, refreshed after every mutation of the queue it touched
+    fn queue_changed(&self, queue: &str) {
+        let depth = self.queues.get(queue).map_or(0, Vec::len);
+        metrics::set_gauge(CHALLENGE, "jobs_queue_depth", Some(("queue", queue)), depth);
+    }
+
+    fn workers_changed(&self) {
+        let in_progress = self.client_jobs.values().map(Vec::len).sum();
+        metrics::set_gauge(CHALLENGE, "jobs_in_progress", None, in_progress);
+        let waiting = self.waiting_clients.len();
+        metrics::set_gauge(CHALLENGE, "jobs_waiting_workers", None, waiting);
+    }
+
     fn generate_error(&self, err_msg: &str) -> Vec<ServerMessage> {
+        metrics::increment(CHALLENGE, Counter::ProtocolErrors);
         vec![ServerMessage::Response(
             json!({
                 "status": "error",
@@ -91,7 +112,8 @@ impl ServerState {
             }
         }
 
-        self.queues.entry(queue).or_default().push(new_job);
+        self.queues.entry(queue.clone()).or_default().push(new_job);
+        self.queue_changed(&queue);
 
         responses
     }
@@ -139,6 +161,9 @@ impl ServerState {
 
             let response = json!({"status": "ok", "id": job.id, "pri": job.priority, "queue": highest_queue, "job": job.task});
             self.client_jobs.entry(client_id).or_default().push(job);
+            self.waiting_clients.remove(&client_id);
+            self.queue_changed(highest_queue);
+            self.workers_changed();
 
             vec![ServerMessage::Response(response.to_string())]
         } else if request
@@ -146,6 +171,7 @@ impl ServerState {
             .is_some_and(|v| v.as_bool().unwrap_or(false))
         {
             self.waiting_clients.insert(client_id, queues);
+            self.workers_changed();
             vec![ServerMessage::Waiting]
         } else {
             vec![ServerMessage::Response(
@@ -154,17 +180,18 @@ impl ServerState {
         }
     }
 
-    fn delete(&mut self, mut request: Value) -> Vec<ServerMessage> {
-        let Value::Number(job_id) = request["id"].take() else {
+    fn delete(&mut self, request: Value) -> Vec<ServerMessage> {
+        let Some(job_id) = request["id"].as_u64() else {
             return self.generate_error("invalid id");
         };
-        let job_id = job_id.as_u64().unwrap();
 
         let mut job_removed = false;
-        for jobs in self.queues.values_mut() {
+        let mut changed_queue = None;
+        for (queue, jobs) in self.queues.iter_mut() {
             if let Some(index) = jobs.iter().position(|job| job.id == job_id) {
                 job_removed = true;
                 jobs.swap_remove(index);
+                changed_queue = Some(queue.clone());
             }
         }
         for jobs in self.client_jobs.values_mut() {
@@ -172,16 +199,19 @@ impl ServerState {
             jobs.retain(|job| job.id != job_id);
             job_removed = job_removed || (jobs.len() < n);
         }
+        if let Some(queue) = changed_queue {
+            self.queue_changed(&queue);
+        }
+        self.workers_changed();
 
         let response = json!({"status": if job_removed { "ok" } else {"no-job"}});
         vec![ServerMessage::Response(response.to_string())]
     }
 
-    fn abort(&mut self, client_id: ClientId, mut request: Value) -> Vec<ServerMessage> {
-        let Value::Number(job_id) = request["id"].take() else {
+    fn abort(&mut self, client_id: ClientId, request: Value) -> Vec<ServerMessage> {
+        let Some(job_id) = request["id"].as_u64() else {
             return self.generate_error("invalid id");
         };
-        let job_id = job_id.as_u64().unwrap();
 
         let Some(jobs) = self.client_jobs.get_mut(&client_id) else {
             return vec![ServerMessage::Response(
@@ -203,37 +233,51 @@ impl ServerState {
             }
         }
 
-        self.queues
-            .entry(job.queue.clone())
-            .or_default()
-            .push(job);
+        let queue = job.queue.clone();
+        self.queues.entry(queue.clone()).or_default().push(job);
+        self.queue_changed(&queue);
+        self.workers_changed();
 
         responses
     }
 
-    fn get_responses(&mut self, client_id: ClientId, request: &str) -> Vec<ServerMessage> {
-        let Ok(request): Result<Value, serde_json::Error> = serde_json::from_str(request) else {
-            return self.generate_error("invalid JSON format");
+    // Responses along with the request type
+    fn get_responses(
+        &mut self,
+        client_id: ClientId,
+        request: &Result<Value, ProtoError>,
+    ) -> (&'static str, Vec<ServerMessage>) {
+        let request = match request {
+            Ok(request) => request.clone(),
+            Err(err) => return ("invalid", self.generate_error(&err.to_string())),
         };
 
         let Some(request_type) = request.get("request") else {
-            return self.generate_error("key 'request' not found in JSON");
+            return (
+                "invalid",
+                self.generate_error("key 'request' not found in JSON"),
+            );
         };
 
         match request_type {
-            Value::String(val) if val == "put" => self.put(request),
-            Value::String(val) if val == "get" => self.get(client_id, request),
-            Value::String(val) if val == "delete" => self.delete(request),
-            Value::String(val) if val == "abort" => self.abort(client_id, request),
-            Value::String(val) => self.generate_error(&format!("invalid request type: '{val}'")),
-            _ => self.generate_error("key 'request' is not a string"),
+            Value::String(val) if val == "put" => ("put", self.put(request)),
+            Value::String(val) if val == "get" => ("get", self.get(client_id, request)),
+            Value::String(val) if val == "delete" => ("delete", self.delete(request)),
+            Value::String(val) if val == "abort" => ("abort", self.abort(client_id, request)),
+            Value::String(val) => (
+                "invalid",
+                self.generate_error(&format!("invalid request type: '{val}'")),
+            ),
+            _ => (
+                "invalid",
+                self.generate_error("key 'request' is not a string"),
+            ),
         }
     }
 
     fn disconnect(&mut self, client_id: ClientId) -> Vec<ServerMessage> {
-        let Some(jobs) = self.client_jobs.remove(&client_id) else {
-            return Vec::new();
-        };
+        self.waiting_clients.remove(&client_id);
+        let jobs = self.client_jobs.remove(&client_id).unwrap_or_default();
 
         let mut responses = Vec::new();
         for job in jobs {
@@ -244,97 +288,164 @@ impl ServerState {
                 }
             }
 
-            self.queues
-                .entry(job.queue.clone())
-                .or_default()
-                .push(job);
+            let queue = job.queue.clone();
+            self.queues.entry(queue.clone()).or_default().push(job);
+            self.queue_changed(&queue);
         }
+        self.workers_changed();
 
         responses
     }
 }
 
+// Only the job state needs a global lock, client ids and wake-ups of waiting
+// clients never contend with it
 pub struct Server {
-    next_client_id: Arc<Mutex<ClientId>>,
+    next_client_id: AtomicU64,
     state: Arc<Mutex<ServerState>>,
-    waiting: Arc<Mutex<HashMap<ClientId, Arc<Notify>>>>,
+    waiting: DashMap<ClientId, Arc<Notify>>,
 }
 
 impl Server {
     pub fn new() -> Self {
         Self {
-            next_client_id: Arc::new(Mutex::new(0)),
+            next_client_id: AtomicU64::new(1),
             state: Arc::new(Mutex::new(ServerState::new())),
-            waiting: Arc::new(Mutex::new(HashMap::new())),
+            waiting: DashMap::new(),
         }
     }
 
-    async fn get_client_id(&self) -> ClientId {
-        let mut next_client_id = self.next_client_id.lock().await;
-        *next_client_id += 1;
-        *next_client_id
+    fn get_client_id(&self) -> ClientId {
+        self.next_client_id.fetch_add(1, Ordering::Relaxed)
     }
 
     async fn wait_job(&self, client_id: ClientId) {
         let event = Arc::new(Notify::new());
-        let event2 = Arc::clone(&event);
-        let mut waiting = self.waiting.lock().await;
-        waiting.insert(client_id, event);
-        drop(waiting);
-        event2.notified().await;
+        self.waiting.insert(client_id, Arc::clone(&event));
+        event.notified().await;
     }
 
-    async fn notify(&self, client_id: ClientId) {
-        let mut waiting = self.waiting.lock().await;
-        waiting
-            .remove(&client_id)
-            .inspect(|event| event.notify_one());
+    fn notify(&self, client_id: ClientId) {
+        if let Some((_, event)) = self.waiting.remove(&client_id) {
+            event.notify_one();
+        }
     }
 }
 
 #[async_trait]
 impl TcpServer for Server {
-    async fn handle_connection(&self, mut stream: TcpStream) {
-        let mut buffer = [0; 1024];
-
-        let client_id = self.get_client_id().await;
-        println!("Client {client_id} connected!");
-
-        while let Some(request) = utils::read_until(&mut stream, &mut buffer, '\n').await {
-            println!("<--- [{client_id}] {request}");
+    async fn handle_connection(&self, stream: ClientStream) {
+        let (reader, writer) = stream.into_split();
+        let mut requests = JsonLines::new(FrameReader::new(reader));
+        let writer = QueuedWriter::new(writer);
+        let closed = writer.closed().clone();
+
+        let client_id = self.get_client_id();
+        tracing::info!(client_id, "client connected");
+
+        while let Some(Some(request)) = closed.run_until_cancelled(requests.next()).await {
+            match &request {
+                Ok(request) => tracing::debug!(client_id, "<-- {request}"),
+                Err(err) => tracing::debug!(client_id, "<-- {err}"),
+            }
+            metrics::increment(CHALLENGE, Counter::Requests);
+            let mut received = Instant::now();
+            let mut request_type = "invalid";
             let mut should_wait = true;
             while should_wait {
                 should_wait = false;
 
                 let mut state = self.state.lock().await;
-                let responses = state.get_responses(client_id, &request);
+                let responses;
+                (request_type, responses) = state.get_responses(client_id, &request);
                 drop(state);
                 for response in responses {
                     match response {
                         ServerMessage::Waiting => should_wait = true,
-                        ServerMessage::Notify(client_to_wake) => self.notify(client_to_wake).await,
+                        ServerMessage::Notify(client_to_wake) => self.notify(client_to_wake),
                         ServerMessage::Response(mut value) => {
-                            println!("---> [{client_id}] {value}");
+                            tracing::debug!(client_id, "--> {value}");
                             value.push('\n');
-                            let _ = stream.write_all(value.as_bytes()).await;
+                            writer.send(value);
                         }
                     }
                 }
 
                 if should_wait {
                     self.wait_job(client_id).await;
+                    // Time spent waiting for a job is not handling time
+                    received = Instant::now();
                 }
             }
+            metrics::observe(CHALLENGE, request_type, received.elapsed());
         }
 
-        println!("Client {client_id} disconnected!");
+        tracing::info!(client_id, "client disconnected");
         let mut state = self.state.lock().await;
         let responses = state.disconnect(client_id);
         for response in responses {
             match response {
-                ServerMessage::Notify(client_to_wake) => self.notify(client_to_wake).await,
+                ServerMessage::Notify(client_to_wake) => self.notify(client_to_wake),
                 _ => unreachable!(),
             }
         }
     }
+
+    fn wants_nodelay(&self) -> bool {
+        true
+    }
+
+    async fn state_sizes(&self) -> StateSizes {
+        let waiting = self.waiting.len();
+        let state = self.state.lock().await;
+        vec![
+            ("queues", state.queues.len()),
+            ("queued_jobs", state.queues.values().map(Vec::len).sum()),
+            ("working_clients", state.client_jobs.len()),
+            (
+                "jobs_in_progress",
+                state.client_jobs.values().map(Vec::len).sum(),
+            ),
+            ("waiting_clients", state.waiting_clients.len()),
+            ("waiting_notifiers", waiting),
+        ]
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    fn gauge(name: &str, labels: &str) -> Option<usize> {
+        let prefix = format!("proto_hackers_{name}{{challenge=\"9\"{labels}}} ");
+        metrics::render()
+            .lines()
+            .find_map(|line| line.strip_prefix(&prefix)?.parse().ok())
+    }
+
+    #[test]
+    fn gauges_follow_every_mutation() {
+        let mut state = ServerState::new();
+        for priority in [1, 2] {
+            state.put(json!({"queue": "gauged", "pri": priority, "job": {}}));
+        }
+        assert_eq!(gauge("jobs_queue_depth", ",queue=\"gauged\""), Some(2));
+
+        state.get(1, json!({"queues": ["gauged"]}));
+        assert_eq!(gauge("jobs_queue_depth", ",queue=\"gauged\""), Some(1));
+        assert_eq!(gauge("jobs_in_progress", ""), Some(1));
+
+        state.get(2, json!({"queues": ["empty"], "wait": true}));
+        assert_eq!(gauge("jobs_waiting_workers", ""), Some(1));
+
+        state.disconnect(1);
+        assert_eq!(gauge("jobs_queue_depth", ",queue=\"gauged\""), Some(2));
+        assert_eq!(gauge("jobs_in_progress", ""), Some(0));
+
+        state.delete(json!({"id": 1}));
+        state.delete(json!({"id": 2}));
+        state.disconnect(2);
+        assert_eq!(gauge("jobs_queue_depth", ",queue=\"gauged\""), None);
+        assert_eq!(gauge("jobs_waiting_workers", ""), Some(0));
+    }
 }
@@ -1,10 +1,16 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use tokio::net::TcpStream;
-use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tokio::{
+    io::{self, AsyncWriteExt},
+    sync::Mutex,
+};
 
-use crate::{utils, TcpServer};
+use crate::metrics::{self, Counter};
+use crate::utils::{self, FrameReader};
+use crate::{ClientStream, ClientWriter, StateSizes, TcpServer};
+
+const CHALLENGE: u8 = 10;
 
 enum ServerMessage {
     Ok(String),
@@ -12,6 +18,18 @@ enum ServerMessage {
     Abort(String),
 }
 
+impl ServerMessage {
+    // Missing files and revisions are regular answers, other errors come from
+    // malformed requests
+    fn is_protocol_error(&self) -> bool {
+        match self {
+            ServerMessage::Ok(msg) => msg.starts_with("ERR") && !msg.starts_with("ERR no such"),
+            ServerMessage::Read(..) => false,
+            ServerMessage::Abort(_) => true,
+        }
+    }
+}
+
 pub fn is_valid_path(path: &str) -> bool {
     path.starts_with('/')
         && !path.contains("//")
@@ -99,6 +117,15 @@ impl Dir {
         node_names.sort_unstable();
         node_names
     }
+
+    fn nb_files(&self) -> usize {
+        self.files.len() + self.subdirs.iter().map(Dir::nb_files).sum::<usize>()
+    }
+
+    fn nb_revisions(&self) -> usize {
+        let revisions: usize = self.files.iter().map(|f| f.revisions.len()).sum();
+        revisions + self.subdirs.iter().map(Dir::nb_revisions).sum::<usize>()
+    }
 }
 
 struct File {
@@ -226,60 +253,71 @@ impl Server {
     }
 }
 
+// Oversized requests are answered before closing, other failures mean the
+// connection is gone
+async fn read_failed(err: io::Error, writer: &mut ClientWriter) {
+    let Some(err) = utils::protocol_error(&err) else {
+        return tracing::debug!("connection lost: {err}");
+    };
+    metrics::increment(CHALLENGE, Counter::ProtocolErrors);
+    let _ = writer.write_all(format!("ERR {err}\n").as_bytes()).await;
+}
+
 #[async_trait]
 impl TcpServer for Server {
-    async fn handle_connection(&self, mut stream: TcpStream) {
-        let mut buffer = [0; 1024];
-
-        let _ = stream.write_all("READY\n".as_bytes()).await;
-        while let Some(request) = utils::read_until(&mut stream, &mut buffer, '\n').await {
+    async fn handle_connection(&self, stream: ClientStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = FrameReader::new(reader);
+
+        let _ = writer.write_all("READY\n".as_bytes()).await;
+        loop {
+            let request = match reader.read_line().await {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(err) => return read_failed(err, &mut writer).await,
+            };
+            let request = String::from_utf8_lossy(&request);
             let mut state = self.state.lock().await;
             let response = state.get_response(&request);
             drop(state);
+            metrics::increment(CHALLENGE, Counter::Requests);
+            if response.is_protocol_error() {
+                metrics::increment(CHALLENGE, Counter::ProtocolErrors);
+            }
             match response {
                 ServerMessage::Ok(mut msg) => {
                     msg.push('\n');
-                    let _ = stream.write_all(msg.as_bytes()).await;
+                    let _ = writer.write_all(msg.as_bytes()).await;
                 }
                 ServerMessage::Read(path, n) => {
-                    let data_len = buffer
-                        .iter()
-                        .position(|&c| c == b'\0')
-                        .unwrap_or(buffer.len());
-                    let mut data: Vec<u8>;
-                    if data_len < n {
-                        let mut buf = Vec::new();
-                        let Some(file_data) =
-                            utils::read_for(&mut stream, &mut buf, n - data_len).await
-                        else {
-                            break;
-                        };
-                        data = Vec::from(&buffer[..data_len]);
-                        data.extend(file_data);
-                        buffer[..buf.len()].copy_from_slice(&buf[..]);
-                        buffer[buf.len()..].fill(0);
-                    } else {
-                        data = Vec::from(&buffer[..n]);
-                        buffer.copy_within(n..data_len, 0);
-                        let remaining_len = data_len - n;
-                        buffer[remaining_len..].fill(0);
-                    }
-
+                    let data = match reader.read_exact(n).await {
+                        Ok(Some(data)) => data,
+                        Ok(None) => break,
+                        Err(err) => return read_failed(err, &mut writer).await,
+                    };
                     let mut state = self.state.lock().await;
-                    match state.put_file_data(path, data) {
+                    match state.put_file_data(path, data.to_vec()) {
                         ServerMessage::Ok(mut msg) => {
                             msg.push('\n');
-                            let _ = stream.write_all(msg.as_bytes()).await;
+                            let _ = writer.write_all(msg.as_bytes()).await;
                         }
                         _ => unreachable!(),
                     }
                 }
                 ServerMessage::Abort(mut msg) => {
                     msg.push('\n');
-                    let _ = stream.write_all(msg.as_bytes()).await;
+                    let _ = writer.write_all(msg.as_bytes()).await;
                     break;
                 }
             };
         }
     }
+
+    async fn state_sizes(&self) -> StateSizes {
+        let state = self.state.lock().await;
+        vec![
+            ("files", state.root.nb_files()),
+            ("revisions", state.root.nb_revisions()),
+        ]
+    }
 }
@@ -1,12 +1,34 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future;
+use std::io::{self, IoSlice};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{self, Arc, LazyLock, Once};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter};
 use tokio::net::TcpStream;
-use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{self, Interval};
 
-use crate::{utils, TcpServer};
+use crate::config::PestControlConfig as Config;
+use crate::metrics::Counter;
+use crate::utils::checksum;
+use crate::utils::wire::{self, LengthPrefix, WireReader, WireWriter};
+use crate::utils::{self, FrameReader};
+use crate::{ClientStream, ClientWriter, ProtoError, StateSizes, TcpServer};
+
+mod breaker;
+mod cache;
+mod metrics;
+pub mod mock;
+
+use breaker::CircuitBreaker;
+use cache::PolicyCache;
+use metrics::Metrics;
 
 #[derive(PartialEq)]
 enum PolicyType {
@@ -21,6 +43,21 @@ impl PolicyType {
             PolicyType::Cull => 0x90,
         }
     }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicyType::Conserve => "conserve",
+            PolicyType::Cull => "cull",
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0xa0 => Some(PolicyType::Conserve),
+            0x90 => Some(PolicyType::Cull),
+            _ => None,
+        }
+    }
 }
 
 struct Policy {
@@ -36,7 +73,20 @@ enum PolicyAction {
 
 type SiteId = u32;
 
-type ServerResult = Result<ServerMessage, &'static str>;
+const CHALLENGE: u8 = 11;
+
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+type ServerResult = Result<ServerMessage, ProtoError>;
+
+// Sent as is to every client
+static HELLO: LazyLock<Bytes> = LazyLock::new(|| {
+    ServerMessage::Hello {
+        protocol: "pestcontrol".into(),
+        version: 1,
+    }
+    .to_bytes()
+});
 
 struct PopulationTarget {
     species: String,
@@ -82,58 +132,26 @@ enum ServerMessage {
 }
 
 impl ServerMessage {
-    fn parse_u8(data: &[u8], index: &mut usize) -> Result<u8, &'static str> {
-        if *index + 1 > data.len() {
-            return Err("Error parsing u8: not enough bytes to read");
-        }
-        let ans = data[*index];
-        *index += 1;
-        Ok(ans)
-    }
-
-    fn parse_u32(data: &[u8], index: &mut usize) -> Result<u32, &'static str> {
-        if *index + 4 > data.len() {
-            return Err("Error parsing u32: not enough bytes to read");
-        }
-        let ans = u32::from_be_bytes(data[*index..*index + 4].try_into().unwrap());
-        *index += 4;
-        Ok(ans)
-    }
-
-    fn parse_str(data: &[u8], index: &mut usize) -> Result<String, &'static str> {
-        let str_len = Self::parse_u32(data, index)? as usize;
-        if *index + str_len > data.len() {
-            return Err("Error parsing str: not enough bytes to read");
-        }
-        let ans = String::from_utf8_lossy(&data[*index..*index + str_len]).to_string();
-        *index += str_len;
-        Ok(ans)
-    }
-
     fn parse_target_populations(
-        data: &[u8],
-        index: &mut usize,
-    ) -> Result<Vec<PopulationTarget>, &'static str> {
-        let pop_len = Self::parse_u32(data, index)?;
+        reader: &mut WireReader,
+    ) -> Result<Vec<PopulationTarget>, ProtoError> {
+        let pop_len = reader.read_u32_be()?;
         let mut targets = Vec::new();
         for _ in 0..pop_len {
-            let species = Self::parse_str(data, index)?;
-            let min = Self::parse_u32(data, index)?;
-            let max = Self::parse_u32(data, index)?;
+            let species = reader.read_lp_string(LengthPrefix::U32)?;
+            let min = reader.read_u32_be()?;
+            let max = reader.read_u32_be()?;
             targets.push(PopulationTarget { species, min, max });
         }
         Ok(targets)
     }
 
-    fn parse_population_obs(
-        data: &[u8],
-        index: &mut usize,
-    ) -> Result<Vec<PopulationObs>, &'static str> {
-        let pop_len = Self::parse_u32(data, index)?;
+    fn parse_population_obs(reader: &mut WireReader) -> Result<Vec<PopulationObs>, ProtoError> {
+        let pop_len = reader.read_u32_be()?;
         let mut observations = Vec::new();
         for _ in 0..pop_len {
-            let species = Self::parse_str(data, index)?;
-            let count = Self::parse_u32(data, index)?;
+            let species = reader.read_lp_string(LengthPrefix::U32)?;
+            let count = reader.read_u32_be()?;
 
             if !observations.iter().all(
                 |PopulationObs {
@@ -141,7 +159,7 @@ impl ServerMessage {
                      count: c,
                  }| species != *sp || count == *c,
             ) {
-                return Err("Error in population observation: conflicting counts");
+                return Err(ProtoError::ConflictingCounts(species));
             }
 
             observations.push(PopulationObs { species, count });
@@ -150,85 +168,67 @@ impl ServerMessage {
     }
 
     fn parse_msg_hello(data: &[u8]) -> ServerResult {
-        let mut index = 0;
-        let protocol = Self::parse_str(data, &mut index)?;
-        let version = Self::parse_u32(data, &mut index)?;
-        if index != data.len() {
-            return Err("Error parsing Hello: found additional data");
-        }
+        let mut reader = WireReader::new(data);
+        let protocol = reader.read_lp_string(LengthPrefix::U32)?;
+        let version = reader.read_u32_be()?;
+        reader.finish("Hello")?;
         Ok(ServerMessage::Hello { protocol, version })
     }
 
     fn parse_msg_error(data: &[u8]) -> ServerResult {
-        let mut index = 0;
-        let msg = Self::parse_str(data, &mut index)?;
-        if index != data.len() {
-            return Err("Error parsing Error: found additional data");
-        }
+        let mut reader = WireReader::new(data);
+        let msg = reader.read_lp_string(LengthPrefix::U32)?;
+        reader.finish("Error")?;
         Ok(ServerMessage::Error { msg })
     }
 
     fn parse_msg_ok(data: &[u8]) -> ServerResult {
-        if !data.is_empty() {
-            return Err("Error parsing Ok: found additional data");
-        }
+        WireReader::new(data).finish("Ok")?;
         Ok(ServerMessage::Ok)
     }
 
     fn parse_msg_dial_authority(data: &[u8]) -> ServerResult {
-        let mut index = 0;
-        let site = Self::parse_u32(data, &mut index)?;
-        if index != data.len() {
-            return Err("Error parsing DialAuthority: found additional data");
-        }
+        let mut reader = WireReader::new(data);
+        let site = reader.read_u32_be()?;
+        reader.finish("DialAuthority")?;
         Ok(ServerMessage::DialAuthority { site })
     }
 
     fn parse_msg_target_population(data: &[u8]) -> ServerResult {
-        let mut index = 0;
-        let site = Self::parse_u32(data, &mut index)?;
-        let targets = Self::parse_target_populations(data, &mut index)?;
-        if index != data.len() {
-            return Err("Error parsing TargetPopulations: found additional data");
-        }
+        let mut reader = WireReader::new(data);
+        let site = reader.read_u32_be()?;
+        let targets = Self::parse_target_populations(&mut reader)?;
+        reader.finish("TargetPopulations")?;
         Ok(ServerMessage::TargetPopulations { site, targets })
     }
 
     fn parse_msg_create_policy(data: &[u8]) -> ServerResult {
-        let mut index = 0;
-        let species = Self::parse_str(data, &mut index)?;
-        let action = Self::parse_u8(data, &mut index)?;
-        if index != data.len() {
-            return Err("Error parsing CreatePolicy: found additional data");
-        }
+        let mut reader = WireReader::new(data);
+        let species = reader.read_lp_string(LengthPrefix::U32)?;
+        let action = reader.read_u8()?;
+        reader.finish("CreatePolicy")?;
         Ok(ServerMessage::CreatePolicy { species, action })
     }
 
     fn parse_msg_delete_policy(data: &[u8]) -> ServerResult {
-        let mut index = 0;
-        let policy = Self::parse_u32(data, &mut index)?;
-        if index != data.len() {
-            return Err("Error parsing DeletePolicy: found additional data");
-        }
+        let mut reader = WireReader::new(data);
+        let policy = reader.read_u32_be()?;
+        reader.finish("DeletePolicy")?;
         Ok(ServerMessage::DeletePolicy { policy })
     }
 
     fn parse_msg_policy_result(data: &[u8]) -> ServerResult {
-        let mut index = 0;
-        let policy = Self::parse_u32(data, &mut index)?;
-        if index != data.len() {
-            return Err("Error parsing PolicyResult: found additional data");
-        }
+        let mut reader = WireReader::new(data);
+        let policy = reader.read_u32_be()?;
+        reader.finish("PolicyResult")?;
         Ok(ServerMessage::PolicyResult { policy })
     }
 
     fn parse_msg_site_visit(data: &[u8]) -> ServerResult {
-        let mut index = 0;
-        let site = Self::parse_u32(data, &mut index)?;
-        let observations = Self::parse_population_obs(data, &mut index)?;
-        if index != data.len() {
-            return Err("Error parsing PopulationObs: found additional data");
-        }
+        let mut reader = WireReader::new(data);
+        let site = reader.read_u32_be()?;
+        let observations = Self::parse_population_obs(&mut reader)?;
+        reader.finish("SiteVisit")?;
         Ok(ServerMessage::SiteVisit { site, observations })
     }
 
@@ -243,99 +243,187 @@ impl ServerMessage {
             0x56 => Self::parse_msg_delete_policy(data),
             0x57 => Self::parse_msg_policy_result(data),
             0x58 => Self::parse_msg_site_visit(data),
-            _ => Err("Invalid message type"),
+            _ => Err(ProtoError::UnknownMessageType(msg_type)),
         }
     }
 
-    fn u32_to_bytes(data: u32) -> Vec<u8> {
-        data.to_be_bytes().into()
-    }
-
-    fn str_to_bytes(data: &str) -> Vec<u8> {
-        let mut bytes = Vec::from(&(data.len() as u32).to_be_bytes());
-        bytes.extend(data.as_bytes());
-        bytes
-    }
-
-    fn target_population_to_bytes(data: &[PopulationTarget]) -> Vec<u8> {
-        let mut bytes = Self::u32_to_bytes(data.len() as u32);
-        bytes.extend(
-            data.iter()
-                .flat_map(|PopulationTarget { species, min, max }| {
-                    let mut pop_bytes = Self::str_to_bytes(species);
-                    pop_bytes.extend(Self::u32_to_bytes(*min));
-                    pop_bytes.extend(Self::u32_to_bytes(*max));
-                    pop_bytes
-                }),
-        );
-        bytes
-    }
-
-    fn population_obs_to_bytes(data: &[PopulationObs]) -> Vec<u8> {
-        let mut bytes = Self::u32_to_bytes(data.len() as u32);
-        bytes.extend(data.iter().flat_map(|PopulationObs { species, count }| {
-            let mut pop_bytes = Self::str_to_bytes(species);
-            pop_bytes.extend(Self::u32_to_bytes(*count));
-            pop_bytes
-        }));
-        bytes
+    // Size of the whole frame: type, length, payload and checksum
+    fn encoded_len(&self) -> usize {
+        let str_len = |data: &str| wire::lp_string_len(LengthPrefix::U32, data);
+        let payload_len = match self {
+            ServerMessage::Hello { protocol, .. } => str_len(protocol) + 4,
+            ServerMessage::Error { msg } => str_len(msg),
+            ServerMessage::Ok => 0,
+            ServerMessage::DialAuthority { .. } => 4,
+            ServerMessage::TargetPopulations { targets, .. } => {
+                let targets_len: usize = targets.iter().map(|t| str_len(&t.species) + 8).sum();
+                8 + targets_len
+            }
+            ServerMessage::CreatePolicy { species, .. } => str_len(species) + 1,
+            ServerMessage::DeletePolicy { .. } => 4,
+            ServerMessage::PolicyResult { .. } => 4,
+            ServerMessage::SiteVisit { observations, .. } => {
+                let obs_len: usize = observations.iter().map(|o| str_len(&o.species) + 4).sum();
+                8 + obs_len
+            }
+        };
+        1 + 4 + payload_len + 1
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let (msg_type, data_bytes) = match self {
+    // Payload alone, along with the message type going in the header
+    fn encode_payload(&self) -> (u8, BytesMut) {
+        let mut writer = WireWriter::with_capacity(self.encoded_len() - 6);
+        let msg_type = match self {
             ServerMessage::Hello { protocol, version } => {
-                let mut bytes = Self::str_to_bytes(protocol);
-                bytes.extend(Self::u32_to_bytes(*version));
-                (0x50, bytes)
+                writer.write_lp_string(LengthPrefix::U32, protocol);
+                writer.write_u32_be(*version);
+                0x50
+            }
+            ServerMessage::Error { msg } => {
+                writer.write_lp_string(LengthPrefix::U32, msg);
+                0x51
+            }
+            ServerMessage::Ok => 0x52,
+            ServerMessage::DialAuthority { site } => {
+                writer.write_u32_be(*site);
+                0x53
             }
-            ServerMessage::Error { msg } => (0x51, Self::str_to_bytes(msg)),
-            ServerMessage::Ok => (0x52, Vec::new()),
-            ServerMessage::DialAuthority { site } => (0x53, Self::u32_to_bytes(*site)),
             ServerMessage::TargetPopulations { site, targets } => {
-                let mut bytes = Self::u32_to_bytes(*site);
-                bytes.extend(Self::target_population_to_bytes(targets));
-                (0x54, bytes)
+                writer.write_u32_be(*site);
+                writer.write_u32_be(targets.len() as u32);
+                for PopulationTarget { species, min, max } in targets {
+                    writer.write_lp_string(LengthPrefix::U32, species);
+                    writer.write_u32_be(*min);
+                    writer.write_u32_be(*max);
+                }
+                0x54
             }
             ServerMessage::CreatePolicy { species, action } => {
-                let mut bytes = Self::str_to_bytes(species);
-                bytes.push(*action);
-                (0x55, bytes)
+                writer.write_lp_string(LengthPrefix::U32, species);
+                writer.write_u8(*action);
+                0x55
+            }
+            ServerMessage::DeletePolicy { policy } => {
+                writer.write_u32_be(*policy);
+                0x56
+            }
+            ServerMessage::PolicyResult { policy } => {
+                writer.write_u32_be(*policy);
+                0x57
             }
-            ServerMessage::DeletePolicy { policy } => (0x56, Self::u32_to_bytes(*policy)),
-            ServerMessage::PolicyResult { policy } => (0x57, Self::u32_to_bytes(*policy)),
             ServerMessage::SiteVisit { site, observations } => {
-                let mut bytes = Self::u32_to_bytes(*site);
-                bytes.extend(Self::population_obs_to_bytes(observations));
-                (0x58, bytes)
+                writer.write_u32_be(*site);
+                writer.write_u32_be(observations.len() as u32);
+                for PopulationObs { species, count } in observations {
+                    writer.write_lp_string(LengthPrefix::U32, species);
+                    writer.write_u32_be(*count);
+                }
+                0x58
             }
         };
+        let payload = writer.into_bytes();
+        debug_assert_eq!(payload.len() + 6, self.encoded_len());
+        (msg_type, payload)
+    }
 
-        let mut bytes = Vec::from([msg_type]);
-        bytes.extend(Self::u32_to_bytes(data_bytes.len() as u32 + 6));
-        bytes.extend(data_bytes);
+    // Header, payload and checksum of the frame, kept apart so that they can be
+    // written without being copied into one buffer
+    fn frame_parts(&self) -> ([u8; 5], BytesMut, [u8; 1]) {
+        let (msg_type, payload) = self.encode_payload();
+        let mut header = [msg_type, 0, 0, 0, 0];
+        header[1..].copy_from_slice(&(payload.len() as u32 + 6).to_be_bytes());
+        let checksum = checksum::compute([&header[..], &payload]);
+        (header, payload, [checksum])
+    }
 
-        let checksum = bytes
-            .iter()
-            .fold(0u8, |acc, &v| acc.wrapping_add(v))
-            .wrapping_neg();
-        bytes.push(checksum);
-        bytes
+    pub fn to_bytes(&self) -> Bytes {
+        let (header, payload, checksum) = self.frame_parts();
+        let mut bytes = BytesMut::with_capacity(self.encoded_len());
+        bytes.put_slice(&header);
+        bytes.put_slice(&payload);
+        bytes.put_slice(&checksum);
+        bytes.freeze()
+    }
+
+    async fn write_to(&self, stream: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        let (header, payload, checksum) = self.frame_parts();
+        let mut slices = [
+            IoSlice::new(&header),
+            IoSlice::new(&payload),
+            IoSlice::new(&checksum),
+        ];
+        utils::write_all_vectored(stream, &mut slices).await
     }
 }
 
 struct SiteState {
     targets: HashMap<String, PopulationTarget>,
+    targets_fetched_at: Instant,
+    observed: HashMap<String, u32>,
     policies: HashMap<String, Policy>,
+    created: HashMap<u32, String>,
+    // Policies whose deletion was sent without the reply coming back, which
+    // an error reply then confirms
+    unconfirmed: HashSet<u32>,
 }
 
 impl SiteState {
-    fn new() -> Self {
+    fn new(policies: HashMap<String, Policy>) -> Self {
+        let created = policies
+            .values()
+            .map(|policy| (policy.id.unwrap(), policy.species.to_owned()))
+            .collect();
         Self {
             targets: HashMap::new(),
-            policies: HashMap::new(),
+            targets_fetched_at: Instant::now(),
+            observed: HashMap::new(),
+            policies,
+            created,
+            unconfirmed: HashSet::new(),
+        }
+    }
+
+    fn forget_policy(&mut self, id: u32) {
+        self.unconfirmed.remove(&id);
+        let Some(species) = self.created.remove(&id) else {
+            return;
+        };
+        if self
+            .policies
+            .get(&species)
+            .is_some_and(|policy| policy.id == Some(id))
+        {
+            self.policies.remove(&species);
         }
     }
 
+    // Left in `created` only, for the audit to delete it again
+    fn unconfirm_deletion(&mut self, id: u32) {
+        self.unconfirmed.insert(id);
+        self.policies.retain(|_, policy| policy.id != Some(id));
+    }
+
+    fn set_targets(&mut self, targets: HashMap<String, PopulationTarget>) {
+        self.targets = targets;
+        self.targets_fetched_at = Instant::now();
+    }
+
+    fn targets_expired(&self, ttl: Option<Duration>) -> bool {
+        ttl.is_some_and(|ttl| self.targets_fetched_at.elapsed() >= ttl)
+    }
+
+    // Policies for species that are no longer part of the site targets
+    fn get_stale_actions(&self) -> Vec<PolicyAction> {
+        self.policies
+            .values()
+            .filter(|policy| !self.targets.contains_key(&policy.species))
+            .map(|policy| PolicyAction::Delete {
+                id: policy.id.unwrap(),
+                species: policy.species.to_owned(),
+            })
+            .collect()
+    }
+
     fn get_action(&self, species: &str, count: u32) -> Vec<PolicyAction> {
         let mut actions = Vec::new();
         let Some(target) = self.targets.get(species) else {
@@ -376,13 +464,13 @@ impl SiteState {
         actions
     }
 
-    pub fn get_actions(&mut self, observations: &[PopulationObs]) -> Vec<PolicyAction> {
+    fn get_pending_actions(&self) -> Vec<PolicyAction> {
         let mut all_species_obs = self
             .targets
             .keys()
             .map(|species| (species, 0u32))
             .collect::<HashMap<_, _>>();
-        for PopulationObs { species, count } in observations {
+        for (species, count) in &self.observed {
             all_species_obs.entry(species).and_modify(|c| *c = *count);
         }
         all_species_obs
@@ -390,179 +478,662 @@ impl SiteState {
             .flat_map(|(&species, &count)| self.get_action(species, count))
             .collect()
     }
+
+    fn observe(&mut self, observations: &[PopulationObs]) {
+        self.observed = observations
+            .iter()
+            .map(|PopulationObs { species, count }| (species.to_owned(), *count))
+            .collect();
+    }
+
+    // Every species checked again against new targets, whose ranges may
+    // have changed since their policy was created
+    fn get_refresh_actions(&self) -> Vec<PolicyAction> {
+        let mut actions = self.get_stale_actions();
+        actions.extend(self.get_pending_actions());
+        actions
+    }
+
+    fn dump(&self) -> Value {
+        let targets: serde_json::Map<String, Value> = self
+            .targets
+            .values()
+            .map(|target| {
+                let range = json!({"min": target.min, "max": target.max});
+                (target.species.to_owned(), range)
+            })
+            .collect();
+        let mut policies: Vec<_> = self.policies.values().collect();
+        policies.sort_by_key(|policy| &policy.species);
+        let policies: Vec<_> = policies
+            .into_iter()
+            .map(|policy| {
+                json!({
+                    "id": policy.id,
+                    "species": policy.species,
+                    "action": policy.policy_type.as_str(),
+                })
+            })
+            .collect();
+        json!({"targets": targets, "policies": policies})
+    }
+
+    // Delete the created policies we lost track of, created by requests that
+    // timed out or not confirmed deleted, then converge the remaining ones
+    // towards the last observation
+    pub fn get_audit_actions(&self) -> Vec<PolicyAction> {
+        let mut actions: Vec<_> = self
+            .created
+            .iter()
+            .filter(|(&id, species)| {
+                self.policies
+                    .get(*species)
+                    .is_none_or(|policy| policy.id != Some(id))
+            })
+            .map(|(&id, species)| PolicyAction::Delete {
+                id,
+                species: species.to_owned(),
+            })
+            .collect();
+        actions.extend(self.get_pending_actions());
+        actions
+    }
 }
 
-pub struct Server {
-    auth_connections: Arc<Mutex<HashMap<SiteId, Arc<Mutex<TcpStream>>>>>,
-    site_states: Arc<Mutex<HashMap<SiteId, Arc<Mutex<SiteState>>>>>,
+fn parse_header(header: &[u8]) -> Result<(u8, usize), ProtoError> {
+    let mut reader = WireReader::new(header);
+    let msg_type = reader.read_u8()?;
+    let msg_len = reader.read_u32_be()? as usize;
+    if !(6..=MAX_MESSAGE_LEN).contains(&msg_len) {
+        return Err(ProtoError::InvalidLength(msg_len));
+    }
+    Ok((msg_type, msg_len))
 }
 
-impl Server {
-    pub fn new() -> Self {
+fn parse_frame(header: &[u8], data: &[u8]) -> ServerResult {
+    checksum::verify([header, data])?;
+    ServerMessage::parse(header[0], &data[..data.len() - 1])
+}
+
+async fn parse_message(reader: &mut FrameReader<impl AsyncRead + Unpin>) -> ServerResult {
+    let Ok(Some(msg_header)) = reader.read_exact(5).await else {
+        return Err(ProtoError::Closed);
+    };
+    let (_, msg_len) = parse_header(&msg_header)?;
+
+    let Ok(Some(data)) = reader.read_exact(msg_len - 5).await else {
+        return Err(ProtoError::InvalidLength(msg_len));
+    };
+
+    parse_frame(&msg_header, &data)
+}
+
+// Split a raw byte stream into frames and parse each of them
+#[cfg(feature = "fuzzing")]
+pub fn parse_frames(mut data: &[u8]) {
+    while data.len() >= 5 {
+        let (header, rest) = data.split_at(5);
+        let Ok((_, msg_len)) = parse_header(header) else {
+            return;
+        };
+        let Some(body) = rest.get(..msg_len - 5) else {
+            return;
+        };
+        let _ = parse_frame(header, body);
+        data = &rest[msg_len - 5..];
+    }
+}
+
+fn to_targets(msg: ServerMessage) -> Result<HashMap<String, PopulationTarget>, ProtoError> {
+    let ServerMessage::TargetPopulations { targets, .. } = msg else {
+        return Err("Invalid TargetPopulations message from authority server".into());
+    };
+    Ok(targets
+        .into_iter()
+        .map(|t| (t.species.to_owned(), t))
+        .collect())
+}
+
+struct Authority {
+    stream: FrameReader<TcpStream>,
+    timeout: Duration,
+    // Requests whose reply did not come in time. The authority answers in
+    // order, so their replies come before the one to any later request
+    in_flight: VecDeque<ServerMessage>,
+}
+
+impl Authority {
+    fn new(stream: TcpStream, timeout: Duration) -> Self {
         Self {
-            auth_connections: Arc::new(Mutex::new(HashMap::new())),
-            site_states: Arc::new(Mutex::new(HashMap::new())),
+            stream: FrameReader::new(stream),
+            timeout,
+            in_flight: VecDeque::new(),
         }
     }
 
-    async fn parse_message(&self, stream: &mut TcpStream, buffer: &mut Vec<u8>) -> ServerResult {
-        let Some(msg_header) = utils::read_for(stream, buffer, 5).await else {
-            return Err("Couldn't read message header");
-        };
-        let msg_type = msg_header[0];
-        let msg_len = u32::from_be_bytes(msg_header[1..5].try_into().unwrap());
+    async fn read_reply(&mut self) -> ServerResult {
+        time::timeout(self.timeout, parse_message(&mut self.stream))
+            .await
+            .unwrap_or(Err(ProtoError::AuthorityTimeout))
+    }
 
-        let Some(mut data) = utils::read_for(stream, buffer, msg_len as usize - 5).await else {
-            return Err("Invalid message length");
-        };
+    // A request timing out is left in flight, its reply having to be
+    // collected by `late_replies` before sending another one
+    async fn request(&mut self, msg: ServerMessage) -> ServerResult {
+        debug_assert!(self.in_flight.is_empty());
+        if msg.write_to(self.stream.get_mut()).await.is_err() {
+            return Err("Could not write to authority server".into());
+        }
+        self.in_flight.push_back(msg);
+        let reply = self.read_reply().await?;
+        self.in_flight.pop_front();
+        Ok(reply)
+    }
 
-        let mut checksum = msg_header.iter().fold(0u8, |acc, &v| acc.wrapping_add(v));
-        checksum = checksum.wrapping_add(data.iter().fold(0u8, |acc, &v| acc.wrapping_add(v)));
-        if checksum != 0 {
-            return Err("Invalid checksum");
+    async fn late_replies(&mut self) -> Result<Vec<(ServerMessage, ServerMessage)>, ProtoError> {
+        let mut replies = Vec::new();
+        while !self.in_flight.is_empty() {
+            let reply = self.read_reply().await?;
+            replies.push((self.in_flight.pop_front().unwrap(), reply));
         }
+        Ok(replies)
+    }
+}
+
+// Follows a visit in the logs, from the client connection it came from to the
+// authority round-trips it triggers
+#[derive(Clone, Copy)]
+struct TraceId {
+    connection: u64,
+    visit: u64,
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "c{}.v{}", self.connection, self.visit)
+    }
+}
 
-        let Some(_) = data.pop() else {
-            return Err("Empty message received");
+struct Visit {
+    trace: TraceId,
+    received_at: Instant,
+    observations: Vec<PopulationObs>,
+}
+
+struct SiteHandle {
+    sender: mpsc::UnboundedSender<Visit>,
+    breaker: Arc<CircuitBreaker>,
+    // Number of policies the worker currently has at the authority
+    nb_policies: Arc<AtomicUsize>,
+    // Last state published by the worker, which cannot answer itself while
+    // waiting on the authority
+    dump: Arc<sync::Mutex<Value>>,
+}
+
+struct SiteWorker {
+    site: SiteId,
+    config: Config,
+    metrics: Arc<Metrics>,
+    cache: Arc<PolicyCache>,
+    breaker: Arc<CircuitBreaker>,
+    nb_policies: Arc<AtomicUsize>,
+    dump: Arc<sync::Mutex<Value>>,
+    authority: Option<Authority>,
+    state: SiteState,
+    // Visit being processed, none for audits and probes
+    trace: Option<TraceId>,
+}
+
+impl SiteWorker {
+    fn new(
+        site: SiteId,
+        config: Config,
+        metrics: Arc<Metrics>,
+        cache: Arc<PolicyCache>,
+        breaker: Arc<CircuitBreaker>,
+        nb_policies: Arc<AtomicUsize>,
+        dump: Arc<sync::Mutex<Value>>,
+    ) -> Self {
+        let state = SiteState::new(cache.get_policies(site));
+        nb_policies.store(state.created.len(), Ordering::Relaxed);
+        let worker = Self {
+            site,
+            config,
+            metrics,
+            cache,
+            breaker,
+            nb_policies,
+            dump,
+            authority: None,
+            state,
+            trace: None,
         };
+        worker.publish();
+        worker
+    }
 
-        ServerMessage::parse(msg_type, &data)
+    fn publish(&self) {
+        let mut dump = self.state.dump();
+        dump["connection"] = json!({"connected": self.authority.is_some()});
+        *self.dump.lock().unwrap() = dump;
     }
 
-    async fn get_connection(&self, site: u32) -> Arc<Mutex<TcpStream>> {
-        let mut connections = self.auth_connections.lock().await;
-        if let Entry::Vacant(entry) = connections.entry(site) {
-            let new_connection = TcpStream::connect("pestcontrol.protohackers.com:20547")
-                .await
-                .expect("Could not connect to authority server");
-            entry.insert(Arc::new(Mutex::new(new_connection)));
+    // Log an authority round-trip along with the visit it was made for
+    fn log_span<T>(&self, operation: &str, start: Instant, result: &Result<T, ProtoError>) {
+        let span = match self.trace {
+            Some(trace) => format!("[{trace}]"),
+            None => String::from("[background]"),
+        };
+        let elapsed = start.elapsed().as_millis();
+        match result {
+            Ok(_) => tracing::info!("{span} site {} {operation} took {elapsed}ms", self.site),
+            Err(msg) => tracing::warn!(
+                "{span} site {} {operation} failed after {elapsed}ms: {msg}",
+                self.site
+            ),
         }
-        Arc::clone(connections.get(&site).unwrap())
     }
 
-    async fn get_targets(
+    async fn dial_authority(
         &self,
-        site: u32,
-    ) -> Result<HashMap<String, PopulationTarget>, &'static str> {
-        let connection = self.get_connection(site).await;
-        let mut connection = connection.lock().await;
-        let mut buffer = Vec::new();
+    ) -> Result<(Authority, HashMap<String, PopulationTarget>), ProtoError> {
+        let start = Instant::now();
+        let result = self.dial().await;
+        self.log_span("dial", start, &result);
+        result
+    }
+
+    async fn dial(&self) -> Result<(Authority, HashMap<String, PopulationTarget>), ProtoError> {
+        let timeout = self.config.authority_timeout;
+        let connection = self.config.connector.connect(&self.config.authority_addr);
+        let Ok(Ok(stream)) = time::timeout(timeout, connection).await else {
+            return Err("Could not connect to authority server".into());
+        };
+        let mut authority = Authority::new(stream, timeout);
+
         let msg = ServerMessage::Hello {
             protocol: "pestcontrol".into(),
             version: 1,
         };
-        let _ = connection.write_all(&msg.to_bytes()).await;
-        let response = self.parse_message(&mut connection, &mut buffer).await?;
-        match response {
+        match authority.request(msg).await? {
             ServerMessage::Hello {
                 protocol,
                 version: 1,
             } if protocol == "pestcontrol" => (),
             ServerMessage::Hello { .. } => {
-                return Err("Invalid Hello message from authority server")
+                return Err("Invalid Hello message from authority server".into())
             }
-            _ => return Err("No Hello message from authority server"),
+            _ => return Err("No Hello message from authority server".into()),
         };
 
-        let msg = ServerMessage::DialAuthority { site };
-        let _ = connection.write_all(&msg.to_bytes()).await;
-        let response = self.parse_message(&mut connection, &mut buffer).await?;
-        let ServerMessage::TargetPopulations { targets, .. } = response else {
-            return Err("Invalid TargetPopulations message from authority server");
-        };
-        Ok(targets
-            .into_iter()
-            .map(|t| (t.species.to_owned(), t))
-            .collect())
+        let msg = ServerMessage::DialAuthority { site: self.site };
+        let targets = to_targets(authority.request(msg).await?)?;
+        self.metrics.site_dialed();
+        Ok((authority, targets))
     }
 
-    async fn get_site_state(&self, site: u32) -> Result<Arc<Mutex<SiteState>>, &'static str> {
-        let mut site_states = self.site_states.lock().await;
-        if site_states.contains_key(&site) {
-            return Ok(Arc::clone(site_states.get(&site).unwrap()));
+    async fn connect(&mut self) -> Result<(), ProtoError> {
+        self.reset_authority();
+        let (authority, targets) = self.dial_authority().await?;
+        self.authority = Some(authority);
+        self.state.set_targets(targets);
+        Ok(())
+    }
+
+    // Targets are asked again on the connection of the site, then applied to
+    // the policies it manages
+    async fn refresh_targets(&mut self) -> Result<(), ProtoError> {
+        let msg = ServerMessage::DialAuthority { site: self.site };
+        let targets = to_targets(self.traced_request("refresh targets", msg).await?)?;
+        self.state.set_targets(targets);
+        for action in self.state.get_refresh_actions() {
+            self.apply_action(action).await?;
         }
+        Ok(())
+    }
+
+    fn get_authority(&mut self) -> Result<&mut Authority, ProtoError> {
+        self.authority
+            .as_mut()
+            .ok_or("No connection to authority server".into())
+    }
 
-        let new_site_state = Arc::new(Mutex::new(SiteState::new()));
-        let site_state_am = Arc::clone(&new_site_state);
-        let mut site_state = site_state_am.lock().await;
-        site_states.insert(site, new_site_state);
-        drop(site_states);
+    // Requests left in flight may or may not have been processed: deletions
+    // are tried again by the audit, creations cannot be without their id
+    fn reset_authority(&mut self) {
+        let Some(authority) = self.authority.take() else {
+            return;
+        };
+        for request in authority.in_flight {
+            match request {
+                ServerMessage::DeletePolicy { policy } => self.state.unconfirm_deletion(policy),
+                ServerMessage::CreatePolicy { species, .. } => tracing::warn!(
+                    "Policy for {species} at site {} may have been created without its id",
+                    self.site
+                ),
+                _ => (),
+            }
+        }
+        self.policies_changed();
+    }
 
-        site_state.targets = self.get_targets(site).await?;
-        drop(site_state);
-        Ok(site_state_am)
+    // Replies to the requests that timed out, the connection being given up
+    // if they still do not come. Policies they created are left for the
+    // audit to delete
+    async fn collect_late_replies(&mut self) -> Result<(), ProtoError> {
+        let Some(authority) = self.authority.as_mut() else {
+            return Ok(());
+        };
+        if authority.in_flight.is_empty() {
+            return Ok(());
+        }
+        let replies = match authority.late_replies().await {
+            Ok(replies) => replies,
+            Err(err) => {
+                self.reset_authority();
+                return Err(err);
+            }
+        };
+        for (request, reply) in replies {
+            match (request, reply) {
+                (
+                    ServerMessage::CreatePolicy { species, .. },
+                    ServerMessage::PolicyResult { policy },
+                ) => {
+                    tracing::warn!("Late policy {policy} for {species} at site {}", self.site);
+                    self.state.created.insert(policy, species);
+                }
+                // An error means that there was nothing left to delete
+                (ServerMessage::DeletePolicy { policy }, _) => self.state.forget_policy(policy),
+                _ => (),
+            }
+        }
+        self.policies_changed();
+        Ok(())
     }
 
-    async fn add_policy(&self, site: u32, policy: &mut Policy) -> Result<(), &'static str> {
-        let connection = self.get_connection(site).await;
-        let mut connection = connection.lock().await;
+    async fn traced_request(&mut self, operation: &str, msg: ServerMessage) -> ServerResult {
+        self.collect_late_replies().await?;
+        let start = Instant::now();
+        let result = match self.get_authority() {
+            Ok(authority) => authority.request(msg).await,
+            Err(msg) => Err(msg),
+        };
+        self.log_span(operation, start, &result);
+        result
+    }
 
-        let mut buffer = Vec::new();
+    async fn add_policy(&mut self, policy: &mut Policy) -> Result<(), ProtoError> {
+        let operation = format!("create policy for {}", policy.species);
         let msg = ServerMessage::CreatePolicy {
             species: policy.species.to_owned(),
             action: policy.policy_type.to_byte(),
         };
-        let _ = connection.write_all(&msg.to_bytes()).await;
-        let response = self.parse_message(&mut connection, &mut buffer).await?;
-        policy.id = match response {
+        policy.id = match self.traced_request(&operation, msg).await? {
             ServerMessage::PolicyResult { policy } => Some(policy),
-            _ => return Err("Error when creating policy"),
+            _ => return Err("Error when creating policy".into()),
         };
         Ok(())
     }
 
-    async fn delete_policy(&self, site: u32, policy_id: u32) -> Result<(), &'static str> {
-        let connection = self.get_connection(site).await;
-        let mut connection = connection.lock().await;
-
-        let mut buffer = Vec::new();
+    async fn delete_policy(&mut self, policy_id: u32, species: &str) -> Result<(), ProtoError> {
+        let operation = format!("delete policy {policy_id} for {species}");
         let msg = ServerMessage::DeletePolicy { policy: policy_id };
-        let _ = connection.write_all(&msg.to_bytes()).await;
-        let response = self.parse_message(&mut connection, &mut buffer).await?;
-        match response {
+        match self.traced_request(&operation, msg).await? {
             ServerMessage::Ok => (),
-            _ => return Err("Error when deleting policy"),
+            // Deleted by an earlier request whose reply was lost
+            ServerMessage::Error { .. } if self.state.unconfirmed.contains(&policy_id) => (),
+            _ => return Err("Error when deleting policy".into()),
         };
         Ok(())
     }
 
+    async fn remove_policy(&mut self, id: u32, species: &str) -> Result<(), ProtoError> {
+        self.delete_policy(id, species).await?;
+        self.metrics.policy_deleted(self.site);
+        self.state.forget_policy(id);
+        self.policies_changed();
+        Ok(())
+    }
+
+    fn policies_changed(&self) {
+        self.cache.update(self.site, &self.state.policies);
+        self.nb_policies
+            .store(self.state.created.len(), Ordering::Relaxed);
+        self.publish();
+    }
+
+    async fn apply_action(&mut self, action: PolicyAction) -> Result<(), ProtoError> {
+        match action {
+            PolicyAction::Delete { id, species } => self.remove_policy(id, &species).await?,
+            PolicyAction::Add { mut policy } => {
+                self.add_policy(&mut policy).await?;
+                self.metrics.policy_created(self.site);
+                self.state
+                    .created
+                    .insert(policy.id.unwrap(), policy.species.to_owned());
+                self.state
+                    .policies
+                    .insert(policy.species.to_owned(), policy);
+                self.policies_changed();
+            }
+        }
+        Ok(())
+    }
+
     async fn process_observation(
-        &self,
-        site: u32,
+        &mut self,
         observations: Vec<PopulationObs>,
-    ) -> Result<(), &'static str> {
-        let site_state = self.get_site_state(site).await?;
-        let mut site_state = site_state.lock().await;
-        for action in site_state.get_actions(&observations) {
-            match action {
-                PolicyAction::Delete { id, species } => {
-                    self.delete_policy(site, id).await?;
-                    site_state.policies.remove_entry(&species);
+    ) -> Result<(), ProtoError> {
+        self.state.observe(&observations);
+        if self.authority.is_none() {
+            self.connect().await?;
+        } else if self.state.targets_expired(self.config.targets_ttl) {
+            self.refresh_targets().await?;
+        }
+        for action in self.state.get_pending_actions() {
+            self.apply_action(action).await?;
+        }
+        Ok(())
+    }
+
+    async fn audit(&mut self) -> Result<(), ProtoError> {
+        if self.authority.is_none() {
+            return Ok(());
+        }
+        self.collect_late_replies().await?;
+        for action in self.state.get_audit_actions() {
+            self.apply_action(action).await?;
+        }
+        Ok(())
+    }
+
+    // The connection state is unknown after a failure, so the next request
+    // goes through a fresh connection. A request timing out keeps it, for its
+    // reply to tell what the authority did
+    fn record_result(&mut self, result: &Result<(), ProtoError>) {
+        match result {
+            Ok(()) => self.breaker.record_success(),
+            Err(err) => {
+                self.metrics.authority_error();
+                crate::metrics::increment(CHALLENGE, Counter::UpstreamErrors);
+                self.breaker.record_failure();
+                if *err != ProtoError::AuthorityTimeout {
+                    self.reset_authority();
+                }
+            }
+        }
+    }
+
+    async fn tick(audit_interval: &mut Option<Interval>) {
+        match audit_interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+
+    async fn wait_probe(probe_at: Option<Instant>) {
+        match probe_at {
+            Some(probe_at) => time::sleep_until(probe_at.into()).await,
+            None => future::pending().await,
+        }
+    }
+
+    async fn run(mut self, mut visits: mpsc::UnboundedReceiver<Visit>) {
+        let site = self.site;
+        let mut audit_interval = self.config.audit_interval.map(time::interval);
+        loop {
+            tokio::select! {
+                visit = visits.recv() => {
+                    let Some(Visit { trace, received_at, observations }) = visit else {
+                        break;
+                    };
+                    if self.breaker.is_open() {
+                        tracing::warn!("[{trace}] Skipping visit of site {site}: circuit is open");
+                        continue;
+                    }
+                    self.trace = Some(trace);
+                    let result = self.process_observation(observations).await;
+                    self.trace = None;
+                    if let Err(msg) = &result {
+                        tracing::warn!("[{trace}] Error when processing visit of site {site}: {msg}");
+                    }
+                    self.record_result(&result);
+                    self.publish();
+                    let latency = received_at.elapsed();
+                    tracing::info!("[{trace}] Visit of site {site} handled in {}ms", latency.as_millis());
+                    self.metrics.visit_processed(latency);
+                    crate::metrics::observe(CHALLENGE, "site_visit", latency);
                 }
-                PolicyAction::Add { mut policy } => {
-                    self.add_policy(site, &mut policy).await?;
-                    site_state
-                        .policies
-                        .insert(policy.species.to_owned(), policy);
+                _ = Self::tick(&mut audit_interval) => {
+                    if self.breaker.is_open() {
+                        continue;
+                    }
+                    let result = self.audit().await;
+                    if let Err(msg) = &result {
+                        tracing::warn!("Audit of site {site} failed: {msg}");
+                    }
+                    self.record_result(&result);
+                    self.publish();
+                }
+                _ = Self::wait_probe(self.breaker.probe_at()) => {
+                    let result = self.connect().await;
+                    if let Err(msg) = &result {
+                        tracing::warn!("Probe of authority for site {site} failed: {msg}");
+                    }
+                    self.record_result(&result);
+                    self.publish();
                 }
             }
         }
-        Ok(())
     }
 }
 
-#[async_trait]
-impl TcpServer for Server {
-    async fn handle_connection(&self, mut stream: TcpStream) {
-        let mut buffer = Vec::new();
+pub struct Server {
+    config: Config,
+    site_workers: Arc<Mutex<HashMap<SiteId, SiteHandle>>>,
+    metrics: Arc<Metrics>,
+    metrics_reporter: Once,
+    cache: Arc<PolicyCache>,
+    nb_connections: AtomicU64,
+}
 
-        let first_message = self.parse_message(&mut stream, &mut buffer).await;
-        let mut buffer = Vec::new();
-        let msg = ServerMessage::Hello {
-            protocol: "pestcontrol".into(),
-            version: 1,
+impl Server {
+    pub fn new(config: Config) -> Self {
+        let cache = Arc::new(PolicyCache::load(config.policy_cache.clone()));
+        Self {
+            config,
+            site_workers: Arc::new(Mutex::new(HashMap::new())),
+            metrics: Arc::new(Metrics::default()),
+            cache,
+            metrics_reporter: Once::new(),
+            nb_connections: AtomicU64::new(0),
+        }
+    }
+
+    fn start_metrics_reporter(&self) {
+        let Some(period) = self.config.metrics_interval else {
+            return;
         };
-        let _ = stream.write_all(&msg.to_bytes()).await;
+        self.metrics_reporter.call_once(|| {
+            let metrics = Arc::clone(&self.metrics);
+            utils::spawn_named("pestcontrol metrics reporter", async move {
+                let mut interval = time::interval(period);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    tracing::info!("Pestcontrol metrics: {metrics}");
+                }
+            });
+        });
+    }
+
+    // Targets and policies as last published by the site worker, along with
+    // the health of its authority connection
+    async fn site_dump(&self, site: SiteId) -> Option<Value> {
+        let site_workers = self.site_workers.lock().await;
+        let handle = site_workers.get(&site)?;
+        let mut dump = handle.dump.lock().unwrap().clone();
+        dump["connection"]["circuit_open"] = json!(handle.breaker.is_open());
+        dump["connection"]["failures"] = json!(handle.breaker.failures());
+        Some(dump)
+    }
+
+    async fn dispatch_visit(
+        &self,
+        site: SiteId,
+        observations: Vec<PopulationObs>,
+        trace: TraceId,
+    ) -> Result<(), ProtoError> {
+        // Visits are queued under the lock so that they reach the site worker
+        // in the order they were received
+        let mut site_workers = self.site_workers.lock().await;
+        let handle = site_workers.entry(site).or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let breaker = Arc::new(CircuitBreaker::new());
+            let nb_policies = Arc::new(AtomicUsize::new(0));
+            let dump = Arc::new(sync::Mutex::new(Value::Null));
+            let worker = SiteWorker::new(
+                site,
+                self.config.clone(),
+                Arc::clone(&self.metrics),
+                Arc::clone(&self.cache),
+                Arc::clone(&breaker),
+                Arc::clone(&nb_policies),
+                Arc::clone(&dump),
+            );
+            let name = format!("pestcontrol site {site} worker");
+            utils::spawn_named(&name, worker.run(receiver));
+            SiteHandle {
+                sender,
+                breaker,
+                nb_policies,
+                dump,
+            }
+        });
+        if handle.breaker.is_open() {
+            return Err(ProtoError::AuthorityUnavailable(site));
+        }
+        let _ = handle.sender.send(Visit {
+            trace,
+            received_at: Instant::now(),
+            observations,
+        });
+        Ok(())
+    }
+
+    async fn serve_client(
+        &self,
+        reader: &mut FrameReader<impl AsyncRead + Unpin>,
+        stream: &mut BufWriter<ClientWriter>,
+    ) {
+        let connection = self.nb_connections.fetch_add(1, Ordering::Relaxed);
+        let mut nb_visits = 0;
+
+        let first_message = parse_message(reader).await;
+        let _ = stream.write_all(&HELLO).await;
 
         match first_message {
             Ok(ServerMessage::Hello {
@@ -570,48 +1141,212 @@ impl TcpServer for Server {
                 version: 1,
             }) if protocol == "pestcontrol" => (),
             Ok(ServerMessage::Hello { protocol, version }) => {
-                let response = ServerMessage::Error {
-                    msg: format!("Invalid Hello message (protocol: {protocol}, version {version})",),
-                };
-                let _ = stream.write_all(&response.to_bytes()).await;
+                send_error(stream, ProtoError::InvalidHello { protocol, version }).await;
                 return;
             }
             Ok(_) => {
-                let response = ServerMessage::Error {
-                    msg: String::from("Connection must start with a Hello message"),
-                };
-                let _ = stream.write_all(&response.to_bytes()).await;
+                let msg = "Connection must start with a Hello message";
+                send_error(stream, msg.into()).await;
                 return;
             }
-            Err(msg) => {
-                let response = ServerMessage::Error { msg: msg.into() };
-                let _ = stream.write_all(&response.to_bytes()).await;
+            Err(err) => {
+                send_error(stream, err).await;
                 return;
             }
         };
 
         loop {
-            let (site, populations) = match self.parse_message(&mut stream, &mut buffer).await {
-                Ok(ServerMessage::SiteVisit { site, observations }) => (site, observations),
+            if !message_pending(reader.buffered()) {
+                let _ = stream.flush().await;
+            }
+            let (site, populations) = match parse_message(reader).await {
+                Ok(ServerMessage::SiteVisit { site, observations }) => {
+                    crate::metrics::increment(CHALLENGE, Counter::Requests);
+                    (site, observations)
+                }
                 Ok(_) => {
-                    let response = ServerMessage::Error {
-                        msg: "Invalid message type from site-visiting client".into(),
-                    };
-                    let _ = stream.write_all(&response.to_bytes()).await;
+                    let msg = "Invalid message type from site-visiting client";
+                    send_error(stream, msg.into()).await;
                     break;
                 }
-                Err(msg) => {
-                    let response = ServerMessage::Error { msg: msg.into() };
-                    let _ = stream.write_all(&response.to_bytes()).await;
+                Err(err) => {
+                    send_error(stream, err).await;
                     break;
                 }
             };
 
-            if let Err(msg) = self.process_observation(site, populations).await {
-                let response = ServerMessage::Error { msg: msg.into() };
-                let _ = stream.write_all(&response.to_bytes()).await;
-                break;
+            let trace = TraceId {
+                connection,
+                visit: nb_visits,
+            };
+            nb_visits += 1;
+            if let Err(err) = self.dispatch_visit(site, populations, trace).await {
+                send_error(stream, err).await;
             }
         }
     }
 }
+
+// Whether another whole message is already buffered, in which case the
+// replies written so far can wait to be flushed along with the next ones
+fn message_pending(buffer: &[u8]) -> bool {
+    buffer.len() >= 5
+        && parse_header(&buffer[..5]).is_ok_and(|(_, msg_len)| buffer.len() >= msg_len)
+}
+
+// A connection closed between two messages is not a protocol error
+async fn send_error(stream: &mut (impl AsyncWrite + Unpin), err: ProtoError) {
+    if err != ProtoError::Closed {
+        crate::metrics::increment(CHALLENGE, Counter::ProtocolErrors);
+    }
+    let response = ServerMessage::Error {
+        msg: err.to_string(),
+    };
+    let _ = response.write_to(stream).await;
+}
+
+#[async_trait]
+impl TcpServer for Server {
+    async fn handle_connection(&self, stream: ClientStream) {
+        self.start_metrics_reporter();
+        // Replies are flushed whenever the client may be waiting for them,
+        // so a burst of errors goes out in a single write
+        let (reader, writer) = stream.into_split();
+        let mut stream = BufWriter::new(writer);
+        self.serve_client(&mut FrameReader::new(reader), &mut stream)
+            .await;
+        let _ = stream.flush().await;
+    }
+
+    async fn state_sizes(&self) -> StateSizes {
+        let site_workers = self.site_workers.lock().await;
+        let nb_policies = site_workers
+            .values()
+            .map(|handle| handle.nb_policies.load(Ordering::Relaxed))
+            .sum();
+        vec![("sites", site_workers.len()), ("policies", nb_policies)]
+    }
+
+    async fn admin_command(&self, command: &str, args: &[&str]) -> Result<Value, ProtoError> {
+        match (command, args) {
+            ("site", [site]) => {
+                let site = site.parse().or(Err(ProtoError::Admin("invalid site id")))?;
+                let dump = self.site_dump(site).await;
+                dump.ok_or(ProtoError::Admin("unknown site"))
+            }
+            _ => Err(ProtoError::UnknownCommand),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_errors_tell_what_failed() {
+        let data = [0, 0, 0, 3, b'a', b'b'];
+        assert_eq!(
+            ServerMessage::parse(0x55, &data).err(),
+            Some(ProtoError::Truncated {
+                what: "str",
+                offset: 4
+            })
+        );
+        assert_eq!(
+            ServerMessage::parse(0x99, &[]).err(),
+            Some(ProtoError::UnknownMessageType(0x99))
+        );
+        assert_eq!(
+            ServerMessage::parse(0x52, &[0]).err(),
+            Some(ProtoError::TrailingData("Ok"))
+        );
+        assert_eq!(
+            parse_frame(&[0x52, 0, 0, 0, 6], &[0]).err(),
+            Some(ProtoError::InvalidChecksum(0x58))
+        );
+    }
+
+    #[test]
+    fn switching_policy_deletes_the_previous_one() {
+        let policy = Policy {
+            id: Some(7),
+            species: "dog".to_owned(),
+            policy_type: PolicyType::Cull,
+        };
+        let mut state = SiteState::new(HashMap::from([("dog".to_owned(), policy)]));
+        let target = PopulationTarget {
+            species: "dog".to_owned(),
+            min: 2,
+            max: 5,
+        };
+        state.targets.insert("dog".to_owned(), target);
+        let actions = state.get_action("dog", 1);
+        assert!(matches!(
+            actions.as_slice(),
+            [
+                PolicyAction::Delete { id: 7, .. },
+                PolicyAction::Add {
+                    policy: Policy {
+                        policy_type: PolicyType::Conserve,
+                        ..
+                    }
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn unconfirmed_deletions_are_audited() {
+        let policy = Policy {
+            id: Some(7),
+            species: "dog".to_owned(),
+            policy_type: PolicyType::Cull,
+        };
+        let mut state = SiteState::new(HashMap::from([("dog".to_owned(), policy)]));
+        assert!(state.get_audit_actions().is_empty());
+
+        state.unconfirm_deletion(7);
+        let actions = state.get_audit_actions();
+        assert!(matches!(
+            actions.as_slice(),
+            [PolicyAction::Delete { id: 7, .. }]
+        ));
+        state.forget_policy(7);
+        assert!(state.get_audit_actions().is_empty());
+        assert!(state.created.is_empty() && state.unconfirmed.is_empty());
+    }
+
+    #[test]
+    fn refreshed_targets_apply_to_every_policy() {
+        let cull = |id, species: &str| Policy {
+            id: Some(id),
+            species: species.to_owned(),
+            policy_type: PolicyType::Cull,
+        };
+        let mut state = SiteState::new(HashMap::from([
+            ("dog".to_owned(), cull(7, "dog")),
+            ("cat".to_owned(), cull(8, "cat")),
+        ]));
+        state.observe(&[PopulationObs {
+            species: "dog".to_owned(),
+            count: 6,
+        }]);
+        let target = PopulationTarget {
+            species: "dog".to_owned(),
+            min: 2,
+            max: 10,
+        };
+        state.set_targets(HashMap::from([("dog".to_owned(), target)]));
+        let mut deleted: Vec<_> = state
+            .get_refresh_actions()
+            .into_iter()
+            .map(|action| match action {
+                PolicyAction::Delete { id, .. } => id,
+                PolicyAction::Add { .. } => panic!("unexpected policy creation"),
+            })
+            .collect();
+        deleted.sort();
+        assert_eq!(deleted, vec![7, 8]);
+    }
+}
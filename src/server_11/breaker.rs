@@ -0,0 +1,61 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const FAILURE_THRESHOLD: u32 = 3;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+struct BreakerState {
+    failures: u32,
+    backoff: Duration,
+    open_until: Option<Instant>,
+}
+
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(BreakerState {
+                failures: 0,
+                backoff: MIN_BACKOFF,
+                open_until: None,
+            }),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .open_until
+            .is_some_and(|open_until| Instant::now() < open_until)
+    }
+
+    // Once tripped, the breaker stays half-open after the backoff until a
+    // request succeeds: the next failure reopens it with a doubled backoff
+    pub fn probe_at(&self) -> Option<Instant> {
+        self.state.lock().unwrap().open_until
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.state.lock().unwrap().failures
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+        state.backoff = MIN_BACKOFF;
+        state.open_until = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        if state.failures >= FAILURE_THRESHOLD {
+            state.open_until = Some(Instant::now() + state.backoff);
+            state.backoff = (state.backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Map, Value};
+
+use super::{Policy, PolicyType, SiteId, CHALLENGE};
+use crate::metrics::{self, Counter};
+
+type SitePolicies = HashMap<String, (u32, u8)>;
+
+pub struct PolicyCache {
+    path: Option<PathBuf>,
+    sites: Mutex<HashMap<SiteId, SitePolicies>>,
+    // Snapshots are numbered under the `sites` lock, and a write is skipped
+    // once a newer one made it to disk
+    generation: AtomicU64,
+    written: Arc<Mutex<u64>>,
+}
+
+impl PolicyCache {
+    fn parse(data: &str) -> Option<HashMap<SiteId, SitePolicies>> {
+        let Value::Object(sites) = serde_json::from_str(data).ok()? else {
+            return None;
+        };
+        sites
+            .into_iter()
+            .map(|(site, policies)| {
+                let policies = policies
+                    .as_object()?
+                    .iter()
+                    .map(|(species, policy)| {
+                        let id = policy.get("id")?.as_u64()? as u32;
+                        let action = policy.get("action")?.as_u64()? as u8;
+                        Some((species.to_owned(), (id, action)))
+                    })
+                    .collect::<Option<_>>()?;
+                Some((site.parse().ok()?, policies))
+            })
+            .collect()
+    }
+
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let sites = match &path {
+            Some(path) if path.exists() => {
+                let data = fs::read_to_string(path).unwrap_or_default();
+                Self::parse(&data).unwrap_or_else(|| {
+                    tracing::warn!("Ignoring invalid policy cache {}", path.display());
+                    HashMap::new()
+                })
+            }
+            _ => HashMap::new(),
+        };
+        Self {
+            path,
+            sites: Mutex::new(sites),
+            generation: AtomicU64::new(0),
+            written: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    pub fn get_policies(&self, site: SiteId) -> HashMap<String, Policy> {
+        let sites = self.sites.lock().unwrap();
+        let Some(policies) = sites.get(&site) else {
+            return HashMap::new();
+        };
+        policies
+            .iter()
+            .filter_map(|(species, &(id, action))| {
+                let policy = Policy {
+                    id: Some(id),
+                    species: species.to_owned(),
+                    policy_type: PolicyType::from_byte(action)?,
+                };
+                Some((species.to_owned(), policy))
+            })
+            .collect()
+    }
+
+    pub fn update(&self, site: SiteId, policies: &HashMap<String, Policy>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let mut sites = self.sites.lock().unwrap();
+        sites.insert(
+            site,
+            policies
+                .iter()
+                .map(|(species, policy)| {
+                    let entry = (policy.id.unwrap(), policy.policy_type.to_byte());
+                    (species.to_owned(), entry)
+                })
+                .collect(),
+        );
+
+        let data: Map<String, Value> = sites
+            .iter()
+            .map(|(site, policies)| {
+                let policies: Map<String, Value> = policies
+                    .iter()
+                    .map(|(species, (id, action))| {
+                        (species.to_owned(), json!({"id": id, "action": action}))
+                    })
+                    .collect();
+                (site.to_string(), Value::Object(policies))
+            })
+            .collect();
+        let data = Value::Object(data).to_string();
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        drop(sites);
+
+        // The site worker does not wait on the disk
+        let path = path.clone();
+        let written = Arc::clone(&self.written);
+        tokio::task::spawn_blocking(move || {
+            let mut written = written.lock().unwrap();
+            if *written < generation {
+                *written = generation;
+                Self::save(&path, &data);
+            }
+        });
+    }
+
+    fn save(path: &Path, data: &str) {
+        // Write to a temporary file first so a crash never leaves a truncated cache
+        let tmp_path = path.with_extension("tmp");
+        let result = fs::write(&tmp_path, data).and_then(|_| fs::rename(&tmp_path, path));
+        if let Err(err) = result {
+            metrics::increment(CHALLENGE, Counter::InternalErrors);
+            tracing::warn!("Could not save policy cache {}: {err}", path.display());
+        }
+    }
+}
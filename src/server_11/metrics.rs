@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::SiteId;
+
+#[derive(Default)]
+struct SiteMetrics {
+    policies_created: u64,
+    policies_deleted: u64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    sites_dialed: AtomicU64,
+    authority_errors: AtomicU64,
+    visits_processed: AtomicU64,
+    visit_latency_total_us: AtomicU64,
+    visit_latency_max_us: AtomicU64,
+    sites: Mutex<HashMap<SiteId, SiteMetrics>>,
+}
+
+impl Metrics {
+    pub fn site_dialed(&self) {
+        self.sites_dialed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn authority_error(&self) {
+        self.authority_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn visit_processed(&self, latency: Duration) {
+        let latency = latency.as_micros() as u64;
+        self.visits_processed.fetch_add(1, Ordering::Relaxed);
+        self.visit_latency_total_us
+            .fetch_add(latency, Ordering::Relaxed);
+        self.visit_latency_max_us
+            .fetch_max(latency, Ordering::Relaxed);
+    }
+
+    pub fn policy_created(&self, site: SiteId) {
+        let mut sites = self.sites.lock().unwrap();
+        sites.entry(site).or_default().policies_created += 1;
+    }
+
+    pub fn policy_deleted(&self, site: SiteId) {
+        let mut sites = self.sites.lock().unwrap();
+        sites.entry(site).or_default().policies_deleted += 1;
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let visits = self.visits_processed.load(Ordering::Relaxed);
+        let latency_total = self.visit_latency_total_us.load(Ordering::Relaxed);
+        let latency_avg = latency_total.checked_div(visits).unwrap_or(0);
+        write!(
+            f,
+            "sites dialed: {}, authority errors: {}, visits: {} (avg {}us, max {}us)",
+            self.sites_dialed.load(Ordering::Relaxed),
+            self.authority_errors.load(Ordering::Relaxed),
+            visits,
+            latency_avg,
+            self.visit_latency_max_us.load(Ordering::Relaxed),
+        )?;
+
+        let sites = self.sites.lock().unwrap();
+        let mut site_ids: Vec<_> = sites.keys().collect();
+        site_ids.sort_unstable();
+        for site in site_ids {
+            let site_metrics = &sites[site];
+            write!(
+                f,
+                "\n  site {site}: {} policies created, {} deleted",
+                site_metrics.policies_created, site_metrics.policies_deleted
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+use super::{parse_message, PopulationTarget, ServerMessage};
+use crate::utils::{FrameReader, Rng};
+
+struct MockPolicy {
+    id: u32,
+    species: String,
+    action: u8,
+}
+
+type Targets = Vec<(String, u32, u32)>;
+
+pub struct MockAuthority {
+    sites: Mutex<HashMap<u32, Targets>>,
+    // Targets of the sites not explicitly configured, unknown otherwise
+    default_targets: Option<Targets>,
+    next_policy_id: Mutex<u32>,
+    policies: Mutex<HashMap<u32, Vec<MockPolicy>>>,
+    // Delay before answering each request
+    latency: Duration,
+    // Probability for a request to be answered with an error
+    error_rate: f64,
+    rng: Mutex<Rng>,
+    // Extra delay before answering the first CreatePolicy, as if its reply
+    // was lost for a while
+    late_first_policy: Mutex<Option<Duration>>,
+    connections: AtomicUsize,
+}
+
+impl Default for MockAuthority {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockAuthority {
+    pub fn new() -> Self {
+        Self {
+            sites: Mutex::new(HashMap::new()),
+            default_targets: None,
+            next_policy_id: Mutex::new(1),
+            policies: Mutex::new(HashMap::new()),
+            latency: Duration::ZERO,
+            error_rate: 0.0,
+            rng: Mutex::new(Rng::new(0)),
+            late_first_policy: Mutex::new(None),
+            connections: AtomicUsize::new(0),
+        }
+    }
+
+    fn to_targets(targets: &[(&str, u32, u32)]) -> Targets {
+        targets
+            .iter()
+            .map(|&(species, min, max)| (species.to_owned(), min, max))
+            .collect()
+    }
+
+    pub fn with_site(mut self, site: u32, targets: &[(&str, u32, u32)]) -> Self {
+        self.sites.get_mut().insert(site, Self::to_targets(targets));
+        self
+    }
+
+    // Change the targets of a site while running, as seen by the next
+    // DialAuthority
+    pub async fn set_site(&self, site: u32, targets: &[(&str, u32, u32)]) {
+        self.sites
+            .lock()
+            .await
+            .insert(site, Self::to_targets(targets));
+    }
+
+    pub fn with_default_targets(mut self, targets: &[(&str, u32, u32)]) -> Self {
+        self.default_targets = Some(Self::to_targets(targets));
+        self
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    // Errors are drawn from a generator seeded with `seed`, so that a run can
+    // be reproduced
+    pub fn with_errors(mut self, error_rate: f64, seed: u64) -> Self {
+        self.error_rate = error_rate;
+        self.rng = Mutex::new(Rng::new(seed));
+        self
+    }
+
+    pub fn with_late_first_policy(mut self, delay: Duration) -> Self {
+        self.late_first_policy = Mutex::new(Some(delay));
+        self
+    }
+
+    pub fn with_first_policy_id(mut self, id: u32) -> Self {
+        self.next_policy_id = Mutex::new(id);
+        self
+    }
+
+    pub async fn spawn(self, addr: &str) -> io::Result<(SocketAddr, Arc<Self>)> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let authority = Arc::new(self);
+        tokio::spawn(Arc::clone(&authority).run(listener));
+        Ok((local_addr, authority))
+    }
+
+    pub async fn run(self: Arc<Self>, listener: TcpListener) {
+        while let Ok((stream, _)) = listener.accept().await {
+            self.connections.fetch_add(1, Ordering::Relaxed);
+            let authority = Arc::clone(&self);
+            tokio::spawn(async move { authority.handle_connection(stream).await });
+        }
+    }
+
+    // Number of connections accepted so far
+    pub fn nb_connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    // Active policies of a site as (id, species, action) tuples
+    pub async fn policies(&self, site: u32) -> Vec<(u32, String, u8)> {
+        self.policies
+            .lock()
+            .await
+            .get(&site)
+            .map(|policies| {
+                policies
+                    .iter()
+                    .map(|policy| (policy.id, policy.species.to_owned(), policy.action))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    async fn create_policy(&self, site: u32, species: String, action: u8) -> u32 {
+        let mut next_policy_id = self.next_policy_id.lock().await;
+        let id = *next_policy_id;
+        *next_policy_id += 1;
+        self.policies
+            .lock()
+            .await
+            .entry(site)
+            .or_default()
+            .push(MockPolicy {
+                id,
+                species,
+                action,
+            });
+        id
+    }
+
+    async fn delete_policy(&self, site: u32, id: u32) -> bool {
+        let mut policies = self.policies.lock().await;
+        let Some(policies) = policies.get_mut(&site) else {
+            return false;
+        };
+        let Some(index) = policies.iter().position(|policy| policy.id == id) else {
+            return false;
+        };
+        policies.swap_remove(index);
+        true
+    }
+
+    async fn send(stream: &mut OwnedWriteHalf, msg: ServerMessage) {
+        let _ = msg.write_to(stream).await;
+    }
+
+    async fn send_error(stream: &mut OwnedWriteHalf, msg: &str) {
+        Self::send(stream, ServerMessage::Error { msg: msg.into() }).await;
+    }
+
+    fn injected_error() -> ServerMessage {
+        ServerMessage::Error {
+            msg: "Injected error".into(),
+        }
+    }
+
+    // Requests failing with an injected error are answered without being
+    // processed
+    async fn inject_error(&self) -> bool {
+        self.rng.lock().await.chance(self.error_rate)
+    }
+
+    async fn target_populations(&self, site: u32) -> ServerMessage {
+        let sites = self.sites.lock().await;
+        let Some(targets) = sites.get(&site).or(self.default_targets.as_ref()) else {
+            return ServerMessage::Error {
+                msg: "Unknown site".into(),
+            };
+        };
+        let targets = targets
+            .iter()
+            .map(|(species, min, max)| PopulationTarget {
+                species: species.to_owned(),
+                min: *min,
+                max: *max,
+            })
+            .collect();
+        ServerMessage::TargetPopulations { site, targets }
+    }
+
+    async fn respond(&self, stream: &mut OwnedWriteHalf, msg: ServerMessage) {
+        time::sleep(self.latency).await;
+        Self::send(stream, msg).await;
+    }
+
+    async fn handle_connection(&self, stream: TcpStream) {
+        let (reader, mut stream) = stream.into_split();
+        let mut reader = FrameReader::new(reader);
+        let hello = ServerMessage::Hello {
+            protocol: "pestcontrol".into(),
+            version: 1,
+        };
+        Self::send(&mut stream, hello).await;
+
+        match parse_message(&mut reader).await {
+            Ok(ServerMessage::Hello {
+                protocol,
+                version: 1,
+            }) if protocol == "pestcontrol" => (),
+            _ => return Self::send_error(&mut stream, "Invalid Hello message").await,
+        }
+
+        let site = match parse_message(&mut reader).await {
+            Ok(ServerMessage::DialAuthority { site }) => site,
+            _ => return Self::send_error(&mut stream, "Expected DialAuthority message").await,
+        };
+        if self.inject_error().await {
+            return self.respond(&mut stream, Self::injected_error()).await;
+        }
+        match self.target_populations(site).await {
+            msg @ ServerMessage::TargetPopulations { .. } => self.respond(&mut stream, msg).await,
+            msg => return Self::send(&mut stream, msg).await,
+        }
+
+        loop {
+            let request = parse_message(&mut reader).await;
+            if request.is_ok() && self.inject_error().await {
+                self.respond(&mut stream, Self::injected_error()).await;
+                continue;
+            }
+            let response = match request {
+                Ok(ServerMessage::CreatePolicy { species, action }) => {
+                    let policy = self.create_policy(site, species, action).await;
+                    if let Some(delay) = self.late_first_policy.lock().await.take() {
+                        time::sleep(delay).await;
+                    }
+                    ServerMessage::PolicyResult { policy }
+                }
+                Ok(ServerMessage::DeletePolicy { policy }) => {
+                    if self.delete_policy(site, policy).await {
+                        ServerMessage::Ok
+                    } else {
+                        ServerMessage::Error {
+                            msg: format!("No such policy: {policy}"),
+                        }
+                    }
+                }
+                // Targets asked again for the site of the connection
+                Ok(ServerMessage::DialAuthority { site: dialed }) if dialed == site => {
+                    self.target_populations(site).await
+                }
+                Ok(_) => ServerMessage::Error {
+                    msg: "Unexpected message".into(),
+                },
+                Err(_) => return,
+            };
+            self.respond(&mut stream, response).await;
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tower::{Service, ServiceExt};
+
+use crate::{
+    ACCEPT_BACKOFF_MAX, ACCEPT_BACKOFF_MIN, Config, ProtoError, Server, ServerType, TcpServer,
+};
+
+// A TCP challenge server as a tower service, each call handling a whole
+// connection, so that timeouts and limits can come from tower layers
+#[derive(Clone)]
+pub struct TcpService {
+    server: Arc<dyn TcpServer>,
+}
+
+impl TcpService {
+    pub fn new(server: Arc<dyn TcpServer>) -> Self {
+        Self { server }
+    }
+
+    pub fn for_challenge(part: u8, config: &Config) -> Result<Self, ProtoError> {
+        match Server::with_config(part, config)?.server {
+            ServerType::Tcp(server) => Ok(Self::new(server)),
+            ServerType::Udp(_) => Err("not a TCP challenge".into()),
+        }
+    }
+}
+
+impl Service<TcpStream> for TcpService {
+    type Response = ();
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<(), Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, stream: TcpStream) -> Self::Future {
+        let server = Arc::clone(&self.server);
+        Box::pin(async move {
+            server.handle_connection(stream.into()).await;
+            Ok(())
+        })
+    }
+}
+
+// Accept loop of a (layered) service: connections are only accepted once the
+// service is ready, so that concurrency limits hold back new clients
+pub async fn serve<S>(listener: TcpListener, mut service: S)
+where
+    S: Service<TcpStream>,
+    S::Error: Display,
+    S::Future: Send + 'static,
+{
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        let service = match service.ready().await {
+            Ok(service) => service,
+            Err(err) => return tracing::warn!("service failed: {err}"),
+        };
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                stream
+            }
+            Err(err) if Server::is_connection_error(&err) => continue,
+            Err(err) => {
+                tracing::warn!("could not accept connection, retrying in {backoff:?}: {err}");
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                continue;
+            }
+        };
+        let connection = service.call(stream);
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                tracing::warn!("connection failed: {err}");
+            }
+        });
+    }
+}
@@ -0,0 +1,253 @@
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::time;
+
+use crate::StateSizes;
+use crate::clients::{
+    Camera, ChatClient, Connection, Dispatcher, JobClient, KvClient, MeansClient, PrimeClient,
+    VcsClient,
+};
+use crate::testing::{self, TestServer};
+
+// Challenges 5 and 11 need an upstream server
+const CHALLENGES: &[u8] = &[0, 1, 2, 3, 4, 6, 7, 8, 9, 10];
+const BATCH_SIZE: usize = 50;
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+pub struct SoakConfig {
+    pub challenges: Vec<u8>,
+    pub rounds: usize,
+    pub connections: usize,
+}
+
+impl SoakConfig {
+    // Parse `soak [challenge...] [--rounds N] [--connections N]`
+    pub fn from_args() -> Result<Self, &'static str> {
+        let mut challenges = Vec::new();
+        let mut rounds = 10;
+        let mut connections = 500;
+
+        let mut args = env::args().skip(2);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--rounds" => {
+                    let value = args.next().ok_or("missing option value")?;
+                    rounds = value.parse().or(Err("invalid number of rounds"))?;
+                }
+                "--connections" => {
+                    let value = args.next().ok_or("missing option value")?;
+                    connections = value.parse().or(Err("invalid number of connections"))?;
+                }
+                _ => challenges.push(arg.parse().or(Err("parsing error"))?),
+            }
+        }
+
+        if challenges.is_empty() {
+            challenges = CHALLENGES.to_vec();
+        }
+        Ok(Self {
+            challenges,
+            rounds,
+            connections,
+        })
+    }
+}
+
+// State sizes of a server sampled before the first round and after each one
+pub struct Report {
+    challenge: u8,
+    connections: usize,
+    failures: usize,
+    samples: Vec<StateSizes>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "server {:02}: {} connections, {} failures",
+            self.challenge, self.connections, self.failures
+        )?;
+        let Some(first) = self.samples.first() else {
+            return Ok(());
+        };
+        for (i, &(name, _)) in first.iter().enumerate() {
+            let values: Vec<usize> = self.samples.iter().map(|sample| sample[i].1).collect();
+            let history: Vec<String> = values.iter().map(usize::to_string).collect();
+            write!(f, "\n  {name}: {}", history.join(" "))?;
+            if values.len() > 2 && values.windows(2).all(|pair| pair[0] < pair[1]) {
+                write!(f, "  <- grew every round")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn echo_session(addr: SocketAddr) -> Option<()> {
+    let mut connection = Connection::connect(addr).await.ok()?;
+    connection.send(b"soak\n").await.ok()?;
+    (connection.recv_exact(5).await? == b"soak\n").then_some(())
+}
+
+async fn prime_session(addr: SocketAddr, id: usize) -> Option<bool> {
+    let mut client = PrimeClient::connect(addr).await.ok()?;
+    client.is_prime(id as u64).await
+}
+
+async fn means_session(addr: SocketAddr, id: usize) -> Option<i32> {
+    let mut client = MeansClient::connect(addr).await.ok()?;
+    client.insert(1, id as i32).await.ok()?;
+    client.insert(2, id as i32).await.ok()?;
+    client.query(0, 10).await
+}
+
+async fn chat_session(addr: SocketAddr, id: usize) -> Option<()> {
+    let mut client = ChatClient::join(addr, &format!("soak{id}")).await.ok()?;
+    client.send("hello").await.ok()
+}
+
+async fn kv_session(addr: SocketAddr, id: usize) -> Option<String> {
+    let client = KvClient::connect(addr).await.ok()?;
+    let key = format!("key{}", id % 100);
+    client.insert(&key, "value").await.ok()?;
+    client.retrieve(&key).await
+}
+
+// Every tenth session also connects a dispatcher for the camera road
+async fn speed_session(addr: SocketAddr, id: usize) -> Option<()> {
+    let road = (id % 10) as u16;
+    let mut camera = Camera::connect(addr, road, (id % 100) as u16, 60)
+        .await
+        .ok()?;
+    camera.plate(&format!("S{id}"), id as u32).await.ok()?;
+    if id.is_multiple_of(10) {
+        Dispatcher::connect(addr, &[road]).await.ok()?;
+    }
+    Some(())
+}
+
+// Half of the sessions are abandoned without closing them nor acknowledging
+// the server data
+async fn lrcp_session(server: &TestServer, id: usize) -> Option<()> {
+    let client = server.udp_client().await.ok()?;
+    client
+        .send(format!("/connect/{id}/").as_bytes())
+        .await
+        .ok()?;
+    client.recv().await?;
+    client
+        .send(format!("/data/{id}/0/hello\n/").as_bytes())
+        .await
+        .ok()?;
+    client.recv().await?;
+    if id.is_multiple_of(2) {
+        client.send(format!("/ack/{id}/6/").as_bytes()).await.ok()?;
+        client.send(format!("/close/{id}/").as_bytes()).await.ok()?;
+    }
+    Some(())
+}
+
+// The cipher is xor(1), so both requests and responses are xored with 1
+async fn cipher_session(addr: SocketAddr) -> Option<()> {
+    let mut connection = Connection::connect(addr).await.ok()?;
+    let request: Vec<u8> = b"4x dog,5x car\n".iter().map(|c| c ^ 1).collect();
+    connection.send(&[0x02, 0x01, 0x00]).await.ok()?;
+    connection.send(&request).await.ok()?;
+    let response = connection.recv_exact(7).await?;
+    let response: Vec<u8> = response.iter().map(|c| c ^ 1).collect();
+    (response == b"5x car\n").then_some(())
+}
+
+// Every other session leaves with its job in progress, which goes back to the
+// queue
+async fn jobs_session(addr: SocketAddr, id: usize) -> Option<()> {
+    let mut client = JobClient::connect(addr).await.ok()?;
+    let queue = format!("soak{}", id % 10);
+    client.put(&queue, json!({"id": id}), id as u64).await?;
+    let job = client.get(&[&queue], false).await??;
+    if id.is_multiple_of(2) {
+        client.delete(job.id).await?;
+    }
+    Some(())
+}
+
+async fn vcs_session(addr: SocketAddr, id: usize) -> Option<()> {
+    let mut client = VcsClient::connect(addr).await.ok()?;
+    let path = format!("/soak/file{}", id % 50);
+    client
+        .put(&path, format!("{id}\n").as_bytes())
+        .await?
+        .ok()?;
+    client.get(&path, None).await?.ok().map(|_| ())
+}
+
+async fn session(challenge: u8, server: Arc<TestServer>, id: usize) -> bool {
+    let addr = server.addr;
+    match challenge {
+        0 => echo_session(addr).await.is_some(),
+        1 => prime_session(addr, id).await.is_some(),
+        2 => means_session(addr, id).await.is_some(),
+        3 => chat_session(addr, id).await.is_some(),
+        4 => kv_session(addr, id).await.is_some(),
+        6 => speed_session(addr, id).await.is_some(),
+        7 => lrcp_session(&server, id).await.is_some(),
+        8 => cipher_session(addr).await.is_some(),
+        9 => jobs_session(addr, id).await.is_some(),
+        10 => vcs_session(addr, id).await.is_some(),
+        _ => unreachable!(),
+    }
+}
+
+async fn soak(challenge: u8, config: &SoakConfig) -> Result<Report, &'static str> {
+    let server = testing::spawn(challenge)
+        .await
+        .or(Err("could not start server"))?;
+    let server = Arc::new(server);
+    let mut report = Report {
+        challenge,
+        connections: config.rounds * config.connections,
+        failures: 0,
+        samples: vec![server.state_sizes().await],
+    };
+
+    let mut id = 0;
+    for _ in 0..config.rounds {
+        let mut remaining = config.connections;
+        while remaining > 0 {
+            let batch = remaining.min(BATCH_SIZE);
+            let handles: Vec<_> = (id..id + batch)
+                .map(|id| tokio::spawn(session(challenge, Arc::clone(&server), id)))
+                .collect();
+            for handle in handles {
+                if !handle.await.unwrap_or(false) {
+                    report.failures += 1;
+                }
+            }
+            id += batch;
+            remaining -= batch;
+        }
+        // Let the server notice the disconnections before sampling
+        time::sleep(SETTLE_TIME).await;
+        report.samples.push(server.state_sizes().await);
+    }
+    Ok(report)
+}
+
+pub async fn run(config: &SoakConfig) -> Result<Vec<Report>, &'static str> {
+    if !config.challenges.iter().all(|c| CHALLENGES.contains(c)) {
+        return Err("no soak scenario for this challenge");
+    }
+
+    let mut reports = Vec::new();
+    for &challenge in &config.challenges {
+        let report = soak(challenge, config).await?;
+        println!("{report}");
+        reports.push(report);
+    }
+    Ok(reports)
+}
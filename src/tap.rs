@@ -0,0 +1,300 @@
+use std::future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::UdpSocket;
+use tokio::time;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+use crate::capture::Capture;
+use crate::chaos::Chaos;
+use crate::lifecycle::{CloseReason, ConnectionEvent, EventLog};
+use crate::metrics::{self, Counter};
+use crate::ratelimit::RateLimiter;
+use crate::transcript::{Event, Recorder, Transcript};
+use crate::utils::DatagramSocket;
+use crate::{ClientStream, Config, ProtoError, TcpServer};
+
+// Bytes buffered in each direction of the pipe between relay and server
+const RELAY_BUFFER_SIZE: usize = 64 * 1024;
+
+// Everything observing or policing the traffic going through the framework
+#[derive(Clone, Default)]
+pub struct Taps {
+    pub part: u8,
+    pub recorder: Option<Arc<Recorder>>,
+    pub capture: Option<Arc<Capture>>,
+    pub events: Option<Arc<EventLog>>,
+    pub idle_timeout: Option<Duration>,
+    // Count the bytes exchanged with the clients for the metrics endpoint
+    pub count_bytes: bool,
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsAcceptor>,
+    // Checked on accept and on receive, before anything else sees the peer
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    // Simulated network faults, on the wire side of everything else
+    pub chaos: Option<Arc<Chaos>>,
+}
+
+impl Taps {
+    pub fn from_config(config: &Config, part: u8) -> Result<Self, ProtoError> {
+        let recorder = config
+            .record_dir
+            .clone()
+            .map(|dir| Arc::new(Recorder::new(dir, part)));
+        let capture = match &config.pcap_path {
+            Some(path) => Some(Arc::new(
+                Capture::create(path).map_err(|_| "could not create capture file")?,
+            )),
+            None => None,
+        };
+        let events = config
+            .events_path
+            .clone()
+            .map(|path| Arc::new(EventLog::new(path)));
+        Ok(Self {
+            part,
+            recorder,
+            capture,
+            events,
+            idle_timeout: config.idle_timeout,
+            count_bytes: config.metrics_addr.is_some(),
+            #[cfg(feature = "tls")]
+            tls: crate::tls::acceptor(&config.tls)?,
+            rate_limiter: RateLimiter::new(&config.rate_limit).map(Arc::new),
+            chaos: Chaos::new(&config.chaos).map(Arc::new),
+        })
+    }
+
+    pub fn admits(&self, peer: &SocketAddr) -> bool {
+        let Some(limiter) = &self.rate_limiter else {
+            return true;
+        };
+        let admitted = limiter.admits(peer.ip());
+        if !admitted {
+            metrics::increment(self.part, Counter::RateLimited);
+        }
+        admitted
+    }
+
+    // Whether connections can go straight to the server, without a relay
+    pub fn is_empty(&self) -> bool {
+        self.recorder.is_none()
+            && self.capture.is_none()
+            && self.events.is_none()
+            && self.idle_timeout.is_none()
+            && !self.count_bytes
+            && !self.uses_tls()
+            && self.chaos.is_none()
+    }
+
+    fn uses_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+
+    // Relay the client through an in-memory pipe to the server, so that every
+    // byte exchanged can be observed on the way
+    pub async fn handle_connection(
+        &self,
+        server: Arc<dyn TcpServer>,
+        client: ClientStream,
+        addrs: (SocketAddr, SocketAddr),
+    ) {
+        let start = Instant::now();
+        let client = match &self.chaos {
+            Some(chaos) => {
+                let (near, far) = io::duplex(RELAY_BUFFER_SIZE);
+                tokio::spawn(Arc::clone(chaos).relay(client, far));
+                ClientStream::new(near)
+            }
+            None => client,
+        };
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = &self.tls {
+            match acceptor.accept(client).await {
+                Ok(client) => self.relay(server, client, addrs, start).await,
+                Err(err) => tracing::info!("TLS handshake failed: {err}"),
+            }
+            return;
+        }
+        self.relay(server, client, addrs, start).await;
+    }
+
+    async fn relay(
+        &self,
+        server: Arc<dyn TcpServer>,
+        client: impl AsyncRead + AsyncWrite + Unpin,
+        (client_addr, server_addr): (SocketAddr, SocketAddr),
+        start: Instant,
+    ) {
+        let (relay, stream) = io::duplex(RELAY_BUFFER_SIZE);
+        tokio::spawn(async move { server.handle_connection(ClientStream::new(stream)).await });
+
+        let mut transcript = Transcript {
+            peer: Some(client_addr),
+            ..Transcript::default()
+        };
+        let mut flow = self
+            .capture
+            .as_ref()
+            .map(|capture| capture.tcp_flow(client_addr, server_addr));
+        let (mut bytes_in, mut bytes_out) = (0, 0);
+        let reason = relay_connection(client, relay, self.idle_timeout, |event| {
+            let (counter, total, len) = match &event {
+                Event::Send(data) => (Counter::BytesIn, &mut bytes_in, data.len() as u64),
+                Event::Recv(data) => (Counter::BytesOut, &mut bytes_out, data.len() as u64),
+            };
+            *total += len;
+            if self.count_bytes {
+                metrics::add(self.part, counter, len);
+            }
+            if let Some(flow) = &mut flow {
+                match &event {
+                    Event::Send(data) => flow.data(true, data),
+                    Event::Recv(data) => flow.data(false, data),
+                }
+            }
+            if self.recorder.is_some() {
+                transcript.push(start.elapsed(), event);
+            }
+        })
+        .await;
+
+        if let Some(recorder) = &self.recorder {
+            recorder.save(&transcript);
+        }
+        if let Some(events) = &self.events {
+            events.push(&ConnectionEvent {
+                challenge: self.part,
+                peer: client_addr,
+                duration: start.elapsed(),
+                bytes_in,
+                bytes_out,
+                reason,
+            });
+        }
+    }
+
+    pub fn wrap_socket(&self, socket: Arc<UdpSocket>) -> Arc<dyn DatagramSocket> {
+        let socket: Arc<dyn DatagramSocket> = if self.capture.is_none() && !self.count_bytes {
+            socket
+        } else {
+            Arc::new(TappedSocket {
+                socket,
+                part: self.part,
+                capture: self.capture.clone(),
+                count_bytes: self.count_bytes,
+            })
+        };
+        match &self.chaos {
+            Some(chaos) => Arc::new(Arc::clone(chaos).wrap_socket(socket)),
+            None => socket,
+        }
+    }
+
+    // How long a received datagram is held back before being handled, if it
+    // is not dropped
+    pub fn datagram_delay(&self) -> Option<Duration> {
+        match &self.chaos {
+            Some(chaos) => chaos.datagram_delay(),
+            None => Some(Duration::ZERO),
+        }
+    }
+
+    pub fn received_datagram(&self, socket: &UdpSocket, addr: SocketAddr, data: &[u8]) {
+        if let (Some(capture), Ok(local_addr)) = (&self.capture, socket.local_addr()) {
+            capture.udp_datagram(addr, local_addr, data);
+        }
+        if self.count_bytes {
+            metrics::add(self.part, Counter::BytesIn, data.len() as u64);
+        }
+    }
+}
+
+struct TappedSocket {
+    socket: Arc<UdpSocket>,
+    part: u8,
+    capture: Option<Arc<Capture>>,
+    count_bytes: bool,
+}
+
+#[async_trait]
+impl DatagramSocket for TappedSocket {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let n = UdpSocket::send_to(&self.socket, buf, addr).await?;
+        if let (Some(capture), Ok(local_addr)) = (&self.capture, self.socket.local_addr()) {
+            capture.udp_datagram(local_addr, addr, &buf[..n]);
+        }
+        if self.count_bytes {
+            metrics::add(self.part, Counter::BytesOut, n as u64);
+        }
+        Ok(n)
+    }
+}
+
+// Relay until both sides are closed, returning why the connection ended. The
+// idle timeout only counts the client's silence, so that data the server
+// keeps sending (heartbeats, chat messages) does not keep a dead peer around
+async fn relay_connection(
+    client: impl AsyncRead + AsyncWrite + Unpin,
+    server: DuplexStream,
+    idle_timeout: Option<Duration>,
+    mut on_event: impl FnMut(Event),
+) -> CloseReason {
+    let (mut client_reader, mut client_writer) = io::split(client);
+    let (mut server_reader, mut server_writer) = io::split(server);
+    let mut client_buf = [0; 1024];
+    let mut server_buf = [0; 1024];
+    let mut client_open = true;
+    let mut server_open = true;
+    let mut reason = None;
+    let mut last_received = time::Instant::now();
+
+    while client_open || server_open {
+        let idle = async {
+            match idle_timeout {
+                Some(timeout) => time::sleep_until(last_received + timeout).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            result = client_reader.read(&mut client_buf), if client_open => match result {
+                Ok(0) | Err(_) => {
+                    client_open = false;
+                    reason.get_or_insert(CloseReason::Eof);
+                    let _ = server_writer.shutdown().await;
+                }
+                Ok(n) => {
+                    last_received = time::Instant::now();
+                    on_event(Event::Send(client_buf[..n].to_vec()));
+                    let _ = server_writer.write_all(&client_buf[..n]).await;
+                }
+            },
+            result = server_reader.read(&mut server_buf), if server_open => match result {
+                Ok(0) | Err(_) => {
+                    server_open = false;
+                    reason.get_or_insert(CloseReason::ProtocolError);
+                    let _ = client_writer.shutdown().await;
+                }
+                Ok(n) => {
+                    on_event(Event::Recv(server_buf[..n].to_vec()));
+                    if client_writer.write_all(&server_buf[..n]).await.is_err() {
+                        reason.get_or_insert(CloseReason::WriteFailure);
+                    }
+                }
+            },
+            () = idle => {
+                reason.get_or_insert(CloseReason::Timeout);
+                break;
+            }
+        }
+    }
+    reason.unwrap_or(CloseReason::Eof)
+}
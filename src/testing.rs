@@ -0,0 +1,173 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, io};
+
+use tokio::io::DuplexStream;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::admin::{self, Admin};
+use crate::clients::Connection;
+use crate::utils::{Faults, FlakySocket, Rng};
+use crate::{ClientStream, Config, RunOptions, ServerType, StateSizes, TcpServer, run_challenge};
+
+mod malformed;
+mod speed;
+
+pub use malformed::{Variant, malformed_json, out_of_range_numbers};
+pub use speed::{Road, Scenario, Sighting};
+
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+// A challenge server running on an ephemeral loopback port, stopped on drop
+pub struct TestServer {
+    pub addr: SocketAddr,
+    server: ServerType,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+pub async fn spawn(part: u8) -> io::Result<TestServer> {
+    spawn_with_config(part, &Config::default()).await
+}
+
+pub async fn spawn_with_config(part: u8, config: &Config) -> io::Result<TestServer> {
+    let options = RunOptions {
+        config: config.clone(),
+        ..RunOptions::default()
+    };
+    let running = run_challenge(part, options).await?;
+    Ok(TestServer {
+        addr: running.addr,
+        server: running.server,
+        handle: running.handle,
+    })
+}
+
+// Serve a single connection over an in-memory pipe and return the client end,
+// so that a handler can be tested without binding any socket
+pub fn duplex(server: Arc<dyn TcpServer>) -> DuplexStream {
+    let (client, stream) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+    tokio::spawn(async move { server.handle_connection(ClientStream::new(stream)).await });
+    client
+}
+
+impl TestServer {
+    pub async fn state_sizes(&self) -> StateSizes {
+        self.server.state_sizes().await
+    }
+
+    // Serve the admin socket of the server on an ephemeral loopback port
+    pub async fn spawn_admin(&self) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let admin = Arc::new(Admin::new(self.server.clone()));
+        tokio::spawn(admin::serve(listener, admin));
+        Ok(addr)
+    }
+
+    pub async fn connect(&self) -> io::Result<TestClient> {
+        let mut client = Connection::connect(self.addr).await?;
+        client.set_timeout(Some(RECV_TIMEOUT));
+        Ok(client)
+    }
+
+    pub async fn udp_client(&self) -> io::Result<UdpClient> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        socket.connect(self.addr).await?;
+        Ok(UdpClient {
+            socket,
+            proxy: None,
+        })
+    }
+
+    // A client whose datagrams, in both directions, go through a proxy
+    // misbehaving as described by `faults`
+    pub async fn flaky_udp_client(&self, faults: Faults, seed: u64) -> io::Result<UdpClient> {
+        let client_side = UdpSocket::bind("127.0.0.1:0").await?;
+        let server_side = UdpSocket::bind("127.0.0.1:0").await?;
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        socket.connect(client_side.local_addr()?).await?;
+        let client_side = FlakySocket::new(client_side, faults, seed);
+        let server_side = FlakySocket::new(server_side, faults, seed.wrapping_add(1));
+        let proxy = tokio::spawn(run_flaky_proxy(client_side, server_side, self.addr));
+        Ok(UdpClient {
+            socket,
+            proxy: Some(proxy),
+        })
+    }
+}
+
+// Seed for the randomized tests, taken from PROTO_HACKERS_SEED to reproduce a
+// failure, and printed so that it shows up in the output of failing tests
+pub fn seed() -> u64 {
+    let seed = env::var("PROTO_HACKERS_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| Rng::from_time().next_u64());
+    println!("PROTO_HACKERS_SEED={seed}");
+    seed
+}
+
+// Forward datagrams between a client and a server, each side sending through a
+// flaky socket
+async fn run_flaky_proxy(client_side: FlakySocket, server_side: FlakySocket, server: SocketAddr) {
+    let mut client_addr = None;
+    let mut client_buf = [0; 2048];
+    let mut server_buf = [0; 2048];
+
+    loop {
+        tokio::select! {
+            Ok((n, addr)) = client_side.recv_from(&mut client_buf) => {
+                client_addr = Some(addr);
+                let _ = server_side.send_to(&client_buf[..n], server).await;
+            }
+            Ok((n, _)) = server_side.recv_from(&mut server_buf) => {
+                if let Some(addr) = client_addr {
+                    let _ = client_side.send_to(&server_buf[..n], addr).await;
+                }
+            }
+        }
+    }
+}
+
+pub type TestClient = Connection;
+
+pub struct UdpClient {
+    socket: UdpSocket,
+    proxy: Option<JoinHandle<()>>,
+}
+
+impl Drop for UdpClient {
+    fn drop(&mut self) {
+        if let Some(proxy) = &self.proxy {
+            proxy.abort();
+        }
+    }
+}
+
+impl UdpClient {
+    pub async fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.socket.send(data).await.map(|_| ())
+    }
+
+    pub async fn recv(&self) -> Option<Vec<u8>> {
+        self.recv_timeout(RECV_TIMEOUT).await
+    }
+
+    pub async fn recv_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        let mut buf = [0; 2048];
+        match time::timeout(timeout, self.socket.recv(&mut buf)).await {
+            Ok(Ok(n)) => Some(buf[..n].to_vec()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use serde_json::{Map, Value, json};
+
+// A request line derived from a valid one, without its trailing newline
+pub struct Variant {
+    pub description: String,
+    pub data: Vec<u8>,
+}
+
+impl Variant {
+    fn new(description: impl Into<String>, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            description: description.into(),
+            data: data.into(),
+        }
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn with_value(object: &Map<String, Value>, key: &str, value: Value) -> Vec<u8> {
+    let mut object = object.clone();
+    object.insert(key.to_owned(), value);
+    Value::Object(object).to_string().into_bytes()
+}
+
+// Variants of `valid`, a JSON object where every key is required, that a
+// server must reject: invalid JSON, other JSON types, missing keys, values of
+// the wrong type (except for `untyped_keys`), trailing garbage and invalid
+// UTF-8
+pub fn malformed_json(valid: &Value, untyped_keys: &[&str]) -> Vec<Variant> {
+    let object = valid.as_object().expect("valid request must be an object");
+    let text = valid.to_string();
+    let mut variants = vec![
+        Variant::new("empty line", ""),
+        Variant::new("not JSON", "not json"),
+        Variant::new("truncated", &text[..text.len() / 2]),
+        Variant::new("unclosed object", &text[..text.len() - 1]),
+        Variant::new("trailing garbage", format!("{text}garbage")),
+        Variant::new("trailing brace", format!("{text}}}")),
+        Variant::new("two objects", format!("{text}{text}")),
+        Variant::new("single quotes", text.replace('"', "'")),
+        Variant::new(
+            "invalid UTF-8 before keys",
+            [b"{\xff".as_slice(), &text.as_bytes()[1..]].concat(),
+        ),
+    ];
+
+    for other in [
+        json!(null),
+        json!(true),
+        json!(7),
+        json!("text"),
+        json!([valid]),
+    ] {
+        variants.push(Variant::new(
+            format!("{} instead of an object", type_name(&other)),
+            other.to_string(),
+        ));
+    }
+
+    let candidates = [
+        json!(null),
+        json!(false),
+        json!(7),
+        json!("7"),
+        json!([]),
+        json!({}),
+    ];
+    for (key, value) in object {
+        let mut missing = object.clone();
+        missing.remove(key);
+        variants.push(Variant::new(
+            format!("missing key '{key}'"),
+            Value::Object(missing).to_string(),
+        ));
+
+        let mut renamed = object.clone();
+        renamed.remove(key);
+        renamed.insert(key.to_uppercase(), value.clone());
+        variants.push(Variant::new(
+            format!("uppercase key '{key}'"),
+            Value::Object(renamed).to_string(),
+        ));
+
+        let corrupted_key = format!("\"{key}\"");
+        let start = text.find(&corrupted_key).unwrap() + 2;
+        variants.push(Variant::new(
+            format!("invalid UTF-8 in key '{key}'"),
+            [
+                &text.as_bytes()[..start],
+                b"\xff",
+                &text.as_bytes()[start..],
+            ]
+            .concat(),
+        ));
+
+        if untyped_keys.contains(&key.as_str()) {
+            continue;
+        }
+        for candidate in &candidates {
+            if type_name(candidate) != type_name(value) {
+                variants.push(Variant::new(
+                    format!("{} for key '{key}'", type_name(candidate)),
+                    with_value(object, key, candidate.clone()),
+                ));
+            }
+        }
+    }
+    variants
+}
+
+// Variants of `valid` where each number is replaced by numbers that don't fit
+// a u64: negative, fractional, over 2^64 or over f64's range
+pub fn out_of_range_numbers(valid: &Value) -> Vec<Variant> {
+    let object = valid.as_object().expect("valid request must be an object");
+    let mut variants = Vec::new();
+    for (key, value) in object.iter().filter(|(_, value)| value.is_number()) {
+        let placeholder = with_value(object, key, json!("@"));
+        let placeholder = String::from_utf8(placeholder).unwrap();
+        for number in ["-1", "1.5", "18446744073709551616", "1e400"] {
+            variants.push(Variant::new(
+                format!("{number} for key '{key}' instead of {value}"),
+                placeholder.replace("\"@\"", number),
+            ));
+        }
+    }
+    variants
+}
@@ -0,0 +1,119 @@
+use crate::clients::Ticket;
+use crate::utils::Rng;
+
+const DAY: u32 = 86400;
+const MAX_MILE: u16 = 500;
+
+// Speeds dividing 3600, so that travel times between cameras are whole seconds
+// and average speeds come out exact
+const SPEEDS: &[u16] = &[40, 45, 48, 50, 60, 72, 75, 80, 90, 100, 120, 144, 150];
+
+pub struct Road {
+    pub id: u16,
+    pub limit: u16,
+    pub cameras: Vec<u16>,
+}
+
+pub struct Sighting {
+    pub road: u16,
+    pub mile: u16,
+    pub plate: String,
+    pub timestamp: u32,
+}
+
+// Random speed daemon traffic along with the tickets it must produce. To keep
+// the expected tickets unambiguous, each car makes at most one journey a day,
+// every other day so that sightings from different journeys never look like
+// speeding, and speeding journeys are only seen by two cameras
+pub struct Scenario {
+    pub roads: Vec<Road>,
+    pub sightings: Vec<Sighting>,
+    pub tickets: Vec<Ticket>,
+}
+
+impl Scenario {
+    pub fn generate(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut scenario = Self {
+            roads: Vec::new(),
+            sightings: Vec::new(),
+            tickets: Vec::new(),
+        };
+
+        for i in 0..1 + rng.below(3) {
+            let mut cameras = Vec::new();
+            while cameras.len() < 2 + rng.below(4) {
+                let mile = rng.below(MAX_MILE as usize + 1) as u16;
+                if !cameras.contains(&mile) {
+                    cameras.push(mile);
+                }
+            }
+            scenario.roads.push(Road {
+                id: 100 * i as u16 + rng.below(100) as u16,
+                limit: 45 + rng.below(56) as u16,
+                cameras,
+            });
+        }
+
+        for car in 0..1 + rng.below(8) {
+            let plate = format!("CAR{car}X{}", rng.below(1000));
+            let first_day = rng.below(100) as u32;
+            for journey in 0..1 + rng.below(4) as u32 {
+                let start = (first_day + 2 * journey) * DAY + rng.below(4 * 3600) as u32;
+                scenario.add_journey(&mut rng, &plate, start);
+            }
+        }
+
+        rng.shuffle(&mut scenario.sightings);
+        scenario
+    }
+
+    fn add_journey(&mut self, rng: &mut Rng, plate: &str, start: u32) {
+        let road = rng.pick(&self.roads);
+        let speeding = rng.chance(0.5);
+        let speeds: Vec<u16> = SPEEDS
+            .iter()
+            .copied()
+            .filter(|&speed| match speeding {
+                true => speed >= road.limit + 5,
+                false => speed + 5 <= road.limit,
+            })
+            .collect();
+        let speed = *rng.pick(&speeds);
+
+        let mut miles = road.cameras.clone();
+        rng.shuffle(&mut miles);
+        let nb_cameras = if speeding {
+            2
+        } else {
+            1 + rng.below(miles.len())
+        };
+        miles.truncate(nb_cameras);
+        miles.sort_unstable();
+        if rng.chance(0.5) {
+            miles.reverse();
+        }
+
+        let timestamp =
+            |mile: u16| start + u32::from(mile.abs_diff(miles[0])) * 3600 / u32::from(speed);
+        if speeding {
+            self.tickets.push(Ticket {
+                plate: plate.to_owned(),
+                road: road.id,
+                mile1: miles[0],
+                timestamp1: timestamp(miles[0]),
+                mile2: miles[1],
+                timestamp2: timestamp(miles[1]),
+                speed: 100 * speed,
+            });
+        }
+        for &mile in &miles {
+            self.sightings.push(Sighting {
+                road: road.id,
+                mile,
+                plate: plate.to_owned(),
+                timestamp: timestamp(mile),
+            });
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+use crate::ProtoError;
+use crate::config::TlsConfig;
+
+fn tls_error(path: &Path, err: impl std::fmt::Display) -> ProtoError {
+    ProtoError::Tls(format!("{}: {err}", path.display()))
+}
+
+pub fn acceptor(config: &TlsConfig) -> Result<Option<TlsAcceptor>, ProtoError> {
+    match (&config.cert_path, &config.key_path) {
+        (Some(cert_path), Some(key_path)) => load(cert_path, key_path).map(Some),
+        (None, None) => Ok(None),
+        _ => Err(ProtoError::Tls("missing certificate or key".into())),
+    }
+}
+
+// Certificate chain and private key are read from PEM files
+fn load(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, ProtoError> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| tls_error(cert_path, err))?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|err| tls_error(key_path, err))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| tls_error(cert_path, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
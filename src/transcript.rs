@@ -0,0 +1,387 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{env, fmt, fs};
+
+use tokio::io;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::time::{self, Instant};
+
+use crate::clients::Connection;
+use crate::metrics::{self, Counter};
+
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Send(Vec<u8>),
+    Recv(Vec<u8>),
+}
+
+// Bytes exchanged on a single TCP connection, seen from the client side, each
+// event with the time since the connection was accepted.
+// The file format has one event per line: the milliseconds since the start,
+// `<` for data sent by the client and `>` for data received from the server,
+// followed by the bytes where `\`, newlines and non-printable characters are
+// escaped. The time can be left out, and a `# peer <addr>` line tells who the
+// client was
+#[derive(Default, Debug, PartialEq)]
+pub struct Transcript {
+    pub peer: Option<SocketAddr>,
+    pub events: Vec<(Duration, Event)>,
+}
+
+fn escape(data: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &c in data {
+        match c {
+            b'\\' => escaped.push_str("\\\\"),
+            b'\n' => escaped.push_str("\\n"),
+            b' '..=b'~' => escaped.push(c as char),
+            _ => escaped.push_str(&format!("\\x{c:02x}")),
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = data.bytes();
+    while let Some(c) = chars.next() {
+        if c != b'\\' {
+            bytes.push(c);
+            continue;
+        }
+        bytes.push(match chars.next()? {
+            b'n' => b'\n',
+            b'x' => {
+                let hex = [chars.next()?, chars.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            b'\\' => b'\\',
+            _ => return None,
+        });
+    }
+    Some(bytes)
+}
+
+impl Transcript {
+    // Consecutive data in the same direction is merged, keeping the time of
+    // the first chunk
+    pub(crate) fn push(&mut self, elapsed: Duration, event: Event) {
+        match (self.events.last_mut(), event) {
+            (Some((_, Event::Send(data))), Event::Send(new_data))
+            | (Some((_, Event::Recv(data))), Event::Recv(new_data)) => data.extend(new_data),
+            (_, event) => self.events.push((elapsed, event)),
+        }
+    }
+
+    pub fn parse(data: &str) -> Result<Self, &'static str> {
+        let mut transcript = Self::default();
+        for line in data.lines().filter(|line| !line.is_empty()) {
+            if let Some(peer) = line.strip_prefix("# peer ") {
+                transcript.peer = Some(peer.parse().or(Err("invalid peer address"))?);
+                continue;
+            }
+            let (mut direction, mut data) =
+                line.split_once(' ').ok_or("invalid transcript line")?;
+            let mut elapsed = Duration::ZERO;
+            if let Ok(millis) = direction.parse() {
+                elapsed = Duration::from_millis(millis);
+                (direction, data) = data.split_once(' ').ok_or("invalid transcript line")?;
+            }
+            let data = unescape(data).ok_or("invalid escaped data")?;
+            let event = match direction {
+                "<" => Event::Send(data),
+                ">" => Event::Recv(data),
+                _ => return Err("invalid transcript direction"),
+            };
+            transcript.push(elapsed, event);
+        }
+        Ok(transcript)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+}
+
+impl fmt::Display for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(peer) = self.peer {
+            writeln!(f, "# peer {peer}")?;
+        }
+        for (elapsed, event) in &self.events {
+            let (direction, data) = match event {
+                Event::Send(data) => ('<', data),
+                Event::Recv(data) => ('>', data),
+            };
+            // Split on newlines to keep line-based protocols readable
+            for line in data.split_inclusive(|&c| c == b'\n') {
+                let millis = elapsed.as_millis();
+                writeln!(f, "{millis} {direction} {}", escape(line))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// A line relayed by the server_05 proxy, `from_client` being false for lines
+// coming from the upstream server
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyEvent {
+    pub elapsed: Duration,
+    pub from_client: bool,
+    pub original: Vec<u8>,
+    pub rewritten: Vec<u8>,
+}
+
+// Lines relayed during a proxy session. Sessions are appended to a fixture
+// file, each starting with a `session` line followed by one line per event:
+// the milliseconds since the session start, `>` towards upstream or `<`
+// towards the client, then the escaped line as received and as rewritten,
+// separated by a tab
+#[derive(Default, Debug, PartialEq)]
+pub struct ProxySession {
+    pub events: Vec<ProxyEvent>,
+}
+
+impl ProxySession {
+    pub fn parse_all(data: &str) -> Result<Vec<Self>, &'static str> {
+        let mut sessions: Vec<Self> = Vec::new();
+        for line in data.lines().filter(|line| !line.is_empty()) {
+            if line == "session" {
+                sessions.push(Self::default());
+                continue;
+            }
+            let session = sessions.last_mut().ok_or("event outside of a session")?;
+            let mut fields = line.splitn(3, ' ');
+            let (Some(elapsed), Some(direction), Some(data)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err("invalid fixture line");
+            };
+            let (original, rewritten) = data.split_once('\t').ok_or("missing rewritten line")?;
+            session.events.push(ProxyEvent {
+                elapsed: Duration::from_millis(elapsed.parse().or(Err("invalid timestamp"))?),
+                from_client: match direction {
+                    ">" => true,
+                    "<" => false,
+                    _ => return Err("invalid fixture direction"),
+                },
+                original: unescape(original).ok_or("invalid escaped data")?,
+                rewritten: unescape(rewritten).ok_or("invalid escaped data")?,
+            });
+        }
+        Ok(sessions)
+    }
+
+    pub fn load_all(path: &Path) -> io::Result<Vec<Self>> {
+        Self::parse_all(&fs::read_to_string(path)?)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+
+    pub fn append_to(&self, path: &Path) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        std::io::Write::write_all(&mut file, self.to_string().as_bytes())
+    }
+}
+
+impl fmt::Display for ProxySession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "session")?;
+        for event in &self.events {
+            writeln!(
+                f,
+                "{} {} {}\t{}",
+                event.elapsed.as_millis(),
+                if event.from_client { '>' } else { '<' },
+                escape(&event.original),
+                escape(&event.rewritten)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Writes the transcript of every connection to `<dir>/server_<part>-<n>.transcript`
+pub struct Recorder {
+    dir: PathBuf,
+    part: u8,
+    nb_connections: AtomicUsize,
+}
+
+impl Recorder {
+    pub fn new(dir: PathBuf, part: u8) -> Self {
+        Self {
+            dir,
+            part,
+            nb_connections: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn save(&self, transcript: &Transcript) {
+        let n = self.nb_connections.fetch_add(1, Ordering::Relaxed);
+        let path = self
+            .dir
+            .join(format!("server_{:02}-{n}.transcript", self.part));
+        if let Err(err) =
+            fs::create_dir_all(&self.dir).and_then(|_| fs::write(&path, transcript.to_string()))
+        {
+            metrics::increment(self.part, Counter::InternalErrors);
+            tracing::warn!("could not write transcript {}: {err}", path.display());
+        }
+    }
+}
+
+// Options of `replay [--timing] <addr> <transcript>...`
+pub struct ReplayConfig {
+    pub addr: String,
+    pub paths: Vec<PathBuf>,
+    // Send the client data at the recorded times rather than right away
+    pub timing: bool,
+}
+
+impl ReplayConfig {
+    pub fn from_args() -> Result<Self, &'static str> {
+        let mut timing = false;
+        let mut addr = None;
+        let mut paths = Vec::new();
+        for arg in env::args().skip(2) {
+            match arg.as_str() {
+                "--timing" => timing = true,
+                _ if addr.is_none() => addr = Some(arg),
+                _ => paths.push(PathBuf::from(arg)),
+            }
+        }
+        if paths.is_empty() {
+            return Err("missing transcript");
+        }
+        Ok(Self {
+            addr: addr.ok_or("missing server address")?,
+            paths,
+            timing,
+        })
+    }
+}
+
+// Replay each transcript in turn, returning whether all of them passed
+pub async fn run(config: &ReplayConfig) -> bool {
+    let mut all_passed = true;
+    for path in &config.paths {
+        let result = match Transcript::load(path) {
+            Ok(transcript) => play(&config.addr, &transcript, config.timing).await,
+            Err(err) => Err(err.to_string()),
+        };
+        match result {
+            Ok(()) => println!("{}: ok", path.display()),
+            Err(err_msg) => {
+                println!("{}: FAILED ({err_msg})", path.display());
+                all_passed = false;
+            }
+        }
+    }
+    all_passed
+}
+
+// Play the client side of a transcript and check the server answers with the
+// exact same bytes, and nothing more
+pub async fn replay(addr: impl ToSocketAddrs, transcript: &Transcript) -> Result<(), String> {
+    play(addr, transcript, false).await
+}
+
+async fn play(
+    addr: impl ToSocketAddrs,
+    transcript: &Transcript,
+    timing: bool,
+) -> Result<(), String> {
+    let mut connection = Connection::connect(addr)
+        .await
+        .map_err(|err| err.to_string())?;
+    connection.set_timeout(Some(REPLAY_TIMEOUT));
+
+    let start = Instant::now();
+    for (elapsed, event) in &transcript.events {
+        match event {
+            Event::Send(data) => {
+                if timing {
+                    time::sleep_until(start + *elapsed).await;
+                }
+                connection.send(data).await.map_err(|err| err.to_string())?;
+            }
+            Event::Recv(expected) => {
+                let Some(data) = connection.recv_exact(expected.len()).await else {
+                    return Err(format!(
+                        "expected \"{}\", got nothing",
+                        expected.escape_ascii()
+                    ));
+                };
+                if data != *expected {
+                    return Err(format!(
+                        "expected \"{}\", got \"{}\"",
+                        expected.escape_ascii(),
+                        data.escape_ascii()
+                    ));
+                }
+            }
+        }
+    }
+
+    let _ = connection.shutdown().await;
+    if !connection.is_closed().await {
+        return Err(String::from("server sent unexpected data"));
+    }
+    Ok(())
+}
+
+// Play both ends of a recorded proxy session: the client connects to the proxy
+// at `addr` and the proxy is expected to connect upstream to `upstream`. Every
+// line has to come out of the proxy rewritten exactly as recorded
+pub async fn replay_proxy(
+    addr: impl ToSocketAddrs,
+    upstream: &TcpListener,
+    session: &ProxySession,
+) -> Result<(), String> {
+    let mut client = Connection::connect(addr)
+        .await
+        .map_err(|err| err.to_string())?;
+    client.set_timeout(Some(REPLAY_TIMEOUT));
+    let (stream, _) = upstream.accept().await.map_err(|err| err.to_string())?;
+    let mut server = Connection::from_stream(stream);
+    server.set_timeout(Some(REPLAY_TIMEOUT));
+
+    for event in &session.events {
+        let (sender, receiver) = match event.from_client {
+            true => (&mut client, &mut server),
+            false => (&mut server, &mut client),
+        };
+        let mut line = event.original.clone();
+        line.push(b'\n');
+        sender.send(&line).await.map_err(|err| err.to_string())?;
+
+        let mut expected = event.rewritten.clone();
+        expected.push(b'\n');
+        match receiver.recv_exact(expected.len()).await {
+            Some(data) if data == expected => {}
+            Some(data) => {
+                return Err(format!(
+                    "expected \"{}\", got \"{}\"",
+                    expected.escape_ascii(),
+                    data.escape_ascii()
+                ));
+            }
+            None => {
+                return Err(format!(
+                    "expected \"{}\", got nothing",
+                    expected.escape_ascii()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
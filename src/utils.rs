@@ -1,74 +1,1122 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::IoSlice;
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use async_trait::async_trait;
-use tokio::io::{self, AsyncReadExt};
-use tokio::net::{TcpStream, tcp::OwnedReadHalf};
+use bytes::{Bytes, BytesMut};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::io::{
+    self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf,
+};
+use tokio::net::{self, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use crate::ProtoError;
+use crate::config::SocketOptions;
+
+pub mod checksum;
+pub mod json_lines;
+pub mod wire;
+
+const LISTEN_BACKLOG: u32 = 1024;
+// Longest frame buffered by default, well above anything the challenges send
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+
+// Anything a TCP client can be served over: a socket, a TLS session or one end
+// of an in-memory pipe
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type ClientReader = ReadHalf<ClientStream>;
+pub type ClientWriter = WriteHalf<ClientStream>;
+
+// The stream handed to TCP servers, which can be split into halves owned by
+// different tasks like a TcpStream
+pub struct ClientStream {
+    inner: Box<dyn AsyncStream>,
+}
+
+impl ClientStream {
+    pub fn new(stream: impl AsyncStream + 'static) -> Self {
+        Self {
+            inner: Box::new(stream),
+        }
+    }
+
+    pub fn into_split(self) -> (ClientReader, ClientWriter) {
+        io::split(self)
+    }
+}
+
+impl From<TcpStream> for ClientStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::new(stream)
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
 
 #[async_trait]
-pub trait AsyncReadHalf {
-    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+pub trait DatagramSocket: Send + Sync {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
 }
 
 #[async_trait]
-impl AsyncReadHalf for TcpStream {
-    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        AsyncReadExt::read(self, buf).await
+impl DatagramSocket for UdpSocket {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr).await
+    }
+}
+
+// Probabilities for each datagram sent to be lost, duplicated or held back
+// behind the following ones, on top of a random delay up to `max_delay`
+#[derive(Clone, Copy, Default)]
+pub struct Faults {
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub max_delay: Duration,
+}
+
+// UDP socket misbehaving on send as described by its faults. The fate of each
+// datagram only depends on the seed and on the order of the sends, so a
+// failure can be reproduced by reusing the seed
+pub struct FlakySocket {
+    socket: Arc<UdpSocket>,
+    faults: Faults,
+    rng: Mutex<Rng>,
+}
+
+impl FlakySocket {
+    pub fn new(socket: UdpSocket, faults: Faults, seed: u64) -> Self {
+        Self {
+            socket: Arc::new(socket),
+            faults,
+            rng: Mutex::new(Rng::new(seed)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf).await
+    }
+
+    // Delivery happens in the background, so a successful send only means the
+    // datagram was accepted
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let delays = {
+            let mut rng = self.rng.lock().unwrap();
+            if rng.chance(self.faults.loss) {
+                return Ok(buf.len());
+            }
+            let nb_copies = if rng.chance(self.faults.duplicate) {
+                2
+            } else {
+                1
+            };
+            let mut delays = Vec::new();
+            for _ in 0..nb_copies {
+                let mut delay = self.faults.max_delay.mul_f64(rng.next_f64());
+                if rng.chance(self.faults.reorder) {
+                    delay += self.faults.max_delay + Duration::from_millis(10);
+                }
+                delays.push(delay);
+            }
+            delays
+        };
+
+        for delay in delays {
+            let socket = Arc::clone(&self.socket);
+            let data = buf.to_vec();
+            tokio::spawn(async move {
+                time::sleep(delay).await;
+                let _ = socket.send_to(&data, addr).await;
+            });
+        }
+        Ok(buf.len())
     }
 }
 
 #[async_trait]
-impl AsyncReadHalf for OwnedReadHalf {
-    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        AsyncReadExt::read(self, buf).await
-    }
-}
-
-pub async fn read_until(
-    stream: &mut impl AsyncReadHalf,
-    buffer: &mut [u8],
-    limit: char,
-) -> Option<String> {
-    let limit = limit as u8;
-
-    let mut data = String::new();
-    let mut data_len = buffer
-        .iter()
-        .position(|&c| c == b'\0')
-        .unwrap_or(buffer.len());
-
-    while !buffer[..data_len].contains(&limit) {
-        data.push_str(&String::from_utf8_lossy(&buffer[..data_len]));
-        data_len = match stream.read(buffer).await {
-            Ok(0) | Err(_) => return None,
-            Ok(n) => n,
+impl DatagramSocket for FlakySocket {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        FlakySocket::send_to(self, buf, addr).await
+    }
+}
+
+// xorshift64*, good enough to pick which datagrams to mess with or to build
+// test scenarios
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    // Scramble the seed with splitmix64 so that close seeds give unrelated
+    // sequences, the state only has to be non-zero
+    pub(crate) fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        Self((z ^ (z >> 31)).max(1))
+    }
+
+    pub(crate) fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self::new(nanos)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub(crate) fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next_f64() * n as f64) as usize
+    }
+
+    pub(crate) fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+
+    pub(crate) fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+// Incremental framing over a byte stream. Data is read into a buffer kept for
+// the whole connection and frames are split off its front without being
+// copied, binary data included. The data already searched for a delimiter is
+// not searched again, and as reads are cancel-safe so are all of these
+pub struct FrameReader<R> {
+    reader: R,
+    // Leased for the whole connection, frames being split off it
+    buffer: PooledBuffer<'static>,
+    // Length of the buffered data known to be free of the delimiter
+    scanned: usize,
+    max_len: usize,
+    cancel: Option<CancellationToken>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: BUFFER_POOL.lease(),
+            scanned: 0,
+            max_len: MAX_FRAME_LEN,
+            cancel: None,
+        }
+    }
+
+    // Frames longer than this fail with `ProtoError::FrameTooLarge` instead of
+    // being buffered, delimiter excluded
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    // Once `cancel` is cancelled, pending and later reads end as if the
+    // stream was closed, so that the caller can still clean up after itself
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    fn too_large(&self) -> io::Error {
+        let err = ProtoError::FrameTooLarge(self.max_len);
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+
+    // False once the stream is closed
+    async fn fill(&mut self) -> io::Result<bool> {
+        self.buffer.reserve(BufferPool::BUFFER_SIZE);
+        let read = self.reader.read_buf(&mut *self.buffer);
+        let nb_read = match &self.cancel {
+            Some(cancel) => tokio::select! {
+                biased;
+                () = cancel.cancelled() => 0,
+                nb_read = read => nb_read?,
+            },
+            None => read.await?,
         };
+        Ok(nb_read > 0)
+    }
+
+    // Next frame ending with `delimiter`, which is dropped. Data left without
+    // a delimiter when the stream closes is not a frame
+    pub async fn read_until(&mut self, delimiter: u8) -> io::Result<Option<BytesMut>> {
+        loop {
+            let unscanned = &self.buffer[self.scanned..];
+            if let Some(index) = unscanned.iter().position(|&c| c == delimiter) {
+                if self.scanned + index > self.max_len {
+                    return Err(self.too_large());
+                }
+                let mut frame = self.buffer.split_to(self.scanned + index + 1);
+                frame.truncate(frame.len() - 1);
+                self.scanned = 0;
+                return Ok(Some(frame));
+            }
+            self.scanned = self.buffer.len();
+            if self.scanned > self.max_len {
+                return Err(self.too_large());
+            }
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    pub async fn read_line(&mut self) -> io::Result<Option<BytesMut>> {
+        self.read_until(b'\n').await
+    }
+
+    pub async fn read_exact(&mut self, nb_bytes: usize) -> io::Result<Option<BytesMut>> {
+        if self.peek(nb_bytes).await?.is_none() {
+            return Ok(None);
+        }
+        self.scanned = self.scanned.saturating_sub(nb_bytes);
+        Ok(Some(self.buffer.split_to(nb_bytes)))
+    }
+
+    // The next `nb_bytes` bytes, left to be read again
+    pub async fn peek(&mut self, nb_bytes: usize) -> io::Result<Option<&[u8]>> {
+        if nb_bytes > self.max_len {
+            return Err(self.too_large());
+        }
+        while self.buffer.len() < nb_bytes {
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+        Ok(Some(&self.buffer[..nb_bytes]))
+    }
+
+    // Data already read from the stream but not handed out yet
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer[..]
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+}
+
+// The protocol error a read failed with, as opposed to the connection failing
+pub fn protocol_error(err: &io::Error) -> Option<&ProtoError> {
+    err.get_ref()?.downcast_ref()
+}
+
+// Buffers handed back by closed connections, leased again by new ones so that
+// accept storms do not allocate a fresh buffer per connection
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+}
+
+pub static BUFFER_POOL: BufferPool = BufferPool::new();
+
+impl BufferPool {
+    pub const BUFFER_SIZE: usize = 1024;
+    // Enough for a burst of connections without holding on to much memory
+    const MAX_BUFFERS: usize = 256;
+    // Buffers grown by long lines are freed rather than kept
+    const MAX_CAPACITY: usize = 64 * 1024;
+
+    const fn new() -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Empty buffer with room for at least `BUFFER_SIZE` bytes
+    pub fn lease(&self) -> PooledBuffer<'_> {
+        let buffer = self.buffers.lock().unwrap().pop();
+        PooledBuffer {
+            buffer: buffer.unwrap_or_else(|| BytesMut::with_capacity(Self::BUFFER_SIZE)),
+            pool: self,
+        }
+    }
+
+    // Buffers still sharing their memory with frames split off them are only
+    // kept if enough of it is theirs alone
+    fn give_back(&self, mut buffer: BytesMut) {
+        buffer.clear();
+        if buffer.capacity() > Self::MAX_CAPACITY || !buffer.try_reclaim(Self::BUFFER_SIZE) {
+            return;
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < Self::MAX_BUFFERS {
+            buffers.push(buffer);
+        }
+    }
+}
+
+// Goes back to its pool when dropped
+pub struct PooledBuffer<'a> {
+    buffer: BytesMut,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buffer
     }
+}
 
-    let index = buffer.iter().position(|&c| c == limit).unwrap();
-    data += &String::from_utf8_lossy(&buffer[..index]);
-    buffer.copy_within(index + 1..data_len, 0);
-    let remaining_len = data_len - index - 1;
-    buffer[remaining_len..].fill(0);
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buffer
+    }
+}
 
-    Some(data)
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.give_back(mem::take(&mut self.buffer));
+    }
 }
 
-pub async fn read_for(
-    stream: &mut impl AsyncReadHalf,
-    buffer: &mut Vec<u8>,
-    nb_bytes: usize,
-) -> Option<Vec<u8>> {
-    let mut data = Vec::new();
-    let mut data_len = buffer.len();
+// Tokio has no `write_all_vectored`: keep writing what is left of the slices
+// until all of them went through
+pub async fn write_all_vectored(
+    writer: &mut (impl AsyncWrite + Unpin),
+    mut slices: &mut [IoSlice<'_>],
+) -> io::Result<()> {
+    while !slices.is_empty() {
+        let n = writer.write_vectored(slices).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut slices, n);
+    }
+    Ok(())
+}
 
-    while data.len() + data_len < nb_bytes {
-        data.extend(buffer.iter());
-        let mut buf = [0; 1024];
-        data_len = match stream.read(&mut buf).await {
-            Ok(0) | Err(_) => return None,
-            Ok(n) => n,
+// Outbound TCP connections of the servers depending on another one, bound to
+// a local address when the default route would pick the wrong interface
+#[derive(Clone, Copy, Default)]
+pub struct Connector {
+    pub local_addr: Option<IpAddr>,
+}
+
+impl Connector {
+    pub async fn connect(&self, addr: &str) -> io::Result<TcpStream> {
+        let Some(local_addr) = self.local_addr else {
+            return TcpStream::connect(addr).await;
         };
-        *buffer = buf[..data_len].to_vec();
+        let mut last_err = None;
+        for remote in net::lookup_host(addr).await? {
+            if remote.is_ipv4() != local_addr.is_ipv4() {
+                continue;
+            }
+            let socket = match local_addr {
+                IpAddr::V4(_) => TcpSocket::new_v4()?,
+                IpAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            socket.bind(SocketAddr::new(local_addr, 0))?;
+            match socket.connect(remote).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            let msg = "no remote address in the family of the local one";
+            io::Error::new(io::ErrorKind::AddrNotAvailable, msg)
+        }))
     }
+}
+
+// IPv6 counterpart of an IPv4 address a server listens on, on the same port.
+// It only takes IPv6 traffic, so that both can be bound whatever the system
+// default for dual-stack sockets
+fn bind_v6_only(v4_addr: SocketAddr, kind: Type, reuse_addr: bool) -> io::Result<Socket> {
+    let ip = match v4_addr.ip().is_loopback() {
+        true => Ipv6Addr::LOCALHOST,
+        false => Ipv6Addr::UNSPECIFIED,
+    };
+    let socket = Socket::new(Domain::IPV6, kind, None)?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(reuse_addr)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(ip.into(), v4_addr.port()).into())?;
+    Ok(socket)
+}
+
+pub fn listen_v6(v4_addr: SocketAddr, reuse_addr: bool) -> io::Result<net::TcpListener> {
+    let socket = bind_v6_only(v4_addr, Type::STREAM, reuse_addr)?;
+    socket.listen(LISTEN_BACKLOG as i32)?;
+    net::TcpListener::from_std(socket.into())
+}
 
-    data.extend(buffer.drain(..nb_bytes - data.len()));
-    Some(data)
+pub fn bind_udp_v6(v4_addr: SocketAddr) -> io::Result<UdpSocket> {
+    UdpSocket::from_std(bind_v6_only(v4_addr, Type::DGRAM, false)?.into())
+}
+
+// A socket file left behind by a previous run is replaced, anything else at
+// `path` makes binding fail
+#[cfg(unix)]
+pub fn listen_unix(path: &str) -> io::Result<net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = std::fs::symlink_metadata(path);
+    if metadata.is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    net::UnixListener::bind(path)
+}
+
+// Without `reuse_addr`, the listener gets the platform default: tokio sets
+// SO_REUSEADDR on Unix but not on Windows
+pub async fn listen(addr: &str, reuse_addr: bool) -> io::Result<net::TcpListener> {
+    if !reuse_addr {
+        return net::TcpListener::bind(addr).await;
+    }
+    let Some(addr) = net::lookup_host(addr).await?.next() else {
+        let msg = "no address to listen on";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    };
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+// Applied to every accepted connection
+pub fn tune_stream(stream: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+    if options.nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(time) = options.keepalive {
+        SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+    Ok(())
+}
+
+// Handle to a task owning the write half of a client, so that sending to a
+// client never waits on it nor on whoever else is sending to it. Clones share
+// the queue, messages staying whole and in order. Once the last clone is
+// dropped, what is left is written and flushed before the write half is shut
+// down
+#[derive(Clone)]
+pub struct QueuedWriter {
+    sender: mpsc::Sender<Bytes>,
+    closed: CancellationToken,
+}
+
+impl QueuedWriter {
+    // Messages queued before the peer is considered too slow to keep up
+    const QUEUE_SIZE: usize = 1024;
+
+    pub fn new(writer: impl AsyncWrite + Unpin + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::channel(Self::QUEUE_SIZE);
+        let closed = CancellationToken::new();
+        spawn_named("client writer", Self::run(writer, receiver, closed.clone()));
+        Self { sender, closed }
+    }
+
+    async fn run(
+        writer: impl AsyncWrite + Unpin,
+        mut receiver: mpsc::Receiver<Bytes>,
+        closed: CancellationToken,
+    ) {
+        let mut writer = io::BufWriter::new(writer);
+        loop {
+            let data = tokio::select! {
+                data = receiver.recv() => data,
+                // Given up on by a sender, the rest is not worth writing
+                () = closed.cancelled() => return,
+            };
+            let Some(data) = data else {
+                break;
+            };
+            if writer.write_all(&data).await.is_err() {
+                return closed.cancel();
+            }
+            // Whatever is queued meanwhile goes out in the same write
+            if receiver.is_empty() && writer.flush().await.is_err() {
+                return closed.cancel();
+            }
+        }
+        let _ = writer.shutdown().await;
+        closed.cancel();
+    }
+
+    // Queues `data` without waiting, false once the peer is gone or too far
+    // behind, in which case it is given up on
+    pub fn send(&self, data: impl Into<Bytes>) -> bool {
+        if self.sender.try_send(data.into()).is_err() {
+            self.closed.cancel();
+            return false;
+        }
+        !self.closed.is_cancelled()
+    }
+
+    // Cancelled once nothing more can be written to the peer
+    pub fn closed(&self) -> &CancellationToken {
+        &self.closed
+    }
+}
+
+// Sending side of a UDP socket shared by the handlers of its datagrams. Each
+// peer gets its own queue, drained by a task that lives as long as there is
+// something to send, so a handler never waits on the socket and datagrams to a
+// peer can be paced
+#[derive(Clone)]
+pub struct UdpSender {
+    socket: Arc<dyn DatagramSocket>,
+    queues: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Bytes>>>>,
+    interval: Duration,
+}
+
+impl UdpSender {
+    // Datagrams queued for a peer before the following ones are dropped
+    const QUEUE_SIZE: usize = 256;
+
+    pub fn new(socket: Arc<dyn DatagramSocket>) -> Self {
+        Self {
+            socket,
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            interval: Duration::ZERO,
+        }
+    }
+
+    // Sends at most `per_second` datagrams to each peer, without pacing for
+    // a rate that is not positive
+    pub fn with_rate(mut self, per_second: f64) -> Self {
+        self.interval = Some(per_second)
+            .filter(|&rate| rate > 0.0)
+            .map_or(Duration::ZERO, |rate| Duration::from_secs_f64(1.0 / rate));
+        self
+    }
+
+    // Queues `data` for `addr` without waiting, false if it had to be dropped
+    pub fn send(&self, addr: SocketAddr, data: impl Into<Bytes>) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(addr).or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel(Self::QUEUE_SIZE);
+            spawn_named("udp sender", self.clone().drain(addr, receiver));
+            sender
+        });
+        if queue.try_send(data.into()).is_err() {
+            tracing::debug!(%addr, "send queue full, dropping datagram");
+            return false;
+        }
+        true
+    }
+
+    // Sends `data` every `interval` until the future is dropped, which the
+    // caller does once the peer acknowledged it, or until `max_retransmits`
+    // retransmissions went unanswered
+    pub async fn send_expect_ack(
+        &self,
+        addr: SocketAddr,
+        data: Bytes,
+        interval: Duration,
+        max_retransmits: usize,
+    ) -> Result<(), ProtoError> {
+        if interval.is_zero() {
+            return Err("Retransmit interval cannot be zero".into());
+        }
+        let mut interval = time::interval(interval);
+        for _ in 0..=max_retransmits {
+            interval.tick().await;
+            self.send(addr, data.clone());
+        }
+        Ok(())
+    }
+
+    async fn drain(self, addr: SocketAddr, mut receiver: mpsc::Receiver<Bytes>) {
+        let mut next_send = time::Instant::now();
+        loop {
+            let Ok(data) = receiver.try_recv() else {
+                // Waiting out the pacing first, so that the next task for the
+                // peer does not send sooner than this one would have
+                time::sleep_until(next_send).await;
+                // Senders queue with the lock held, nothing can be missed
+                let mut queues = self.queues.lock().unwrap();
+                if receiver.is_empty() {
+                    queues.remove(&addr);
+                    return;
+                }
+                continue;
+            };
+            time::sleep_until(next_send).await;
+            if let Err(err) = self.socket.send_to(&data, addr).await {
+                tracing::debug!(%addr, "could not send datagram: {err}");
+            }
+            next_send = time::Instant::now() + self.interval;
+        }
+    }
+}
+
+// Spawn a long-lived task, named so that it can be told apart in
+// tokio-console, which needs the `console` feature and `--cfg tokio_unstable`
+pub fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    return tokio::task::Builder::new()
+        .name(name)
+        .spawn(future)
+        .expect("could not spawn task");
+
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::spawn(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    // Yields the scripted reads one after the other, then EOF
+    struct ScriptedStream {
+        reads: VecDeque<io::Result<Vec<u8>>>,
+    }
+
+    impl ScriptedStream {
+        fn new(chunks: &[&[u8]]) -> Self {
+            Self {
+                reads: chunks.iter().map(|chunk| Ok(chunk.to_vec())).collect(),
+            }
+        }
+
+        fn bytewise(data: &[u8]) -> Self {
+            Self {
+                reads: data.iter().map(|&c| Ok(vec![c])).collect(),
+            }
+        }
+
+        fn then_error(mut self) -> Self {
+            self.reads
+                .push_back(Err(io::Error::from(io::ErrorKind::ConnectionReset)));
+            self
+        }
+    }
+
+    impl AsyncRead for ScriptedStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let chunk = match self.reads.pop_front() {
+                None => return Poll::Ready(Ok(())),
+                Some(read) => read?,
+            };
+            let n = chunk.len().min(buf.remaining());
+            buf.put_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.reads.push_front(Ok(chunk[n..].to_vec()));
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn read_lines(stream: ScriptedStream) -> Vec<String> {
+        let mut reader = FrameReader::new(stream);
+        let mut lines = Vec::new();
+        while let Some(line) = reader.read_line().await.unwrap() {
+            lines.push(String::from_utf8(line.to_vec()).unwrap());
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn read_line_carries_following_lines() {
+        let stream = ScriptedStream::new(&[b"one\ntwo\nthr", b"ee\n"]);
+        assert_eq!(read_lines(stream).await, ["one", "two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn read_line_reads_byte_by_byte() {
+        let stream = ScriptedStream::bytewise(b"hello\n\nworld\n");
+        assert_eq!(read_lines(stream).await, ["hello", "", "world"]);
+    }
+
+    #[tokio::test]
+    async fn read_line_handles_lines_longer_than_buffer() {
+        let line = "x".repeat(5000);
+        let stream = ScriptedStream::new(&[format!("{line}\n{line}\n").as_bytes()]);
+        assert_eq!(read_lines(stream).await, [line.clone(), line]);
+    }
+
+    #[tokio::test]
+    async fn read_line_keeps_split_characters() {
+        let data = "héllo wörld\n".as_bytes();
+        let stream = ScriptedStream::new(&[&data[..2], &data[2..9], &data[9..]]);
+        assert_eq!(read_lines(stream).await, ["héllo wörld"]);
+
+        let stream = ScriptedStream::bytewise(data);
+        assert_eq!(read_lines(stream).await, ["héllo wörld"]);
+    }
+
+    #[tokio::test]
+    async fn read_line_drops_unterminated_line() {
+        let stream = ScriptedStream::new(&[b"one\ntw", b"o"]);
+        assert_eq!(read_lines(stream).await, ["one"]);
+    }
+
+    #[tokio::test]
+    async fn read_line_reports_read_errors() {
+        let stream = ScriptedStream::new(&[b"one\ntw"]).then_error();
+        let mut reader = FrameReader::new(stream);
+        assert_eq!(&reader.read_line().await.unwrap().unwrap()[..], b"one");
+        let err = reader.read_line().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn frames_mix_lines_and_binary_data() {
+        let stream = ScriptedStream::new(&[b"PUT 3\n\x00\n\xff", b"next\n"]);
+        let mut reader = FrameReader::new(stream);
+        assert_eq!(&reader.read_line().await.unwrap().unwrap()[..], b"PUT 3");
+        assert_eq!(reader.peek(2).await.unwrap(), Some(&b"\x00\n"[..]));
+        assert_eq!(
+            &reader.read_exact(3).await.unwrap().unwrap()[..],
+            b"\x00\n\xff"
+        );
+        assert_eq!(&reader.read_line().await.unwrap().unwrap()[..], b"next");
+    }
+
+    #[tokio::test]
+    async fn frames_longer_than_limit_are_rejected() {
+        let stream = ScriptedStream::new(&[b"short\nlong line\n"]);
+        let mut reader = FrameReader::new(stream).with_max_len(8);
+        assert_eq!(&reader.read_line().await.unwrap().unwrap()[..], b"short");
+        let err = reader.read_line().await.unwrap_err();
+        assert_eq!(protocol_error(&err), Some(&ProtoError::FrameTooLarge(8)));
+
+        let stream = ScriptedStream::bytewise(b"0123456789");
+        let mut reader = FrameReader::new(stream).with_max_len(8);
+        assert_eq!(
+            &reader.read_exact(8).await.unwrap().unwrap()[..],
+            b"01234567"
+        );
+        let err = reader.read_exact(9).await.unwrap_err();
+        assert_eq!(protocol_error(&err), Some(&ProtoError::FrameTooLarge(8)));
+    }
+
+    #[tokio::test]
+    async fn cancelled_reads_end_the_stream() {
+        let (_client, stream) = io::duplex(64);
+        let cancel = CancellationToken::new();
+        let mut reader = FrameReader::new(stream).with_cancel(cancel.clone());
+        let pending = tokio::spawn(async move { reader.read_line().await.unwrap() });
+        time::sleep(Duration::from_millis(10)).await;
+        cancel.cancel();
+        assert_eq!(pending.await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn queued_writer_flushes_on_close() {
+        let (client, mut peer) = io::duplex(64);
+        let writer = QueuedWriter::new(client);
+        let clone = writer.clone();
+        assert!(writer.send(&b"one "[..]));
+        assert!(clone.send(&b"two"[..]));
+        drop((writer, clone));
+        let mut received = String::new();
+        peer.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, "one two");
+    }
+
+    #[tokio::test]
+    async fn queued_writer_notices_dead_peer() {
+        let (client, peer) = io::duplex(64);
+        let writer = QueuedWriter::new(client);
+        drop(peer);
+        writer.send(&b"lost"[..]);
+        writer.closed().cancelled().await;
+        assert!(!writer.send(&b"lost too"[..]));
+    }
+
+    #[test]
+    fn buffer_pool_reuses_returned_buffers() {
+        let pool = BufferPool::new();
+        let mut buffer = pool.lease();
+        assert!(buffer.capacity() >= BufferPool::BUFFER_SIZE);
+        buffer.extend_from_slice(b"data");
+        let address = buffer.as_ptr();
+        drop(buffer);
+        assert_eq!(pool.buffers.lock().unwrap().len(), 1);
+
+        let buffer = pool.lease();
+        assert_eq!(buffer.as_ptr(), address);
+        assert!(buffer.is_empty());
+        assert_eq!(pool.buffers.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn buffer_pool_frees_grown_buffers() {
+        let pool = BufferPool::new();
+        let mut buffer = pool.lease();
+        buffer.reserve(BufferPool::MAX_CAPACITY + 1);
+        drop(buffer);
+        assert_eq!(pool.buffers.lock().unwrap().len(), 0);
+
+        let buffers: Vec<_> = (0..BufferPool::MAX_BUFFERS + 10)
+            .map(|_| pool.lease())
+            .collect();
+        drop(buffers);
+        assert_eq!(pool.buffers.lock().unwrap().len(), BufferPool::MAX_BUFFERS);
+    }
+
+    #[test]
+    fn buffer_pool_takes_back_split_buffers() {
+        let pool = BufferPool::new();
+        let mut buffer = pool.lease();
+        buffer.extend_from_slice(b"line\n");
+        let frame = buffer.split_to(5);
+        drop(frame);
+        drop(buffer);
+        assert_eq!(pool.buffers.lock().unwrap().len(), 1);
+        assert!(pool.lease().capacity() >= BufferPool::BUFFER_SIZE);
+
+        // Memory still used by a frame is not the pool's to lend
+        let mut buffer = pool.lease();
+        buffer.extend_from_slice(b"line\n");
+        let _frame = buffer.split_to(5);
+        drop(buffer);
+        assert_eq!(pool.buffers.lock().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn read_exact_carries_remaining_bytes() {
+        let stream = ScriptedStream::new(&[b"abc", b"defgh", b"ij"]);
+        let mut reader = FrameReader::new(stream);
+        assert_eq!(&reader.read_exact(4).await.unwrap().unwrap()[..], b"abcd");
+        assert_eq!(reader.buffered(), b"efgh");
+        assert_eq!(&reader.read_exact(2).await.unwrap().unwrap()[..], b"ef");
+        assert_eq!(&reader.read_exact(4).await.unwrap().unwrap()[..], b"ghij");
+        assert!(reader.buffered().is_empty());
+        assert_eq!(reader.read_exact(1).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn read_exact_reads_byte_by_byte() {
+        let mut reader = FrameReader::new(ScriptedStream::bytewise(b"hello world"));
+        assert_eq!(&reader.read_exact(5).await.unwrap().unwrap()[..], b"hello");
+        assert_eq!(&reader.read_exact(6).await.unwrap().unwrap()[..], b" world");
+        assert_eq!(&reader.read_exact(0).await.unwrap().unwrap()[..], b"");
+    }
+
+    #[tokio::test]
+    async fn read_exact_fails_on_early_eof() {
+        let mut reader = FrameReader::new(ScriptedStream::new(&[b"abc"]));
+        assert_eq!(reader.read_exact(4).await.unwrap(), None);
+
+        let mut reader = FrameReader::new(ScriptedStream::new(&[b"abc"]).then_error());
+        assert!(reader.read_exact(4).await.is_err());
+    }
+
+    // Indices of the datagrams out of `nb_sent` that made it through
+    async fn flaky_deliveries(faults: Faults, seed: u64, nb_sent: u8) -> Vec<u8> {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = FlakySocket::new(sender, faults, seed);
+        for i in 0..nb_sent {
+            sender.send_to(&[i], addr).await.unwrap();
+        }
+
+        let mut received = Vec::new();
+        let mut buf = [0; 1];
+        let timeout = Duration::from_millis(100);
+        while let Ok(Ok(_)) = time::timeout(timeout, receiver.recv(&mut buf)).await {
+            received.push(buf[0]);
+        }
+        received.sort_unstable();
+        received
+    }
+
+    #[tokio::test]
+    async fn flaky_socket_is_reproducible() {
+        let faults = Faults {
+            loss: 0.3,
+            duplicate: 0.3,
+            ..Faults::default()
+        };
+        let received = flaky_deliveries(faults, 42, 100).await;
+        assert_eq!(flaky_deliveries(faults, 42, 100).await, received);
+        assert_ne!(flaky_deliveries(faults, 43, 100).await, received);
+
+        let mut unique = received.clone();
+        unique.dedup();
+        assert!(unique.len() < 100, "nothing lost");
+        assert!(unique.len() < received.len(), "nothing duplicated");
+    }
+
+    #[tokio::test]
+    async fn flaky_socket_without_faults_delivers_everything() {
+        let received = flaky_deliveries(Faults::default(), 42, 100).await;
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn udp_sender_paces_each_peer() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let sender = UdpSender::new(socket).with_rate(20.0);
+        let start = time::Instant::now();
+        for i in 0..3 {
+            assert!(sender.send(addr, vec![i]));
+        }
+
+        let mut buf = [0; 1];
+        for i in 0..3 {
+            receiver.recv(&mut buf).await.unwrap();
+            assert_eq!(buf[0], i);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        // The queue goes away once the last datagram is paced
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(sender.queues.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn udp_sender_rejects_degenerate_pacing() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        for rate in [0.0, -1.0, f64::NAN] {
+            let sender = UdpSender::new(socket.clone()).with_rate(rate);
+            assert_eq!(sender.interval, Duration::ZERO);
+        }
+
+        let sender = UdpSender::new(socket);
+        let sent = sender.send_expect_ack(addr, Bytes::from_static(b"x"), Duration::ZERO, 3);
+        assert!(sent.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn vectored_write_survives_partial_writes() {
+        // Only a few bytes fit in the pipe at once, splitting slices
+        let (mut writer, mut reader) = io::duplex(3);
+        let reading = tokio::spawn(async move {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await.unwrap();
+            data
+        });
+        let mut slices = [
+            IoSlice::new(b"head"),
+            IoSlice::new(b""),
+            IoSlice::new(b"payload"),
+            IoSlice::new(b"!"),
+        ];
+        write_all_vectored(&mut writer, &mut slices).await.unwrap();
+        drop(writer);
+        assert_eq!(reading.await.unwrap(), b"headpayload!");
+    }
+
+    #[tokio::test]
+    async fn connector_binds_local_address() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let connector = Connector {
+            local_addr: Some("127.0.0.2".parse().unwrap()),
+        };
+        let _stream = connector.connect(&addr).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip().to_string(), "127.0.0.2");
+
+        let connector = Connector {
+            local_addr: Some("::1".parse().unwrap()),
+        };
+        let err = connector.connect(&addr).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[tokio::test]
+    async fn socket_options_apply_to_accepted_streams() {
+        let listener = listen("127.0.0.1:0", true).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(SockRef::from(&listener).reuse_address().unwrap());
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(30)),
+            ..SocketOptions::default()
+        };
+        tune_stream(&stream, &options).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
 }
@@ -0,0 +1,58 @@
+use crate::ProtoError;
+
+// Checksum byte closing a frame, chosen so that all the bytes of the frame sum
+// to 0 modulo 256. Frames are given as chunks so that their parts do not need
+// to be gathered first
+
+// Byte to append to the chunks for the frame to be valid
+pub fn compute<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> u8 {
+    sum(chunks).wrapping_neg()
+}
+
+// Checks a frame ending with its checksum byte
+pub fn verify<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Result<(), ProtoError> {
+    match sum(chunks) {
+        0 => Ok(()),
+        sum => Err(ProtoError::InvalidChecksum(sum)),
+    }
+}
+
+fn sum<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> u8 {
+    chunks
+        .into_iter()
+        .flatten()
+        .fold(0u8, |acc, &v| acc.wrapping_add(v))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn computed_checksum_verifies(
+            data in prop::collection::vec(any::<u8>(), 0..300),
+            split in any::<prop::sample::Index>(),
+        ) {
+            let checksum = compute([&data[..]]);
+            let (head, tail) = data.split_at(split.index(data.len() + 1));
+            prop_assert_eq!(verify([head, tail, &[checksum]]), Ok(()));
+        }
+
+        #[test]
+        fn altered_byte_fails_verification(
+            data in prop::collection::vec(any::<u8>(), 1..300),
+            index in any::<prop::sample::Index>(),
+            delta in 1..=255u8,
+        ) {
+            let mut frame = data.clone();
+            frame.push(compute([&data[..]]));
+            let index = index.index(frame.len());
+            frame[index] = frame[index].wrapping_add(delta);
+            let sum = Err(ProtoError::InvalidChecksum(delta));
+            prop_assert_eq!(verify([&frame[..]]), sum);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use serde::de::DeserializeOwned;
+use tokio::io::AsyncRead;
+
+use super::{FrameReader, protocol_error};
+use crate::ProtoError;
+
+// One JSON message per line. Lines that are not valid UTF-8 or JSON are
+// reported and skipped, while an oversized line or a read error ends the
+// stream after being reported, as the line boundaries are lost
+pub struct JsonLines<R> {
+    reader: FrameReader<R>,
+    closed: bool,
+}
+
+impl<R: AsyncRead + Unpin> JsonLines<R> {
+    pub fn new(reader: FrameReader<R>) -> Self {
+        Self {
+            reader,
+            closed: false,
+        }
+    }
+
+    pub async fn next<T: DeserializeOwned>(&mut self) -> Option<Result<T, ProtoError>> {
+        if self.closed {
+            return None;
+        }
+        let line = match self.reader.read_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return None,
+            Err(err) => {
+                self.closed = true;
+                return protocol_error(&err).cloned().map(Err);
+            }
+        };
+        let Ok(line) = str::from_utf8(&line) else {
+            return Some(Err(ProtoError::InvalidUtf8));
+        };
+        let parsed = serde_json::from_str(line);
+        Some(parsed.map_err(|err| ProtoError::InvalidJson(err.to_string())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn invalid_lines_are_reported_and_skipped() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut lines = JsonLines::new(FrameReader::new(server).with_max_len(32));
+        client
+            .write_all(b"{\"n\": 1}\n{\"n\n\xff\n[1,\n2]\n")
+            .await
+            .unwrap();
+        client.write_all(&[b' '; 40]).await.unwrap();
+
+        assert_eq!(lines.next::<Value>().await, Some(Ok(json!({"n": 1}))));
+        assert!(matches!(
+            lines.next::<Value>().await,
+            Some(Err(ProtoError::InvalidJson(_)))
+        ));
+        assert_eq!(
+            lines.next::<Value>().await,
+            Some(Err(ProtoError::InvalidUtf8))
+        );
+        assert!(matches!(
+            lines.next::<Value>().await,
+            Some(Err(ProtoError::InvalidJson(_)))
+        ));
+        assert!(matches!(
+            lines.next::<Value>().await,
+            Some(Err(ProtoError::InvalidJson(_)))
+        ));
+        assert_eq!(
+            lines.next::<Value>().await,
+            Some(Err(ProtoError::FrameTooLarge(32)))
+        );
+        assert_eq!(lines.next::<Value>().await, None);
+    }
+}
@@ -0,0 +1,158 @@
+use bytes::{BufMut, BytesMut};
+
+use crate::ProtoError;
+
+// Size of the length in front of a string
+#[derive(Clone, Copy)]
+pub enum LengthPrefix {
+    U8,
+    U32,
+}
+
+// Decodes the big-endian fields of a frame, failing with the offset of the
+// first field that does not fit in it
+pub struct WireReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> WireReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ProtoError> {
+        Ok(self.take(1, "u8")?[0])
+    }
+
+    pub fn read_u16_be(&mut self) -> Result<u16, ProtoError> {
+        let bytes = self.take(2, "u16")?;
+        Ok(u16::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_u32_be(&mut self) -> Result<u32, ProtoError> {
+        let bytes = self.take(4, "u32")?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    // Invalid UTF-8 is replaced rather than rejected
+    pub fn read_lp_string(&mut self, prefix: LengthPrefix) -> Result<String, ProtoError> {
+        let str_len = match prefix {
+            LengthPrefix::U8 => self.read_u8()? as usize,
+            LengthPrefix::U32 => self.read_u32_be()? as usize,
+        };
+        let bytes = self.take(str_len, "str")?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    // Fails if the frame holds more than what was read, `what` naming it
+    pub fn finish(self, what: &'static str) -> Result<(), ProtoError> {
+        if self.offset != self.data.len() {
+            return Err(ProtoError::TrailingData(what));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, nb_bytes: usize, what: &'static str) -> Result<&'a [u8], ProtoError> {
+        let Some(bytes) = self.data.get(self.offset..self.offset + nb_bytes) else {
+            return Err(ProtoError::Truncated {
+                what,
+                offset: self.offset,
+            });
+        };
+        self.offset += nb_bytes;
+        Ok(bytes)
+    }
+}
+
+// Encodes big-endian fields one after the other
+pub struct WireWriter {
+    bytes: BytesMut,
+}
+
+impl WireWriter {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            bytes: BytesMut::with_capacity(capacity),
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.put_slice(bytes);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.put_u8(value);
+    }
+
+    pub fn write_u16_be(&mut self, value: u16) {
+        self.bytes.put_u16(value);
+    }
+
+    pub fn write_u32_be(&mut self, value: u32) {
+        self.bytes.put_u32(value);
+    }
+
+    // Strings too long for their prefix are a bug of the caller
+    pub fn write_lp_string(&mut self, prefix: LengthPrefix, value: &str) {
+        match prefix {
+            LengthPrefix::U8 => self.write_u8(u8::try_from(value.len()).unwrap()),
+            LengthPrefix::U32 => self.write_u32_be(u32::try_from(value.len()).unwrap()),
+        }
+        self.write_bytes(value.as_bytes());
+    }
+
+    pub fn into_bytes(self) -> BytesMut {
+        self.bytes
+    }
+}
+
+// Length of a string once written with its prefix
+pub fn lp_string_len(prefix: LengthPrefix, value: &str) -> usize {
+    let prefix_len = match prefix {
+        LengthPrefix::U8 => 1,
+        LengthPrefix::U32 => 4,
+    };
+    prefix_len + value.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_round_trip() {
+        let mut writer = WireWriter::with_capacity(32);
+        writer.write_u8(7);
+        writer.write_u16_be(0x1234);
+        writer.write_u32_be(0xdead_beef);
+        writer.write_lp_string(LengthPrefix::U8, "UN1X");
+        writer.write_lp_string(LengthPrefix::U32, "dog");
+        let bytes = writer.into_bytes();
+        assert_eq!(&bytes[1..3], [0x12, 0x34]);
+
+        let mut reader = WireReader::new(&bytes);
+        assert_eq!(reader.read_u8(), Ok(7));
+        assert_eq!(reader.read_u16_be(), Ok(0x1234));
+        assert_eq!(reader.read_u32_be(), Ok(0xdead_beef));
+        assert_eq!(reader.read_lp_string(LengthPrefix::U8).unwrap(), "UN1X");
+        assert_eq!(reader.read_lp_string(LengthPrefix::U32).unwrap(), "dog");
+        assert_eq!(reader.finish("test"), Ok(()));
+    }
+
+    #[test]
+    fn short_or_long_frames_are_rejected() {
+        let mut reader = WireReader::new(&[0, 0, 0, 5, b'a']);
+        assert_eq!(
+            reader.read_lp_string(LengthPrefix::U32),
+            Err(ProtoError::Truncated {
+                what: "str",
+                offset: 4
+            })
+        );
+
+        let mut reader = WireReader::new(&[1, 2, 3]);
+        assert_eq!(reader.read_u16_be(), Ok(0x0102));
+        assert_eq!(reader.finish("test"), Err(ProtoError::TrailingData("test")));
+    }
+}
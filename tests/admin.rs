@@ -0,0 +1,163 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use proto_hackers::admin::{self, Admin};
+use proto_hackers::clients::{ChatClient, Connection, SiteVisitor};
+use proto_hackers::{Config, MockAuthority, RunOptions, run_challenge, testing};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tokio::time;
+
+async fn command(addr: SocketAddr, command: &str) -> Value {
+    let mut admin = Connection::connect(addr).await.unwrap();
+    admin.send_line(command).await.unwrap();
+    serde_json::from_str(&admin.recv_line().await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn state_reports_chat_members() {
+    let server = testing::spawn(3).await.unwrap();
+    let admin_addr = server.spawn_admin().await.unwrap();
+    assert_eq!(command(admin_addr, "state").await, json!({"members": 0}));
+
+    let _alice = ChatClient::join(server.addr, "alice").await.unwrap();
+    let _bob = ChatClient::join(server.addr, "bob").await.unwrap();
+    assert_eq!(command(admin_addr, "state").await, json!({"members": 2}));
+}
+
+#[tokio::test]
+async fn state_reports_pestcontrol_policies() {
+    let (authority_addr, _authority) = MockAuthority::new()
+        .with_site(7, &[("fox", 0, 1), ("rat", 2, 4)])
+        .spawn("127.0.0.1:0")
+        .await
+        .unwrap();
+    let mut config = Config::default();
+    config.pestcontrol.authority_addr = authority_addr.to_string();
+    let server = testing::spawn_with_config(11, &config).await.unwrap();
+    let admin_addr = server.spawn_admin().await.unwrap();
+
+    let mut visitor = SiteVisitor::connect(server.addr).await.unwrap();
+    visitor.visit(7, &[("fox", 3)]).await.unwrap();
+
+    // Policies are created in the background by the site worker
+    let expected = json!({"sites": 1, "policies": 2});
+    let mut state = Value::Null;
+    for _ in 0..50 {
+        state = command(admin_addr, "state").await;
+        if state == expected {
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(state, expected);
+}
+
+#[tokio::test]
+async fn unknown_commands_are_reported() {
+    let server = testing::spawn(0).await.unwrap();
+    let admin_addr = server.spawn_admin().await.unwrap();
+    assert_eq!(
+        command(admin_addr, "reboot").await,
+        json!({"error": "unknown command"})
+    );
+}
+
+#[tokio::test]
+async fn site_dumps_pestcontrol_state() {
+    let (authority_addr, _authority) = MockAuthority::new()
+        .with_site(7, &[("fox", 0, 1), ("rat", 2, 4)])
+        .spawn("127.0.0.1:0")
+        .await
+        .unwrap();
+    let mut config = Config::default();
+    config.pestcontrol.authority_addr = authority_addr.to_string();
+    let server = testing::spawn_with_config(11, &config).await.unwrap();
+    let admin_addr = server.spawn_admin().await.unwrap();
+    assert_eq!(
+        command(admin_addr, "site 7").await,
+        json!({"error": "unknown site"})
+    );
+
+    let mut visitor = SiteVisitor::connect(server.addr).await.unwrap();
+    visitor.visit(7, &[("fox", 3)]).await.unwrap();
+
+    let mut dump = Value::Null;
+    for _ in 0..50 {
+        dump = command(admin_addr, "site 7").await;
+        if dump["policies"].as_array().is_some_and(|p| p.len() == 2) {
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        dump["targets"],
+        json!({"fox": {"min": 0, "max": 1}, "rat": {"min": 2, "max": 4}})
+    );
+    let policies: Vec<_> = dump["policies"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|policy| (policy["species"].clone(), policy["action"].clone()))
+        .collect();
+    assert_eq!(
+        policies,
+        [
+            (json!("fox"), json!("cull")),
+            (json!("rat"), json!("conserve"))
+        ]
+    );
+    assert!(dump["policies"][0]["id"].is_u64());
+    assert_eq!(
+        dump["connection"],
+        json!({"connected": true, "circuit_open": false, "failures": 0})
+    );
+    assert_eq!(
+        command(admin_addr, "site seven").await,
+        json!({"error": "invalid site id"})
+    );
+}
+
+#[tokio::test]
+async fn switch_replaces_running_challenge() {
+    let running = run_challenge(0, RunOptions::default()).await.unwrap();
+    let addr = running.addr;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_addr = listener.local_addr().unwrap();
+    let admin = Admin::switchable(running, Config::default());
+    tokio::spawn(admin::serve(listener, Arc::new(admin)));
+
+    let mut echo = Connection::connect(addr).await.unwrap();
+    assert_eq!(
+        command(admin_addr, "switch 99").await,
+        json!({"error": "invalid challenge number 99"})
+    );
+    assert_eq!(
+        command(admin_addr, "switch 1").await,
+        json!({"challenge": 1, "addr": addr.to_string()})
+    );
+
+    // Clients of the previous challenge are still served
+    echo.send_line("still there").await.unwrap();
+    assert_eq!(echo.recv_line().await.unwrap(), "still there");
+    let mut prime = Connection::connect(addr).await.unwrap();
+    prime
+        .send_line(r#"{"method":"isPrime","number":7}"#)
+        .await
+        .unwrap();
+    assert_eq!(
+        prime.recv_line().await.unwrap(),
+        r#"{"method":"isPrime","prime":true}"#
+    );
+}
+
+#[tokio::test]
+async fn switch_needs_ownership_of_the_server() {
+    let server = testing::spawn(0).await.unwrap();
+    let admin_addr = server.spawn_admin().await.unwrap();
+    assert_eq!(
+        command(admin_addr, "switch 1").await,
+        json!({"error": "challenge switching not available"})
+    );
+}
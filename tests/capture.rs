@@ -0,0 +1,83 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use proto_hackers::{Config, testing};
+use tokio::time;
+
+fn capture_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("proto_hackers-{}-{name}.pcap", std::process::id()))
+}
+
+// Raw IPv4 packets of a pcap file
+fn read_packets(path: &Path) -> Vec<Vec<u8>> {
+    let data = fs::read(path).unwrap();
+    assert_eq!(data[..4], 0xa1b2c3d4u32.to_le_bytes());
+    assert_eq!(data[20..24], 101u32.to_le_bytes());
+
+    let mut packets = Vec::new();
+    let mut records = &data[24..];
+    while records.len() >= 16 {
+        let len = u32::from_le_bytes(records[8..12].try_into().unwrap()) as usize;
+        packets.push(records[16..16 + len].to_vec());
+        records = &records[16 + len..];
+    }
+    packets
+}
+
+async fn wait_for_packets(path: &Path, nb_packets: usize) -> Vec<Vec<u8>> {
+    for _ in 0..50 {
+        let packets = read_packets(path);
+        if packets.len() >= nb_packets {
+            return packets;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    read_packets(path)
+}
+
+#[tokio::test]
+async fn capture_tcp_connection() {
+    let path = capture_path("tcp");
+    let config = Config {
+        pcap_path: Some(path.clone()),
+        ..Config::default()
+    };
+    let server = testing::spawn_with_config(0, &config).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send(b"hello").await.unwrap();
+    assert_eq!(client.recv_exact(5).await.unwrap(), b"hello");
+    client.shutdown().await.unwrap();
+    assert!(client.is_closed().await);
+
+    // Handshake, data in both directions, then FIN from both sides
+    let packets = wait_for_packets(&path, 8).await;
+    fs::remove_file(&path).unwrap();
+    assert_eq!(packets.len(), 8);
+    let flags: Vec<u8> = packets.iter().map(|packet| packet[33]).collect();
+    assert_eq!(flags, [0x02, 0x12, 0x10, 0x18, 0x18, 0x11, 0x11, 0x10]);
+    assert_eq!(packets[3][40..], *b"hello");
+    assert_eq!(packets[4][40..], *b"hello");
+    assert_eq!(packets[3][20..22], packets[4][22..24]);
+}
+
+#[tokio::test]
+async fn capture_udp_datagrams() {
+    let path = capture_path("udp");
+    let config = Config {
+        pcap_path: Some(path.clone()),
+        ..Config::default()
+    };
+    let server = testing::spawn_with_config(4, &config).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    client.send(b"foo=bar").await.unwrap();
+    client.send(b"foo").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"foo=bar");
+
+    let packets = wait_for_packets(&path, 3).await;
+    fs::remove_file(&path).unwrap();
+    let payloads: Vec<&[u8]> = packets.iter().map(|packet| &packet[28..]).collect();
+    assert_eq!(payloads, [&b"foo=bar"[..], b"foo", b"foo=bar"]);
+    assert!(packets.iter().all(|packet| packet[9] == 17));
+}
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use proto_hackers::testing::{self, UdpClient};
+use proto_hackers::{ChaosConfig, Config, Faults};
+use tokio::time::Instant;
+
+const FAULTS: Faults = Faults {
+    loss: 0.2,
+    duplicate: 0.2,
+    reorder: 0.2,
+    max_delay: Duration::from_millis(20),
+};
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_ATTEMPTS: usize = 100;
+
+// Nothing is acknowledged, so keep inserting until the value can be read back
+#[tokio::test]
+async fn key_value_store_tolerates_chaos() {
+    let server = testing::spawn(4).await.unwrap();
+    let client = server
+        .flaky_udp_client(FAULTS, testing::seed())
+        .await
+        .unwrap();
+
+    let mut attempts = 0;
+    loop {
+        client.send(b"foo=bar").await.unwrap();
+        client.send(b"foo").await.unwrap();
+        if let Some(response) = client.recv_timeout(POLL_TIMEOUT).await {
+            assert_eq!(response, b"foo=bar");
+            break;
+        }
+        attempts += 1;
+        assert!(attempts < MAX_ATTEMPTS, "value never retrieved");
+    }
+
+    // Retrieving a missing key is ignored, so any answer must be for `foo`
+    while let Some(response) = client.recv_timeout(POLL_TIMEOUT).await {
+        assert_eq!(response, b"foo=bar");
+    }
+}
+
+fn parse_data(msg: &str, session: &str) -> Option<(usize, String)> {
+    let msg = msg.strip_prefix(&format!("/data/{session}/"))?;
+    let (pos, data) = msg.strip_suffix('/')?.split_once('/')?;
+    Some((pos.parse().ok()?, data.to_owned()))
+}
+
+// Minimal LRCP client, retransmitting until everything sent is acknowledged
+// and reassembling the server data whatever the order it arrives in
+async fn lrcp_exchange(client: &UdpClient, session: &str, data: &str) -> String {
+    let mut connected = false;
+    let mut sent_acked = false;
+    let mut received = String::new();
+
+    for _ in 0..MAX_ATTEMPTS {
+        if !connected {
+            client
+                .send(format!("/connect/{session}/").as_bytes())
+                .await
+                .unwrap();
+        } else if !sent_acked {
+            let msg = format!("/data/{session}/0/{data}/");
+            client.send(msg.as_bytes()).await.unwrap();
+        }
+
+        while let Some(msg) = client.recv_timeout(POLL_TIMEOUT).await {
+            let msg = String::from_utf8(msg).unwrap();
+            if msg == format!("/ack/{session}/0/") {
+                connected = true;
+            } else if msg == format!("/ack/{session}/{}/", data.len()) {
+                connected = true;
+                sent_acked = true;
+            } else if let Some((pos, chunk)) = parse_data(&msg, session) {
+                if pos <= received.len() && pos + chunk.len() > received.len() {
+                    received.push_str(&chunk[received.len() - pos..]);
+                }
+                let ack = format!("/ack/{session}/{}/", received.len());
+                client.send(ack.as_bytes()).await.unwrap();
+            }
+        }
+
+        if sent_acked && received.len() == data.len() {
+            return received;
+        }
+    }
+    panic!("LRCP exchange did not complete, received {received:?}");
+}
+
+#[tokio::test]
+async fn lrcp_tolerates_chaos() {
+    let server = testing::spawn(7).await.unwrap();
+    let client = server
+        .flaky_udp_client(FAULTS, testing::seed())
+        .await
+        .unwrap();
+    let received = lrcp_exchange(&client, "1234", "hello\nworld\n").await;
+    assert_eq!(received, "olleh\ndlrow\n");
+}
+
+fn server_chaos(chaos: ChaosConfig) -> Config {
+    Config {
+        chaos: ChaosConfig {
+            seed: Some(testing::seed()),
+            ..chaos
+        },
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn lrcp_tolerates_server_side_chaos() {
+    let chaos = ChaosConfig {
+        jitter: Duration::from_millis(20),
+        loss: 0.2,
+        reorder: 0.2,
+        ..ChaosConfig::default()
+    };
+    let server = testing::spawn_with_config(7, &server_chaos(chaos))
+        .await
+        .unwrap();
+    let client = server.udp_client().await.unwrap();
+    let received = lrcp_exchange(&client, "5678", "hello\nworld\n").await;
+    assert_eq!(received, "olleh\ndlrow\n");
+}
+
+#[tokio::test]
+async fn tcp_latency_applies_both_ways() {
+    let chaos = ChaosConfig {
+        latency: Duration::from_millis(50),
+        ..ChaosConfig::default()
+    };
+    let server = testing::spawn_with_config(0, &server_chaos(chaos))
+        .await
+        .unwrap();
+    let mut client = server.connect().await.unwrap();
+    let start = Instant::now();
+    client.send(b"hello\n").await.unwrap();
+    assert_eq!(client.recv_line().await.unwrap(), "hello");
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
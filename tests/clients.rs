@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use proto_hackers::clients::{
+    Camera, ChatClient, Dispatcher, JobClient, KvClient, MeansClient, PrimeClient, SiteVisitor,
+    Ticket, VcsClient,
+};
+use proto_hackers::testing;
+use proto_hackers::{Config, MockAuthority};
+use serde_json::json;
+
+#[tokio::test]
+async fn prime_client() {
+    let server = testing::spawn(1).await.unwrap();
+    let mut client = PrimeClient::connect(server.addr).await.unwrap();
+    assert_eq!(client.is_prime(7).await, Some(true));
+    assert_eq!(client.is_prime(8).await, Some(false));
+}
+
+#[tokio::test]
+async fn means_client() {
+    let server = testing::spawn(2).await.unwrap();
+    let mut client = MeansClient::connect(server.addr).await.unwrap();
+    client.insert(10, 100).await.unwrap();
+    client.insert(20, 200).await.unwrap();
+    assert_eq!(client.query(0, 30).await, Some(150));
+}
+
+#[tokio::test]
+async fn chat_client() {
+    let server = testing::spawn(3).await.unwrap();
+    let mut alice = ChatClient::join(server.addr, "alice").await.unwrap();
+    assert!(alice.members().is_empty());
+    let mut bob = ChatClient::join(server.addr, "bob").await.unwrap();
+    assert_eq!(bob.members(), ["alice"]);
+    assert_eq!(alice.recv().await.unwrap(), "* bob has entered the room");
+
+    bob.send("hello").await.unwrap();
+    assert_eq!(alice.recv().await.unwrap(), "[bob] hello");
+}
+
+#[tokio::test]
+async fn kv_client() {
+    let server = testing::spawn(4).await.unwrap();
+    let client = KvClient::connect(server.addr).await.unwrap();
+    client.insert("foo", "bar").await.unwrap();
+    assert_eq!(client.retrieve("foo").await.unwrap(), "bar");
+}
+
+#[tokio::test]
+async fn speed_daemon_clients() {
+    let server = testing::spawn(6).await.unwrap();
+    let mut first = Camera::connect(server.addr, 42, 8, 60).await.unwrap();
+    let mut second = Camera::connect(server.addr, 42, 9, 60).await.unwrap();
+    first.plate("UN1X", 0).await.unwrap();
+    second.plate("UN1X", 45).await.unwrap();
+
+    let mut dispatcher = Dispatcher::connect(server.addr, &[42]).await.unwrap();
+    assert_eq!(
+        dispatcher.recv_ticket().await.unwrap(),
+        Ticket {
+            plate: "UN1X".to_owned(),
+            road: 42,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 9,
+            timestamp2: 45,
+            speed: 8000,
+        }
+    );
+}
+
+#[tokio::test]
+async fn job_client() {
+    let server = testing::spawn(9).await.unwrap();
+    let mut client = JobClient::connect(server.addr).await.unwrap();
+    let id = client.put("queue", json!({"task": 1}), 10).await.unwrap();
+
+    let job = client.get(&["queue"], false).await.unwrap().unwrap();
+    assert_eq!(job.id, id);
+    assert_eq!(job.priority, 10);
+    assert_eq!(job.task, json!({"task": 1}));
+    assert_eq!(client.delete(id).await, Some(true));
+    assert_eq!(client.get(&["queue"], false).await, Some(None));
+}
+
+#[tokio::test]
+async fn vcs_client() {
+    let server = testing::spawn(10).await.unwrap();
+    let mut client = VcsClient::connect(server.addr).await.unwrap();
+    assert_eq!(client.put("/dir/file", b"one\n").await, Some(Ok(1)));
+    assert_eq!(client.put("/dir/file", b"two\n").await, Some(Ok(2)));
+    assert_eq!(
+        client.get("/dir/file", Some(1)).await,
+        Some(Ok(b"one\n".to_vec()))
+    );
+    assert!(client.get("/missing", None).await.unwrap().is_err());
+    assert_eq!(
+        client.list("/").await,
+        Some(Ok(vec!["dir/ DIR".to_owned()]))
+    );
+}
+
+#[tokio::test]
+async fn site_visitor() {
+    let (authority_addr, authority) = MockAuthority::new()
+        .with_site(7, &[("fox", 0, 1)])
+        .spawn("127.0.0.1:0")
+        .await
+        .unwrap();
+    let mut config = Config::default();
+    config.pestcontrol.authority_addr = authority_addr.to_string();
+    let server = testing::spawn_with_config(11, &config).await.unwrap();
+
+    let mut visitor = SiteVisitor::connect(server.addr).await.unwrap();
+    visitor.visit(7, &[("fox", 3)]).await.unwrap();
+
+    let mut policies = Vec::new();
+    for _ in 0..50 {
+        policies = authority.policies(7).await;
+        if !policies.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(policies, vec![(1, "fox".to_owned(), 0x90)]);
+}
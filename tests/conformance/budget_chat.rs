@@ -0,0 +1,62 @@
+use proto_hackers::clients::ChatClient;
+use proto_hackers::testing;
+
+use crate::SETTLE_TIME;
+
+#[tokio::test]
+async fn presence_notifications() {
+    let server = testing::spawn(3).await.unwrap();
+    let mut alice = ChatClient::join(server.addr, "alice").await.unwrap();
+    let bob = ChatClient::join(server.addr, "bob").await.unwrap();
+    assert_eq!(bob.members(), ["alice"]);
+    assert_eq!(alice.recv().await.unwrap(), "* bob has entered the room");
+
+    drop(bob);
+    assert_eq!(alice.recv().await.unwrap(), "* bob has left the room");
+}
+
+#[tokio::test]
+async fn messages_are_not_echoed_to_sender() {
+    let server = testing::spawn(3).await.unwrap();
+    let mut alice = ChatClient::join(server.addr, "alice").await.unwrap();
+    let mut bob = ChatClient::join(server.addr, "bob").await.unwrap();
+    alice.recv().await.unwrap();
+
+    alice.send("hi bob").await.unwrap();
+    bob.send("hi alice").await.unwrap();
+    assert_eq!(bob.recv().await.unwrap(), "[alice] hi bob");
+    assert_eq!(alice.recv().await.unwrap(), "[bob] hi alice");
+
+    bob.connection().set_timeout(Some(SETTLE_TIME));
+    assert_eq!(bob.recv().await, None);
+}
+
+#[tokio::test]
+async fn unjoined_clients_are_invisible() {
+    let server = testing::spawn(3).await.unwrap();
+    let mut alice = ChatClient::join(server.addr, "alice").await.unwrap();
+    let mut lurker = server.connect().await.unwrap();
+    lurker.recv_line().await.unwrap();
+
+    let bob = ChatClient::join(server.addr, "bob").await.unwrap();
+    assert_eq!(bob.members(), ["alice"]);
+    assert_eq!(alice.recv().await.unwrap(), "* bob has entered the room");
+
+    lurker.set_timeout(Some(SETTLE_TIME));
+    assert_eq!(lurker.recv_line().await, None);
+    drop(lurker);
+    alice.connection().set_timeout(Some(SETTLE_TIME));
+    assert_eq!(alice.recv().await, None);
+}
+
+#[tokio::test]
+async fn illegal_names_are_rejected() {
+    let server = testing::spawn(3).await.unwrap();
+    for name in ["", "bad name", "bad!"] {
+        let mut client = server.connect().await.unwrap();
+        client.recv_line().await.unwrap();
+        client.send_line(name).await.unwrap();
+        while client.recv_line().await.is_some() {}
+        assert!(client.is_closed().await, "{name:?}");
+    }
+}
@@ -0,0 +1,37 @@
+use proto_hackers::testing;
+
+use crate::SETTLE_TIME;
+
+#[tokio::test]
+async fn applies_spec_example() {
+    let server = testing::spawn(8).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    // xor(123),addpos,reversebits
+    client.send(&[0x02, 0x7b, 0x05, 0x01, 0x00]).await.unwrap();
+    client
+        .send(&[
+            0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e,
+        ])
+        .await
+        .unwrap();
+    assert_eq!(
+        client.recv_exact(7).await.unwrap(),
+        [0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee]
+    );
+}
+
+#[tokio::test]
+async fn disconnects_on_noop_cipher() {
+    let server = testing::spawn(8).await.unwrap();
+    for spec in [
+        &[0x00][..],
+        &[0x02, 0x00, 0x00],
+        &[0x02, 0xab, 0x02, 0xab, 0x00],
+    ] {
+        let mut client = server.connect().await.unwrap();
+        client.set_timeout(Some(SETTLE_TIME));
+        client.send(spec).await.unwrap();
+        client.send(b"1x dog\n").await.ok();
+        assert!(client.is_closed().await, "{spec:?}");
+    }
+}
@@ -0,0 +1,96 @@
+use proto_hackers::clients::JobClient;
+use proto_hackers::testing::{self, TestClient};
+use serde_json::{Value, json};
+use tokio::time;
+
+use crate::SETTLE_TIME;
+
+async fn recv_json(client: &mut TestClient) -> Value {
+    let line = client.recv_line().await.expect("no response");
+    serde_json::from_str(&line).expect("invalid JSON response")
+}
+
+#[tokio::test]
+async fn highest_priority_job_first() {
+    let server = testing::spawn(9).await.unwrap();
+    let mut client = JobClient::connect(server.addr).await.unwrap();
+    client.put("q1", json!(1), 10).await.unwrap();
+    let high = client.put("q2", json!(2), 20).await.unwrap();
+    let job = client.get(&["q1", "q2"], false).await.unwrap().unwrap();
+    assert_eq!((job.id, job.queue.as_str()), (high, "q2"));
+}
+
+#[tokio::test]
+async fn aborted_jobs_return_to_their_queue() {
+    let server = testing::spawn(9).await.unwrap();
+    let mut worker = JobClient::connect(server.addr).await.unwrap();
+    let mut other = JobClient::connect(server.addr).await.unwrap();
+    let id = worker.put("q", json!("task"), 1).await.unwrap();
+    worker.get(&["q"], false).await.unwrap().unwrap();
+
+    // Only the client working on a job may abort it
+    assert_ne!(other.abort(id).await, Some(true));
+    assert_eq!(other.get(&["q"], false).await, Some(None));
+
+    assert_eq!(worker.abort(id).await, Some(true));
+    assert_eq!(other.get(&["q"], false).await.unwrap().unwrap().id, id);
+}
+
+#[tokio::test]
+async fn disconnecting_aborts_jobs() {
+    let server = testing::spawn(9).await.unwrap();
+    let mut worker = JobClient::connect(server.addr).await.unwrap();
+    let mut other = JobClient::connect(server.addr).await.unwrap();
+    let id = worker.put("q", json!("task"), 1).await.unwrap();
+    worker.get(&["q"], false).await.unwrap().unwrap();
+
+    drop(worker);
+    time::sleep(SETTLE_TIME).await;
+    assert_eq!(other.get(&["q"], false).await.unwrap().unwrap().id, id);
+}
+
+#[tokio::test]
+async fn deleted_jobs_are_gone() {
+    let server = testing::spawn(9).await.unwrap();
+    let mut worker = JobClient::connect(server.addr).await.unwrap();
+    let mut other = JobClient::connect(server.addr).await.unwrap();
+    let id = worker.put("q", json!("task"), 1).await.unwrap();
+    worker.get(&["q"], false).await.unwrap().unwrap();
+
+    assert_eq!(other.delete(id).await, Some(true));
+    assert_eq!(other.delete(id).await, Some(false));
+    assert_ne!(worker.abort(id).await, Some(true));
+}
+
+#[tokio::test]
+async fn waiting_get_is_served_by_later_put() {
+    let server = testing::spawn(9).await.unwrap();
+    let mut waiter = server.connect().await.unwrap();
+    waiter
+        .send_line(r#"{"request":"get","queues":["q"],"wait":true}"#)
+        .await
+        .unwrap();
+    time::sleep(SETTLE_TIME).await;
+
+    let mut client = JobClient::connect(server.addr).await.unwrap();
+    let id = client.put("q", json!("task"), 3).await.unwrap();
+    let response = recv_json(&mut waiter).await;
+    assert_eq!(
+        (response["status"].clone(), response["id"].clone()),
+        (json!("ok"), json!(id))
+    );
+}
+
+#[tokio::test]
+async fn invalid_requests_are_errors() {
+    let server = testing::spawn(9).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    for request in [
+        "not json",
+        r#"{"request":"nope"}"#,
+        r#"{"request":"put","queue":"q"}"#,
+    ] {
+        client.send_line(request).await.unwrap();
+        assert_eq!(recv_json(&mut client).await["status"], "error", "{request}");
+    }
+}
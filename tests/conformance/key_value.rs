@@ -0,0 +1,25 @@
+use proto_hackers::clients::KvClient;
+use proto_hackers::testing;
+
+#[tokio::test]
+async fn version_cannot_be_modified() {
+    let server = testing::spawn(4).await.unwrap();
+    let client = KvClient::connect(server.addr).await.unwrap();
+    let version = client.retrieve("version").await.unwrap();
+    assert!(!version.is_empty());
+
+    client.insert("version", "hacked").await.unwrap();
+    assert_eq!(client.retrieve("version").await.unwrap(), version);
+}
+
+#[tokio::test]
+async fn values_are_split_on_first_equal_sign() {
+    let server = testing::spawn(4).await.unwrap();
+    let client = KvClient::connect(server.addr).await.unwrap();
+    client.insert("foo", "bar=baz").await.unwrap();
+    assert_eq!(client.retrieve("foo").await.unwrap(), "bar=baz");
+    client.insert("foo", "").await.unwrap();
+    assert_eq!(client.retrieve("foo").await.unwrap(), "");
+    client.insert("", "empty key").await.unwrap();
+    assert_eq!(client.retrieve("").await.unwrap(), "empty key");
+}
@@ -0,0 +1,51 @@
+use proto_hackers::testing;
+
+#[tokio::test]
+async fn reverses_lines_across_packets() {
+    let server = testing::spawn(7).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    client.send(b"/connect/7/").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"/ack/7/0/");
+
+    client.send(b"/data/7/0/foo/").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"/ack/7/3/");
+    client.send(b"/data/7/3/bar\\/\n/").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"/ack/7/8/");
+    assert_eq!(client.recv().await.unwrap(), b"/data/7/0/\\/raboof\n/");
+    client.send(b"/ack/7/8/").await.unwrap();
+}
+
+#[tokio::test]
+async fn unknown_session_is_closed() {
+    let server = testing::spawn(7).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    client.send(b"/data/99/0/hello/").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"/close/99/");
+    client.send(b"/ack/99/0/").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"/close/99/");
+}
+
+#[tokio::test]
+async fn out_of_order_data_is_reacked() {
+    let server = testing::spawn(7).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    client.send(b"/connect/5/").await.unwrap();
+    client.recv().await.unwrap();
+    client.send(b"/data/5/3/late/").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"/ack/5/0/");
+}
+
+#[tokio::test]
+async fn illegal_packets_are_ignored() {
+    let server = testing::spawn(7).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    for packet in [
+        &b"/connect/"[..],
+        b"/data/1/0/a/b/",
+        b"connect/1/",
+        b"/ack/1/x/",
+    ] {
+        client.send(packet).await.unwrap();
+    }
+    assert_eq!(client.recv_timeout(crate::SETTLE_TIME).await, None);
+}
@@ -0,0 +1,31 @@
+// Observable requirements from each Protohackers problem statement, checked
+// against the local servers. Run with `cargo test --features conformance`
+use std::time::Duration;
+
+#[cfg(feature = "server_03")]
+mod budget_chat;
+#[cfg(feature = "server_08")]
+mod insecure_sockets;
+#[cfg(feature = "server_09")]
+mod job_centre;
+#[cfg(feature = "server_04")]
+mod key_value;
+#[cfg(feature = "server_07")]
+mod lrcp;
+#[cfg(feature = "server_02")]
+mod means;
+#[cfg(feature = "server_11")]
+mod pestcontrol;
+#[cfg(feature = "server_01")]
+mod prime;
+#[cfg(feature = "server_00")]
+mod smoke;
+#[cfg(feature = "server_06")]
+mod speed_daemon;
+#[cfg(feature = "server_10")]
+mod vcs;
+
+// Time given to the server to (not) do something before checking, unused
+// when none of the challenges waiting on it are compiled
+#[allow(dead_code)]
+const SETTLE_TIME: Duration = Duration::from_millis(200);
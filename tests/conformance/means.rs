@@ -0,0 +1,32 @@
+use proto_hackers::clients::MeansClient;
+use proto_hackers::testing;
+
+#[tokio::test]
+async fn computes_mean_over_range() {
+    let server = testing::spawn(2).await.unwrap();
+    let mut client = MeansClient::connect(server.addr).await.unwrap();
+    for (timestamp, price) in [(12345, 101), (12346, 102), (12347, 100), (40960, 5)] {
+        client.insert(timestamp, price).await.unwrap();
+    }
+    assert_eq!(client.query(12288, 16384).await, Some(101));
+}
+
+#[tokio::test]
+async fn empty_or_inverted_ranges_give_zero() {
+    let server = testing::spawn(2).await.unwrap();
+    let mut client = MeansClient::connect(server.addr).await.unwrap();
+    assert_eq!(client.query(0, 100).await, Some(0));
+    client.insert(50, 10).await.unwrap();
+    assert_eq!(client.query(100, 0).await, Some(0));
+}
+
+#[tokio::test]
+async fn sessions_are_separate() {
+    let server = testing::spawn(2).await.unwrap();
+    let mut first = MeansClient::connect(server.addr).await.unwrap();
+    let mut second = MeansClient::connect(server.addr).await.unwrap();
+    first.insert(1, 100).await.unwrap();
+    second.insert(1, -100).await.unwrap();
+    assert_eq!(first.query(0, 10).await, Some(100));
+    assert_eq!(second.query(0, 10).await, Some(-100));
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use proto_hackers::clients::SiteVisitor;
+use proto_hackers::testing::{self, TestServer};
+use proto_hackers::{Config, MockAuthority};
+use tokio::time;
+
+async fn spawn(authority: MockAuthority) -> (TestServer, std::sync::Arc<MockAuthority>) {
+    let (authority_addr, authority) = authority.spawn("127.0.0.1:0").await.unwrap();
+    let mut config = Config::default();
+    config.pestcontrol.authority_addr = authority_addr.to_string();
+    (
+        testing::spawn_with_config(11, &config).await.unwrap(),
+        authority,
+    )
+}
+
+async fn wait_policies(authority: &MockAuthority, site: u32, expected: &[(&str, u8)]) {
+    let mut policies = Vec::new();
+    for _ in 0..50 {
+        policies = authority.policies(site).await;
+        let mut current: Vec<_> = policies.iter().map(|(_, s, a)| (s.as_str(), *a)).collect();
+        current.sort();
+        if current == expected {
+            return;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("expected policies {expected:?}, got {policies:?}");
+}
+
+#[tokio::test]
+async fn policies_follow_observations() {
+    let authority = MockAuthority::new().with_site(1, &[("fox", 2, 4), ("owl", 1, 10)]);
+    let (server, authority) = spawn(authority).await;
+    let mut visitor = SiteVisitor::connect(server.addr).await.unwrap();
+
+    // Species not observed have a count of 0
+    visitor.visit(1, &[("fox", 9)]).await.unwrap();
+    wait_policies(&authority, 1, &[("fox", 0x90), ("owl", 0xa0)]).await;
+
+    visitor.visit(1, &[("fox", 3), ("owl", 5)]).await.unwrap();
+    wait_policies(&authority, 1, &[]).await;
+}
+
+#[tokio::test]
+async fn conflicting_counts_are_an_error() {
+    let (server, _authority) = spawn(MockAuthority::new().with_site(1, &[])).await;
+    let mut visitor = SiteVisitor::connect(server.addr).await.unwrap();
+    visitor.visit(1, &[("fox", 1), ("fox", 2)]).await.unwrap();
+    assert!(visitor.recv_error().await.is_some());
+}
+
+#[tokio::test]
+async fn bad_hello_is_an_error() {
+    let (server, _authority) = spawn(MockAuthority::new()).await;
+    let mut client = server.connect().await.unwrap();
+    let mut hello = vec![0x50, 0, 0, 0, 0x19, 0, 0, 0, 0x0b];
+    hello.extend(b"pestcontrol");
+    hello.extend(2u32.to_be_bytes());
+    let checksum = hello.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+    hello.push(checksum.wrapping_neg());
+    client.send(&hello).await.unwrap();
+
+    // The server greets first, then reports the wrong version
+    let greeting = client.recv_exact(25).await.unwrap();
+    assert_eq!(greeting[0], 0x50);
+    assert_eq!(client.recv_u8().await, Some(0x51));
+}
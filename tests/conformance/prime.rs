@@ -0,0 +1,57 @@
+use proto_hackers::clients::PrimeClient;
+use proto_hackers::testing;
+use serde_json::json;
+
+#[tokio::test]
+async fn answers_primality() {
+    let server = testing::spawn(1).await.unwrap();
+    let mut client = PrimeClient::connect(server.addr).await.unwrap();
+    for (number, prime) in [(2, true), (1, false), (0, false), (-7, false), (7919, true)] {
+        assert_eq!(client.is_prime(number).await, Some(prime), "{number}");
+    }
+    assert_eq!(client.is_prime(7.5).await, Some(false));
+    // Largest prime below 2^64
+    assert_eq!(client.is_prime(18446744073709551557u64).await, Some(true));
+}
+
+#[tokio::test]
+async fn answers_batches() {
+    let server = testing::spawn(1).await.unwrap();
+    let mut client = PrimeClient::connect(server.addr).await.unwrap();
+    let request = json!({"method": "isPrime", "number": [2, 4, 7.5, 7919]});
+    let response = client.request(&request).await.unwrap();
+    assert_eq!(response["prime"], json!([true, false, false, true]));
+}
+
+#[tokio::test]
+async fn ignores_extraneous_fields() {
+    let server = testing::spawn(1).await.unwrap();
+    let mut client = PrimeClient::connect(server.addr).await.unwrap();
+    let request = json!({"method": "isPrime", "number": 13, "extra": [1, 2]});
+    let response = client.request(&request).await.unwrap();
+    assert_eq!(response["prime"], true);
+}
+
+#[tokio::test]
+async fn disconnects_after_malformed_request() {
+    let server = testing::spawn(1).await.unwrap();
+    for request in [
+        r#"{"method":"isPrime"}"#,
+        r#"{"method":"isNotPrime","number":3}"#,
+        r#"{"method":"isPrime","number":"3"}"#,
+        r#"{"number":3}"#,
+        r#"{"method":"isPrime","number":[3,"5"]}"#,
+        "{",
+    ] {
+        let mut client = server.connect().await.unwrap();
+        client.send_line(request).await.unwrap();
+        let response = client.recv_line().await.unwrap();
+        let response: Option<serde_json::Value> = serde_json::from_str(&response).ok();
+        assert_ne!(
+            response.as_ref().and_then(|r| r.get("method")),
+            Some(&json!("isPrime")),
+            "{request}"
+        );
+        assert!(client.is_closed().await, "{request}");
+    }
+}
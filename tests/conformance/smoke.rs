@@ -0,0 +1,26 @@
+use proto_hackers::testing;
+
+#[tokio::test]
+async fn echoes_binary_data_until_eof() {
+    let server = testing::spawn(0).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    let data: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+    client.send(&data).await.unwrap();
+    assert_eq!(client.recv_exact(data.len()).await.unwrap(), data);
+
+    client.shutdown().await.unwrap();
+    assert!(client.is_closed().await);
+}
+
+#[tokio::test]
+async fn handles_five_simultaneous_clients() {
+    let server = testing::spawn(0).await.unwrap();
+    let mut clients = Vec::new();
+    for _ in 0..5 {
+        clients.push(server.connect().await.unwrap());
+    }
+    for (i, client) in clients.iter_mut().enumerate().rev() {
+        client.send_line(&format!("client {i}")).await.unwrap();
+        assert_eq!(client.recv_line().await.unwrap(), format!("client {i}"));
+    }
+}
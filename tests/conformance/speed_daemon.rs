@@ -0,0 +1,64 @@
+use proto_hackers::clients::{Camera, Dispatcher, SpeedMessage};
+use proto_hackers::testing;
+use tokio::time;
+
+use crate::SETTLE_TIME;
+
+#[tokio::test]
+async fn ticket_waits_for_a_dispatcher() {
+    let server = testing::spawn(6).await.unwrap();
+    let mut first = Camera::connect(server.addr, 123, 8, 60).await.unwrap();
+    let mut second = Camera::connect(server.addr, 123, 9, 60).await.unwrap();
+    first.plate("UN1X", 0).await.unwrap();
+    second.plate("UN1X", 45).await.unwrap();
+    time::sleep(SETTLE_TIME).await;
+
+    let mut dispatcher = Dispatcher::connect(server.addr, &[123]).await.unwrap();
+    let ticket = dispatcher.recv_ticket().await.unwrap();
+    assert_eq!((ticket.plate.as_str(), ticket.speed), ("UN1X", 8000));
+}
+
+#[tokio::test]
+async fn one_ticket_per_car_per_day() {
+    let server = testing::spawn(6).await.unwrap();
+    let mut dispatcher = Dispatcher::connect(server.addr, &[1]).await.unwrap();
+    let mut first = Camera::connect(server.addr, 1, 0, 60).await.unwrap();
+    let mut second = Camera::connect(server.addr, 1, 10, 60).await.unwrap();
+
+    first.plate("FAST", 1000).await.unwrap();
+    second.plate("FAST", 1300).await.unwrap();
+    first.plate("FAST", 2000).await.unwrap();
+    second.plate("FAST", 2300).await.unwrap();
+
+    let ticket = dispatcher.recv_ticket().await.unwrap();
+    assert_eq!(ticket.plate, "FAST");
+    dispatcher.connection().set_timeout(Some(SETTLE_TIME));
+    assert_eq!(dispatcher.recv().await, None);
+}
+
+#[tokio::test]
+async fn heartbeats_are_sent() {
+    let server = testing::spawn(6).await.unwrap();
+    let mut camera = Camera::connect(server.addr, 1, 0, 60).await.unwrap();
+    camera.want_heartbeat(1).await.unwrap();
+    for _ in 0..3 {
+        assert_eq!(camera.recv().await, Some(SpeedMessage::Heartbeat));
+    }
+}
+
+#[tokio::test]
+async fn errors_on_illegal_messages() {
+    let server = testing::spawn(6).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send(&[0x21]).await.unwrap();
+    assert_eq!(client.recv_u8().await, Some(0x10));
+
+    // Identifying twice is an error
+    let mut camera = Camera::connect(server.addr, 1, 0, 60).await.unwrap();
+    camera
+        .connection()
+        .send(&[0x80, 0, 1, 0, 1, 0, 60])
+        .await
+        .unwrap();
+    assert!(matches!(camera.recv().await, Some(SpeedMessage::Error(_))));
+}
@@ -0,0 +1,50 @@
+use proto_hackers::clients::VcsClient;
+use proto_hackers::testing;
+
+#[tokio::test]
+async fn identical_put_keeps_revision() {
+    let server = testing::spawn(10).await.unwrap();
+    let mut client = VcsClient::connect(server.addr).await.unwrap();
+    assert_eq!(client.put("/f", b"a\n").await, Some(Ok(1)));
+    assert_eq!(client.put("/f", b"a\n").await, Some(Ok(1)));
+    assert_eq!(client.put("/f", b"b\n").await, Some(Ok(2)));
+    assert_eq!(client.get("/f", Some(1)).await, Some(Ok(b"a\n".to_vec())));
+    assert!(client.get("/f", Some(3)).await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn rejects_illegal_names_and_binary_data() {
+    let server = testing::spawn(10).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    assert_eq!(client.recv_line().await.unwrap(), "READY");
+    for request in ["PUT /dir//f 1", "PUT f 1", "PUT /f! 1", "GET /dir/"] {
+        client.send_line(request).await.unwrap();
+        let mut response = client.recv_line().await.unwrap();
+        if response == "READY" {
+            response = client.recv_line().await.unwrap();
+        }
+        assert!(response.starts_with("ERR "), "{request}: {response}");
+    }
+
+    let mut client = VcsClient::connect(server.addr).await.unwrap();
+    assert!(client.put("/bin", &[0x01, 0x02]).await.unwrap().is_err());
+    assert!(client.get("/missing", None).await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn lists_files_and_directories_sorted() {
+    let server = testing::spawn(10).await.unwrap();
+    let mut client = VcsClient::connect(server.addr).await.unwrap();
+    client.put("/b/file", b"x").await.unwrap().unwrap();
+    client.put("/a", b"x").await.unwrap().unwrap();
+    client.put("/a", b"y").await.unwrap().unwrap();
+    assert_eq!(
+        client.list("/").await,
+        Some(Ok(vec!["a r2".to_owned(), "b/ DIR".to_owned()]))
+    );
+    assert_eq!(
+        client.list("/b/").await,
+        Some(Ok(vec!["file r1".to_owned()]))
+    );
+    assert_eq!(client.list("/nothing").await, Some(Ok(Vec::new())));
+}
@@ -0,0 +1,116 @@
+use proto_hackers::{Config, RunOptions, run_challenge};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpStream, UdpSocket};
+
+#[tokio::test]
+async fn embedded_server_stops_on_shutdown() {
+    let server = run_challenge(0, RunOptions::default()).await.unwrap();
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buffer = [0; 5];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"hello");
+
+    let addr = server.addr;
+    server.shutdown().await;
+    assert!(TcpStream::connect(addr).await.is_err());
+
+    // Connections accepted before the shutdown are still served
+    stream.write_all(b"again").await.unwrap();
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"again");
+}
+
+#[tokio::test]
+async fn embedded_udp_server_binds_requested_address() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    drop(socket);
+
+    let options = RunOptions {
+        addr: addr.clone(),
+        ..RunOptions::default()
+    };
+    let server = run_challenge(4, options).await.unwrap();
+    assert_eq!(server.addr.to_string(), addr);
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(b"version", server.addr).await.unwrap();
+    let mut buffer = [0; 64];
+    let (n, _) = client.recv_from(&mut buffer).await.unwrap();
+    assert!(buffer[..n].starts_with(b"version="));
+    server.shutdown().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn server_listens_on_unix_socket() {
+    let path = std::env::temp_dir().join(format!("protohackers-{}.sock", std::process::id()));
+    let options = RunOptions {
+        addr: format!("unix:{}", path.display()),
+        ..RunOptions::default()
+    };
+    let server = run_challenge(0, options).await.unwrap();
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buffer = [0; 5];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"hello");
+    server.shutdown().await;
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn unknown_challenge_is_rejected() {
+    let err = run_challenge(42, RunOptions::default())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "invalid challenge number 42");
+}
+
+fn dual_stack() -> RunOptions {
+    RunOptions {
+        config: Config {
+            dual_stack: true,
+            ..Config::default()
+        },
+        ..RunOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn dual_stack_server_accepts_ipv6_clients() {
+    let server = run_challenge(0, dual_stack()).await.unwrap();
+    let addr_v6 = server.addr_v6.unwrap();
+    assert!(addr_v6.is_ipv6());
+    assert_eq!(addr_v6.port(), server.addr.port());
+
+    for addr in [server.addr, addr_v6] {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buffer = [0; 5];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+    }
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn dual_stack_udp_server_answers_on_both_sockets() {
+    let server = run_challenge(4, dual_stack()).await.unwrap();
+    for (local, addr) in [
+        ("127.0.0.1:0", server.addr),
+        ("[::1]:0", server.addr_v6.unwrap()),
+    ] {
+        let client = UdpSocket::bind(local).await.unwrap();
+        client.send_to(b"version", addr).await.unwrap();
+        let mut buffer = [0; 64];
+        let (n, from) = client.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(from, addr);
+        assert!(buffer[..n].starts_with(b"version="));
+    }
+    server.shutdown().await;
+}
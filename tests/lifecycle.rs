@@ -0,0 +1,112 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use proto_hackers::{Config, testing};
+use serde_json::Value;
+use tokio::time;
+
+fn events_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("proto_hackers-{}-{name}.jsonl", std::process::id()))
+}
+
+// The event is logged once the relay is done with the connection, which may
+// be after the client saw it closed
+async fn wait_for_event(path: &Path) -> Value {
+    for _ in 0..50 {
+        if let Ok(data) = fs::read_to_string(path) {
+            if let Some(line) = data.lines().next() {
+                fs::remove_file(path).unwrap();
+                return serde_json::from_str(line).unwrap();
+            }
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("no connection event logged");
+}
+
+fn config(path: &Path) -> Config {
+    Config {
+        events_path: Some(path.to_path_buf()),
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn client_close_is_logged_with_byte_counts() {
+    let path = events_path("eof");
+    let server = testing::spawn_with_config(0, &config(&path)).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send(b"hello").await.unwrap();
+    assert_eq!(client.recv_exact(5).await.unwrap(), b"hello");
+    client.shutdown().await.unwrap();
+    assert!(client.is_closed().await);
+
+    let event = wait_for_event(&path).await;
+    assert_eq!(event["challenge"], 0);
+    assert!(event["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+    assert_eq!(event["bytes_in"], 5);
+    assert_eq!(event["bytes_out"], 5);
+    assert_eq!(event["reason"], "eof");
+    assert!(event["duration_ms"].is_u64());
+}
+
+#[tokio::test]
+async fn server_hang_up_is_logged_as_protocol_error() {
+    let path = events_path("protocol-error");
+    let server = testing::spawn_with_config(10, &config(&path))
+        .await
+        .unwrap();
+    let mut client = server.connect().await.unwrap();
+    assert_eq!(client.recv_line().await.as_deref(), Some("READY"));
+    client.send_line("DANCE").await.unwrap();
+    assert_eq!(
+        client.recv_line().await.as_deref(),
+        Some("ERR illegal method: DANCE")
+    );
+    assert!(client.is_closed().await);
+    drop(client);
+
+    let event = wait_for_event(&path).await;
+    assert_eq!(event["challenge"], 10);
+    assert_eq!(event["bytes_in"], 6);
+    assert_eq!(event["reason"], "protocol_error");
+}
+
+#[tokio::test]
+async fn idle_connection_is_closed_and_logged() {
+    let path = events_path("timeout");
+    let config = Config {
+        idle_timeout: Some(Duration::from_millis(100)),
+        ..config(&path)
+    };
+    let server = testing::spawn_with_config(0, &config).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    assert!(client.is_closed().await);
+
+    let event = wait_for_event(&path).await;
+    assert_eq!(event["bytes_in"], 0);
+    assert_eq!(event["reason"], "timeout");
+}
+
+#[tokio::test]
+async fn server_traffic_does_not_keep_silent_client_alive() {
+    let path = events_path("heartbeat-timeout");
+    let config = Config {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..config(&path)
+    };
+    let server = testing::spawn_with_config(6, &config).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    // Heartbeat every 100ms
+    client.send(&[0x40, 0, 0, 0, 1]).await.unwrap();
+    let drained = time::timeout(Duration::from_secs(2), async {
+        while client.recv_exact(1).await.is_some() {}
+    });
+    assert!(drained.await.is_ok());
+
+    let event = wait_for_event(&path).await;
+    assert_eq!(event["bytes_in"], 5);
+    assert_eq!(event["reason"], "timeout");
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use proto_hackers::{Config, ConnectionLimit, Overflow, RateLimit, testing};
+use tokio::time;
+
+fn limited(overflow: Overflow) -> Config {
+    Config {
+        connection_limit: ConnectionLimit {
+            max: Some(1),
+            overflow,
+        },
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_queued() {
+    let server = testing::spawn_with_config(0, &limited(Overflow::Queue))
+        .await
+        .unwrap();
+    let mut first = server.connect().await.unwrap();
+    first.send(b"first\n").await.unwrap();
+    assert_eq!(first.recv_line().await.unwrap(), "first");
+
+    let mut second = server.connect().await.unwrap();
+    second.send(b"second\n").await.unwrap();
+    let held_back = time::timeout(Duration::from_millis(200), second.recv_line()).await;
+    assert!(held_back.is_err());
+
+    drop(first);
+    assert_eq!(second.recv_line().await.unwrap(), "second");
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_reset() {
+    let server = testing::spawn_with_config(0, &limited(Overflow::Reset))
+        .await
+        .unwrap();
+    let mut first = server.connect().await.unwrap();
+    first.send(b"first\n").await.unwrap();
+    assert_eq!(first.recv_line().await.unwrap(), "first");
+
+    let mut second = server.connect().await.unwrap();
+    let _ = second.send(b"second\n").await;
+    assert_eq!(second.recv_line().await, None);
+
+    // The slot is given back once the first client leaves
+    drop(first);
+    time::sleep(Duration::from_millis(50)).await;
+    let mut third = server.connect().await.unwrap();
+    third.send(b"third\n").await.unwrap();
+    assert_eq!(third.recv_line().await.unwrap(), "third");
+}
+
+// A burst of two, never refilled during a test
+fn rate_limited() -> Config {
+    Config {
+        rate_limit: RateLimit {
+            per_second: Some(0.01),
+            burst: Some(2),
+        },
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn connections_over_the_rate_are_reset() {
+    let server = testing::spawn_with_config(0, &rate_limited())
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        let mut client = server.connect().await.unwrap();
+        client.send(b"hello\n").await.unwrap();
+        assert_eq!(client.recv_line().await.unwrap(), "hello");
+    }
+    let mut client = server.connect().await.unwrap();
+    let _ = client.send(b"hello\n").await;
+    assert_eq!(client.recv_line().await, None);
+}
+
+#[tokio::test]
+async fn datagrams_over_the_rate_are_dropped() {
+    let server = testing::spawn_with_config(4, &rate_limited())
+        .await
+        .unwrap();
+    let client = server.udp_client().await.unwrap();
+    for _ in 0..2 {
+        client.send(b"version").await.unwrap();
+        assert!(client.recv().await.is_some());
+    }
+    client.send(b"version").await.unwrap();
+    let dropped = client.recv_timeout(Duration::from_millis(200)).await;
+    assert_eq!(dropped, None);
+}
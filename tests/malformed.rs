@@ -0,0 +1,89 @@
+use proto_hackers::testing::{self, TestServer, Variant};
+use serde_json::{Value, json};
+
+// Send the variant on a fresh connection, returning the response line if any
+async fn send_variant(server: &TestServer, variant: &Variant) -> Option<Value> {
+    let mut client = server.connect().await.unwrap();
+    client.send(&variant.data).await.unwrap();
+    client.send(b"\n").await.unwrap();
+    let line = client.recv_line().await?;
+    Some(serde_json::from_str(&line).unwrap_or(Value::Null))
+}
+
+fn is_prime_response(response: &Value) -> bool {
+    response.get("method") == Some(&json!("isPrime"))
+        && response.get("prime").is_some_and(Value::is_boolean)
+}
+
+#[tokio::test]
+async fn prime_rejects_malformed_requests() {
+    let server = testing::spawn(1).await.unwrap();
+    let valid = json!({"method": "isPrime", "number": 7});
+    for variant in testing::malformed_json(&valid, &[]) {
+        if let Some(response) = send_variant(&server, &variant).await {
+            assert!(
+                !is_prime_response(&response),
+                "{}: got {response}",
+                variant.description
+            );
+        }
+    }
+
+    // Numbers out of the u64 range are still valid requests, the server only
+    // has to answer without dying
+    for variant in testing::out_of_range_numbers(&valid) {
+        assert!(
+            send_variant(&server, &variant).await.is_some(),
+            "{}: no response",
+            variant.description
+        );
+    }
+
+    let response = send_variant(
+        &server,
+        &Variant {
+            description: String::from("valid"),
+            data: valid.to_string().into_bytes(),
+        },
+    )
+    .await;
+    assert_eq!(response, Some(json!({"method": "isPrime", "prime": true})));
+}
+
+#[tokio::test]
+async fn job_centre_rejects_malformed_requests() {
+    let server = testing::spawn(9).await.unwrap();
+    let requests = [
+        (
+            json!({"request": "put", "queue": "q1", "job": {"title": "j"}, "pri": 12}),
+            &["job"][..],
+        ),
+        (json!({"request": "get", "queues": ["q1"]}), &[]),
+        (json!({"request": "delete", "id": 1}), &[]),
+        (json!({"request": "abort", "id": 1}), &[]),
+    ];
+    for (valid, untyped_keys) in requests {
+        let variants = testing::malformed_json(&valid, untyped_keys)
+            .into_iter()
+            .chain(testing::out_of_range_numbers(&valid));
+        for variant in variants {
+            let response = send_variant(&server, &variant).await;
+            let Some(response) = response else {
+                panic!("{}: no response", variant.description);
+            };
+            assert_eq!(
+                response["status"], "error",
+                "{}: got {response}",
+                variant.description
+            );
+        }
+    }
+
+    let mut client = server.connect().await.unwrap();
+    client
+        .send_line(r#"{"request":"get","queues":["q1"]}"#)
+        .await
+        .unwrap();
+    let response: Value = serde_json::from_str(&client.recv_line().await.unwrap()).unwrap();
+    assert_eq!(response, json!({"status": "no-job"}));
+}
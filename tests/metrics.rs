@@ -0,0 +1,171 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use proto_hackers::clients::JobClient;
+use proto_hackers::metrics;
+use proto_hackers::{Config, testing};
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+async fn serve_metrics() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(metrics::serve(listener));
+    addr
+}
+
+async fn scrape(addr: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+// Scrape until every expected line shows up, as some are only recorded after
+// the client got its response
+async fn scrape_until(addr: SocketAddr, expected: &[&str]) -> String {
+    time::timeout(Duration::from_secs(5), async {
+        loop {
+            let response = scrape(addr).await;
+            if expected.iter().all(|line| response.contains(line)) {
+                return response;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("metrics not exported: {}", expected.join(", ")))
+}
+
+#[tokio::test]
+async fn counters_are_exported_by_challenge() {
+    let metrics_addr = serve_metrics().await;
+
+    let server = testing::spawn(1).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    for number in [2, 4] {
+        let request = format!(r#"{{"method":"isPrime","number":{number}}}"#);
+        client.send_line(&request).await.unwrap();
+        client.recv_line().await.unwrap();
+    }
+    client.send_line("not json").await.unwrap();
+    client.recv_line().await.unwrap();
+    drop(client);
+
+    let response = scrape_until(
+        metrics_addr,
+        &[
+            "proto_hackers_requests_total{challenge=\"1\"} 3",
+            "proto_hackers_protocol_errors_total{challenge=\"1\"} 1",
+            "proto_hackers_disconnects_total{challenge=\"1\"} 1",
+        ],
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("# TYPE proto_hackers_requests_total counter\n"));
+    assert!(!response.contains("challenge=\"0\""));
+}
+
+#[tokio::test]
+async fn latencies_are_exported_by_message_type() {
+    let metrics_addr = serve_metrics().await;
+
+    let server = testing::spawn(9).await.unwrap();
+    let mut client = JobClient::connect(server.addr).await.unwrap();
+    for priority in [1, 2] {
+        client.put("queue", json!({}), priority).await.unwrap();
+    }
+    client.get(&["queue"], false).await.unwrap().unwrap();
+
+    let labels = "challenge=\"9\",type=\"put\"";
+    let response = scrape_until(
+        metrics_addr,
+        &[
+            &format!("proto_hackers_handling_seconds_count{{{labels}}} 2"),
+            &format!("proto_hackers_handling_seconds_bucket{{{labels},le=\"+Inf\"}} 2"),
+            "proto_hackers_handling_seconds_count{challenge=\"9\",type=\"get\"} 1",
+        ],
+    )
+    .await;
+    assert!(response.contains("# TYPE proto_hackers_handling_seconds histogram\n"));
+    assert!(!response.contains("type=\"delete\""));
+}
+
+#[tokio::test]
+async fn upstream_errors_are_told_apart() {
+    let metrics_addr = serve_metrics().await;
+
+    // Nothing listens on the upstream address once the listener is dropped
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = Config::default();
+    config.proxy.upstream_addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let server = testing::spawn_with_config(5, &config).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    assert_eq!(client.recv_line().await, None);
+
+    let response = scrape_until(
+        metrics_addr,
+        &["proto_hackers_upstream_errors_total{challenge=\"5\"} 1"],
+    )
+    .await;
+    assert!(response.contains("# TYPE proto_hackers_internal_errors_total counter\n"));
+    assert!(!response.contains("proto_hackers_protocol_errors_total{challenge=\"5\"}"));
+}
+
+#[tokio::test]
+async fn truncated_datagrams_are_counted() {
+    let metrics_addr = serve_metrics().await;
+
+    let server = testing::spawn(7).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    client.send(&[b'/'; 2000]).await.unwrap();
+    client.send(b"/connect/1234/").await.unwrap();
+    client.recv().await.unwrap();
+
+    scrape_until(
+        metrics_addr,
+        &[
+            "proto_hackers_datagrams_total{challenge=\"7\"} 2",
+            "proto_hackers_truncated_datagrams_total{challenge=\"7\"} 1",
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn bytes_and_active_connections_are_exported() {
+    let metrics_addr = serve_metrics().await;
+
+    let config = Config {
+        metrics_addr: Some(metrics_addr.to_string()),
+        ..Config::default()
+    };
+    let server = testing::spawn_with_config(2, &config).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send(b"I\0\0\0\x01\0\0\0\x64").await.unwrap();
+    client.send(b"Q\0\0\0\0\0\0\0\x0a").await.unwrap();
+    assert_eq!(client.recv_exact(4).await.unwrap(), 100i32.to_be_bytes());
+
+    scrape_until(
+        metrics_addr,
+        &[
+            "proto_hackers_received_bytes_total{challenge=\"2\"} 18",
+            "proto_hackers_sent_bytes_total{challenge=\"2\"} 4",
+            "proto_hackers_active_connections{challenge=\"2\"} 1",
+        ],
+    )
+    .await;
+    drop(client);
+    scrape_until(
+        metrics_addr,
+        &["proto_hackers_active_connections{challenge=\"2\"} 0"],
+    )
+    .await;
+}
@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use proto_hackers::{
+    ClientStream, ProtoError, Server, ServerType, TcpServer, Transport, list_challenges,
+    parse_challenge, testing,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Echoes back every chunk in upper case
+struct Shout;
+
+#[async_trait]
+impl TcpServer for Shout {
+    async fn handle_connection(&self, mut stream: ClientStream) {
+        let mut buffer = [0; 1024];
+        while let Ok(n @ 1..) = stream.read(&mut buffer).await {
+            let _ = stream.write_all(&buffer[..n].to_ascii_uppercase()).await;
+        }
+    }
+}
+
+fn shout() -> ServerType {
+    ServerType::Tcp(Arc::new(Shout))
+}
+
+// Panics on the first chunk it receives
+struct Fragile;
+
+#[async_trait]
+impl TcpServer for Fragile {
+    async fn handle_connection(&self, mut stream: ClientStream) {
+        let mut buffer = [0; 1024];
+        let n = stream.read(&mut buffer).await.unwrap();
+        panic!("cannot handle {:?}", &buffer[..n]);
+    }
+}
+
+#[tokio::test]
+async fn registered_challenge_runs_in_test_harness() {
+    Server::register(40, "shout", shout()).unwrap();
+    let server = testing::spawn(40).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send(b"hello\n").await.unwrap();
+    assert_eq!(client.recv_line().await.unwrap(), "HELLO");
+}
+
+#[test]
+fn registration_rejects_taken_numbers() {
+    assert_eq!(
+        Server::register(6, "shout", shout()),
+        Err(ProtoError::BuiltinChallenge(6))
+    );
+    assert_eq!(
+        Server::register(200, "shout", shout()),
+        Err(ProtoError::ChallengeOutOfRange(200))
+    );
+    Server::register(41, "shout", shout()).unwrap();
+    assert_eq!(
+        Server::register(41, "shout again", shout()),
+        Err(ProtoError::AlreadyRegistered(41))
+    );
+}
+
+#[tokio::test]
+async fn panicking_handler_leaves_server_running() {
+    Server::register(42, "fragile", ServerType::Tcp(Arc::new(Fragile))).unwrap();
+    let server = testing::spawn(42).await.unwrap();
+    for _ in 0..2 {
+        let mut client = server.connect().await.unwrap();
+        client.send(b"hello").await.unwrap();
+        assert!(client.is_closed().await);
+    }
+}
+
+#[test]
+fn challenges_are_found_by_name() {
+    assert_eq!(parse_challenge("7"), Ok(7));
+    assert_eq!(parse_challenge("budgetchat"), Ok(3));
+    assert_eq!(parse_challenge("Chat"), Ok(3));
+    assert_eq!(
+        parse_challenge("telnet"),
+        Err(ProtoError::ChallengeArg("telnet".into()))
+    );
+
+    Server::register(43, "yell", shout()).unwrap();
+    assert_eq!(parse_challenge("yell"), Ok(43));
+    let challenges = list_challenges();
+    let yell = challenges
+        .iter()
+        .find(|challenge| challenge.part == 43)
+        .unwrap();
+    assert_eq!(yell.transport, Transport::Tcp);
+}
@@ -0,0 +1,64 @@
+#![cfg(feature = "server_06")]
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use proto_hackers::clients::{Camera, Dispatcher};
+use proto_hackers::testing::{self, Scenario};
+
+// Returns the number of tickets issued
+async fn run_speed_scenario(seed: u64) -> usize {
+    let scenario = Scenario::generate(seed);
+    let server = testing::spawn(6).await.unwrap();
+
+    let mut cameras = HashMap::new();
+    for road in &scenario.roads {
+        for &mile in &road.cameras {
+            let camera = Camera::connect(server.addr, road.id, mile, road.limit)
+                .await
+                .unwrap();
+            cameras.insert((road.id, mile), camera);
+        }
+    }
+    for sighting in &scenario.sightings {
+        let camera = cameras.get_mut(&(sighting.road, sighting.mile)).unwrap();
+        camera
+            .plate(&sighting.plate, sighting.timestamp)
+            .await
+            .unwrap();
+    }
+
+    // Connect the dispatcher last, so that tickets also go through the queue
+    // of undelivered ones
+    let roads: Vec<u16> = scenario.roads.iter().map(|road| road.id).collect();
+    let mut dispatcher = Dispatcher::connect(server.addr, &roads).await.unwrap();
+    dispatcher
+        .connection()
+        .set_timeout(Some(Duration::from_secs(2)));
+    let mut tickets = HashSet::new();
+    for _ in 0..scenario.tickets.len() {
+        match dispatcher.recv_ticket().await {
+            Some(ticket) => assert!(tickets.insert(ticket), "seed {seed}: duplicate ticket"),
+            None => break,
+        }
+    }
+    dispatcher
+        .connection()
+        .set_timeout(Some(Duration::from_millis(200)));
+    if let Some(ticket) = dispatcher.recv_ticket().await {
+        tickets.insert(ticket);
+    }
+
+    let expected: HashSet<_> = scenario.tickets.into_iter().collect();
+    assert_eq!(tickets, expected, "seed {seed}");
+    tickets.len()
+}
+
+#[tokio::test]
+async fn speed_daemon_issues_expected_tickets() {
+    let mut nb_tickets = 0;
+    for seed in 0..20 {
+        nb_tickets += run_speed_scenario(seed).await;
+    }
+    assert!(nb_tickets > 0);
+}
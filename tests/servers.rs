@@ -0,0 +1,529 @@
+use std::time::Duration;
+use std::{env, fs, process};
+
+use proto_hackers::clients::{Camera, Dispatcher, SiteVisitor};
+use proto_hackers::testing::{self, TestClient};
+use proto_hackers::{Config, EchoMode, MockAuthority};
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+async fn recv_json(client: &mut TestClient) -> Value {
+    let line = client.recv_line().await.expect("no response");
+    serde_json::from_str(&line).expect("invalid JSON response")
+}
+
+#[tokio::test]
+async fn echo_returns_data() {
+    let server = testing::spawn(0).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send(b"hello world").await.unwrap();
+    assert_eq!(client.recv_exact(11).await.unwrap(), b"hello world");
+}
+
+#[tokio::test]
+async fn echo_returns_data_larger_than_socket_buffers() {
+    let server = testing::spawn(0).await.unwrap();
+    let stream = TcpStream::connect(server.addr).await.unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+    let data: Vec<u8> = (0..4 << 20).map(|i| (i % 251) as u8).collect();
+    let sent = data.clone();
+    tokio::spawn(async move {
+        writer.write_all(&sent).await.unwrap();
+        writer.shutdown().await.unwrap();
+    });
+
+    let mut echoed = Vec::new();
+    reader.read_to_end(&mut echoed).await.unwrap();
+    assert!(echoed == data, "echo differs from the data sent");
+}
+
+#[tokio::test]
+async fn echo_modes_shape_the_echo() {
+    let mut config = Config::default();
+    config.echo.mode = EchoMode::Lines;
+    let server = testing::spawn_with_config(0, &config).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send(b"hello").await.unwrap();
+    client.send(b" world\nbye").await.unwrap();
+    assert_eq!(client.recv_line().await.unwrap(), "hello world");
+
+    config.echo.mode = EchoMode::Limited(4);
+    let server = testing::spawn_with_config(0, &config).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send(b"hello world").await.unwrap();
+    assert_eq!(client.recv_exact(4).await.unwrap(), b"hell");
+    assert!(client.is_closed().await);
+}
+
+#[tokio::test]
+async fn prime_answers_requests() {
+    let server = testing::spawn(1).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client
+        .send_line(r#"{"method":"isPrime","number":7}"#)
+        .await
+        .unwrap();
+    assert_eq!(
+        recv_json(&mut client).await,
+        json!({"method": "isPrime", "prime": true})
+    );
+    client
+        .send_line(r#"{"method":"isPrime","number":12}"#)
+        .await
+        .unwrap();
+    assert_eq!(
+        recv_json(&mut client).await,
+        json!({"method": "isPrime", "prime": false})
+    );
+}
+
+fn price_msg(kind: u8, first: i32, second: i32) -> Vec<u8> {
+    let mut msg = vec![kind];
+    msg.extend(first.to_be_bytes());
+    msg.extend(second.to_be_bytes());
+    msg
+}
+
+#[tokio::test]
+async fn means_computes_average() {
+    let server = testing::spawn(2).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send(&price_msg(b'I', 12345, 101)).await.unwrap();
+    client.send(&price_msg(b'I', 12346, 102)).await.unwrap();
+    client.send(&price_msg(b'I', 12347, 100)).await.unwrap();
+    client.send(&price_msg(b'I', 40960, 5)).await.unwrap();
+    client.send(&price_msg(b'Q', 12288, 16384)).await.unwrap();
+    assert_eq!(client.recv_exact(4).await.unwrap(), 101i32.to_be_bytes());
+}
+
+async fn join_chat(client: &mut TestClient, name: &str) -> String {
+    client.recv_line().await.unwrap();
+    client.send_line(name).await.unwrap();
+    client.recv_line().await.unwrap()
+}
+
+#[tokio::test]
+async fn budget_chat_relays_messages() {
+    let server = testing::spawn(3).await.unwrap();
+    let mut alice = server.connect().await.unwrap();
+    let mut bob = server.connect().await.unwrap();
+
+    join_chat(&mut alice, "alice").await;
+    assert_eq!(
+        join_chat(&mut bob, "bob").await,
+        "* The room contains alice"
+    );
+    assert_eq!(
+        alice.recv_line().await.unwrap(),
+        "* bob has entered the room"
+    );
+
+    alice.send_line("hi bob").await.unwrap();
+    assert_eq!(bob.recv_line().await.unwrap(), "[alice] hi bob");
+
+    drop(bob);
+    assert_eq!(alice.recv_line().await.unwrap(), "* bob has left the room");
+}
+
+#[tokio::test]
+async fn budget_chat_reports_members() {
+    let server = testing::spawn(3).await.unwrap();
+    let mut alice = server.connect().await.unwrap();
+    join_chat(&mut alice, "alice").await;
+    assert_eq!(server.state_sizes().await, [("members", 1)]);
+
+    drop(alice);
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(server.state_sizes().await, [("members", 0)]);
+}
+
+#[tokio::test]
+async fn key_value_store_retrieves_values() {
+    let server = testing::spawn(4).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    client.send(b"foo=bar=baz").await.unwrap();
+    client.send(b"foo").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"foo=bar=baz");
+    client.send(b"version").await.unwrap();
+    assert_eq!(
+        client.recv().await.unwrap(),
+        b"version=Ken's Key-Value Store 1.0"
+    );
+}
+
+#[tokio::test]
+async fn key_value_store_handles_bursts_in_order() {
+    let server = testing::spawn(4).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    for i in 0..50 {
+        let insert = format!("key{i}=value{i}");
+        client.send(insert.as_bytes()).await.unwrap();
+        client.send(format!("key{i}").as_bytes()).await.unwrap();
+    }
+    for i in 0..50 {
+        let response = client.recv().await.unwrap();
+        assert_eq!(response, format!("key{i}=value{i}").as_bytes());
+    }
+}
+
+#[tokio::test]
+async fn key_value_store_keeps_values_over_1024_bytes() {
+    let server = testing::spawn(4).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    let insert = format!("long={}", "x".repeat(1500));
+    client.send(insert.as_bytes()).await.unwrap();
+    client.send(b"long").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), insert.as_bytes());
+}
+
+#[tokio::test]
+async fn lrcp_ignores_messages_of_1000_bytes() {
+    let server = testing::spawn(7).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    client.send(b"/connect/12345/").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"/ack/12345/0/");
+
+    let data = format!("/data/12345/0/{}\n/", "x".repeat(984));
+    assert_eq!(data.len(), 1000);
+    client.send(data.as_bytes()).await.unwrap();
+    let short = Duration::from_millis(200);
+    assert_eq!(client.recv_timeout(short).await, None);
+}
+
+fn camera_msg(road: u16, mile: u16, limit: u16) -> Vec<u8> {
+    let mut msg = vec![0x80];
+    msg.extend(road.to_be_bytes());
+    msg.extend(mile.to_be_bytes());
+    msg.extend(limit.to_be_bytes());
+    msg
+}
+
+fn plate_msg(plate: &str, timestamp: u32) -> Vec<u8> {
+    let mut msg = vec![0x20, plate.len() as u8];
+    msg.extend(plate.as_bytes());
+    msg.extend(timestamp.to_be_bytes());
+    msg
+}
+
+#[tokio::test]
+async fn speed_daemon_issues_ticket() {
+    let server = testing::spawn(6).await.unwrap();
+    let mut camera_1 = server.connect().await.unwrap();
+    let mut camera_2 = server.connect().await.unwrap();
+    let mut dispatcher = server.connect().await.unwrap();
+
+    camera_1.send(&camera_msg(123, 8, 60)).await.unwrap();
+    camera_1.send(&plate_msg("UN1X", 0)).await.unwrap();
+    camera_2.send(&camera_msg(123, 9, 60)).await.unwrap();
+    camera_2.send(&plate_msg("UN1X", 45)).await.unwrap();
+    dispatcher.send(&[0x81, 1, 0, 123]).await.unwrap();
+
+    let mut ticket = vec![0x21, 4];
+    ticket.extend(b"UN1X");
+    ticket.extend(123u16.to_be_bytes());
+    ticket.extend(8u16.to_be_bytes());
+    ticket.extend(0u32.to_be_bytes());
+    ticket.extend(9u16.to_be_bytes());
+    ticket.extend(45u32.to_be_bytes());
+    ticket.extend(8000u16.to_be_bytes());
+    assert_eq!(dispatcher.recv_exact(ticket.len()).await.unwrap(), ticket);
+}
+
+#[tokio::test]
+async fn speed_daemon_audits_tickets() {
+    let path = env::temp_dir().join(format!("proto_hackers-{}-tickets.jsonl", process::id()));
+    let mut config = Config::default();
+    config.speed.audit_path = Some(path.clone());
+    let server = testing::spawn_with_config(6, &config).await.unwrap();
+
+    let mut dispatcher = Dispatcher::connect(server.addr, &[123]).await.unwrap();
+    let mut camera_1 = Camera::connect(server.addr, 123, 8, 60).await.unwrap();
+    let mut camera_2 = Camera::connect(server.addr, 123, 9, 60).await.unwrap();
+    for (plate, timestamp) in [("UN1X", 0), ("RE05BKG", 100)] {
+        camera_1.plate(plate, timestamp).await.unwrap();
+        camera_2.plate(plate, timestamp + 45).await.unwrap();
+        dispatcher.recv_ticket().await.unwrap();
+    }
+
+    // Tickets are audited once written to the dispatcher
+    let mut lines = Vec::new();
+    for _ in 0..50 {
+        lines = fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect();
+        if lines.len() == 2 {
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    fs::remove_file(&path).unwrap();
+
+    let entries: Vec<Value> = lines
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(entries.len(), 2);
+    for (entry, (plate, timestamp)) in entries.iter().zip([("UN1X", 0), ("RE05BKG", 100)]) {
+        assert_eq!(entry["plate"], plate);
+        assert_eq!(entry["road"], 123);
+        assert_eq!(entry["mile1"], 8);
+        assert_eq!(entry["timestamp1"], timestamp);
+        assert_eq!(entry["mile2"], 9);
+        assert_eq!(entry["timestamp2"], timestamp + 45);
+        assert_eq!(entry["speed"], 8000);
+        assert!(entry["dispatcher"].is_u64());
+    }
+}
+
+#[tokio::test]
+async fn lrcp_reverses_lines() {
+    let server = testing::spawn(7).await.unwrap();
+    let client = server.udp_client().await.unwrap();
+    client.send(b"/connect/12345/").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"/ack/12345/0/");
+
+    client.send(b"/data/12345/0/hello\n/").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"/ack/12345/6/");
+    assert_eq!(client.recv().await.unwrap(), b"/data/12345/0/olleh\n/");
+    client.send(b"/ack/12345/6/").await.unwrap();
+
+    client.send(b"/close/12345/").await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), b"/close/12345/");
+}
+
+#[tokio::test]
+async fn insecure_sockets_layer_picks_most_copies() {
+    let server = testing::spawn(8).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    let xor = |data: &[u8]| data.iter().map(|b| b ^ 1).collect::<Vec<_>>();
+
+    client.send(&[0x02, 0x01, 0x00]).await.unwrap();
+    client.send(&xor(b"4x dog,5x car\n")).await.unwrap();
+    assert_eq!(xor(&client.recv_exact(7).await.unwrap()), b"5x car\n");
+}
+
+#[tokio::test]
+async fn job_centre_hands_out_jobs() {
+    let server = testing::spawn(9).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client
+        .send_line(r#"{"request":"put","queue":"q1","job":{"title":"x"},"pri":123}"#)
+        .await
+        .unwrap();
+    assert_eq!(
+        recv_json(&mut client).await,
+        json!({"status": "ok", "id": 1})
+    );
+
+    client
+        .send_line(r#"{"request":"get","queues":["q1"]}"#)
+        .await
+        .unwrap();
+    assert_eq!(
+        recv_json(&mut client).await,
+        json!({"status": "ok", "id": 1, "pri": 123, "queue": "q1", "job": {"title": "x"}})
+    );
+
+    client
+        .send_line(r#"{"request":"get","queues":["q1"]}"#)
+        .await
+        .unwrap();
+    assert_eq!(recv_json(&mut client).await, json!({"status": "no-job"}));
+}
+
+// Responses to pipelined requests are coalesced, but every one must go out
+#[tokio::test]
+async fn job_centre_answers_pipelined_requests() {
+    let server = testing::spawn(9).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    let request = r#"{"request":"put","queue":"q1","job":{},"pri":1}"#;
+    client
+        .send(format!("{request}\n{request}\n{request}\n").as_bytes())
+        .await
+        .unwrap();
+    for id in 1..=3 {
+        assert_eq!(
+            recv_json(&mut client).await,
+            json!({"status": "ok", "id": id})
+        );
+    }
+}
+
+#[tokio::test]
+async fn vcs_stores_revisions() {
+    let server = testing::spawn(10).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    assert_eq!(client.recv_line().await.unwrap(), "READY");
+
+    client.send(b"PUT /dir/test.txt 6\nhello\n").await.unwrap();
+    assert_eq!(client.recv_line().await.unwrap(), "OK r1");
+    assert_eq!(client.recv_line().await.unwrap(), "READY");
+
+    client.send_line("GET /dir/test.txt").await.unwrap();
+    assert_eq!(client.recv_line().await.unwrap(), "OK 6");
+    assert_eq!(client.recv_line().await.unwrap(), "hello");
+    assert_eq!(client.recv_line().await.unwrap(), "READY");
+
+    client.send_line("LIST /").await.unwrap();
+    assert_eq!(client.recv_line().await.unwrap(), "OK 1");
+    assert_eq!(client.recv_line().await.unwrap(), "dir/ DIR");
+    assert_eq!(client.recv_line().await.unwrap(), "READY");
+}
+
+fn pestcontrol_msg(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut msg = vec![kind];
+    msg.extend((body.len() as u32 + 6).to_be_bytes());
+    msg.extend(body);
+    let checksum = msg.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+    msg.push(checksum.wrapping_neg());
+    msg
+}
+
+fn pestcontrol_str(data: &str) -> Vec<u8> {
+    let mut bytes = (data.len() as u32).to_be_bytes().to_vec();
+    bytes.extend(data.as_bytes());
+    bytes
+}
+
+#[tokio::test]
+async fn pestcontrol_creates_policies() {
+    let (authority_addr, authority) = MockAuthority::new()
+        .with_site(12, &[("dog", 2, 5), ("cat", 0, 3)])
+        .spawn("127.0.0.1:0")
+        .await
+        .unwrap();
+    let mut config = Config::default();
+    config.pestcontrol.authority_addr = authority_addr.to_string();
+    let server = testing::spawn_with_config(11, &config).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+
+    let mut hello = pestcontrol_str("pestcontrol");
+    hello.extend(1u32.to_be_bytes());
+    client.send(&pestcontrol_msg(0x50, &hello)).await.unwrap();
+    assert_eq!(
+        client.recv_exact(25).await.unwrap(),
+        pestcontrol_msg(0x50, &hello)
+    );
+
+    let mut visit = 12u32.to_be_bytes().to_vec();
+    visit.extend(2u32.to_be_bytes());
+    visit.extend(pestcontrol_str("dog"));
+    visit.extend(1u32.to_be_bytes());
+    visit.extend(pestcontrol_str("cat"));
+    visit.extend(2u32.to_be_bytes());
+    client.send(&pestcontrol_msg(0x58, &visit)).await.unwrap();
+
+    let mut policies = Vec::new();
+    for _ in 0..50 {
+        policies = authority.policies(12).await;
+        if !policies.is_empty() {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(policies, vec![(1, "dog".to_owned(), 0xa0)]);
+}
+
+// The first policy is only confirmed after the visit gave up on it, so the
+// audit must delete it once its late reply comes in
+#[tokio::test]
+async fn pestcontrol_audit_deletes_policies_confirmed_late() {
+    let (authority_addr, authority) = MockAuthority::new()
+        .with_default_targets(&[("fox", 0, 1)])
+        .with_late_first_policy(Duration::from_millis(450))
+        .spawn("127.0.0.1:0")
+        .await
+        .unwrap();
+    let mut config = Config::default();
+    config.pestcontrol.authority_addr = authority_addr.to_string();
+    config.pestcontrol.authority_timeout = Duration::from_millis(300);
+    config.pestcontrol.audit_interval = Some(Duration::from_millis(100));
+    let server = testing::spawn_with_config(11, &config).await.unwrap();
+    let mut visitor = SiteVisitor::connect(server.addr).await.unwrap();
+    visitor.visit(3, &[("fox", 5)]).await.unwrap();
+
+    let expected = vec![(2, "fox".to_owned(), 0x90)];
+    let mut policies = Vec::new();
+    for _ in 0..50 {
+        time::sleep(Duration::from_millis(100)).await;
+        policies = authority.policies(3).await;
+        if policies == expected {
+            break;
+        }
+    }
+    assert_eq!(policies, expected);
+}
+
+// New targets are asked on the connection of the site, and the policy
+// created for the old range is deleted once the count fits the new one
+#[tokio::test]
+async fn pestcontrol_refreshes_targets_on_the_site_connection() {
+    let (authority_addr, authority) = MockAuthority::new()
+        .with_site(3, &[("fox", 0, 1)])
+        .spawn("127.0.0.1:0")
+        .await
+        .unwrap();
+    let mut config = Config::default();
+    config.pestcontrol.authority_addr = authority_addr.to_string();
+    config.pestcontrol.targets_ttl = Some(Duration::from_millis(100));
+    let server = testing::spawn_with_config(11, &config).await.unwrap();
+    let mut visitor = SiteVisitor::connect(server.addr).await.unwrap();
+    visitor.visit(3, &[("fox", 5)]).await.unwrap();
+
+    let mut policies = Vec::new();
+    for _ in 0..50 {
+        policies = authority.policies(3).await;
+        if !policies.is_empty() {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(policies, vec![(1, "fox".to_owned(), 0x90)]);
+
+    authority.set_site(3, &[("fox", 0, 10)]).await;
+    time::sleep(Duration::from_millis(200)).await;
+    visitor.visit(3, &[("fox", 5)]).await.unwrap();
+    for _ in 0..50 {
+        policies = authority.policies(3).await;
+        if policies.is_empty() {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(policies.is_empty());
+    assert_eq!(authority.nb_connections(), 1);
+}
+
+// Visits failing because of the authority are dropped, so keep visiting until
+// the policy is created, which must then happen exactly once
+#[tokio::test]
+async fn pestcontrol_recovers_from_authority_errors() {
+    let (authority_addr, authority) = MockAuthority::new()
+        .with_default_targets(&[("fox", 0, 1)])
+        .with_latency(Duration::from_millis(10))
+        .with_errors(0.3, 42)
+        .spawn("127.0.0.1:0")
+        .await
+        .unwrap();
+    let mut config = Config::default();
+    config.pestcontrol.authority_addr = authority_addr.to_string();
+    let server = testing::spawn_with_config(11, &config).await.unwrap();
+    let mut visitor = SiteVisitor::connect(server.addr).await.unwrap();
+
+    let mut policies = Vec::new();
+    for _ in 0..100 {
+        visitor.visit(3, &[("fox", 5)]).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        policies = authority.policies(3).await;
+        if !policies.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(policies.len(), 1);
+    assert_eq!(policies[0].1, "fox");
+    assert_eq!(policies[0].2, 0x90);
+}
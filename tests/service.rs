@@ -0,0 +1,67 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use proto_hackers::service::{self, TcpService};
+use proto_hackers::{Config, ProtoError};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tower::ServiceBuilder;
+
+async fn echo(stream: &mut TcpStream, data: &[u8]) -> Vec<u8> {
+    stream.write_all(data).await.unwrap();
+    let mut buffer = vec![0; data.len()];
+    stream.read_exact(&mut buffer).await.unwrap();
+    buffer
+}
+
+async fn listen() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+#[tokio::test]
+async fn timeout_layer_closes_connections() {
+    let (listener, addr) = listen().await;
+    let service = ServiceBuilder::new()
+        .timeout(Duration::from_millis(100))
+        .service(TcpService::for_challenge(0, &Config::default()).unwrap());
+    tokio::spawn(service::serve(listener, service));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(echo(&mut stream, b"hello").await, b"hello");
+    let mut buffer = [0; 16];
+    let read = time::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+}
+
+#[tokio::test]
+async fn concurrency_limit_holds_back_clients() {
+    let (listener, addr) = listen().await;
+    let service = ServiceBuilder::new()
+        .concurrency_limit(1)
+        .service(TcpService::for_challenge(0, &Config::default()).unwrap());
+    tokio::spawn(service::serve(listener, service));
+
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(echo(&mut first, b"first").await, b"first");
+
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    let held_back = time::timeout(Duration::from_millis(200), echo(&mut second, b"second")).await;
+    assert!(held_back.is_err());
+
+    drop(first);
+    let mut buffer = [0; 6];
+    time::timeout(Duration::from_secs(5), second.read_exact(&mut buffer))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buffer, b"second");
+}
+
+#[test]
+fn udp_challenges_are_rejected() {
+    let err = TcpService::for_challenge(4, &Config::default()).err();
+    assert_eq!(err, Some(ProtoError::Message("not a TCP challenge")));
+}
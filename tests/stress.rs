@@ -0,0 +1,151 @@
+#![cfg(feature = "server_03")]
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::time::Duration;
+
+use proto_hackers::clients::ChatClient;
+use proto_hackers::testing;
+
+const NB_CLIENTS: usize = 30;
+const NB_MESSAGES: usize = 20;
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A chat member checking that the events it receives are consistent with the
+// room as it knows it: no join from a present member, no message nor leave
+// from an absent one, and each member's messages in the order they were sent
+struct Observer {
+    name: String,
+    client: ChatClient,
+    members: HashSet<String>,
+    left: HashSet<String>,
+    // Number of messages received from each member so far
+    received: HashMap<String, usize>,
+}
+
+impl Observer {
+    async fn join(addr: SocketAddr, name: String) -> Self {
+        let mut client = ChatClient::join(addr, &name).await.unwrap();
+        client.connection().set_timeout(Some(RECV_TIMEOUT));
+        let members = client.members().iter().cloned().collect();
+        Self {
+            name,
+            client,
+            members,
+            left: HashSet::new(),
+            received: HashMap::new(),
+        }
+    }
+
+    async fn observe(&mut self) {
+        let name = &self.name;
+        let Some(line) = self.client.recv().await else {
+            panic!("{name}: no event, knows {:?}", self.members);
+        };
+
+        if let Some(member) = line
+            .strip_prefix("* ")
+            .and_then(|line| line.strip_suffix(" has entered the room"))
+        {
+            assert!(member != name, "{name}: own join announced");
+            assert!(self.members.insert(member.to_owned()), "{name}: {line}");
+        } else if let Some(member) = line
+            .strip_prefix("* ")
+            .and_then(|line| line.strip_suffix(" has left the room"))
+        {
+            assert!(self.members.remove(member), "{name}: {line}");
+            self.left.insert(member.to_owned());
+        } else if let Some((member, msg)) = line
+            .strip_prefix('[')
+            .and_then(|line| line.split_once("] "))
+        {
+            assert!(member != name, "{name}: own message echoed");
+            assert!(self.members.contains(member), "{name}: {line}");
+            let count = self.received.entry(member.to_owned()).or_default();
+            assert_eq!(msg, format!("message {count}"), "{name}: out of order");
+            *count += 1;
+        } else {
+            panic!("{name}: unexpected line {line:?}");
+        }
+    }
+}
+
+fn names(range: Range<usize>) -> impl Iterator<Item = String> {
+    range.map(|i| format!("user{i:02}"))
+}
+
+// Clients join concurrently, then all send their messages at once while every
+// other one leaves right after sending. The remaining ones must end up with
+// every message, those of a leaver all arriving before its departure
+#[tokio::test]
+async fn budget_chat_orders_events() {
+    let server = testing::spawn(3).await.unwrap();
+    let addr = server.addr;
+
+    let handles: Vec<_> = names(0..NB_CLIENTS)
+        .map(|name| tokio::spawn(Observer::join(addr, name)))
+        .collect();
+    let mut observers = Vec::new();
+    for handle in handles {
+        observers.push(handle.await.unwrap());
+    }
+
+    // Every client must learn about every other one, from the room listing
+    // or from a join event
+    let handles: Vec<_> = observers
+        .into_iter()
+        .map(|mut observer| {
+            tokio::spawn(async move {
+                while observer.members.len() < NB_CLIENTS - 1 {
+                    observer.observe().await;
+                }
+                observer
+            })
+        })
+        .collect();
+    let mut observers = Vec::new();
+    for handle in handles {
+        observers.push(handle.await.unwrap());
+    }
+
+    let leavers: HashSet<String> = names(0..NB_CLIENTS).step_by(2).collect();
+    let handles: Vec<_> = observers
+        .into_iter()
+        .map(|mut observer| {
+            let leavers = leavers.clone();
+            tokio::spawn(async move {
+                for i in 0..NB_MESSAGES {
+                    observer.client.send(&format!("message {i}")).await.unwrap();
+                }
+                if leavers.contains(&observer.name) {
+                    return;
+                }
+
+                let others: Vec<String> = names(0..NB_CLIENTS)
+                    .filter(|name| *name != observer.name)
+                    .collect();
+                let done = |observer: &Observer| {
+                    observer.left == leavers
+                        && others
+                            .iter()
+                            .all(|name| observer.received.get(name) == Some(&NB_MESSAGES))
+                };
+                while !done(&observer) {
+                    observer.observe().await;
+                    for leaver in &observer.left {
+                        assert_eq!(
+                            observer.received.get(leaver),
+                            Some(&NB_MESSAGES),
+                            "{}: {leaver} left before all its messages",
+                            observer.name
+                        );
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
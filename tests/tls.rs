@@ -0,0 +1,79 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use proto_hackers::config::TlsConfig;
+use proto_hackers::{Config, ProtoError, RunOptions, run_challenge};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/tls")
+        .join(name)
+}
+
+fn tls_options() -> RunOptions {
+    let tls = TlsConfig {
+        cert_path: Some(fixture("cert.pem")),
+        key_path: Some(fixture("key.pem")),
+    };
+    RunOptions {
+        config: Config {
+            tls,
+            ..Config::default()
+        },
+        ..RunOptions::default()
+    }
+}
+
+fn connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(fixture("ca.pem")).unwrap())
+        .unwrap();
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+#[tokio::test]
+async fn tcp_server_is_served_over_tls() {
+    let server = run_challenge(1, tls_options()).await.unwrap();
+    let stream = TcpStream::connect(server.addr).await.unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut stream = connector().connect(name, stream).await.unwrap();
+
+    stream
+        .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+        .await
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await.unwrap();
+    assert_eq!(line, "{\"method\":\"isPrime\",\"prime\":true}\n");
+}
+
+#[tokio::test]
+async fn plain_clients_are_turned_away() {
+    let server = run_challenge(0, tls_options()).await.unwrap();
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    stream.write_all(b"hello\n").await.unwrap();
+    let mut line = String::new();
+    let read = BufReader::new(stream).read_line(&mut line).await;
+    assert!(read.is_err() || !line.starts_with("hello"));
+}
+
+#[tokio::test]
+async fn incomplete_tls_config_is_rejected() {
+    let mut options = tls_options();
+    options.config.tls.key_path = None;
+    let err = run_challenge(0, options).await.err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        ProtoError::Tls("missing certificate or key".into()).to_string()
+    );
+}
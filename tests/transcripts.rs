@@ -0,0 +1,132 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use proto_hackers::Config;
+use proto_hackers::testing;
+use proto_hackers::transcript::{self, ProxySession, Transcript};
+use tokio::net::TcpListener;
+use tokio::time;
+
+// Replay every `tests/transcripts/server_<part>-<name>.transcript` file against
+// a fresh server for that challenge
+#[tokio::test]
+async fn replay_golden_transcripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/transcripts");
+    let mut nb_replayed = 0;
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_stem().unwrap().to_string_lossy();
+        let part = name
+            .strip_prefix("server_")
+            .and_then(|name| name.split_once('-'))
+            .and_then(|(part, _)| part.parse().ok())
+            .unwrap_or_else(|| panic!("invalid transcript name {name}"));
+
+        let transcript = Transcript::load(&path).unwrap();
+        let server = testing::spawn(part).await.unwrap();
+        if let Err(err) = transcript::replay(server.addr, &transcript).await {
+            panic!("{name}: {err}");
+        }
+        nb_replayed += 1;
+    }
+    assert!(nb_replayed > 0);
+}
+
+// A recorded session can be replayed against another server right away
+#[tokio::test]
+async fn recorded_connection_replays() {
+    let dir = env::temp_dir().join(format!("proto_hackers-record-{}", std::process::id()));
+    let config = Config {
+        record_dir: Some(dir.clone()),
+        ..Config::default()
+    };
+    let server = testing::spawn_with_config(0, &config).await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send(b"hello\n").await.unwrap();
+    assert_eq!(client.recv_line().await.unwrap(), "hello");
+    drop(client);
+
+    // The transcript is written once the connection is closed
+    let path = dir.join("server_00-0.transcript");
+    let mut recorded = None;
+    for _ in 0..50 {
+        if let Ok(transcript) = Transcript::load(&path) {
+            recorded = Some(transcript);
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    let _ = fs::remove_dir_all(&dir);
+    let recorded = recorded.unwrap();
+    assert!(recorded.peer.unwrap().ip().is_loopback());
+    assert_eq!(recorded.events.len(), 2);
+
+    let server = testing::spawn(0).await.unwrap();
+    transcript::replay(server.addr, &recorded).await.unwrap();
+}
+
+async fn spawn_proxy(config: Config) -> (testing::TestServer, TcpListener) {
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config;
+    config.proxy.upstream_addr = upstream.local_addr().unwrap().to_string();
+    let server = testing::spawn_with_config(5, &config).await.unwrap();
+    (server, upstream)
+}
+
+// Play the recorded upstream side of `tests/fixtures/server_05-*.fixture`
+// sessions against the proxy, which has to rewrite lines as recorded
+#[tokio::test]
+async fn replay_proxy_fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut nb_replayed = 0;
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_stem().unwrap().to_string_lossy();
+        if !name.starts_with("server_05-") {
+            continue;
+        }
+        for session in ProxySession::load_all(&path).unwrap() {
+            let (server, upstream) = spawn_proxy(Config::default()).await;
+            if let Err(err) = transcript::replay_proxy(server.addr, &upstream, &session).await {
+                panic!("{name}: {err}");
+            }
+            nb_replayed += 1;
+        }
+    }
+    assert!(nb_replayed > 0);
+}
+
+#[tokio::test]
+async fn proxy_records_sessions() {
+    let path = env::temp_dir().join(format!("proto_hackers-{}.fixture", std::process::id()));
+    let mut config = Config::default();
+    config.proxy.record_path = Some(path.clone());
+    let (server, upstream) = spawn_proxy(config).await;
+
+    let text = "session\n0 < Welcome\tWelcome\n0 > Hi 7F1u3wSD5RbOHQmupo9nx4TnhQ\tHi 7YWHMfk9JZe0LM0g1ZauHuiSxhI\n";
+    let session = ProxySession::parse_all(text).unwrap().remove(0);
+    transcript::replay_proxy(server.addr, &upstream, &session)
+        .await
+        .unwrap();
+
+    // The session is written once both sides of the proxy are closed
+    let mut recorded = Vec::new();
+    for _ in 0..50 {
+        if let Ok(sessions) = ProxySession::load_all(&path) {
+            recorded = sessions;
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    let _ = fs::remove_file(&path);
+    assert_eq!(recorded.len(), 1);
+    let events = &recorded[0].events;
+    assert_eq!(events.len(), 2);
+    for (event, expected) in events.iter().zip(&session.events) {
+        assert_eq!(event.from_client, expected.from_client);
+        assert_eq!(event.original, expected.original);
+        assert_eq!(event.rewritten, expected.rewritten);
+    }
+}
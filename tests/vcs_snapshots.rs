@@ -0,0 +1,144 @@
+#![cfg(feature = "server_10")]
+
+use insta::assert_snapshot;
+use proto_hackers::clients::VcsClient;
+use proto_hackers::testing::{self, TestServer};
+
+// Store the revisions in order, checking the revision numbers returned
+async fn put_files(server: &TestServer, files: &[(&str, &str, usize)]) {
+    let mut client = VcsClient::connect(server.addr).await.unwrap();
+    for &(path, data, revision) in files {
+        assert_eq!(
+            client.put(path, data.as_bytes()).await,
+            Some(Ok(revision)),
+            "PUT {path}"
+        );
+    }
+}
+
+// Raw exchange of each request with the response lines up to READY
+async fn transcript(server: &TestServer, requests: &[&str]) -> String {
+    let mut client = server.connect().await.unwrap();
+    assert_eq!(client.recv_line().await.as_deref(), Some("READY"));
+
+    let mut output = String::new();
+    for request in requests {
+        client.send_line(request).await.unwrap();
+        output.push_str(&format!("> {request}\n"));
+        loop {
+            let line = client.recv_line().await.expect("no READY");
+            output.push_str(&format!("< {line}\n"));
+            if line == "READY" {
+                break;
+            }
+        }
+    }
+    output
+}
+
+#[tokio::test]
+async fn list_ordering() {
+    let server = testing::spawn(10).await.unwrap();
+    put_files(
+        &server,
+        &[
+            ("/b", "b\n", 1),
+            ("/a.txt", "a\n", 1),
+            ("/a/file", "a\n", 1),
+            ("/a-b", "a\n", 1),
+            ("/B", "b\n", 1),
+            ("/a.txt", "a2\n", 2),
+            ("/c/d/e", "e\n", 1),
+        ],
+    )
+    .await;
+    let output = transcript(&server, &["LIST /"]).await;
+    assert_snapshot!(output);
+}
+
+// A name used by both a file and a directory is only listed as the file
+#[tokio::test]
+async fn list_dirs_and_files_with_the_same_name() {
+    let server = testing::spawn(10).await.unwrap();
+    put_files(
+        &server,
+        &[
+            ("/dir/name", "dir\n", 1),
+            ("/name", "file\n", 1),
+            ("/dir/name/nested", "nested\n", 1),
+            ("/other/file", "file\n", 1),
+        ],
+    )
+    .await;
+    let output = transcript(&server, &["LIST /", "LIST /dir", "LIST /dir/name"]).await;
+    assert_snapshot!(output);
+}
+
+#[tokio::test]
+async fn list_trailing_slashes() {
+    let server = testing::spawn(10).await.unwrap();
+    put_files(&server, &[("/dir/sub/file", "data\n", 1)]).await;
+    let output = transcript(
+        &server,
+        &[
+            "LIST /dir",
+            "LIST /dir/",
+            "LIST /dir/sub/",
+            "LIST /missing/",
+        ],
+    )
+    .await;
+    assert_snapshot!(output);
+}
+
+#[tokio::test]
+async fn list_nested_paths() {
+    let server = testing::spawn(10).await.unwrap();
+    put_files(
+        &server,
+        &[
+            ("/a/b/c/d", "d\n", 1),
+            ("/a/b/file", "b\n", 1),
+            ("/a/b/file", "b2\n", 2),
+            ("/a/b/file", "b2\n", 2),
+            ("/a/top", "top\n", 1),
+        ],
+    )
+    .await;
+    let output = transcript(
+        &server,
+        &["LIST /a", "LIST /a/b", "LIST /a/b/c", "LIST /a/b/file"],
+    )
+    .await;
+    assert_snapshot!(output);
+}
+
+#[tokio::test]
+async fn get_revision_selection() {
+    let server = testing::spawn(10).await.unwrap();
+    put_files(
+        &server,
+        &[
+            ("/file", "one\n", 1),
+            ("/file", "two\n", 2),
+            ("/file", "two\n", 2),
+            ("/file", "three\n", 3),
+        ],
+    )
+    .await;
+    let output = transcript(
+        &server,
+        &[
+            "GET /file",
+            "GET /file r1",
+            "GET /file 2",
+            "GET /file r3",
+            "GET /file r0",
+            "GET /file r4",
+            "GET /file rev",
+            "GET /missing",
+        ],
+    )
+    .await;
+    assert_snapshot!(output);
+}